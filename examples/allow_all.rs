@@ -0,0 +1,22 @@
+//! A PAM module that lets everybody in, built as `liballow_all.so`.
+//!
+//! This is also compiled as an ordinary Rust module by `tests/module_crate.rs`,
+//! to show that a module's hooks can be tested without exporting symbols.
+
+use pam::constants::{PamFlag, PamResultCode};
+use pam::module::{PamHandle, PamHooks};
+use pam::pam_hooks;
+use std::ffi::CStr;
+
+pub struct AllowAll;
+pam_hooks!(AllowAll);
+
+impl PamHooks for AllowAll {
+    fn sm_authenticate(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+        PamResultCode::PAM_SUCCESS
+    }
+
+    fn acct_mgmt(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+        PamResultCode::PAM_SUCCESS
+    }
+}
@@ -0,0 +1,76 @@
+//! Authenticates the current user from async code, with the `tokio` feature.
+//!
+//! The transaction runs on a blocking thread, while the event loop answers
+//! its messages.  Each prompt is handed to a terminal task along with a
+//! oneshot channel, on which the task sends back the answer.  Unlike
+//! `TerminalConverse`, the terminal task here doesn't turn echo off for
+//! passwords.
+//!
+//! Run it with `cargo run --example async_login --features tokio`.
+
+use pam::client::{ChannelConverse, PamContext};
+use pam::constants::{
+    PamFlag, PamResultCode, PAM_ERROR_MSG, PAM_PROMPT_ECHO_OFF, PAM_PROMPT_ECHO_ON, PAM_TEXT_INFO,
+};
+use std::ffi::CString;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{mpsc, oneshot};
+
+/// A prompt for the terminal task, and where to send the answer.
+struct Prompt {
+    text: String,
+    answer: oneshot::Sender<Option<String>>,
+}
+
+#[tokio::main]
+async fn main() {
+    let user = std::env::var("USER").expect("USER is not set");
+    let (prompts, prompts_rx) = mpsc::channel(1);
+    tokio::spawn(terminal(prompts_rx));
+
+    let (conv, mut requests) = ChannelConverse::unbounded();
+    let login = PamContext::authenticate_async("login", Some(&user), conv, PamFlag::default());
+    // Ends when the transaction does, and drops the conversation.
+    let answer = async {
+        while let Some(request) = requests.recv().await {
+            let text = request.message().to_string_lossy().into_owned();
+            match request.style() {
+                PAM_PROMPT_ECHO_OFF | PAM_PROMPT_ECHO_ON => {
+                    let (answer, reply) = oneshot::channel();
+                    let _ = prompts.send(Prompt { text, answer }).await;
+                    let answer = reply.await.ok().flatten();
+                    let answer = answer.and_then(|answer| CString::new(answer).ok());
+                    request.respond(answer.map(Some).ok_or(PamResultCode::PAM_CONV_ERR));
+                }
+                PAM_TEXT_INFO => {
+                    println!("{text}");
+                    request.respond(Ok(None));
+                }
+                PAM_ERROR_MSG => {
+                    eprintln!("{text}");
+                    request.respond(Ok(None));
+                }
+                _ => request.respond(Err(PamResultCode::PAM_CONV_ERR)),
+            }
+        }
+    };
+    match tokio::join!(login, answer) {
+        (Ok(()), ()) => println!("Authenticated as {user}."),
+        (Err(e), ()) => {
+            eprintln!("Could not authenticate: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Shows each prompt, and answers it with a line from standard input,
+/// or with nothing at the end of the input.
+async fn terminal(mut prompts: mpsc::Receiver<Prompt>) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut stderr = tokio::io::stderr();
+    while let Some(prompt) = prompts.recv().await {
+        let _ = stderr.write_all(prompt.text.as_bytes()).await;
+        let _ = stderr.flush().await;
+        let _ = prompt.answer.send(lines.next_line().await.ok().flatten());
+    }
+}
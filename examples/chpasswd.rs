@@ -0,0 +1,65 @@
+//! Changes the current user's password, using the `passwd` service.
+//!
+//! This reads responses from standard input, so passwords will be echoed
+//! unless the terminal has been told not to.
+
+use pam::client::{Converse, PamContext};
+use pam::constants::{
+    PamFlag, PamMessageStyle, PamResultCode, PAM_ERROR_MSG, PAM_PROMPT_ECHO_OFF, PAM_PROMPT_ECHO_ON,
+};
+use pam::module::PamResult;
+use std::ffi::{CStr, CString};
+use std::io::{self, BufRead, Write};
+
+struct StdioConv;
+
+impl Converse for StdioConv {
+    fn converse(&mut self, style: PamMessageStyle, msg: &CStr) -> PamResult<Option<CString>> {
+        let msg = msg.to_string_lossy();
+        match style {
+            PAM_PROMPT_ECHO_OFF | PAM_PROMPT_ECHO_ON => {
+                print!("{msg}");
+                io::stdout()
+                    .flush()
+                    .map_err(|_| PamResultCode::PAM_CONV_ERR)?;
+                let mut line = String::new();
+                io::stdin()
+                    .lock()
+                    .read_line(&mut line)
+                    .map_err(|_| PamResultCode::PAM_CONV_ERR)?;
+                let line = line.trim_end_matches('\n');
+                CString::new(line)
+                    .map(Some)
+                    .map_err(|_| PamResultCode::PAM_CONV_ERR)
+            }
+            PAM_ERROR_MSG => {
+                eprintln!("{msg}");
+                Ok(None)
+            }
+            _ => {
+                println!("{msg}");
+                Ok(None)
+            }
+        }
+    }
+}
+
+fn main() {
+    let user = std::env::var("USER").expect("USER is not set");
+    let mut ctx = PamContext::new("passwd", Some(&user), StdioConv).expect("pam_start failed");
+    for _ in 0..3 {
+        match ctx.chauthtok(PamFlag::default()) {
+            Ok(()) => {
+                println!("Password changed.");
+                return;
+            }
+            Err(e) if e.code() == PamResultCode::PAM_TRY_AGAIN => println!("Please try again."),
+            Err(e) => {
+                eprintln!("Could not change password: {e}");
+                std::process::exit(1);
+            }
+        }
+    }
+    eprintln!("Too many attempts.");
+    std::process::exit(1);
+}
@@ -0,0 +1,22 @@
+//! Authenticates the current user on the terminal, using the `login`
+//! service and `misc_conv` from `libpam_misc`.
+//!
+//! Run it with `cargo run --example login --features misc_conv`.
+
+use pam::client::{MiscConv, PamContext};
+use pam::constants::PamFlag;
+
+fn main() {
+    let user = std::env::var("USER").expect("USER is not set");
+    let mut ctx = PamContext::new("login", Some(&user), MiscConv::new()).expect("pam_start failed");
+    let result = ctx
+        .authenticate(PamFlag::default())
+        .and_then(|()| ctx.acct_mgmt(PamFlag::default()));
+    match result {
+        Ok(()) => println!("Authenticated as {user}."),
+        Err(e) => {
+            eprintln!("Could not authenticate: {e}");
+            std::process::exit(1);
+        }
+    }
+}
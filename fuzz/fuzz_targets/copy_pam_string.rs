@@ -0,0 +1,41 @@
+//! Copies arbitrary bytes, including ones that aren't UTF-8 and ones with
+//! a nul in the middle, the way strings returned by libpam are copied.
+//!
+//! A pointer carries no length, so a string missing its nul can't be caught
+//! by the copy; libpam always terminates what it returns.  The buffer here
+//! is terminated the same way, after the fuzzed bytes, so that a copy that
+//! read past the first nul or the end would show up as a wrong result or
+//! under a sanitizer.
+
+#![no_main]
+
+use std::os::unix::ffi::OsStrExt;
+use std::ptr;
+
+use libfuzzer_sys::fuzz_target;
+use pam::constants::PamResultCode;
+use pam::fuzzing::{copy_pam_os_string, copy_pam_secret, copy_pam_string};
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = data.to_vec();
+    buf.push(0);
+    let ptr = buf.as_ptr().cast();
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    let expected = &data[..end];
+
+    assert_eq!(expected, unsafe { copy_pam_os_string(ptr) }.as_bytes());
+    let copied = unsafe { copy_pam_string(ptr) };
+    let secret = unsafe { copy_pam_secret(ptr) };
+    match std::str::from_utf8(expected) {
+        Ok(expected) => {
+            assert_eq!(Ok(expected), copied.as_deref());
+            assert_eq!(Ok(expected), secret.as_ref().map(|s| s.expose()));
+        }
+        Err(_) => {
+            assert_eq!(Err(PamResultCode::PAM_BUF_ERR), copied);
+            assert_eq!(Err(PamResultCode::PAM_BUF_ERR), secret.map(|_| ()));
+        }
+    }
+
+    assert_eq!(Ok(String::new()), unsafe { copy_pam_string(ptr::null()) });
+});
@@ -0,0 +1,57 @@
+//! Feeds `extract_argv` the sort of `argc` and `argv` a broken stack might
+//! pass: a null `argv`, null elements, and an `argc` that is negative or
+//! shorter than the array.  The arguments it returns are then parsed.
+
+#![no_main]
+
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::ptr;
+
+use libfuzzer_sys::fuzz_target;
+use pam::args::ModuleArgs;
+use pam::fuzzing::extract_argv;
+
+fuzz_target!(|input: (bool, c_int, Vec<Option<Vec<u8>>>)| {
+    let (null_argv, argc, args) = input;
+    // Each argument up to its first nul, as C would see it.
+    let args: Vec<Option<CString>> = args
+        .into_iter()
+        .map(|arg| {
+            arg.map(|bytes| {
+                let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+                CString::new(&bytes[..end]).unwrap()
+            })
+        })
+        .collect();
+    let ptrs: Vec<*const c_char> = args
+        .iter()
+        .map(|arg| arg.as_deref().map_or(ptr::null(), CStr::as_ptr))
+        .collect();
+    // Past the end of the array, there is nothing to check but UB.
+    let argc = argc.min(c_int::try_from(ptrs.len()).unwrap_or(c_int::MAX));
+    let argv = if null_argv {
+        ptr::null()
+    } else {
+        ptrs.as_ptr()
+    };
+
+    let extracted = unsafe { extract_argv(argc, argv) };
+
+    let expected: Vec<&CStr> = if null_argv {
+        Vec::new()
+    } else {
+        args.iter()
+            .take(usize::try_from(argc).unwrap_or(0))
+            .map(|arg| arg.as_deref().unwrap_or_default())
+            .collect()
+    };
+    assert_eq!(expected, extracted);
+
+    let parsed = ModuleArgs::new(&extracted);
+    assert_eq!(extracted.len(), parsed.iter_ordered().count());
+    for arg in parsed.iter_ordered() {
+        let _ = parsed.get_bytes(arg.name());
+        let _ = parsed.get_all(arg.name()).count();
+    }
+    let _ = parsed.as_os_args();
+});
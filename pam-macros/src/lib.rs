@@ -0,0 +1,150 @@
+//! The `#[pam_hooks]` attribute, which generates a PAM module's entrypoints
+//! from its `impl PamHooks` block.
+//!
+//! Use it through the `pam` crate, with its `macros` feature enabled, as
+//! [`pam::attr::pam_hooks`](../pam/attr/index.html).  The generated code
+//! refers to that crate as `::pam`.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{quote, quote_spanned};
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
+use syn::spanned::Spanned;
+use syn::{bracketed, parse_macro_input, Error, Ident, ImplItem, ItemImpl, Token, Type};
+
+/// The PAM management groups, with the hooks of each.
+const GROUPS: &[(&str, &[&str])] = &[
+    ("auth", &["sm_authenticate", "sm_setcred"]),
+    ("account", &["acct_mgmt"]),
+    ("session", &["sm_open_session", "sm_close_session"]),
+    ("password", &["sm_chauthtok"]),
+];
+
+/// The arguments to the attribute, as in
+/// `#[pam_hooks(groups = [auth], panic_code = PAM_AUTH_ERR)]`.
+#[derive(Default)]
+struct Args {
+    groups: Option<Vec<Ident>>,
+    panic_code: Option<Ident>,
+}
+
+impl Parse for Args {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = Args::default();
+        while !input.is_empty() {
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            if key == "groups" && args.groups.is_none() {
+                let content;
+                let brackets = bracketed!(content in input);
+                let groups = Punctuated::<Ident, Token![,]>::parse_terminated(&content)?;
+                if groups.is_empty() {
+                    return Err(Error::new(brackets.span.join(), "no groups to export"));
+                }
+                if let Some(unknown) = groups
+                    .iter()
+                    .find(|group| !GROUPS.iter().any(|(name, _)| *group == name))
+                {
+                    return Err(Error::new(
+                        unknown.span(),
+                        format!(
+                            "unknown PAM management group `{unknown}`; \
+                             expected `auth`, `account`, `session` or `password`"
+                        ),
+                    ));
+                }
+                args.groups = Some(groups.into_iter().collect());
+            } else if key == "panic_code" && args.panic_code.is_none() {
+                args.panic_code = Some(input.parse()?);
+            } else if key == "groups" || key == "panic_code" {
+                return Err(Error::new(key.span(), format!("`{key}` given twice")));
+            } else {
+                return Err(Error::new(
+                    key.span(),
+                    format!("unknown argument `{key}`; expected `groups` or `panic_code`"),
+                ));
+            }
+            if !input.is_empty() {
+                input.parse::<Token![,]>()?;
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Generates the `extern "C"` entrypoints for the module whose hooks are
+/// implemented by the `impl PamHooks for ...` block it is placed on.
+///
+/// This is the same as calling `pam_hooks!` on the type, except that by
+/// default only the groups the block implements a hook for are exported.
+/// See the `pam` crate's `attr` module for details.
+#[proc_macro_attribute]
+pub fn pam_hooks(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as Args);
+    let item = parse_macro_input!(item as ItemImpl);
+    let entrypoints = expand(args, &item).unwrap_or_else(Error::into_compile_error);
+    quote!(#item #entrypoints).into()
+}
+
+/// Generates the `pam_hooks!` call for `item`.
+fn expand(args: Args, item: &ItemImpl) -> syn::Result<TokenStream2> {
+    let is_pam_hooks = matches!(
+        &item.trait_,
+        Some((None, path, _)) if path.segments.last().is_some_and(|s| s.ident == "PamHooks")
+    );
+    if !is_pam_hooks {
+        return Err(Error::new(
+            item.impl_token.span,
+            "`#[pam_hooks]` must be placed on an `impl PamHooks for ...` block",
+        ));
+    }
+    if !item.generics.params.is_empty() {
+        return Err(Error::new(
+            item.generics.span(),
+            "a module's hooks can't be generic",
+        ));
+    }
+    let ident = match &*item.self_ty {
+        Type::Path(path) if path.qself.is_none() => path.path.get_ident(),
+        _ => None,
+    }
+    .ok_or_else(|| {
+        Error::new(
+            item.self_ty.span(),
+            "`#[pam_hooks]` needs a type named by a plain identifier, defined in this module",
+        )
+    })?;
+    let span = item.self_ty.span();
+    let groups = args
+        .groups
+        .unwrap_or_else(|| implemented_groups(item, span));
+    let panic_code = args
+        .panic_code
+        .unwrap_or_else(|| Ident::new("PAM_SERVICE_ERR", Span::call_site()));
+    Ok(quote_spanned! {span=>
+        ::pam::pam_hooks!(#ident, groups = [#(#groups),*], panic_code = #panic_code);
+    })
+}
+
+/// The groups with a hook implemented in `item`,
+/// or every group if it implements none.
+fn implemented_groups(item: &ItemImpl, span: Span) -> Vec<Ident> {
+    let implemented = |hook: &&str| {
+        item.items
+            .iter()
+            .any(|item| matches!(item, ImplItem::Fn(f) if f.sig.ident == hook))
+    };
+    let mut groups: Vec<&str> = GROUPS
+        .iter()
+        .filter(|(_, hooks)| hooks.iter().any(implemented))
+        .map(|(name, _)| *name)
+        .collect();
+    if groups.is_empty() {
+        groups = GROUPS.iter().map(|(name, _)| *name).collect();
+    }
+    groups
+        .into_iter()
+        .map(|name| Ident::new(name, span))
+        .collect()
+}
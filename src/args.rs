@@ -0,0 +1,304 @@
+//! Parsing of the arguments given to a module in its PAM configuration.
+
+use std::ffi::{CStr, OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+
+use crate::constants::PamResultCode;
+use crate::module::PamResult;
+
+/// The arguments passed to a hook, as `key=value` options and bare flags.
+///
+/// Arguments are kept as the bytes libpam passed in, so nothing is lost
+/// if an option is not UTF-8 (for instance, a path in a legacy encoding),
+/// and nothing is allocated.  Values are only decoded when requested with
+/// [`ModuleArgs::get`].
+///
+/// There are two ways to read them.  [`get`](Self::get),
+/// [`get_bytes`](Self::get_bytes) and [`has_flag`](Self::has_flag) look
+/// up one setting, and if an option is given more than once, the last one
+/// wins, so that an administrator can override an earlier argument.  When
+/// the order or repetition of arguments means something, as with a list of
+/// rules checked in turn (`allow=alice deny=bob deny=carol`), read them
+/// all with [`iter_ordered`](Self::iter_ordered) or
+/// [`get_all`](Self::get_all) instead.
+///
+/// ```no_run
+/// # use pam::args::ModuleArgs;
+/// # use pam::constants::{PamFlag, PamResultCode};
+/// # use pam::module::PamHandle;
+/// # use std::ffi::CStr;
+/// fn sm_authenticate(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+///     let args = ModuleArgs::new(&args);
+///     let debug = args.has_flag(b"debug");
+///     let keytab = args.get_bytes(b"keytab").unwrap_or(b"/etc/krb5.keytab");
+///     // ...
+/// #   PamResultCode::PAM_SUCCESS
+/// }
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct ModuleArgs<'a> {
+    args: &'a [&'a CStr],
+}
+
+impl<'a> ModuleArgs<'a> {
+    /// Wraps the arguments passed to a hook.
+    pub fn new(args: &'a [&'a CStr]) -> Self {
+        Self { args }
+    }
+
+    /// Iterates over the arguments in order, as `(key, value)` pairs.
+    ///
+    /// The value is `None` for an argument with no `=`.
+    pub fn iter(&self) -> impl Iterator<Item = (&'a [u8], Option<&'a [u8]>)> + 'a {
+        self.args.iter().map(|arg| split(arg.to_bytes()))
+    }
+
+    /// Iterates over the arguments in the order they were given,
+    /// repetitions included, as flags and options.
+    ///
+    /// ```no_run
+    /// # use pam::args::{Arg, ModuleArgs};
+    /// # use std::ffi::CStr;
+    /// /// Checks `allow=` and `deny=` rules in order; the first match wins.
+    /// fn is_allowed(args: &ModuleArgs, user: &str) -> bool {
+    ///     for arg in args.iter_ordered() {
+    ///         match arg {
+    ///             Arg::KeyValue { key: b"allow", value } if value == user.as_bytes() => return true,
+    ///             Arg::KeyValue { key: b"deny", value } if value == user.as_bytes() => return false,
+    ///             _ => {}
+    ///         }
+    ///     }
+    ///     true
+    /// }
+    /// ```
+    pub fn iter_ordered(&self) -> impl Iterator<Item = Arg<'a>> + 'a {
+        self.iter().map(|(key, value)| match value {
+            Some(value) => Arg::KeyValue { key, value },
+            None => Arg::Flag(key),
+        })
+    }
+
+    /// Returns the raw values of every `key=value` option, in order.
+    pub fn get_all<'k>(&self, key: &'k [u8]) -> impl Iterator<Item = &'a [u8]> + 'k
+    where
+        'a: 'k,
+    {
+        self.iter()
+            .filter(move |&(k, _)| k == key)
+            .filter_map(|(_, v)| v)
+    }
+
+    /// Returns the raw value of the last `key=value` option.
+    pub fn get_bytes(&self, key: &[u8]) -> Option<&'a [u8]> {
+        self.iter()
+            .filter(|&(k, _)| k == key)
+            .filter_map(|(_, v)| v)
+            .last()
+    }
+
+    /// Returns the value of the last `key=value` option, as a string.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_SERVICE_ERR` (the module is misconfigured)
+    /// if the value is not valid UTF-8.
+    pub fn get(&self, key: &str) -> PamResult<Option<&'a str>> {
+        self.get_bytes(key.as_bytes())
+            .map(|value| std::str::from_utf8(value).map_err(|_| PamResultCode::PAM_SERVICE_ERR))
+            .transpose()
+    }
+
+    /// True if `name` was passed as a bare argument, with no `=`.
+    pub fn has_flag(&self, name: &[u8]) -> bool {
+        self.iter().any(|(k, v)| k == name && v.is_none())
+    }
+
+    /// The arguments as a command line, for parsing with a crate like `clap`.
+    ///
+    /// Parsers expect the program name first, so the arguments are preceded
+    /// by [`ARGV0`], which isn't one of them.  They are otherwise passed
+    /// verbatim, so a module parsed this way is configured with options
+    /// in the parser's syntax, like `pam_example.so --retry=3 --debug`.
+    ///
+    /// ```no_run
+    /// # use pam::args::ModuleArgs;
+    /// # use std::ffi::CStr;
+    /// # fn parse(args: Vec<&CStr>) -> Result<(), clap::Error> {
+    /// let matches = clap::Command::new("pam_example")
+    ///     .arg(clap::Arg::new("retry").long("retry"))
+    ///     .try_get_matches_from(ModuleArgs::new(&args).as_os_args())?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn as_os_args(&self) -> Vec<OsString> {
+        std::iter::once(OsStr::new(ARGV0))
+            .chain(
+                self.args
+                    .iter()
+                    .map(|arg| OsStr::from_bytes(arg.to_bytes())),
+            )
+            .map(OsStr::to_owned)
+            .collect()
+    }
+}
+
+/// One argument, from [`ModuleArgs::iter_ordered`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Arg<'a> {
+    /// A bare argument, with no `=`, like `debug`.
+    Flag(&'a [u8]),
+    /// An option, like `deny=bob`.  The value may be empty, as for `deny=`.
+    KeyValue {
+        /// The part before the first `=`.
+        key: &'a [u8],
+        /// The part after the first `=`.
+        value: &'a [u8],
+    },
+}
+
+impl<'a> Arg<'a> {
+    /// The flag's name, or the option's key.
+    pub fn name(&self) -> &'a [u8] {
+        match *self {
+            Arg::Flag(name) | Arg::KeyValue { key: name, .. } => name,
+        }
+    }
+}
+
+/// The program name that [`ModuleArgs::as_os_args`] puts before the arguments.
+pub const ARGV0: &str = "pam_module";
+
+fn split(arg: &[u8]) -> (&[u8], Option<&[u8]>) {
+    match arg.iter().position(|&b| b == b'=') {
+        Some(eq) => (&arg[..eq], Some(&arg[eq + 1..])),
+        None => (arg, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_and_flags() {
+        let raw = [
+            c"debug",
+            c"keytab=/etc/one",
+            c"realm=EXAMPLE.COM",
+            c"keytab=/etc/two",
+            c"empty=",
+        ];
+        let args = ModuleArgs::new(&raw);
+        assert!(args.has_flag(b"debug"));
+        assert!(!args.has_flag(b"keytab"));
+        assert_eq!(Some(&b"/etc/two"[..]), args.get_bytes(b"keytab"));
+        assert_eq!(Ok(Some("EXAMPLE.COM")), args.get("realm"));
+        assert_eq!(Ok(Some("")), args.get("empty"));
+        assert_eq!(Ok(None), args.get("debug"));
+        assert_eq!(Ok(None), args.get("missing"));
+        assert_eq!(5, args.iter().count());
+    }
+
+    #[test]
+    fn ordered_with_repeats() {
+        let raw = [
+            c"deny=bob",
+            c"allow=alice",
+            c"debug",
+            c"deny=carol",
+            c"deny=",
+            c"debug",
+            c"rule=a=b",
+        ];
+        let args = ModuleArgs::new(&raw);
+        let ordered: Vec<_> = args.iter_ordered().collect();
+        assert_eq!(
+            vec![
+                Arg::KeyValue {
+                    key: b"deny",
+                    value: b"bob"
+                },
+                Arg::KeyValue {
+                    key: b"allow",
+                    value: b"alice"
+                },
+                Arg::Flag(b"debug"),
+                Arg::KeyValue {
+                    key: b"deny",
+                    value: b"carol"
+                },
+                Arg::KeyValue {
+                    key: b"deny",
+                    value: b""
+                },
+                Arg::Flag(b"debug"),
+                Arg::KeyValue {
+                    key: b"rule",
+                    value: b"a=b"
+                },
+            ],
+            ordered
+        );
+        assert_eq!(
+            vec![
+                &b"deny"[..],
+                b"allow",
+                b"debug",
+                b"deny",
+                b"deny",
+                b"debug",
+                b"rule"
+            ],
+            ordered.iter().map(Arg::name).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![&b"bob"[..], b"carol", b""],
+            args.get_all(b"deny").collect::<Vec<_>>()
+        );
+        assert_eq!(0, args.get_all(b"debug").count());
+        assert_eq!(Some(&b""[..]), args.get_bytes(b"deny"));
+    }
+
+    #[test]
+    fn parse_with_clap() {
+        use clap::{Arg, ArgAction, Command};
+
+        let raw = [c"--retry=3", c"--debug", c"--dir", c"/home/jos\xe9"];
+        let args = ModuleArgs::new(&raw);
+        assert_eq!(ARGV0, args.as_os_args()[0]);
+        let matches = Command::new("pam_test")
+            .arg(Arg::new("retry").long("retry"))
+            .arg(Arg::new("debug").long("debug").action(ArgAction::SetTrue))
+            .arg(
+                Arg::new("dir")
+                    .long("dir")
+                    .value_parser(clap::value_parser!(OsString)),
+            )
+            .try_get_matches_from(args.as_os_args())
+            .unwrap();
+        assert_eq!(Some(&"3".to_owned()), matches.get_one::<String>("retry"));
+        assert!(matches.get_flag("debug"));
+        assert_eq!(
+            Some(OsStr::from_bytes(b"/home/jos\xe9")),
+            matches.get_one::<OsString>("dir").map(OsString::as_os_str)
+        );
+
+        let raw = [c"retry=3"];
+        let matches =
+            Command::new("pam_test").try_get_matches_from(ModuleArgs::new(&raw).as_os_args());
+        assert!(matches.is_err());
+    }
+
+    #[test]
+    fn non_utf8_value() {
+        // "dir=/home/jos\xe9" in Latin-1.
+        let raw = [c"dir=/home/jos\xe9"];
+        let args = ModuleArgs::new(&raw);
+        assert_eq!(Some(&b"/home/jos\xe9"[..]), args.get_bytes(b"dir"));
+        assert_eq!(Err(PamResultCode::PAM_SERVICE_ERR), args.get("dir"));
+        assert_eq!(
+            vec![(&b"dir"[..], Some(&b"/home/jos\xe9"[..]))],
+            args.iter().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! The `#[pam_hooks]` attribute, with the `macros` feature.
+//!
+//! Placed on a type's `impl PamHooks` block, it generates the module's
+//! entrypoints just as [`pam_hooks!`](crate::pam_hooks) does for the type,
+//! including catching panics and defining `PAM_HOOKS_EXPORTED`.  The
+//! differences are:
+//!
+//! - Only the management groups that the block implements a hook for are
+//!   exported, as if they were passed to `pam_hooks!` as `groups`.  A block
+//!   that implements only `sm_open_session`, say, exports just
+//!   `pam_sm_open_session` and `pam_sm_close_session`.  A block that
+//!   implements no hooks exports them all.
+//! - Mistakes, like an unknown group or a type the entrypoints can't name,
+//!   are reported where they are in the source.
+//!
+//! It takes the same options as `pam_hooks!`, either of which may be left
+//! out: `#[pam_hooks(groups = [auth, account], panic_code = PAM_AUTH_ERR)]`.
+//!
+//! ```
+//! use pam::attr::pam_hooks;
+//! use pam::constants::{PamFlag, PamResultCode};
+//! use pam::module::{PamHandle, PamHooks};
+//! use std::ffi::CStr;
+//!
+//! struct Motd;
+//!
+//! #[pam_hooks]
+//! impl PamHooks for Motd {
+//!     fn sm_open_session(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+//!         PamResultCode::PAM_SUCCESS
+//!     }
+//! }
+//!
+//! # fn main() {
+//! assert_eq!(&["pam_sm_open_session", "pam_sm_close_session"], PAM_HOOKS_EXPORTED);
+//! # }
+//! ```
+//!
+//! ```compile_fail
+//! # use pam::attr::pam_hooks;
+//! # use pam::module::PamHooks;
+//! struct Motd;
+//!
+//! #[pam_hooks(groups = [sessions])]
+//! impl PamHooks for Motd {}
+//! # fn main() {}
+//! ```
+//!
+//! The generated code refers to this crate as `pam`, its library name,
+//! so it can't be renamed in `Cargo.toml`.  Those who would rather not
+//! depend on a procedural macro can keep using `pam_hooks!`.
+
+pub use pam_macros::pam_hooks;
+
+#[cfg(test)]
+mod tests {
+    pub mod account {
+        use crate::constants::{PamFlag, PamResultCode};
+        use crate::mock::MockPam;
+        use crate::module::{PamHandle, PamHooks};
+        use std::ffi::CStr;
+
+        struct Expired;
+
+        #[super::super::pam_hooks(panic_code = PAM_ACCT_EXPIRED)]
+        impl PamHooks for Expired {
+            fn acct_mgmt(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+                panic!("no expiry data")
+            }
+        }
+
+        #[test]
+        fn panic_code_is_returned() {
+            assert_eq!(&["pam_sm_acct_mgmt"], PAM_HOOKS_EXPORTED);
+            let mut mock = MockPam::new();
+            assert_eq!(
+                PamResultCode::PAM_ACCT_EXPIRED,
+                pam_sm_acct_mgmt(mock.handle(), PamFlag::default(), 0, std::ptr::null())
+            );
+            #[cfg(not(feature = "tracing"))]
+            assert_eq!(
+                vec![(libc::LOG_CRIT, "acct_mgmt panicked".to_owned())],
+                crate::mock::take_logged()
+            );
+        }
+    }
+
+    pub mod session {
+        use crate::constants::{PamFlag, PamResultCode};
+        use crate::mock::MockPam;
+        use crate::module::{PamHandle, PamHooks};
+        use std::ffi::CStr;
+
+        struct Motd;
+
+        #[super::super::pam_hooks]
+        impl PamHooks for Motd {
+            fn sm_open_session(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+                PamResultCode::PAM_SUCCESS
+            }
+        }
+
+        #[test]
+        fn group_of_implemented_hook_is_exported() {
+            assert_eq!(
+                &["pam_sm_open_session", "pam_sm_close_session"],
+                PAM_HOOKS_EXPORTED
+            );
+            let mut mock = MockPam::new();
+            let flags = PamFlag::default();
+            let null = std::ptr::null();
+            assert_eq!(
+                PamResultCode::PAM_SUCCESS,
+                pam_sm_open_session(mock.handle(), flags, 0, null)
+            );
+            assert_eq!(
+                PamResultCode::PAM_IGNORE,
+                pam_sm_close_session(mock.handle(), flags, 0, null)
+            );
+        }
+    }
+}
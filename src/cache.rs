@@ -0,0 +1,299 @@
+//! Module data that expires, for remembering things like recent failures.
+
+use std::any::Any;
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+use crate::constants::PamResultCode;
+use crate::module::{PamHandle, PamResult};
+
+/// A source of monotonic timestamps for a [`TtlCache`].
+///
+/// Modules use [`MonotonicClock`]; tests can supply a clock they advance
+/// by hand, rather than sleeping until an entry expires.
+pub trait Clock {
+    /// The current time.
+    fn now(&self) -> Instant;
+}
+
+/// The system's monotonic clock, as read by [`Instant::now`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+/// A value stored as module data under a key, which is treated as absent
+/// once `ttl` has passed since it was inserted.
+///
+/// This is the usual way to "remember failures for N seconds": the first
+/// failure inserts a count, later ones [`update`](Self::update) it without
+/// restarting the clock, and once the time is up, the count is gone.
+///
+/// ```no_run
+/// # use pam::cache::TtlCache;
+/// # use pam::constants::{PamFlag, PamResultCode};
+/// # use pam::module::{PamHandle, PamResult};
+/// # use std::time::Duration;
+/// # fn check_password(_: &mut PamHandle) -> bool { false }
+/// // SAFETY: Nothing else in the stack stores data under this key.
+/// const FAILURES: TtlCache<u32> =
+///     unsafe { TtlCache::new("pam_example.failures", Duration::from_secs(30)) };
+///
+/// fn authenticate(pamh: &mut PamHandle) -> PamResult<()> {
+///     if FAILURES.get(pamh)?.is_some_and(|&failures| failures >= 3) {
+///         return Err(PamResultCode::PAM_MAXTRIES);
+///     }
+///     if check_password(pamh) {
+///         return Ok(());
+///     }
+///     if FAILURES.update(pamh, |failures| *failures += 1)?.is_none() {
+///         FAILURES.insert(pamh, 1)?;
+///     }
+///     Err(PamResultCode::PAM_AUTH_ERR)
+/// }
+/// ```
+///
+/// Like any module data, the value only lives as long as the handle: libpam
+/// drops it at `pam_end`.  It carries over between the hooks an application
+/// calls on one transaction, such as retried calls to `pam_authenticate`,
+/// but not to the next login, which gets a new handle, often in a new
+/// process.  Limits that must hold across logins need persistent storage,
+/// like the tally files of `pam_faillock`.
+///
+/// An expired value is not dropped until it is replaced or the handle ends.
+///
+/// Module data keys are shared by every module in the stack, so a key should
+/// be specific to its module, as in `"pam_example.failures"`, and must not
+/// be used to store anything but the cache's value; see [`TtlCache::new`].
+pub struct TtlCache<T, C = MonotonicClock> {
+    key: &'static str,
+    ttl: Duration,
+    clock: C,
+    _value: PhantomData<fn() -> T>,
+}
+
+/// What is stored under the key, behind a `Box<dyn Any>` so that reading it
+/// as the wrong type fails rather than being undefined behavior.
+struct Entry<T> {
+    value: T,
+    inserted: Instant,
+}
+
+impl<T: 'static> TtlCache<T> {
+    /// Creates a cache that stores its value under `key`, for `ttl`.
+    ///
+    /// # Safety
+    ///
+    /// Nothing but a `TtlCache` may store data under `key`, not even another
+    /// module in the stack, since the cache reads whatever is there as its
+    /// own entry.  A `TtlCache` of another value type is fine: reading its
+    /// entry fails with `PAM_SYSTEM_ERR`.
+    pub const unsafe fn new(key: &'static str, ttl: Duration) -> Self {
+        Self::with_clock(key, ttl, MonotonicClock)
+    }
+}
+
+impl<T: 'static, C: Clock> TtlCache<T, C> {
+    /// Creates a cache like [`new`](TtlCache::new), which reads the time
+    /// from `clock`.
+    ///
+    /// # Safety
+    ///
+    /// As for [`new`](TtlCache::new).
+    pub const unsafe fn with_clock(key: &'static str, ttl: Duration, clock: C) -> Self {
+        Self {
+            key,
+            ttl,
+            clock,
+            _value: PhantomData,
+        }
+    }
+
+    /// How long a value lasts.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Gets the value, or `None` if there is none or it has expired.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_SYSTEM_ERR` if a `TtlCache` of another type stored the
+    /// value, or an error if the underlying PAM function call fails.
+    pub fn get<'a>(&self, pamh: &'a PamHandle) -> PamResult<Option<&'a T>> {
+        Ok(self.entry(pamh)?.map(|entry| &entry.value))
+    }
+
+    /// How long until the value expires, or `None` if there is no value or
+    /// it has expired.
+    ///
+    /// # Errors
+    ///
+    /// As for [`get`](Self::get).
+    pub fn remaining(&self, pamh: &PamHandle) -> PamResult<Option<Duration>> {
+        let now = self.clock.now();
+        Ok(self.entry(pamh)?.map(|entry| {
+            self.ttl
+                .saturating_sub(now.saturating_duration_since(entry.inserted))
+        }))
+    }
+
+    /// Stores `value`, replacing any value there was, and starts its time
+    /// to live over.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails.
+    pub fn insert(&self, pamh: &mut PamHandle, value: T) -> PamResult<()> {
+        let entry: Box<dyn Any> = Box::new(Entry {
+            value,
+            inserted: self.clock.now(),
+        });
+        pamh.set_data(self.key, Box::new(entry))
+    }
+
+    /// Modifies the value with `f`, without starting its time to live over,
+    /// and returns the result of `f`.  If there is no value or it has expired,
+    /// `f` isn't called, and this returns `None`.
+    ///
+    /// # Errors
+    ///
+    /// As for [`get`](Self::get).
+    pub fn update<R>(
+        &self,
+        pamh: &mut PamHandle,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> PamResult<Option<R>> {
+        let now = self.clock.now();
+        // SAFETY: Per the contract of `new`, only a `TtlCache` stores data
+        // under its key, always as a `Box<dyn Any>`.
+        let update = unsafe {
+            pamh.update_data(self.key, |entry: &mut Box<dyn Any>| {
+                let entry = entry.downcast_mut::<Entry<T>>()?;
+                Some(self.is_live(entry, now).then(|| f(&mut entry.value)))
+            })
+        };
+        match update {
+            Ok(Some(result)) => Ok(result),
+            Ok(None) => Err(PamResultCode::PAM_SYSTEM_ERR),
+            Err(PamResultCode::PAM_NO_MODULE_DATA) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The stored entry, if it hasn't expired.
+    fn entry<'a>(&self, pamh: &'a PamHandle) -> PamResult<Option<&'a Entry<T>>> {
+        // SAFETY: Per the contract of `new`, only a `TtlCache` stores data
+        // under its key, always as a `Box<dyn Any>`.
+        let entry = match unsafe { pamh.get_data::<Box<dyn Any>>(self.key) } {
+            Ok(entry) => entry,
+            Err(PamResultCode::PAM_NO_MODULE_DATA) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let entry = entry
+            .downcast_ref::<Entry<T>>()
+            .ok_or(PamResultCode::PAM_SYSTEM_ERR)?;
+        Ok(self.is_live(entry, self.clock.now()).then_some(entry))
+    }
+
+    fn is_live(&self, entry: &Entry<T>, now: Instant) -> bool {
+        now.saturating_duration_since(entry.inserted) < self.ttl
+    }
+}
+
+impl<T, C: fmt::Debug> fmt::Debug for TtlCache<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TtlCache")
+            .field("key", &self.key)
+            .field("ttl", &self.ttl)
+            .field("clock", &self.clock)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockPam;
+    use std::cell::Cell;
+
+    /// A clock that only moves when told to.
+    struct FakeClock(Cell<Instant>);
+
+    impl FakeClock {
+        fn new() -> Self {
+            Self(Cell::new(Instant::now()))
+        }
+
+        fn advance(&self, by: Duration) {
+            self.0.set(self.0.get() + by);
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        let clock = FakeClock::new();
+        let cache =
+            unsafe { TtlCache::<u32, _>::with_clock("failures", Duration::from_secs(30), &clock) };
+
+        assert_eq!(Ok(None), cache.get(pamh));
+        assert_eq!(Ok(None), cache.update(pamh, |n| *n += 1));
+        cache.insert(pamh, 1).unwrap();
+        assert_eq!(Ok(Some(&1)), cache.get(pamh));
+
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(
+            Ok(Some(2)),
+            cache.update(pamh, |n| {
+                *n += 1;
+                *n
+            })
+        );
+        assert_eq!(Ok(Some(Duration::from_secs(10))), cache.remaining(pamh));
+
+        // Updating didn't restart the clock.
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(Ok(None), cache.get(pamh));
+        assert_eq!(Ok(None), cache.remaining(pamh));
+        assert_eq!(Ok(None), cache.update(pamh, |_| panic!("expired")));
+
+        cache.insert(pamh, 5).unwrap();
+        clock.advance(Duration::from_secs(29));
+        assert_eq!(Ok(Some(&5)), cache.get(pamh));
+    }
+
+    /// Two caches sharing a key is allowed, unlike anything else using it.
+    #[test]
+    fn cache_of_other_type_is_an_error() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        unsafe { TtlCache::<u32>::new("shared", Duration::from_secs(1)) }
+            .insert(pamh, 1)
+            .unwrap();
+        let strings = unsafe { TtlCache::<String>::new("shared", Duration::from_secs(1)) };
+        assert_eq!(Err(PamResultCode::PAM_SYSTEM_ERR), strings.get(pamh));
+        assert_eq!(
+            Err(PamResultCode::PAM_SYSTEM_ERR),
+            strings.update(pamh, |s| s.clear())
+        );
+    }
+}
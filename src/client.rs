@@ -0,0 +1,1900 @@
+//! Functions for use in pam applications, which start pam transactions.
+//!
+//! An application starts a transaction by creating a [`PamContext`] for its
+//! service, providing a [`Converse`] implementation that relays messages
+//! from modules to the user.
+//!
+//! Its methods fail with a [`pam::Error`](crate::Error), which says which
+//! operation failed and for which service along with the result code.
+//!
+//! libpam's calls block until the whole stack is done, including while the
+//! modules wait for the user to answer.  An application built around an
+//! event loop should run the transaction on a thread of its own, and answer
+//! the conversation from the loop with a [`ChannelConverse`].  With the
+//! `tokio` feature, [`PamContext::authenticate_async`] does this on a
+//! `tokio` runtime.
+
+use libc::{c_char, c_int};
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr::{self, NonNull};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use crate::constants::{
+    PamFlag, PamMessageStyle, PamResultCode, PAM_CHANGE_EXPIRED_AUTHTOK, PAM_ERROR_MSG,
+    PAM_MAX_NUM_MSG, PAM_PROMPT_ECHO_OFF, PAM_PROMPT_ECHO_ON, PAM_TEXT_INFO,
+};
+use crate::conv::{Inner, PamMessage, PamResponse};
+use crate::error::{Error, Operation};
+use crate::items::{FromRaw, IntoRaw, ItemType};
+use crate::module::{to_c_string, PamHandle, PamResult};
+
+#[cfg_attr(not(test), link(name = "pam"))]
+extern "C" {
+    fn pam_start(
+        service_name: *const c_char,
+        user: *const c_char,
+        pam_conversation: *const Inner,
+        pamh: &mut *mut PamHandle,
+    ) -> c_int;
+
+    fn pam_end(pamh: *mut PamHandle, pam_status: c_int) -> c_int;
+
+    fn pam_acct_mgmt(pamh: *mut PamHandle, flags: PamFlag) -> c_int;
+
+    fn pam_authenticate(pamh: *mut PamHandle, flags: PamFlag) -> c_int;
+
+    fn pam_chauthtok(pamh: *mut PamHandle, flags: PamFlag) -> c_int;
+}
+
+/// The application's side of a conversation with pam modules.
+///
+/// Modules send messages, which may be prompts (`PAM_PROMPT_ECHO_OFF`,
+/// `PAM_PROMPT_ECHO_ON`) that need a response from the user, or notices
+/// (`PAM_TEXT_INFO`, `PAM_ERROR_MSG`) that should just be shown.
+pub trait Converse {
+    /// Handles a single message from a module.
+    ///
+    /// For a prompt, this should return the user's response.  For a notice,
+    /// it should return `Ok(None)`.
+    ///
+    /// # Errors
+    ///
+    /// Returning an error (typically `PAM_CONV_ERR`) aborts the conversation.
+    /// Returning `PAM_CONV_AGAIN` means the answer isn't available yet;
+    /// see [`PamContext::resume`].
+    fn converse(&mut self, style: PamMessageStyle, msg: &CStr) -> PamResult<Option<CString>>;
+}
+
+/// How a [`PamContext::login`] attempt ended, when it got as far as an answer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoginOutcome {
+    /// The user was authenticated, and their account is valid.
+    Success,
+    /// The user was authenticated, and their expired token was changed.
+    TokenChanged,
+    /// The user could not be authenticated (`PAM_AUTH_ERR` or
+    /// `PAM_MAXTRIES`) in the number of tries allowed.
+    AuthFailed,
+    /// The modules couldn't reach what they authenticate against, such as
+    /// a directory server (`PAM_AUTHINFO_UNAVAIL`).
+    ///
+    /// This says nothing about the user's password, so the application
+    /// shouldn't report it as wrong.  It should say that authentication is
+    /// unavailable, and, if it has one, fall back to another source.
+    AuthInfoUnavailable,
+    /// The modules don't know the user (`PAM_USER_UNKNOWN`).
+    UserUnknown,
+    /// The user's account has expired (`PAM_ACCT_EXPIRED`).
+    AccountExpired,
+    /// The user may not log in at this time (`PAM_PERM_DENIED`).
+    PermissionDenied,
+    /// The user's token had expired, and changing it failed.
+    TokenChangeFailed {
+        /// The code `pam_chauthtok` failed with.
+        code: PamResultCode,
+        /// Why, if a module said (see [`PamContext::error_message`]).
+        reason: Option<String>,
+    },
+}
+
+/// The type of a conversation function written in C, as found in
+/// `struct pam_conv`:
+///
+/// ```c
+/// int (*conv)(int num_msg, const struct pam_message **msg,
+///             struct pam_response **resp, void *appdata_ptr);
+/// ```
+///
+/// The function is given `num_msg` pointers to messages, and must set `*resp`
+/// to a `malloc`ed array of `num_msg` responses whose `resp` strings are also
+/// `malloc`ed (or null), returning `PAM_SUCCESS`.  On failure, it must not
+/// allocate anything, and return an error such as `PAM_CONV_ERR`.
+pub type RawConvFn = unsafe extern "C" fn(
+    num_msg: c_int,
+    msg: *const *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut libc::c_void,
+) -> c_int;
+
+/// A non-null [`RawConvFn`], with the same representation.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug)]
+pub struct ConvCallback(RawConvFn);
+
+impl ConvCallback {
+    /// Wraps a conversation function pointer obtained from C, which may
+    /// be null (`None`).
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_CONV_ERR` if `conv` is null.
+    pub fn new(conv: Option<RawConvFn>) -> PamResult<Self> {
+        conv.map(Self).ok_or(PamResultCode::PAM_CONV_ERR)
+    }
+
+    /// The function pointer.
+    pub fn get(self) -> RawConvFn {
+        self.0
+    }
+}
+
+/// A [`Converse`] implementation that calls a conversation function
+/// written in C, so that it can be used with a [`PamContext`].
+///
+/// Each message is passed to the function on its own.
+#[derive(Debug)]
+pub struct RawConverse {
+    callback: ConvCallback,
+    appdata_ptr: *mut libc::c_void,
+}
+
+impl RawConverse {
+    /// Wraps `callback`, which will be passed `appdata_ptr` on every call.
+    ///
+    /// # Safety
+    ///
+    /// `callback` must follow the contract described in [`RawConvFn`],
+    /// and be safe to call with `appdata_ptr` for as long as this exists.
+    pub unsafe fn new(callback: ConvCallback, appdata_ptr: *mut libc::c_void) -> Self {
+        Self {
+            callback,
+            appdata_ptr,
+        }
+    }
+}
+
+impl Converse for RawConverse {
+    fn converse(&mut self, style: PamMessageStyle, msg: &CStr) -> PamResult<Option<CString>> {
+        let message = PamMessage {
+            msg_style: style,
+            msg: msg.as_ptr(),
+        };
+        let message_ptr: *const PamMessage = &message;
+        let mut resp: *mut PamResponse = ptr::null_mut();
+        let res = unsafe { (self.callback.0)(1, &message_ptr, &mut resp, self.appdata_ptr) };
+        let res = PamResultCode::try_from(res).unwrap_or(PamResultCode::PAM_CONV_ERR);
+        res.into_result()?;
+        if resp.is_null() {
+            return Err(PamResultCode::PAM_CONV_ERR);
+        }
+        unsafe {
+            let answer = (*resp).resp;
+            let owned = (!answer.is_null()).then(|| CStr::from_ptr(answer).to_owned());
+            libc::free(answer.cast());
+            libc::free(resp.cast());
+            Ok(owned)
+        }
+    }
+}
+
+#[cfg(feature = "misc_conv")]
+#[link(name = "pam_misc")]
+extern "C" {
+    fn misc_conv(
+        num_msg: c_int,
+        msg: *const *const PamMessage,
+        resp: *mut *mut PamResponse,
+        appdata_ptr: *mut libc::c_void,
+    ) -> c_int;
+}
+
+/// A [`Converse`] implementation that talks to the user on a terminal with
+/// `misc_conv`, the conversation function in `libpam_misc`.
+///
+/// This needs the `misc_conv` feature, which links `libpam_misc`.
+/// [`TerminalConverse`] does much the same without it.
+///
+/// See the [`misc_conv` manual page](
+/// https://www.man7.org/linux/man-pages/man3/misc_conv.3.html).
+///
+/// ```no_run
+/// # use pam::client::{MiscConv, PamContext};
+/// let mut ctx = PamContext::new("login", None, MiscConv::new()).unwrap();
+/// ```
+#[cfg(feature = "misc_conv")]
+#[derive(Debug)]
+pub struct MiscConv(RawConverse);
+
+#[cfg(feature = "misc_conv")]
+impl MiscConv {
+    /// Creates a conversation that calls `misc_conv`.
+    pub fn new() -> Self {
+        let callback = ConvCallback(misc_conv);
+        // SAFETY: `misc_conv` follows the usual contract, and ignores `appdata_ptr`.
+        Self(unsafe { RawConverse::new(callback, ptr::null_mut()) })
+    }
+}
+
+#[cfg(feature = "misc_conv")]
+impl Default for MiscConv {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "misc_conv")]
+impl Converse for MiscConv {
+    fn converse(&mut self, style: PamMessageStyle, msg: &CStr) -> PamResult<Option<CString>> {
+        self.0.converse(style, msg)
+    }
+}
+
+/// A [`Converse`] implementation that gives up on the user if they take
+/// too long to answer.
+///
+/// libpam has no timeout of its own, so a module waiting for an answer
+/// (e.g. in `pam_get_authtok`) waits forever if the user has walked away or
+/// their connection has silently dropped.  This runs the wrapped
+/// conversation on its own thread, and if a message isn't handled within
+/// the timeout, fails it with `PAM_CONV_ERR`, which modules generally pass
+/// on (or turn into `PAM_AUTH_ERR`).  From then on, every message fails
+/// immediately, since the wrapped conversation is still stuck.
+///
+/// The stuck call can't be interrupted: its thread is left to finish (or
+/// not) on its own.  This is only possible on the application's side.
+/// A module has no portable way to interrupt the application's conversation
+/// function, and must not try to leave it running on another thread.
+///
+/// ```no_run
+/// # use pam::client::{Converse, PamContext, TimeoutConverse};
+/// # use std::time::Duration;
+/// # fn f(conv: impl Converse + Send + 'static) {
+/// let conv = TimeoutConverse::new(conv, Duration::from_secs(60));
+/// let mut ctx = PamContext::new("screensaver", Some("alice"), conv).unwrap();
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct TimeoutConverse {
+    requests: mpsc::Sender<(PamMessageStyle, CString)>,
+    responses: mpsc::Receiver<PamResult<Option<CString>>>,
+    timeout: Duration,
+    expired: bool,
+}
+
+impl TimeoutConverse {
+    /// Starts a thread to run `conv` on, answering each message within `timeout`.
+    pub fn new<C: Converse + Send + 'static>(mut conv: C, timeout: Duration) -> Self {
+        let (requests, request_rx) = mpsc::channel::<(PamMessageStyle, CString)>();
+        let (response_tx, responses) = mpsc::channel();
+        thread::spawn(move || {
+            for (style, msg) in request_rx {
+                let result = catch_unwind(AssertUnwindSafe(|| conv.converse(style, &msg)))
+                    .unwrap_or(Err(PamResultCode::PAM_CONV_ERR));
+                if response_tx.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            requests,
+            responses,
+            timeout,
+            expired: false,
+        }
+    }
+}
+
+impl Converse for TimeoutConverse {
+    fn converse(&mut self, style: PamMessageStyle, msg: &CStr) -> PamResult<Option<CString>> {
+        if self.expired || self.requests.send((style, msg.to_owned())).is_err() {
+            return Err(PamResultCode::PAM_CONV_ERR);
+        }
+        match self.responses.recv_timeout(self.timeout) {
+            Ok(result) => result,
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                self.expired = true;
+                Err(PamResultCode::PAM_CONV_ERR)
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(PamResultCode::PAM_CONV_ERR),
+        }
+    }
+}
+
+/// A message from a module, passed on by a [`ChannelConverse`] to be
+/// answered with [`respond`](Self::respond).
+///
+/// Dropping the request without answering it fails the message with
+/// `PAM_CONV_ERR`.
+#[derive(Debug)]
+pub struct ConvRequest {
+    style: PamMessageStyle,
+    msg: CString,
+    reply: mpsc::SyncSender<PamResult<Option<CString>>>,
+}
+
+impl ConvRequest {
+    /// The style of the message, e.g. `PAM_PROMPT_ECHO_OFF`.
+    pub fn style(&self) -> PamMessageStyle {
+        self.style
+    }
+
+    /// The text of the message.
+    pub fn message(&self) -> &CStr {
+        &self.msg
+    }
+
+    /// Answers the message, as [`Converse::converse`] would.  This never
+    /// blocks, so it can be called from an event loop.
+    pub fn respond(self, answer: PamResult<Option<CString>>) {
+        // Each request has its own channel, so there is always room; if the
+        // conversation has given up waiting, there is no one to answer.
+        let _ = self.reply.try_send(answer);
+    }
+}
+
+/// A [`Converse`] implementation that passes each message on as a
+/// [`ConvRequest`], and waits for it to be answered.
+///
+/// This is for applications built around an event loop, like one on
+/// `tokio`, that can't block while the user thinks about a prompt.  Each of
+/// libpam's calls blocks until the stack is done, so the transaction runs
+/// on a thread of its own (with `tokio`, in `spawn_blocking`), where it must
+/// also be created, since a [`PamContext`] can't move between threads.
+/// The conversation is called on that thread, and blocks only it, waiting
+/// for the event loop to answer through the request's own one-shot channel.
+///
+/// [`channel`](Self::channel) passes requests on through a standard
+/// channel.  An async event loop should instead [`new`](Self::new) one that
+/// sends them to an async channel with a send that doesn't block, so that it
+/// can await them.  With the `tokio` feature, `unbounded` makes one that
+/// sends them to a `tokio` channel; see
+/// [`PamContext::authenticate_async`] for an example.
+///
+/// ```no_run
+/// # use pam::client::{ChannelConverse, PamContext};
+/// # use pam::constants::{PamFlag, PAM_PROMPT_ECHO_OFF, PAM_PROMPT_ECHO_ON};
+/// # fn ask(_: &std::ffi::CStr) -> std::ffi::CString { unimplemented!() }
+/// use std::thread;
+///
+/// let (conv, requests) = ChannelConverse::channel();
+/// let login = thread::spawn(move || {
+///     let mut ctx = PamContext::new("login", Some("alice"), conv)?;
+///     ctx.authenticate(PamFlag::default())?;
+///     ctx.acct_mgmt(PamFlag::default())
+/// });
+/// // Ends when the transaction does, and drops the conversation.
+/// for request in requests {
+///     let answer = match request.style() {
+///         PAM_PROMPT_ECHO_OFF | PAM_PROMPT_ECHO_ON => Some(ask(request.message())),
+///         _ => None,
+///     };
+///     request.respond(Ok(answer));
+/// }
+/// let result = login.join().unwrap();
+/// ```
+pub struct ChannelConverse {
+    forward: Box<dyn FnMut(ConvRequest) -> bool + Send>,
+}
+
+impl ChannelConverse {
+    /// Passes each request to `forward`, which returns false if there is no
+    /// one left to answer it, failing the message with `PAM_CONV_ERR`.
+    pub fn new(forward: impl FnMut(ConvRequest) -> bool + Send + 'static) -> Self {
+        Self {
+            forward: Box::new(forward),
+        }
+    }
+
+    /// Passes requests on to the returned receiver.
+    pub fn channel() -> (Self, mpsc::Receiver<ConvRequest>) {
+        let (requests, receiver) = mpsc::channel();
+        (
+            Self::new(move |request| requests.send(request).is_ok()),
+            receiver,
+        )
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl ChannelConverse {
+    /// Passes requests on to the returned `tokio` receiver, which an async
+    /// event loop can await.
+    pub fn unbounded() -> (Self, tokio::sync::mpsc::UnboundedReceiver<ConvRequest>) {
+        let (requests, receiver) = tokio::sync::mpsc::unbounded_channel();
+        (
+            Self::new(move |request| requests.send(request).is_ok()),
+            receiver,
+        )
+    }
+}
+
+impl std::fmt::Debug for ChannelConverse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ChannelConverse").finish_non_exhaustive()
+    }
+}
+
+impl Converse for ChannelConverse {
+    fn converse(&mut self, style: PamMessageStyle, msg: &CStr) -> PamResult<Option<CString>> {
+        let (reply, answer) = mpsc::sync_channel(1);
+        let request = ConvRequest {
+            style,
+            msg: msg.to_owned(),
+            reply,
+        };
+        if !(self.forward)(request) {
+            return Err(PamResultCode::PAM_CONV_ERR);
+        }
+        answer.recv().unwrap_or(Err(PamResultCode::PAM_CONV_ERR))
+    }
+}
+
+/// A [`Converse`] implementation that talks to the user on a terminal,
+/// like `misc_conv` from `libpam_misc`, but without depending on it.
+///
+/// Prompts and `PAM_ERROR_MSG` messages are written to standard error, and
+/// `PAM_TEXT_INFO` messages to standard output.  Answers are read a line at
+/// a time, and echo is turned off while the user types the answer to a
+/// `PAM_PROMPT_ECHO_OFF` prompt (and turned back on afterward, even if
+/// reading fails or panics).  Other message styles, like
+/// `PAM_BINARY_PROMPT`, fail with `PAM_CONV_ERR`, as does reaching the end
+/// of the input.
+///
+/// ```no_run
+/// # use pam::client::{PamContext, TerminalConverse};
+/// let conv = TerminalConverse::new().expect("no terminal");
+/// let mut ctx = PamContext::new("login", None, conv).unwrap();
+/// ```
+pub struct TerminalConverse {
+    input: Box<dyn BufRead + Send>,
+    output: Box<dyn Write + Send>,
+    errors: Box<dyn Write + Send>,
+    /// The terminal to turn echo off on, if the input is one.
+    tty: Option<RawFd>,
+}
+
+impl TerminalConverse {
+    /// Reads answers from the controlling terminal (`/dev/tty`), or from
+    /// standard input if the process has no controlling terminal.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `/dev/tty` exists but cannot be opened.
+    pub fn new() -> io::Result<Self> {
+        let (input, fd): (Box<dyn BufRead + Send>, RawFd) =
+            match File::options().read(true).write(true).open("/dev/tty") {
+                Ok(tty) => {
+                    let fd = tty.as_raw_fd();
+                    (Box::new(BufReader::new(tty)), fd)
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ENXIO) => (
+                    Box::new(BufReader::new(io::stdin())),
+                    io::stdin().as_raw_fd(),
+                ),
+                Err(e) => return Err(e),
+            };
+        let is_tty = unsafe { libc::isatty(fd) } == 1;
+        Ok(Self {
+            input,
+            output: Box::new(io::stdout()),
+            errors: Box::new(io::stderr()),
+            tty: is_tty.then_some(fd),
+        })
+    }
+
+    /// Reads answers from `input`, writing `PAM_TEXT_INFO` messages to
+    /// `output` and everything else to `errors`.
+    ///
+    /// Echo is never changed, since `input` is not known to be a terminal,
+    /// so this suits piped input and tests.
+    pub fn with_streams(
+        input: impl BufRead + Send + 'static,
+        output: impl Write + Send + 'static,
+        errors: impl Write + Send + 'static,
+    ) -> Self {
+        Self {
+            input: Box::new(input),
+            output: Box::new(output),
+            errors: Box::new(errors),
+            tty: None,
+        }
+    }
+
+    /// Shows `prompt` and reads a line, without its line ending.
+    fn read_answer(&mut self, prompt: &CStr, echo: bool) -> io::Result<Option<CString>> {
+        self.errors.write_all(prompt.to_bytes())?;
+        self.errors.flush()?;
+        let mut line = Vec::new();
+        let echo_off = match self.tty {
+            Some(fd) if !echo => Some(EchoOff::new(fd)?),
+            _ => None,
+        };
+        self.input.read_until(b'\n', &mut line)?;
+        if echo_off.is_some() {
+            drop(echo_off);
+            // The newline the user typed wasn't echoed.
+            self.errors.write_all(b"\n")?;
+        }
+        if line.is_empty() {
+            return Ok(None);
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+        Ok(CString::new(line).ok())
+    }
+}
+
+impl Converse for TerminalConverse {
+    fn converse(&mut self, style: PamMessageStyle, msg: &CStr) -> PamResult<Option<CString>> {
+        let result = match style {
+            PAM_PROMPT_ECHO_OFF | PAM_PROMPT_ECHO_ON => {
+                match self.read_answer(msg, style == PAM_PROMPT_ECHO_ON) {
+                    Ok(Some(answer)) => return Ok(Some(answer)),
+                    Ok(None) => return Err(PamResultCode::PAM_CONV_ERR),
+                    Err(e) => Err(e),
+                }
+            }
+            PAM_TEXT_INFO => writeln!(self.output, "{}", msg.to_string_lossy()),
+            PAM_ERROR_MSG => writeln!(self.errors, "{}", msg.to_string_lossy()),
+            _ => return Err(PamResultCode::PAM_CONV_ERR),
+        };
+        result
+            .map(|()| None)
+            .map_err(|_| PamResultCode::PAM_CONV_ERR)
+    }
+}
+
+/// Turns echo off on a terminal until dropped.
+struct EchoOff {
+    fd: RawFd,
+    saved: libc::termios,
+}
+
+impl EchoOff {
+    fn new(fd: RawFd) -> io::Result<Self> {
+        let mut saved = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut quiet = saved;
+        quiet.c_lflag &= !(libc::ECHO | libc::ECHONL);
+        if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &quiet) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd, saved })
+    }
+}
+
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        // There's nothing to do about a failure while dropping.
+        unsafe { libc::tcsetattr(self.fd, libc::TCSAFLUSH, &self.saved) };
+    }
+}
+
+/// A pam transaction, started by an application.
+///
+/// The context keeps track of the result of the most recent operation.
+/// The transaction should be ended with [`end`](Self::end), but if the
+/// context is dropped first, `pam_end` is called with that latest result.
+///
+/// The context owns the [`Converse`] implementation, and passes libpam a
+/// pointer to it as the conversation's `appdata_ptr`.  It stays at the same
+/// address until the context is dropped, which is after `pam_end`, so libpam
+/// and the modules can never call the conversation once it is gone.  Any
+/// state the application keeps in it can be read between operations with
+/// [`converse`](Self::converse) and [`converse_mut`](Self::converse_mut),
+/// without any casts of `appdata_ptr`.
+pub struct PamContext<C: Converse> {
+    /// The handle, which is only `None` once `pam_end` has been called.
+    handle: Option<NonNull<PamHandle>>,
+    service: String,
+    conv: *mut AppData<C>,
+    last_status: PamResultCode,
+    /// The operation that returned `PAM_INCOMPLETE`, to be resumed.
+    incomplete: Option<(StackCall, PamFlag)>,
+}
+
+/// An operation that runs the module stack, and so may be incomplete.
+#[derive(Clone, Copy, Debug)]
+enum StackCall {
+    Authenticate,
+    AcctMgmt,
+    Chauthtok,
+}
+
+impl StackCall {
+    /// The libpam function that runs it.
+    fn function(self) -> unsafe extern "C" fn(*mut PamHandle, PamFlag) -> c_int {
+        match self {
+            StackCall::Authenticate => pam_authenticate,
+            StackCall::AcctMgmt => pam_acct_mgmt,
+            StackCall::Chauthtok => pam_chauthtok,
+        }
+    }
+
+    /// The operation to report in errors.
+    fn operation(self) -> Operation {
+        match self {
+            StackCall::Authenticate => Operation::Authenticate,
+            StackCall::AcctMgmt => Operation::AcctMgmt,
+            StackCall::Chauthtok => Operation::Chauthtok,
+        }
+    }
+}
+
+/// What the conversation function's `appdata_ptr` points to.
+struct AppData<C> {
+    conv: C,
+    /// The last `PAM_ERROR_MSG` sent during the current operation.
+    error_message: Option<String>,
+}
+
+impl<C: Converse> PamContext<C> {
+    /// Starts a pam transaction for `service`, optionally for `user`.
+    ///
+    /// The service name selects the policy file in `/etc/pam.d`, so it must be
+    /// usable as a plain file name there: it may not be empty, `.` or `..`,
+    /// and may not contain `/` or any control characters (including nul).
+    ///
+    /// See the [`pam_start` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_start.3.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidService`] if the service name is not allowed.
+    /// Otherwise, fails with `PAM_CONV_ERR` if the user name contains a nul
+    /// byte, or an error if the underlying PAM function call fails.
+    pub fn new(service: &str, user: Option<&str>, conv: C) -> Result<Self, Error> {
+        if !is_valid_service(service) {
+            return Err(Error::InvalidService(service.to_owned()));
+        }
+        let failed = |code| Error::failed(Operation::Start, service, code);
+        let c_service = to_c_string(service).map_err(failed)?;
+        let c_user = user.map(to_c_string).transpose().map_err(failed)?;
+        let conv = Box::into_raw(Box::new(AppData {
+            conv,
+            error_message: None,
+        }));
+        let inner = Inner {
+            conv: Some(converse::<C>),
+            appdata_ptr: conv.cast_const().cast(),
+        };
+        let mut handle: *mut PamHandle = ptr::null_mut();
+        let res = PamResultCode::from_libpam(unsafe {
+            pam_start(
+                c_service.as_ptr(),
+                c_user.as_ref().map_or(ptr::null(), |u| u.as_ptr()),
+                &inner,
+                &mut handle,
+            )
+        });
+        match res {
+            PamResultCode::PAM_SUCCESS => Ok(Self {
+                handle: NonNull::new(handle),
+                service: service.to_owned(),
+                conv,
+                last_status: res,
+                incomplete: None,
+            }),
+            otherwise => {
+                drop(unsafe { Box::from_raw(conv) });
+                Err(failed(otherwise))
+            }
+        }
+    }
+
+    /// Authenticates the user, by asking the modules in the stack.
+    ///
+    /// See the [`pam_authenticate` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_authenticate.3.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if authentication fails, e.g. `PAM_AUTH_ERR`.
+    /// Returns `PAM_INCOMPLETE` if a module is waiting for the conversation
+    /// to answer; see [`resume`](Self::resume).
+    pub fn authenticate(&mut self, flags: PamFlag) -> Result<(), Error> {
+        self.call(StackCall::Authenticate, flags)
+    }
+
+    /// Checks that the user's account is valid, for instance that it has not
+    /// expired and that the user may log in at this time.
+    ///
+    /// See the [`pam_acct_mgmt` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_acct_mgmt.3.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the account is not valid.  `PAM_NEW_AUTHTOK_REQD`
+    /// means the account is fine, but the user must change their token
+    /// (with [`chauthtok`](Self::chauthtok)) before continuing.
+    pub fn acct_mgmt(&mut self, flags: PamFlag) -> Result<(), Error> {
+        self.call(StackCall::AcctMgmt, flags)
+    }
+
+    /// Runs the usual login flow: authenticates the user, then checks their
+    /// account, changing their token if it has expired.
+    ///
+    /// Authentication is tried up to `max_tries` times, as long as it fails
+    /// with `PAM_AUTH_ERR`.  It stops early if it fails with `PAM_MAXTRIES`,
+    /// which means that a module has reached its own limit, or with
+    /// `PAM_AUTHINFO_UNAVAIL`, since trying again won't help while the
+    /// modules can't reach their backend.  If the account
+    /// needs a new token, `chauthtok` is called with
+    /// `PAM_CHANGE_EXPIRED_AUTHTOK`, again up to `max_tries` times as long as
+    /// it fails with `PAM_TRY_AGAIN`.
+    ///
+    /// ```no_run
+    /// # use pam::client::{Converse, LoginOutcome, PamContext};
+    /// # fn login<C: Converse>(ctx: &mut PamContext<C>) -> bool {
+    /// match ctx.login(3) {
+    ///     Ok(LoginOutcome::Success | LoginOutcome::TokenChanged) => true,
+    ///     Ok(outcome) => {
+    ///         eprintln!("Login failed: {outcome:?}");
+    ///         false
+    ///     }
+    ///     Err(e) => {
+    ///         eprintln!("Login error: {e:?}");
+    ///         false
+    ///     }
+    /// }
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error for results that don't say anything about the user,
+    /// such as `PAM_CONV_ERR` or `PAM_SERVICE_ERR`.
+    pub fn login(&mut self, max_tries: u32) -> Result<LoginOutcome, Error> {
+        let flags = PamFlag::default();
+        let mut authenticated = false;
+        for _ in 0..max_tries {
+            match self.authenticate(flags).map_err(|e| e.code()) {
+                Ok(()) => {
+                    authenticated = true;
+                    break;
+                }
+                Err(PamResultCode::PAM_AUTH_ERR) => continue,
+                Err(PamResultCode::PAM_MAXTRIES) => break,
+                Err(code) => return self.outcome(Operation::Authenticate, code),
+            }
+        }
+        if !authenticated {
+            return Ok(LoginOutcome::AuthFailed);
+        }
+        match self.acct_mgmt(flags).map_err(|e| e.code()) {
+            Ok(()) => Ok(LoginOutcome::Success),
+            Err(PamResultCode::PAM_NEW_AUTHTOK_REQD) => {
+                let mut result = Err(PamResultCode::PAM_TRY_AGAIN);
+                for _ in 0..max_tries {
+                    result = self
+                        .chauthtok(PAM_CHANGE_EXPIRED_AUTHTOK)
+                        .map_err(|e| e.code());
+                    if result != Err(PamResultCode::PAM_TRY_AGAIN) {
+                        break;
+                    }
+                }
+                match result {
+                    Ok(()) => Ok(LoginOutcome::TokenChanged),
+                    Err(code) => Ok(LoginOutcome::TokenChangeFailed {
+                        code,
+                        reason: self.error_message().map(str::to_owned),
+                    }),
+                }
+            }
+            Err(code) => self.outcome(Operation::AcctMgmt, code),
+        }
+    }
+
+    /// Maps a failure code to the `LoginOutcome` it represents, if any,
+    /// or else to the error from `operation`.
+    fn outcome(&self, operation: Operation, code: PamResultCode) -> Result<LoginOutcome, Error> {
+        match code {
+            PamResultCode::PAM_AUTH_ERR | PamResultCode::PAM_MAXTRIES => {
+                Ok(LoginOutcome::AuthFailed)
+            }
+            PamResultCode::PAM_AUTHINFO_UNAVAIL => Ok(LoginOutcome::AuthInfoUnavailable),
+            PamResultCode::PAM_USER_UNKNOWN => Ok(LoginOutcome::UserUnknown),
+            PamResultCode::PAM_ACCT_EXPIRED => Ok(LoginOutcome::AccountExpired),
+            PamResultCode::PAM_PERM_DENIED => Ok(LoginOutcome::PermissionDenied),
+            other => Err(Error::failed(operation, &self.service, other)),
+        }
+    }
+
+    /// Changes the user's authentication token (e.g., their password).
+    ///
+    /// libpam calls each module's `sm_chauthtok` twice: first with
+    /// `PAM_PRELIM_CHECK`, to make sure that the token can be changed at all,
+    /// and then, only if every module agreed, with `PAM_UPDATE_AUTHTOK`
+    /// to actually change it.  Both passes happen within this one call.
+    ///
+    /// Pass `PAM_CHANGE_EXPIRED_AUTHTOK` to only change the token if it has
+    /// expired, which is the usual flow when `acct_mgmt` returns
+    /// `PAM_NEW_AUTHTOK_REQD` during login.
+    ///
+    /// See the [`pam_chauthtok` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_chauthtok.3.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_TRY_AGAIN` if the preliminary check failed, in which case
+    /// nothing was changed, and the application can ask the user to try again.
+    /// Any other error means the change failed for good (for instance,
+    /// `PAM_AUTHTOK_ERR` if a module rejected the new token).
+    ///
+    /// ```no_run
+    /// # use pam::client::{Converse, PamContext};
+    /// # use pam::constants::{PamFlag, PamResultCode};
+    /// # use pam::Error;
+    /// # fn change<C: Converse>(ctx: &mut PamContext<C>) -> Result<(), Error> {
+    /// for _ in 0..3 {
+    ///     match ctx.chauthtok(PamFlag::default()) {
+    ///         Err(e) if e.code() == PamResultCode::PAM_TRY_AGAIN => println!("Please try again."),
+    ///         other => return other,
+    ///     }
+    /// }
+    /// Err(PamResultCode::PAM_MAXTRIES.into())
+    /// # }
+    /// ```
+    pub fn chauthtok(&mut self, flags: PamFlag) -> Result<(), Error> {
+        self.call(StackCall::Chauthtok, flags)
+    }
+
+    /// Calls the operation that last returned `PAM_INCOMPLETE` again,
+    /// with the same flags.
+    ///
+    /// This is how an application whose conversation can't answer right away
+    /// (say, because it is waiting on a GUI event loop or a network client)
+    /// avoids blocking inside libpam.  The protocol, from the Linux-PAM
+    /// Application Developers' Guide, is:
+    ///
+    /// 1. When the [`Converse`] implementation can't answer a message yet,
+    ///    it returns `PAM_CONV_AGAIN`, and remembers the message.
+    /// 2. A module that supports this saves its progress with `pam_set_data`
+    ///    and returns `PAM_INCOMPLETE`.  libpam remembers where it was in the
+    ///    stack, and returns `PAM_INCOMPLETE` from the operation (here,
+    ///    `authenticate`, `acct_mgmt`, or `chauthtok`).
+    /// 3. Once the answer is available, the application calls the same
+    ///    operation again (which is what this does), and libpam picks up at
+    ///    the module that returned `PAM_INCOMPLETE`, which asks again.
+    ///    Modules before it in the stack are not called again.
+    ///
+    /// In the meantime, the application must not start a different operation
+    /// on this transaction; libpam fails it with `PAM_ABORT`.  Modules that
+    /// don't support resuming generally turn `PAM_CONV_AGAIN` into
+    /// `PAM_CONV_ERR` or `PAM_AUTH_ERR`.
+    ///
+    /// ```no_run
+    /// # use pam::client::{Converse, PamContext};
+    /// # use pam::constants::{PamFlag, PamResultCode};
+    /// # fn wait_for_answer() {}
+    /// # use pam::Error;
+    /// # fn f<C: Converse>(ctx: &mut PamContext<C>) -> Result<(), Error> {
+    /// let mut result = ctx.authenticate(PamFlag::default());
+    /// while ctx.is_incomplete() {
+    ///     wait_for_answer();
+    ///     result = ctx.resume();
+    /// }
+    /// result
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// As for the operation being resumed.  Returns `PAM_SYSTEM_ERR`,
+    /// with no operation, if nothing is waiting to be resumed.
+    pub fn resume(&mut self) -> Result<(), Error> {
+        let (op, flags) = self.incomplete.ok_or(PamResultCode::PAM_SYSTEM_ERR)?;
+        self.call(op, flags)
+    }
+
+    /// The code libpam returned from the most recent operation, or
+    /// `PAM_SUCCESS` if there has been none.
+    ///
+    /// This is the exact code, for audit logs, even when a method like
+    /// [`login`](Self::login) sums it up as a [`LoginOutcome`].  It is also
+    /// the status that `pam_end` is called with if the context is dropped.
+    pub fn last_result(&self) -> PamResultCode {
+        self.last_status
+    }
+
+    /// True if the last operation returned `PAM_INCOMPLETE`, and should be
+    /// [resumed](Self::resume).
+    pub fn is_incomplete(&self) -> bool {
+        self.incomplete.is_some()
+    }
+
+    /// The last error message (`PAM_ERROR_MSG`) that a module sent during
+    /// the most recent operation, which is usually why it failed.
+    ///
+    /// By convention, a module that rejects something the user did, like a
+    /// new password that is too short, sends the reason as an error message
+    /// before failing (see [`password::reject`](crate::password::reject)).
+    /// The message is still passed to the [`Converse`] implementation as
+    /// usual; this just keeps a copy, so that an application can show it
+    /// alongside the result code, or log it.  Modules called with `PAM_SILENT`
+    /// send no messages, so there will be no reason to read.
+    pub fn error_message(&self) -> Option<&str> {
+        unsafe { (*self.conv).error_message.as_deref() }
+    }
+
+    /// The conversation, for reading any state it keeps.
+    pub fn converse(&self) -> &C {
+        // Conversations only happen during operations, which borrow
+        // `self` mutably, so libpam can't be using it now.
+        unsafe { &(*self.conv).conv }
+    }
+
+    /// The conversation, for changing any state it keeps before the next
+    /// operation.
+    pub fn converse_mut(&mut self) -> &mut C {
+        unsafe { &mut (*self.conv).conv }
+    }
+
+    /// Sets an item, usually to tell the modules about the user and where
+    /// they are logging in from before calling [`authenticate`](Self::authenticate).
+    ///
+    /// Applications commonly set [`User`](crate::items::User) (if it wasn't
+    /// given to [`new`](Self::new)), [`Tty`](crate::items::Tty),
+    /// [`RHost`](crate::items::RHost), [`RUser`](crate::items::RUser),
+    /// [`UserPrompt`](crate::items::UserPrompt),
+    /// [`XDisplay`](crate::items::XDisplay), and
+    /// [`AuthTokType`](crate::items::AuthTokType).  Only modules may set
+    /// the tokens, [`AuthTok`](crate::items::AuthTok) and
+    /// [`OldAuthTok`](crate::items::OldAuthTok), so libpam fails with
+    /// `PAM_BAD_ITEM` if an application tries.  The context owns the
+    /// conversation, so it can't be replaced, and setting
+    /// [`Conv`](crate::conv::Conv) also fails with `PAM_BAD_ITEM`.
+    ///
+    /// ```no_run
+    /// # use pam::client::{Converse, PamContext};
+    /// # use pam::items::{RHost, Tty};
+    /// # fn f<C: Converse>(ctx: &mut PamContext<C>) -> Result<(), pam::Error> {
+    /// ctx.set_item(RHost(c"203.0.113.7"))?;
+    /// ctx.set_item(Tty(c"ssh"))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// See the [`pam_set_item` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_set_item.3.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_BAD_ITEM` as described above.
+    pub fn set_item<T: IntoRaw>(&mut self, item: T) -> Result<(), Error> {
+        let failed = |code| Error::failed(Operation::SetItem, &self.service, code);
+        if ItemType::try_from(T::type_id()) == Ok(ItemType::Conv) {
+            return Err(failed(PamResultCode::PAM_BAD_ITEM));
+        }
+        unsafe { &mut *self.handle_ptr() }
+            .set_item(item)
+            .map_err(failed)
+    }
+
+    /// Gets an item, borrowed for as long as the context is.
+    ///
+    /// This can read back what the application set, or what the modules
+    /// set, like the user name they settled on.
+    ///
+    /// See the [`pam_get_item` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_get_item.3.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails.
+    pub fn get_item<'a, T: FromRaw<'a>>(&'a self) -> Result<Option<T>, Error> {
+        unsafe { &*self.handle_ptr() }
+            .get_item()
+            .map_err(|code| Error::failed(Operation::GetItem, &self.service, code))
+    }
+
+    /// Ends the transaction with `pam_end`, passing `status` to the modules'
+    /// cleanup functions.
+    ///
+    /// See the [`pam_end` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_end.3.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails.
+    pub fn end(mut self, status: PamResultCode) -> Result<(), Error> {
+        match self.handle.take() {
+            Some(handle) => {
+                PamResultCode::from_libpam(unsafe { pam_end(handle.as_ptr(), status.into()) })
+                    .into_result()
+                    .map_err(|code| Error::failed(Operation::End, &self.service, code))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn handle_ptr(&self) -> *mut PamHandle {
+        self.handle.map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    /// Runs an operation, remembering its result to use when ending the
+    /// transaction, and the operation itself if it is incomplete.
+    fn call(&mut self, op: StackCall, flags: PamFlag) -> Result<(), Error> {
+        unsafe { (*self.conv).error_message = None };
+        let res = PamResultCode::from_libpam(unsafe { op.function()(self.handle_ptr(), flags) });
+        self.last_status = res;
+        self.incomplete = res.is_incomplete().then_some((op, flags));
+        res.into_result()
+            .map_err(|code| Error::failed(op.operation(), &self.service, code))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<C: Converse + Send + 'static> PamContext<C> {
+    /// Starts a transaction for `service` and authenticates the user,
+    /// without blocking the `tokio` runtime it is awaited on.
+    ///
+    /// A `PamContext` can't move between threads, so the whole transaction,
+    /// from `pam_start` to `pam_end`, runs on a thread of its own with
+    /// `tokio::task::spawn_blocking`.  The conversation is called on that
+    /// thread too, so it should pass the messages back to be answered
+    /// asynchronously, as a [`ChannelConverse`] does:
+    ///
+    /// ```no_run
+    /// # use pam::client::{ChannelConverse, PamContext};
+    /// # use pam::constants::{PamFlag, PamResultCode};
+    /// # async fn ask(_: &std::ffi::CStr) -> Option<std::ffi::CString> { None }
+    /// # async fn f() -> Result<(), pam::Error> {
+    /// let (conv, mut requests) = ChannelConverse::unbounded();
+    /// let login = PamContext::authenticate_async("login", Some("alice"), conv, PamFlag::default());
+    /// // Ends when the transaction does, and drops the conversation.
+    /// let answer = async {
+    ///     while let Some(request) = requests.recv().await {
+    ///         let answer = ask(request.message()).await;
+    ///         request.respond(answer.map(Some).ok_or(PamResultCode::PAM_CONV_ERR));
+    ///     }
+    /// };
+    /// let (result, ()) = tokio::join!(login, answer);
+    /// result
+    /// # }
+    /// ```
+    ///
+    /// To do more in the same transaction, like checking the account with
+    /// [`acct_mgmt`](Self::acct_mgmt), create and use the context inside a
+    /// `spawn_blocking` closure of your own in the same way.  The
+    /// `async_login` example shows a whole application.
+    ///
+    /// # Errors
+    ///
+    /// As for [`new`](Self::new) and [`authenticate`](Self::authenticate).
+    /// Fails with `PAM_ABORT` if the runtime shuts down before the
+    /// transaction can run.
+    ///
+    /// # Panics
+    ///
+    /// Panics if it is not awaited on a `tokio` runtime, and passes on any
+    /// panic from the conversation.
+    pub async fn authenticate_async(
+        service: &str,
+        user: Option<&str>,
+        conv: C,
+        flags: PamFlag,
+    ) -> Result<(), Error> {
+        let task = {
+            let service = service.to_owned();
+            let user = user.map(str::to_owned);
+            tokio::task::spawn_blocking(move || {
+                PamContext::new(&service, user.as_deref(), conv)?.authenticate(flags)
+            })
+        };
+        match task.await {
+            Ok(result) => result,
+            Err(err) => match err.try_into_panic() {
+                Ok(panic) => std::panic::resume_unwind(panic),
+                Err(_) => Err(Error::failed(
+                    Operation::Authenticate,
+                    service,
+                    PamResultCode::PAM_ABORT,
+                )),
+            },
+        }
+    }
+}
+
+impl<C: Converse> Drop for PamContext<C> {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(handle) = self.handle.take() {
+                // There's nothing to do about a failure while dropping.
+                let _ = pam_end(handle.as_ptr(), self.last_status.into());
+            }
+            drop(Box::from_raw(self.conv));
+        }
+    }
+}
+
+/// Checks that `service` can safely be used as a file name in `/etc/pam.d`.
+fn is_valid_service(service: &str) -> bool {
+    !matches!(service, "" | "." | "..") && !service.chars().any(|c| c == '/' || c.is_control())
+}
+
+/// The conversation function passed to libpam, which forwards each message
+/// to the [`Converse`] implementation in the `AppData` that `appdata_ptr`
+/// points to.
+extern "C" fn converse<C: Converse>(
+    num_msg: c_int,
+    pam_message: *const *const PamMessage,
+    pam_response: *mut *mut PamResponse,
+    appdata_ptr: *const libc::c_void,
+) -> c_int {
+    answer_messages::<C>(num_msg, pam_message, pam_response, appdata_ptr).into()
+}
+
+/// The body of [`converse`], with the result as a code.
+fn answer_messages<C: Converse>(
+    num_msg: c_int,
+    pam_message: *const *const PamMessage,
+    pam_response: *mut *mut PamResponse,
+    appdata_ptr: *const libc::c_void,
+) -> PamResultCode {
+    if !(1..=PAM_MAX_NUM_MSG).contains(&num_msg) {
+        return PamResultCode::PAM_CONV_ERR;
+    }
+    let count = num_msg as usize;
+    if pam_message.is_null() || pam_response.is_null() || appdata_ptr.is_null() {
+        return PamResultCode::PAM_CONV_ERR;
+    }
+    let app_data = unsafe { &mut *appdata_ptr.cast_mut().cast::<AppData<C>>() };
+    let responses =
+        unsafe { libc::calloc(count, std::mem::size_of::<PamResponse>()) }.cast::<PamResponse>();
+    if responses.is_null() {
+        return PamResultCode::PAM_BUF_ERR;
+    }
+    for i in 0..count {
+        let msg = unsafe { &**pam_message.add(i) };
+        let text = if msg.msg.is_null() {
+            Default::default()
+        } else {
+            unsafe { CStr::from_ptr(msg.msg) }
+        };
+        if msg.msg_style == PAM_ERROR_MSG {
+            app_data.error_message = Some(text.to_string_lossy().into_owned());
+        }
+        let conv = &mut app_data.conv;
+        let result = catch_unwind(AssertUnwindSafe(|| conv.converse(msg.msg_style, text)))
+            .unwrap_or(Err(PamResultCode::PAM_CONV_ERR));
+        let resp = match result {
+            Ok(Some(answer)) => unsafe { libc::strdup(answer.as_ptr()) },
+            Ok(None) => continue,
+            Err(code) => {
+                unsafe { free_responses(responses, i) };
+                return code;
+            }
+        };
+        if resp.is_null() {
+            unsafe { free_responses(responses, i) };
+            return PamResultCode::PAM_BUF_ERR;
+        }
+        unsafe { (*responses.add(i)).resp = resp };
+    }
+    unsafe { *pam_response = responses };
+    PamResultCode::PAM_SUCCESS
+}
+
+/// Frees the first `count` response strings, and the response array itself.
+unsafe fn free_responses(responses: *mut PamResponse, count: usize) {
+    for i in 0..count {
+        libc::free((*responses.add(i)).resp.cast());
+    }
+    libc::free(responses.cast());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conv::{Conv, Prompt};
+    use crate::items::{RHost, Service, Tty, User};
+    use crate::mock;
+    use crate::module::PamHooks;
+
+    /// Just the code of a failure, for tests where the context is obvious.
+    fn code<T>(result: Result<T, Error>) -> Result<T, PamResultCode> {
+        result.map_err(|e| e.code())
+    }
+
+    struct NoConv;
+
+    impl Converse for NoConv {
+        fn converse(&mut self, _: PamMessageStyle, _: &CStr) -> PamResult<Option<CString>> {
+            Err(PamResultCode::PAM_CONV_ERR)
+        }
+    }
+
+    #[test]
+    fn rejects_bad_service_names() {
+        for name in [
+            "",
+            ".",
+            "..",
+            "../login",
+            "/etc/pam.d/login",
+            "lo\0gin",
+            "log\nin",
+        ] {
+            assert_eq!(
+                Some(Error::InvalidService(name.to_owned())),
+                PamContext::new(name, None, NoConv).err(),
+                "{name:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn starts_typical_service() {
+        let ctx = PamContext::new("login", Some("alice"), NoConv).unwrap();
+        let handle = unsafe { &*ctx.handle_ptr() };
+        let service = handle.get_item::<Service>().unwrap().unwrap();
+        assert_eq!("login", service.to_str().unwrap());
+        let user = handle.get_item::<User>().unwrap().unwrap();
+        assert_eq!("alice", user.to_str().unwrap());
+    }
+
+    #[test]
+    fn set_items_before_authenticating() {
+        let mut ctx = PamContext::new("sshd", None, NoConv).unwrap();
+        assert_eq!(Ok(None), ctx.get_item::<RHost>().map(|h| h.map(|h| h.0)));
+        assert_eq!(Ok(()), ctx.set_item(RHost(c"203.0.113.7")));
+        assert_eq!(Ok(()), ctx.set_item(Tty(c"ssh")));
+        assert_eq!(Ok(()), ctx.set_item(User(c"alice")));
+        assert_eq!(
+            Ok(Some(c"203.0.113.7")),
+            ctx.get_item::<RHost>().map(|h| h.map(|h| h.0))
+        );
+        assert_eq!(
+            Ok(Some(c"ssh")),
+            ctx.get_item::<Tty>().map(|t| t.map(|t| t.0))
+        );
+        assert_eq!(
+            Ok(Some(c"sshd")),
+            ctx.get_item::<Service>().map(|s| s.map(|s| s.0))
+        );
+        let handle = unsafe { &*ctx.handle_ptr() };
+        assert_eq!(Ok(Some("ssh".to_owned())), handle.tty());
+        assert_eq!(Ok("alice".to_owned()), handle.get_user(None));
+
+        let conv = ctx.get_item::<Conv>().unwrap().unwrap();
+        let inner = Inner {
+            conv: conv.0.conv,
+            appdata_ptr: conv.appdata_ptr(),
+        };
+        assert_eq!(
+            Err(Error::failed(
+                Operation::SetItem,
+                "sshd",
+                PamResultCode::PAM_BAD_ITEM
+            )),
+            ctx.set_item(Conv(&inner))
+        );
+    }
+
+    struct Denier;
+
+    impl PamHooks for Denier {
+        fn sm_authenticate(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+            PamResultCode::PAM_AUTH_ERR
+        }
+    }
+
+    #[test]
+    fn drop_ends_with_last_status() {
+        mock::take_ended();
+        drop(PamContext::new("login", None, NoConv).unwrap());
+        assert_eq!(
+            vec![c_int::from(PamResultCode::PAM_SUCCESS)],
+            mock::take_ended()
+        );
+
+        mock::set_module::<Denier>();
+        let mut ctx = PamContext::new("login", None, NoConv).unwrap();
+        assert_eq!(
+            Err(Error::failed(
+                Operation::Authenticate,
+                "login",
+                PamResultCode::PAM_AUTH_ERR
+            )),
+            ctx.authenticate(PamFlag::default())
+        );
+        drop(ctx);
+        assert_eq!(
+            vec![c_int::from(PamResultCode::PAM_AUTH_ERR)],
+            mock::take_ended()
+        );
+    }
+
+    /// Changes the password from "old" to anything else, prompting for both.
+    struct Changer;
+
+    impl PamHooks for Changer {
+        fn sm_chauthtok(pamh: &mut PamHandle, _: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+            let conv = match Conv::from_borrowed(pamh) {
+                Ok(Some(conv)) => conv,
+                _ => return PamResultCode::PAM_CONV_ERR,
+            };
+            let prompt = if flags.is_prelim_check() {
+                "Current password: "
+            } else {
+                "New password: "
+            };
+            let answer = match conv.send(&Prompt::secret(prompt)) {
+                Ok(Some(answer)) => answer,
+                _ => return PamResultCode::PAM_CONV_ERR,
+            };
+            match (flags.is_prelim_check(), answer.as_bytes()) {
+                (true, b"old") => PamResultCode::PAM_SUCCESS,
+                (true, _) => PamResultCode::PAM_TRY_AGAIN,
+                (false, b"old") => PamResultCode::PAM_AUTHTOK_ERR,
+                (false, _) => PamResultCode::PAM_SUCCESS,
+            }
+        }
+    }
+
+    /// Answers prompts from a script, and ignores error messages.
+    struct Script(Vec<&'static str>);
+
+    impl Converse for Script {
+        fn converse(&mut self, style: PamMessageStyle, _: &CStr) -> PamResult<Option<CString>> {
+            if style == PAM_ERROR_MSG {
+                return Ok(None);
+            }
+            assert_eq!(PAM_PROMPT_ECHO_OFF, style);
+            Ok(Some(CString::new(self.0.remove(0)).unwrap()))
+        }
+    }
+
+    #[test]
+    fn chauthtok() {
+        mock::set_module::<Changer>();
+        let flags = PamFlag::default();
+
+        let mut ctx = PamContext::new("passwd", None, Script(vec!["wrong"])).unwrap();
+        assert_eq!(
+            Err(PamResultCode::PAM_TRY_AGAIN),
+            code(ctx.chauthtok(flags))
+        );
+
+        let mut ctx = PamContext::new("passwd", None, Script(vec!["old", "old"])).unwrap();
+        assert_eq!(
+            Err(PamResultCode::PAM_AUTHTOK_ERR),
+            code(ctx.chauthtok(flags))
+        );
+
+        let mut ctx = PamContext::new("passwd", None, Script(vec!["old", "new"])).unwrap();
+        assert_eq!(Ok(()), ctx.chauthtok(flags));
+    }
+
+    /// Counts the prompts it answers with a script.
+    struct Counting {
+        script: Script,
+        prompts: usize,
+    }
+
+    impl Converse for Counting {
+        fn converse(&mut self, style: PamMessageStyle, msg: &CStr) -> PamResult<Option<CString>> {
+            if style != PAM_ERROR_MSG {
+                self.prompts += 1;
+            }
+            self.script.converse(style, msg)
+        }
+    }
+
+    #[test]
+    fn conversation_state() {
+        mock::set_module::<Changer>();
+        let conv = Counting {
+            script: Script(vec!["old", "new"]),
+            prompts: 0,
+        };
+        let mut ctx = PamContext::new("passwd", None, conv).unwrap();
+        assert_eq!(Ok(()), ctx.chauthtok(PamFlag::default()));
+        assert_eq!(2, ctx.converse().prompts);
+        assert!(ctx.converse().script.0.is_empty());
+
+        ctx.converse_mut().script.0.push("wrong");
+        assert_eq!(
+            Err(PamResultCode::PAM_TRY_AGAIN),
+            code(ctx.chauthtok(PamFlag::default()))
+        );
+        assert_eq!(3, ctx.converse().prompts);
+    }
+
+    /// Accepts the password "old", which has expired, and requires a new one.
+    struct Expired;
+
+    #[cfg(feature = "misc_conv")]
+    #[test]
+    fn misc_conv_error_message() {
+        assert_eq!(
+            Ok(None),
+            MiscConv::new().converse(PAM_ERROR_MSG, c"misc_conv says hello")
+        );
+    }
+
+    #[test]
+    fn channel_converse_from_another_thread() {
+        let (conv, requests) = ChannelConverse::channel();
+        let login = thread::spawn(move || {
+            mock::set_module::<Expired>();
+            let mut ctx = PamContext::new("login", None, conv)?;
+            ctx.authenticate(PamFlag::default())
+        });
+        let request = requests.recv().unwrap();
+        assert_eq!(PAM_PROMPT_ECHO_OFF, request.style());
+        assert_eq!(c"Password: ", request.message());
+        request.respond(Ok(Some(c"old".to_owned())));
+        assert_eq!(Ok(()), code(login.join().unwrap()));
+        // The conversation was dropped along with the context.
+        assert!(requests.recv().is_err());
+    }
+
+    #[test]
+    fn channel_converse_unanswered() {
+        let (conv, requests) = ChannelConverse::channel();
+        let login = thread::spawn(move || {
+            mock::set_module::<Expired>();
+            let mut ctx = PamContext::new("login", None, conv)?;
+            ctx.authenticate(PamFlag::default())
+        });
+        drop(requests.recv().unwrap());
+        assert_eq!(
+            Err(PamResultCode::PAM_CONV_ERR),
+            code(login.join().unwrap())
+        );
+
+        let (conv, requests) = ChannelConverse::channel();
+        drop(requests);
+        mock::set_module::<Expired>();
+        let mut ctx = PamContext::new("login", None, conv).unwrap();
+        assert_eq!(
+            Err(PamResultCode::PAM_CONV_ERR),
+            code(ctx.authenticate(PamFlag::default()))
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn authenticate_async() {
+        // The mock's module is per-thread, so set it on the blocking thread.
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .on_thread_start(mock::set_module::<Expired>)
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (conv, mut requests) = ChannelConverse::unbounded();
+            let login = PamContext::authenticate_async("login", None, conv, PamFlag::default());
+            let answer = async {
+                let request = requests.recv().await.unwrap();
+                assert_eq!(PAM_PROMPT_ECHO_OFF, request.style());
+                assert_eq!(c"Password: ", request.message());
+                request.respond(Ok(Some(c"old".to_owned())));
+                // The conversation was dropped along with the context.
+                assert!(requests.recv().await.is_none());
+            };
+            let (result, ()) = tokio::join!(login, answer);
+            assert_eq!(Ok(()), code(result));
+        });
+
+        let (conv, _) = ChannelConverse::unbounded();
+        let login = PamContext::authenticate_async("no/such", None, conv, PamFlag::default());
+        assert_eq!(
+            Err(Error::InvalidService("no/such".to_owned())),
+            runtime.block_on(login)
+        );
+    }
+
+    fn prompt(pamh: &PamHandle, msg: &str) -> Option<String> {
+        let conv = Conv::from_borrowed(pamh).ok()??;
+        let answer = conv.send(&Prompt::secret(msg)).ok()??;
+        answer.into_string().ok()
+    }
+
+    impl PamHooks for Expired {
+        fn sm_authenticate(pamh: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+            match prompt(pamh, "Password: ").as_deref() {
+                Some("old") => PamResultCode::PAM_SUCCESS,
+                Some(_) => PamResultCode::PAM_AUTH_ERR,
+                None => PamResultCode::PAM_CONV_ERR,
+            }
+        }
+
+        fn acct_mgmt(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+            PamResultCode::PAM_NEW_AUTHTOK_REQD
+        }
+
+        fn sm_chauthtok(pamh: &mut PamHandle, _: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+            if !flags.is_change_expired_authtok() {
+                return PamResultCode::PAM_SERVICE_ERR;
+            }
+            if flags.is_prelim_check() {
+                return PamResultCode::PAM_SUCCESS;
+            }
+            match prompt(pamh, "New password: ").as_deref() {
+                Some("old") => crate::password::reject(
+                    pamh,
+                    flags,
+                    PamResultCode::PAM_AUTHTOK_ERR,
+                    "The password has not been changed.",
+                ),
+                Some(_) => PamResultCode::PAM_SUCCESS,
+                None => PamResultCode::PAM_CONV_ERR,
+            }
+        }
+    }
+
+    /// Rejects every new password, saying why unless `PAM_SILENT` is set.
+    struct Rejecter;
+
+    impl PamHooks for Rejecter {
+        fn sm_chauthtok(pamh: &mut PamHandle, _: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+            if flags.is_prelim_check() {
+                return PamResultCode::PAM_SUCCESS;
+            }
+            let reason = "BAD PASSWORD: it is based on a dictionary word";
+            crate::password::reject(pamh, flags, PamResultCode::PAM_AUTHTOK_ERR, reason)
+        }
+
+        fn sm_authenticate(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+            PamResultCode::PAM_SUCCESS
+        }
+    }
+
+    #[test]
+    fn chauthtok_reason() {
+        mock::set_module::<Rejecter>();
+        let mut ctx = PamContext::new("passwd", None, Script(vec![])).unwrap();
+        assert_eq!(None, ctx.error_message());
+        assert_eq!(
+            Err(PamResultCode::PAM_AUTHTOK_ERR),
+            code(ctx.chauthtok(PamFlag::default()))
+        );
+        assert_eq!(
+            Some("BAD PASSWORD: it is based on a dictionary word"),
+            ctx.error_message()
+        );
+
+        assert_eq!(Ok(()), ctx.authenticate(PamFlag::default()));
+        assert_eq!(None, ctx.error_message());
+
+        assert_eq!(
+            Err(PamResultCode::PAM_AUTHTOK_ERR),
+            code(ctx.chauthtok(crate::constants::PAM_SILENT))
+        );
+        assert_eq!(None, ctx.error_message());
+    }
+
+    /// Asks for a password, and if the conversation can't answer yet,
+    /// returns `PAM_INCOMPLETE` to be resumed later.
+    struct Suspender;
+
+    impl PamHooks for Suspender {
+        fn sm_authenticate(pamh: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+            let conv = match Conv::from_borrowed(pamh) {
+                Ok(Some(conv)) => conv,
+                _ => return PamResultCode::PAM_CONV_ERR,
+            };
+            let answer = match conv.send(&Prompt::Password) {
+                Ok(Some(answer)) => answer,
+                Err(PamResultCode::PAM_CONV_AGAIN) => {
+                    let waits = unsafe { pamh.get_data::<u32>("waits") }.map_or(0, |n| *n);
+                    if pamh.set_data("waits", Box::new(waits + 1)).is_err() {
+                        return PamResultCode::PAM_SYSTEM_ERR;
+                    }
+                    return PamResultCode::PAM_INCOMPLETE;
+                }
+                _ => return PamResultCode::PAM_CONV_ERR,
+            };
+            match (answer.as_bytes(), unsafe { pamh.get_data::<u32>("waits") }) {
+                (b"hunter2", Ok(2)) => PamResultCode::PAM_SUCCESS,
+                _ => PamResultCode::PAM_AUTH_ERR,
+            }
+        }
+    }
+
+    /// Answers once the answer has arrived from elsewhere.
+    struct Later(std::rc::Rc<std::cell::Cell<Option<&'static str>>>);
+
+    impl Converse for Later {
+        fn converse(&mut self, _: PamMessageStyle, _: &CStr) -> PamResult<Option<CString>> {
+            match self.0.take() {
+                Some(answer) => Ok(Some(CString::new(answer).unwrap())),
+                None => Err(PamResultCode::PAM_CONV_AGAIN),
+            }
+        }
+    }
+
+    #[test]
+    fn resume_incomplete() {
+        mock::set_module::<Suspender>();
+        let answer = std::rc::Rc::default();
+        let mut ctx = PamContext::new("login", None, Later(std::rc::Rc::clone(&answer))).unwrap();
+        assert!(!ctx.is_incomplete());
+        assert_eq!(Err(PamResultCode::PAM_SYSTEM_ERR), code(ctx.resume()));
+
+        let flags = PamFlag::default();
+        assert_eq!(
+            Err(PamResultCode::PAM_INCOMPLETE),
+            code(ctx.authenticate(flags))
+        );
+        assert!(ctx.is_incomplete());
+        assert_eq!(Err(PamResultCode::PAM_INCOMPLETE), code(ctx.resume()));
+        assert!(ctx.is_incomplete());
+
+        answer.set(Some("hunter2"));
+        assert_eq!(Ok(()), ctx.resume());
+        assert!(!ctx.is_incomplete());
+        assert_eq!(Err(PamResultCode::PAM_SYSTEM_ERR), code(ctx.resume()));
+    }
+
+    #[test]
+    fn login() {
+        mock::set_module::<Expired>();
+        let login = |answers, tries| {
+            let mut ctx = PamContext::new("login", Some("alice"), Script(answers)).unwrap();
+            ctx.login(tries)
+        };
+        assert_eq!(
+            Ok(LoginOutcome::TokenChanged),
+            login(vec!["typo", "old", "new"], 3)
+        );
+        assert_eq!(Ok(LoginOutcome::AuthFailed), login(vec!["typo", "old"], 1));
+        assert_eq!(
+            Ok(LoginOutcome::TokenChangeFailed {
+                code: PamResultCode::PAM_AUTHTOK_ERR,
+                reason: Some("The password has not been changed.".to_owned()),
+            }),
+            login(vec!["old", "old"], 3)
+        );
+        let err = PamContext::new("login", None, NoConv)
+            .unwrap()
+            .login(3)
+            .unwrap_err();
+        assert_eq!(
+            Error::failed(
+                Operation::Authenticate,
+                "login",
+                PamResultCode::PAM_CONV_ERR
+            ),
+            err
+        );
+
+        mock::set_module::<Denier>();
+        let mut ctx = PamContext::new("login", None, NoConv).unwrap();
+        assert_eq!(PamResultCode::PAM_SUCCESS, ctx.last_result());
+        assert_eq!(Ok(LoginOutcome::AuthFailed), ctx.login(3));
+        assert_eq!(PamResultCode::PAM_AUTH_ERR, ctx.last_result());
+        let denied = Err(PamResultCode::PAM_PERM_DENIED);
+        assert_eq!(denied, code(ctx.acct_mgmt(PamFlag::default())));
+        assert_eq!(PamResultCode::PAM_PERM_DENIED, ctx.last_result());
+    }
+
+    /// Asks for a password, but can't reach the server to check it.
+    struct Unreachable;
+
+    impl PamHooks for Unreachable {
+        fn sm_authenticate(pamh: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+            match prompt(pamh, "Password: ") {
+                Some(_) => PamResultCode::PAM_AUTHINFO_UNAVAIL,
+                None => PamResultCode::PAM_CONV_ERR,
+            }
+        }
+    }
+
+    #[test]
+    fn login_with_backend_down() {
+        mock::set_module::<Unreachable>();
+        let conv = Counting {
+            script: Script(vec!["right", "right", "right"]),
+            prompts: 0,
+        };
+        let mut ctx = PamContext::new("login", Some("alice"), conv).unwrap();
+        assert_eq!(Ok(LoginOutcome::AuthInfoUnavailable), ctx.login(3));
+        assert_eq!(PamResultCode::PAM_AUTHINFO_UNAVAIL, ctx.last_result());
+        assert_eq!(1, ctx.converse().prompts);
+    }
+
+    /// A conversation function in the style of C code, which answers
+    /// prompts with the string that `appdata_ptr` points to.
+    unsafe extern "C" fn c_style_conv(
+        num_msg: c_int,
+        msg: *const *const PamMessage,
+        resp: *mut *mut PamResponse,
+        appdata_ptr: *mut libc::c_void,
+    ) -> c_int {
+        let count = num_msg as usize;
+        let responses =
+            libc::calloc(count, std::mem::size_of::<PamResponse>()).cast::<PamResponse>();
+        for i in 0..count {
+            if (**msg.add(i)).msg_style == PAM_PROMPT_ECHO_OFF {
+                (*responses.add(i)).resp = libc::strdup(appdata_ptr.cast());
+            }
+        }
+        *resp = responses;
+        PamResultCode::PAM_SUCCESS.into()
+    }
+
+    #[test]
+    fn raw_converse() {
+        assert_eq!(
+            Some(PamResultCode::PAM_CONV_ERR),
+            ConvCallback::new(None).err()
+        );
+        let callback = ConvCallback::new(Some(c_style_conv)).unwrap();
+        let answer = c"hunter2";
+        let mut conv = unsafe { RawConverse::new(callback, answer.as_ptr().cast_mut().cast()) };
+        assert_eq!(
+            Ok(Some(answer.to_owned())),
+            conv.converse(PAM_PROMPT_ECHO_OFF, c"Password: ")
+        );
+        assert_eq!(
+            Ok(None),
+            conv.converse(crate::constants::PAM_TEXT_INFO, c"Hi")
+        );
+
+        mock::set_module::<Changer>();
+        let conv = unsafe { RawConverse::new(callback, c"old".as_ptr().cast_mut().cast()) };
+        let mut ctx = PamContext::new("passwd", None, conv).unwrap();
+        assert_eq!(
+            Err(PamResultCode::PAM_AUTHTOK_ERR),
+            code(ctx.chauthtok(PamFlag::default()))
+        );
+    }
+
+    /// Answers every prompt with "hunter2", after a delay.
+    struct Slow(Duration);
+
+    impl Converse for Slow {
+        fn converse(&mut self, _: PamMessageStyle, _: &CStr) -> PamResult<Option<CString>> {
+            thread::sleep(self.0);
+            Ok(Some(c"hunter2".to_owned()))
+        }
+    }
+
+    #[test]
+    fn timeout_converse() {
+        let mut conv = TimeoutConverse::new(Slow(Duration::ZERO), Duration::from_secs(10));
+        for _ in 0..2 {
+            assert_eq!(
+                Ok(Some(c"hunter2".to_owned())),
+                conv.converse(PAM_PROMPT_ECHO_OFF, c"Password: ")
+            );
+        }
+
+        let mut conv =
+            TimeoutConverse::new(Slow(Duration::from_secs(5)), Duration::from_millis(20));
+        let start = std::time::Instant::now();
+        assert_eq!(
+            Err(PamResultCode::PAM_CONV_ERR),
+            conv.converse(PAM_PROMPT_ECHO_OFF, c"Password: ")
+        );
+        assert_eq!(
+            Err(PamResultCode::PAM_CONV_ERR),
+            conv.converse(PAM_PROMPT_ECHO_OFF, c"Password: ")
+        );
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    /// A writer whose output can be read while it is owned elsewhere.
+    #[derive(Clone, Default)]
+    struct Shared(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn take(&self) -> String {
+            String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+        }
+    }
+
+    #[test]
+    fn terminal_converse_piped() {
+        let (output, errors) = (Shared::default(), Shared::default());
+        let input = io::Cursor::new(b"alice\nhunter2\r\nno newline".to_vec());
+        let mut conv = TerminalConverse::with_streams(input, output.clone(), errors.clone());
+
+        assert_eq!(
+            Ok(Some(c"alice".to_owned())),
+            conv.converse(PAM_PROMPT_ECHO_ON, c"login: ")
+        );
+        assert_eq!(
+            Ok(Some(c"hunter2".to_owned())),
+            conv.converse(PAM_PROMPT_ECHO_OFF, c"Password: ")
+        );
+        assert_eq!(Ok(None), conv.converse(PAM_TEXT_INFO, c"Last login: never"));
+        assert_eq!(Ok(None), conv.converse(PAM_ERROR_MSG, c"Try again."));
+        assert_eq!(
+            Ok(Some(c"no newline".to_owned())),
+            conv.converse(PAM_PROMPT_ECHO_OFF, c"Password: ")
+        );
+        assert_eq!(
+            Err(PamResultCode::PAM_CONV_ERR),
+            conv.converse(PAM_PROMPT_ECHO_OFF, c"Password: ")
+        );
+        assert_eq!(
+            Err(PamResultCode::PAM_CONV_ERR),
+            conv.converse(crate::constants::PAM_BINARY_PROMPT, c"")
+        );
+
+        assert_eq!("Last login: never\n", output.take());
+        assert_eq!(
+            "login: Password: Try again.\nPassword: Password: ",
+            errors.take()
+        );
+    }
+
+    #[test]
+    fn timeout_through_context() {
+        mock::set_module::<Expired>();
+        let conv = TimeoutConverse::new(Slow(Duration::from_secs(5)), Duration::from_millis(20));
+        let mut ctx = PamContext::new("login", Some("alice"), conv).unwrap();
+        assert_eq!(
+            Err(PamResultCode::PAM_CONV_ERR),
+            code(ctx.authenticate(PamFlag::default()))
+        );
+    }
+
+    #[test]
+    fn too_many_messages() {
+        let msg = PamMessage {
+            msg_style: crate::constants::PAM_TEXT_INFO,
+            msg: c"hello".as_ptr(),
+        };
+        let msgs = [&msg as *const PamMessage; PAM_MAX_NUM_MSG as usize + 1];
+        let mut app_data = AppData {
+            conv: Script(Vec::new()),
+            error_message: None,
+        };
+        let appdata: *const libc::c_void = (&mut app_data as *mut AppData<Script>).cast();
+        let mut resp = ptr::null_mut();
+        for num_msg in [0, PAM_MAX_NUM_MSG + 1] {
+            let res = converse::<Script>(num_msg, msgs.as_ptr(), &mut resp, appdata);
+            assert_eq!(c_int::from(PamResultCode::PAM_CONV_ERR), res);
+            assert!(resp.is_null());
+        }
+    }
+
+    #[test]
+    fn explicit_end_only_ends_once() {
+        mock::take_ended();
+        let ctx = PamContext::new("login", None, NoConv).unwrap();
+        assert_eq!(Ok(()), ctx.end(PamResultCode::PAM_ABORT));
+        assert_eq!(
+            vec![c_int::from(PamResultCode::PAM_ABORT)],
+            mock::take_ended()
+        );
+    }
+}
@@ -0,0 +1,447 @@
+//! Calls to libpam functions that old versions of Linux-PAM don't have.
+//!
+//! By default, these are linked directly, like every other libpam function,
+//! so a module using them fails to load where libpam lacks them.  With the
+//! `dlsym` feature, they are instead looked up the first time they are used,
+//! and if libpam doesn't have them, a fallback is used.  This lets one
+//! module binary load against any version of libpam, at the cost of
+//! behaving slightly differently on old ones:
+//!
+//! - `pam_get_authtok` falls back to reading the item, and if it is not set,
+//!   prompting for it through the conversation and setting it.  Unlike
+//!   `pam_get_authtok`, this ignores `use_first_pass` and the other options
+//!   libpam reads from the module arguments, and `PAM_AUTHTOK_TYPE`.
+//! - `pam_get_authtok_noverify` and `pam_get_authtok_verify` fall back to
+//!   the same, prompting for the new token and then for it again, and
+//!   failing with `PAM_TRY_AGAIN` if the two don't match, as libpam does.
+//! - `pam_syslog` falls back to `syslog(3)`, using `LOG_AUTHPRIV` if no
+//!   facility is given, but without the `service(module:hook)` prefix.
+
+use libc::{c_char, c_int};
+use std::ffi::CStr;
+
+use crate::constants::{PamItemType, PamResultCode};
+use crate::module::PamHandle;
+
+#[cfg_attr(not(test), link(name = "pam"))]
+extern "C" {
+    #[cfg(any(test, not(feature = "dlsym")))]
+    fn pam_get_authtok(
+        pamh: *const PamHandle,
+        item_type: PamItemType,
+        data: &mut *const c_char,
+        prompt: *const c_char,
+    ) -> c_int;
+
+    #[cfg(any(test, not(feature = "dlsym")))]
+    fn pam_get_authtok_noverify(
+        pamh: *const PamHandle,
+        authtok: &mut *const c_char,
+        prompt: *const c_char,
+    ) -> c_int;
+
+    #[cfg(any(test, not(feature = "dlsym")))]
+    fn pam_get_authtok_verify(
+        pamh: *const PamHandle,
+        authtok: &mut *const c_char,
+        prompt: *const c_char,
+    ) -> c_int;
+
+    #[cfg(not(any(test, feature = "dlsym")))]
+    fn pam_syslog(pamh: *const PamHandle, priority: c_int, fmt: *const c_char, ...);
+
+    // Stable Rust can't define a variadic function, so the mock takes
+    // exactly the one string that `syslog` passes, and tests declare it
+    // that way too, rather than calling it through a mismatched signature.
+    #[cfg(test)]
+    fn pam_syslog(pamh: *const PamHandle, priority: c_int, fmt: *const c_char, msg: *const c_char);
+
+    #[cfg(feature = "dlsym")]
+    fn pam_get_item(
+        pamh: *const PamHandle,
+        item_type: PamItemType,
+        item: &mut *const libc::c_void,
+    ) -> c_int;
+
+    #[cfg(feature = "dlsym")]
+    fn pam_set_item(
+        pamh: *mut PamHandle,
+        item_type: PamItemType,
+        item: *const libc::c_void,
+    ) -> c_int;
+}
+
+/// Calls `pam_get_authtok`.
+///
+/// # Safety
+///
+/// As for `pam_get_authtok` itself.
+pub(crate) unsafe fn get_authtok(
+    pamh: *const PamHandle,
+    item_type: PamItemType,
+    data: &mut *const c_char,
+    prompt: *const c_char,
+) -> PamResultCode {
+    #[cfg(not(feature = "dlsym"))]
+    return PamResultCode::from_libpam(pam_get_authtok(pamh, item_type, data, prompt));
+    #[cfg(feature = "dlsym")]
+    match symbols::get_authtok() {
+        Some(get_authtok) => PamResultCode::from_libpam(get_authtok(pamh, item_type, data, prompt)),
+        None => fallback::get_authtok(pamh, item_type, data, prompt),
+    }
+}
+
+/// Calls `pam_get_authtok_noverify`.
+///
+/// # Safety
+///
+/// As for `pam_get_authtok_noverify` itself.
+pub(crate) unsafe fn get_authtok_noverify(
+    pamh: *const PamHandle,
+    authtok: &mut *const c_char,
+    prompt: *const c_char,
+) -> PamResultCode {
+    #[cfg(not(feature = "dlsym"))]
+    return PamResultCode::from_libpam(pam_get_authtok_noverify(pamh, authtok, prompt));
+    #[cfg(feature = "dlsym")]
+    match symbols::get_authtok_noverify() {
+        Some(noverify) => PamResultCode::from_libpam(noverify(pamh, authtok, prompt)),
+        None => fallback::get_authtok_noverify(pamh, authtok, prompt),
+    }
+}
+
+/// Calls `pam_get_authtok_verify`.
+///
+/// # Safety
+///
+/// As for `pam_get_authtok_verify` itself.
+pub(crate) unsafe fn get_authtok_verify(
+    pamh: *const PamHandle,
+    authtok: &mut *const c_char,
+    prompt: *const c_char,
+) -> PamResultCode {
+    #[cfg(not(feature = "dlsym"))]
+    return PamResultCode::from_libpam(pam_get_authtok_verify(pamh, authtok, prompt));
+    #[cfg(feature = "dlsym")]
+    match symbols::get_authtok_verify() {
+        Some(verify) => PamResultCode::from_libpam(verify(pamh, authtok, prompt)),
+        None => fallback::get_authtok_verify(pamh, authtok, prompt),
+    }
+}
+
+/// Logs `msg` with `pam_syslog`.
+pub(crate) fn syslog(pamh: &PamHandle, priority: c_int, msg: &CStr) {
+    #[cfg(not(feature = "dlsym"))]
+    unsafe {
+        pam_syslog(pamh, priority, c"%s".as_ptr(), msg.as_ptr());
+    }
+    #[cfg(feature = "dlsym")]
+    match symbols::syslog() {
+        Some(syslog) => unsafe { syslog(pamh, priority, c"%s".as_ptr(), msg.as_ptr()) },
+        None => fallback::syslog(priority, msg),
+    }
+}
+
+/// Looking up the functions at runtime.
+#[cfg(feature = "dlsym")]
+mod symbols {
+    use super::*;
+
+    pub(super) type GetAuthtokFn = unsafe extern "C" fn(
+        *const PamHandle,
+        PamItemType,
+        &mut *const c_char,
+        *const c_char,
+    ) -> c_int;
+
+    pub(super) type VerifyFn =
+        unsafe extern "C" fn(*const PamHandle, &mut *const c_char, *const c_char) -> c_int;
+
+    #[cfg(not(test))]
+    pub(super) type SyslogFn = unsafe extern "C" fn(*const PamHandle, c_int, *const c_char, ...);
+
+    #[cfg(test)]
+    pub(super) type SyslogFn =
+        unsafe extern "C" fn(*const PamHandle, c_int, *const c_char, *const c_char);
+
+    /// Finds a function in the libraries that are already loaded.
+    pub(super) fn lookup(name: &CStr) -> Option<*mut libc::c_void> {
+        let sym = unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) };
+        (!sym.is_null()).then_some(sym)
+    }
+
+    #[cfg(not(test))]
+    pub(super) fn get_authtok() -> Option<GetAuthtokFn> {
+        static SYMBOL: std::sync::OnceLock<Option<GetAuthtokFn>> = std::sync::OnceLock::new();
+        *SYMBOL.get_or_init(|| {
+            lookup(c"pam_get_authtok").map(|sym| unsafe { std::mem::transmute(sym) })
+        })
+    }
+
+    #[cfg(not(test))]
+    pub(super) fn get_authtok_noverify() -> Option<VerifyFn> {
+        static SYMBOL: std::sync::OnceLock<Option<VerifyFn>> = std::sync::OnceLock::new();
+        *SYMBOL.get_or_init(|| {
+            lookup(c"pam_get_authtok_noverify").map(|sym| unsafe { std::mem::transmute(sym) })
+        })
+    }
+
+    #[cfg(not(test))]
+    pub(super) fn get_authtok_verify() -> Option<VerifyFn> {
+        static SYMBOL: std::sync::OnceLock<Option<VerifyFn>> = std::sync::OnceLock::new();
+        *SYMBOL.get_or_init(|| {
+            lookup(c"pam_get_authtok_verify").map(|sym| unsafe { std::mem::transmute(sym) })
+        })
+    }
+
+    #[cfg(not(test))]
+    pub(super) fn syslog() -> Option<SyslogFn> {
+        static SYMBOL: std::sync::OnceLock<Option<SyslogFn>> = std::sync::OnceLock::new();
+        *SYMBOL.get_or_init(|| lookup(c"pam_syslog").map(|sym| unsafe { std::mem::transmute(sym) }))
+    }
+
+    // The mock's functions aren't dynamic symbols, so tests use them
+    // directly, unless pretending that libpam doesn't have them.
+    #[cfg(test)]
+    thread_local! {
+        pub(super) static MISSING: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    }
+
+    #[cfg(test)]
+    pub(super) fn get_authtok() -> Option<GetAuthtokFn> {
+        (!MISSING.get()).then_some(pam_get_authtok as GetAuthtokFn)
+    }
+
+    #[cfg(test)]
+    pub(super) fn get_authtok_noverify() -> Option<VerifyFn> {
+        (!MISSING.get()).then_some(pam_get_authtok_noverify as VerifyFn)
+    }
+
+    #[cfg(test)]
+    pub(super) fn get_authtok_verify() -> Option<VerifyFn> {
+        (!MISSING.get()).then_some(pam_get_authtok_verify as VerifyFn)
+    }
+
+    #[cfg(test)]
+    pub(super) fn syslog() -> Option<SyslogFn> {
+        (!MISSING.get()).then_some(pam_syslog as SyslogFn)
+    }
+}
+
+/// What to do when libpam doesn't have a function.
+#[cfg(feature = "dlsym")]
+mod fallback {
+    use super::*;
+    use crate::conv::{Conv, Prompt};
+    use crate::items::ItemType;
+    use crate::module::PamResult;
+
+    pub(super) unsafe fn get_authtok(
+        pamh: *const PamHandle,
+        item_type: PamItemType,
+        data: &mut *const c_char,
+        prompt: *const c_char,
+    ) -> PamResultCode {
+        write_out(data, get_or_prompt(pamh, item_type, prompt, "Password: "))
+    }
+
+    pub(super) unsafe fn get_authtok_noverify(
+        pamh: *const PamHandle,
+        authtok: &mut *const c_char,
+        prompt: *const c_char,
+    ) -> PamResultCode {
+        let token = get_or_prompt(pamh, ItemType::AuthTok.into(), prompt, "New password: ");
+        write_out(authtok, token)
+    }
+
+    pub(super) unsafe fn get_authtok_verify(
+        pamh: *const PamHandle,
+        authtok: &mut *const c_char,
+        prompt: *const c_char,
+    ) -> PamResultCode {
+        write_out(authtok, verify(pamh, *authtok, prompt))
+    }
+
+    unsafe fn write_out(
+        out: &mut *const c_char,
+        result: PamResult<*const c_char>,
+    ) -> PamResultCode {
+        match result {
+            Ok(value) => {
+                *out = value;
+                PamResultCode::PAM_SUCCESS
+            }
+            Err(e) => e,
+        }
+    }
+
+    unsafe fn get_or_prompt(
+        pamh: *const PamHandle,
+        item_type: PamItemType,
+        prompt: *const c_char,
+        default_prompt: &str,
+    ) -> PamResult<*const c_char> {
+        let mut item: *const libc::c_void = std::ptr::null();
+        PamResultCode::from_libpam(pam_get_item(pamh, item_type, &mut item)).into_result()?;
+        if !item.is_null() {
+            return Ok(item.cast());
+        }
+        let answer = ask(pamh, prompt, default_prompt)?;
+        PamResultCode::from_libpam(pam_set_item(
+            pamh.cast_mut(),
+            item_type,
+            answer.as_ptr().cast(),
+        ))
+        .into_result()?;
+        PamResultCode::from_libpam(pam_get_item(pamh, item_type, &mut item)).into_result()?;
+        Ok(item.cast())
+    }
+
+    unsafe fn verify(
+        pamh: *const PamHandle,
+        first: *const c_char,
+        prompt: *const c_char,
+    ) -> PamResult<*const c_char> {
+        let item_type = ItemType::AuthTok.into();
+        let answer = ask(pamh, prompt, "Retype new password: ")?;
+        if first.is_null() || CStr::from_ptr(first) != answer.as_c_str() {
+            PamResultCode::from_libpam(pam_set_item(pamh.cast_mut(), item_type, std::ptr::null()))
+                .into_result()?;
+            let conv = Conv::from_borrowed(&*pamh)?.ok_or(PamResultCode::PAM_CONV_ERR)?;
+            let _ = conv.send(&Prompt::Error("Sorry, passwords do not match.".to_owned()));
+            return Err(PamResultCode::PAM_TRY_AGAIN);
+        }
+        PamResultCode::from_libpam(pam_set_item(
+            pamh.cast_mut(),
+            item_type,
+            answer.as_ptr().cast(),
+        ))
+        .into_result()?;
+        let mut item: *const libc::c_void = std::ptr::null();
+        PamResultCode::from_libpam(pam_get_item(pamh, item_type, &mut item)).into_result()?;
+        Ok(item.cast())
+    }
+
+    /// Prompts for a token through the conversation, with echo off.
+    unsafe fn ask(
+        pamh: *const PamHandle,
+        prompt: *const c_char,
+        default_prompt: &str,
+    ) -> PamResult<std::ffi::CString> {
+        let prompt = if prompt.is_null() {
+            default_prompt
+        } else {
+            CStr::from_ptr(prompt)
+                .to_str()
+                .map_err(|_| PamResultCode::PAM_CONV_ERR)?
+        };
+        Conv::from_borrowed(&*pamh)?
+            .ok_or(PamResultCode::PAM_CONV_ERR)?
+            .send(&Prompt::secret(prompt))?
+            .ok_or(PamResultCode::PAM_CONV_ERR)
+    }
+
+    pub(super) fn syslog(priority: c_int, msg: &CStr) {
+        let priority = match priority & libc::LOG_FACMASK {
+            0 => priority | libc::LOG_AUTHPRIV,
+            _ => priority,
+        };
+        unsafe { libc::syslog(priority, c"%s".as_ptr(), msg.as_ptr()) };
+    }
+}
+
+#[cfg(all(test, feature = "dlsym"))]
+mod tests {
+    use super::*;
+    use crate::conv::{PamMessage, PamResponse};
+    use crate::mock::{self, MockPam};
+
+    extern "C" fn answer_hunter2(
+        _: c_int,
+        _: *const *const PamMessage,
+        resp: *mut *mut PamResponse,
+        appdata_ptr: *const libc::c_void,
+    ) -> c_int {
+        let calls = unsafe { &*appdata_ptr.cast::<std::cell::Cell<u32>>() };
+        calls.set(calls.get() + 1);
+        unsafe {
+            let response =
+                libc::calloc(1, std::mem::size_of::<PamResponse>()).cast::<PamResponse>();
+            (*response).resp = libc::strdup(c"hunter2".as_ptr());
+            *resp = response;
+        }
+        PamResultCode::PAM_SUCCESS.into()
+    }
+
+    /// Answers prompts with the `&CStr`s in the `Vec` that `appdata_ptr`
+    /// points to, in order, and ignores error messages.
+    extern "C" fn answer_each(
+        _: c_int,
+        msg: *const *const PamMessage,
+        resp: *mut *mut PamResponse,
+        appdata_ptr: *const libc::c_void,
+    ) -> c_int {
+        unsafe {
+            let response =
+                libc::calloc(1, std::mem::size_of::<PamResponse>()).cast::<PamResponse>();
+            if (**msg).msg_style != crate::constants::PAM_ERROR_MSG {
+                let answers = &mut *appdata_ptr.cast_mut().cast::<Vec<&CStr>>();
+                (*response).resp = libc::strdup(answers.remove(0).as_ptr());
+            }
+            *resp = response;
+        }
+        PamResultCode::PAM_SUCCESS.into()
+    }
+
+    #[test]
+    fn verify_falls_back() {
+        symbols::MISSING.set(true);
+        let mut answers = vec![c"one", c"two", c"three", c"three"];
+        let mut mock = MockPam::new();
+        mock.set_conv(answer_each, (&mut answers as *mut Vec<&CStr>).cast());
+        let pamh = mock.handle();
+
+        assert_eq!(
+            Err(PamResultCode::PAM_AUTHTOK_ERR),
+            pamh.get_authtok_verify(None)
+        );
+        assert_eq!(Ok(None), pamh.get_authtok_cached());
+        assert_eq!(Ok("three".to_owned()), pamh.get_authtok_verify(None));
+        assert_eq!(Ok(Some("three".to_owned())), pamh.get_authtok_cached());
+        assert!(answers.is_empty());
+        symbols::MISSING.set(false);
+    }
+
+    #[test]
+    fn lookup() {
+        assert!(symbols::lookup(c"malloc").is_some());
+        assert!(symbols::lookup(c"pam_no_such_function").is_none());
+    }
+
+    #[test]
+    fn missing_symbols_fall_back() {
+        symbols::MISSING.set(true);
+        let calls = std::cell::Cell::new(0_u32);
+        let mut mock = MockPam::new();
+        mock.set_conv(
+            answer_hunter2,
+            (&calls as *const std::cell::Cell<u32>).cast(),
+        );
+        let pamh = mock.handle();
+
+        assert_eq!(Ok("hunter2".to_owned()), pamh.get_authtok(None));
+        assert_eq!(Ok(Some("hunter2".to_owned())), pamh.get_authtok_cached());
+        assert_eq!(Ok("hunter2".to_owned()), pamh.get_authtok(Some("Again: ")));
+        assert_eq!(1, calls.get());
+
+        pamh.syslog(libc::LOG_INFO, "to the system log");
+        assert!(mock::take_logged().is_empty());
+        symbols::MISSING.set(false);
+
+        pamh.syslog(libc::LOG_INFO, "to pam_syslog");
+        assert_eq!(
+            vec![(libc::LOG_INFO, "to pam_syslog".to_owned())],
+            mock::take_logged()
+        );
+    }
+}
@@ -0,0 +1,243 @@
+//! Module settings from a configuration file named in the module arguments.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::args::ModuleArgs;
+use crate::constants::PamResultCode;
+use crate::module::PamResult;
+
+/// The argument naming the configuration file, as in `conf=/etc/mymodule.conf`.
+pub const CONF_KEY: &str = "conf";
+
+/// A module's settings, from its configuration file and its arguments.
+///
+/// The file holds one setting per line, in the same form as module
+/// arguments: `key=value` for an option, or a bare `name` for a flag.
+/// Whitespace around keys and values is ignored, as are blank lines and
+/// lines starting with `#` or `;`.  Arguments in the PAM configuration
+/// override settings from the file, so an administrator can change one
+/// option for one service without copying the whole file.
+///
+/// As in an INI file, settings can be grouped under `[section]` headers.
+/// The settings after a header are named with its section as a prefix, so
+/// `server=` under `[ldap]` is read with `settings.get("ldap.server")`, and
+/// can be overridden by an `ldap.server=` argument.  Settings before the
+/// first header have no prefix.
+///
+/// ```no_run
+/// # use pam::args::ModuleArgs;
+/// # use pam::config::Settings;
+/// # use pam::constants::{PamFlag, PamResultCode};
+/// # use pam::module::PamHandle;
+/// # use std::ffi::CStr;
+/// fn sm_authenticate(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+///     let settings = match Settings::load(&ModuleArgs::new(&args)) {
+///         Ok(settings) => settings,
+///         Err(code) => return code,
+///     };
+///     let server = settings.get("server").unwrap_or("localhost");
+///     // ...
+/// #   PamResultCode::PAM_SUCCESS
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Settings {
+    values: BTreeMap<String, Option<String>>,
+}
+
+impl Settings {
+    /// Loads the file named by the `conf=` argument, if any,
+    /// and merges the other arguments over it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_SERVICE_ERR` (the module is misconfigured) if the file
+    /// does not exist, a line of it is malformed, or it or an argument is
+    /// not valid UTF-8.  Returns `PAM_SYSTEM_ERR` if the file cannot be read
+    /// for any other reason.
+    pub fn load(args: &ModuleArgs) -> PamResult<Self> {
+        let mut settings = match args.get(CONF_KEY)? {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+        for (key, value) in args.iter() {
+            let key = std::str::from_utf8(key).map_err(|_| PamResultCode::PAM_SERVICE_ERR)?;
+            if key == CONF_KEY {
+                continue;
+            }
+            let value = value
+                .map(std::str::from_utf8)
+                .transpose()
+                .map_err(|_| PamResultCode::PAM_SERVICE_ERR)?;
+            settings
+                .values
+                .insert(key.to_owned(), value.map(str::to_owned));
+        }
+        Ok(settings)
+    }
+
+    /// Loads settings from the file at `path` alone.
+    ///
+    /// # Errors
+    ///
+    /// As for [`Settings::load`].
+    pub fn from_file(path: impl AsRef<Path>) -> PamResult<Self> {
+        let text = fs::read_to_string(path).map_err(|err| match err.kind() {
+            ErrorKind::NotFound | ErrorKind::InvalidData => PamResultCode::PAM_SERVICE_ERR,
+            _ => PamResultCode::PAM_SYSTEM_ERR,
+        })?;
+        Self::parse(&text)
+    }
+
+    fn parse(text: &str) -> PamResult<Self> {
+        let mut values = BTreeMap::new();
+        let mut prefix = String::new();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[') {
+                let section = header
+                    .strip_suffix(']')
+                    .map(str::trim)
+                    .filter(|section| !section.is_empty())
+                    .ok_or(PamResultCode::PAM_SERVICE_ERR)?;
+                prefix = format!("{section}.");
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), Some(value.trim().to_owned())),
+                None => (line, None),
+            };
+            if key.is_empty() {
+                return Err(PamResultCode::PAM_SERVICE_ERR);
+            }
+            values.insert(format!("{prefix}{key}"), value);
+        }
+        Ok(Self { values })
+    }
+
+    /// Returns the value of a `key=value` setting.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key)?.as_deref()
+    }
+
+    /// True if `name` was set as a bare flag, with no `=`.
+    pub fn has_flag(&self, name: &str) -> bool {
+        matches!(self.values.get(name), Some(None))
+    }
+
+    /// Iterates over all the settings in key order, as `(key, value)` pairs.
+    ///
+    /// The value is `None` for a flag.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.values.iter().map(|(k, v)| (k.as_str(), v.as_deref()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    fn temp_conf(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("pam-rs-{}-{name}.conf", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn argv_overrides_file() {
+        let path = temp_conf(
+            "overrides",
+            "# settings\n\
+             server = auth.example.com\n\
+             timeout=5\n\
+             \n\
+             ; flags\n\
+             debug\n",
+        );
+        let conf = CString::new(format!("conf={}", path.display())).unwrap();
+        let raw = [conf.as_c_str(), c"timeout=30", c"use_first_pass"];
+        let settings = Settings::load(&ModuleArgs::new(&raw));
+        fs::remove_file(&path).unwrap();
+        let settings = settings.unwrap();
+
+        assert_eq!(Some("auth.example.com"), settings.get("server"));
+        assert_eq!(Some("30"), settings.get("timeout"));
+        assert!(settings.has_flag("debug"));
+        assert!(settings.has_flag("use_first_pass"));
+        assert!(!settings.has_flag("server"));
+        assert_eq!(None, settings.get(CONF_KEY));
+        assert_eq!(
+            vec![
+                ("debug", None),
+                ("server", Some("auth.example.com")),
+                ("timeout", Some("30")),
+                ("use_first_pass", None),
+            ],
+            settings.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn no_conf_argument() {
+        let raw = [c"debug", c"server=localhost"];
+        let settings = Settings::load(&ModuleArgs::new(&raw)).unwrap();
+        assert_eq!(Some("localhost"), settings.get("server"));
+        assert!(settings.has_flag("debug"));
+    }
+
+    #[test]
+    fn missing_file() {
+        let raw = [c"conf=/nonexistent/pam-rs.conf"];
+        assert_eq!(
+            Err(PamResultCode::PAM_SERVICE_ERR),
+            Settings::load(&ModuleArgs::new(&raw))
+        );
+    }
+
+    #[test]
+    fn sections() {
+        let path = temp_conf(
+            "sections",
+            "debug\n\
+             [ldap]\n\
+             server = ldap.example.com\n\
+             [ krb5 ]\n\
+             realm=EXAMPLE.COM\n\
+             debug\n",
+        );
+        let conf = CString::new(format!("conf={}", path.display())).unwrap();
+        let raw = [conf.as_c_str(), c"ldap.server=localhost"];
+        let settings = Settings::load(&ModuleArgs::new(&raw));
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            vec![
+                ("debug", None),
+                ("krb5.debug", None),
+                ("krb5.realm", Some("EXAMPLE.COM")),
+                ("ldap.server", Some("localhost")),
+            ],
+            settings.unwrap().iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn malformed_file() {
+        for (name, contents) in [
+            ("empty-key", "=value\n"),
+            ("unclosed-section", "[main\nkey=value\n"),
+            ("empty-section", "[ ]\nkey=value\n"),
+        ] {
+            let path = temp_conf(name, contents);
+            let result = Settings::from_file(&path);
+            fs::remove_file(&path).unwrap();
+            assert_eq!(Err(PamResultCode::PAM_SERVICE_ERR), result, "{name}");
+        }
+    }
+}
@@ -1,20 +1,247 @@
-use libc::{c_int, c_uint};
+//! The constants and codes from the PAM headers.
+//!
+//! Everything here but [`PamResultCode::description`] and the conversion
+//! from `std::io::Error` is plain data, and is available without the `std`
+//! feature, for `no_std` code that needs to speak PAM's numbers.
+
+use core::ffi::{c_int, c_uint};
+use core::fmt;
+use core::ops::{BitAnd, BitOr, BitOrAssign};
 
 // TODO: Import constants from C header file at compile time.
 
-pub type PamFlag = c_uint;
 pub type PamItemType = c_int;
 pub type PamMessageStyle = c_int;
 
+/// The flags passed to PAM functions and module hooks.
+///
+/// This is a bitmask of the `PAM_*` flag constants.  The raw integer value
+/// is available as the public field or through [`PamFlag::bits`].
+///
+/// Formatting shows the names of the flags that are set, like
+/// `PAM_SILENT | PAM_UPDATE_AUTHTOK`, with any unknown bits in hex;
+/// `Debug` wraps that in `PamFlag(...)`.
+#[repr(transparent)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct PamFlag(pub c_uint);
+
 // The Linux-PAM flags
 // see /usr/include/security/_pam_types.h
-pub const PAM_SILENT: PamFlag = 0x8000;
-pub const PAM_DISALLOW_NULL_AUTHTOK: PamFlag = 0x0001;
-pub const PAM_ESTABLISH_CRED: PamFlag = 0x0002;
-pub const PAM_DELETE_CRED: PamFlag = 0x0004;
-pub const PAM_REINITIALIZE_CRED: PamFlag = 0x0008;
-pub const PAM_REFRESH_CRED: PamFlag = 0x0010;
-pub const PAM_CHANGE_EXPIRED_AUTHTOK: PamFlag = 0x0020;
+pub const PAM_SILENT: PamFlag = PamFlag(0x8000);
+pub const PAM_DISALLOW_NULL_AUTHTOK: PamFlag = PamFlag(0x0001);
+pub const PAM_ESTABLISH_CRED: PamFlag = PamFlag(0x0002);
+pub const PAM_DELETE_CRED: PamFlag = PamFlag(0x0004);
+pub const PAM_REINITIALIZE_CRED: PamFlag = PamFlag(0x0008);
+pub const PAM_REFRESH_CRED: PamFlag = PamFlag(0x0010);
+pub const PAM_CHANGE_EXPIRED_AUTHTOK: PamFlag = PamFlag(0x0020);
+pub const PAM_PRELIM_CHECK: PamFlag = PamFlag(0x4000);
+pub const PAM_UPDATE_AUTHTOK: PamFlag = PamFlag(0x2000);
+
+impl PamFlag {
+    /// Returns the raw integer value of these flags.
+    pub const fn bits(self) -> c_uint {
+        self.0
+    }
+
+    /// Returns true if every flag set in `other` is also set in `self`.
+    pub const fn contains(self, other: PamFlag) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// True if the module should not send any messages to the user.
+    pub const fn is_silent(self) -> bool {
+        self.contains(PAM_SILENT)
+    }
+
+    /// True if authentication should fail for users with a null token.
+    pub const fn disallow_null_authtok(self) -> bool {
+        self.contains(PAM_DISALLOW_NULL_AUTHTOK)
+    }
+
+    /// True if `sm_setcred` should establish the user's credentials.
+    pub const fn is_establish_cred(self) -> bool {
+        self.contains(PAM_ESTABLISH_CRED)
+    }
+
+    /// True if `sm_setcred` should delete the user's credentials.
+    pub const fn is_delete_cred(self) -> bool {
+        self.contains(PAM_DELETE_CRED)
+    }
+
+    /// True if `sm_setcred` should fully reinitialize the user's credentials.
+    pub const fn is_reinitialize_cred(self) -> bool {
+        self.contains(PAM_REINITIALIZE_CRED)
+    }
+
+    /// True if `sm_setcred` should extend the lifetime of the user's credentials.
+    pub const fn is_refresh_cred(self) -> bool {
+        self.contains(PAM_REFRESH_CRED)
+    }
+
+    /// True if `sm_chauthtok` should only change expired tokens.
+    pub const fn is_change_expired_authtok(self) -> bool {
+        self.contains(PAM_CHANGE_EXPIRED_AUTHTOK)
+    }
+
+    /// True if this is the preliminary-check pass of `sm_chauthtok`.
+    pub const fn is_prelim_check(self) -> bool {
+        self.contains(PAM_PRELIM_CHECK)
+    }
+
+    /// True if this is the pass of `sm_chauthtok` that changes the token.
+    pub const fn is_update_authtok(self) -> bool {
+        self.contains(PAM_UPDATE_AUTHTOK)
+    }
+}
+
+/// The name of each flag, in the order they are shown.
+const FLAG_NAMES: [(PamFlag, &str); 9] = [
+    (PAM_SILENT, "PAM_SILENT"),
+    (PAM_DISALLOW_NULL_AUTHTOK, "PAM_DISALLOW_NULL_AUTHTOK"),
+    (PAM_ESTABLISH_CRED, "PAM_ESTABLISH_CRED"),
+    (PAM_DELETE_CRED, "PAM_DELETE_CRED"),
+    (PAM_REINITIALIZE_CRED, "PAM_REINITIALIZE_CRED"),
+    (PAM_REFRESH_CRED, "PAM_REFRESH_CRED"),
+    (PAM_CHANGE_EXPIRED_AUTHTOK, "PAM_CHANGE_EXPIRED_AUTHTOK"),
+    (PAM_PRELIM_CHECK, "PAM_PRELIM_CHECK"),
+    (PAM_UPDATE_AUTHTOK, "PAM_UPDATE_AUTHTOK"),
+];
+
+/// Shows the set flags by name, as in `PAM_SILENT | PAM_UPDATE_AUTHTOK`,
+/// or `0` if none are set.
+impl fmt::Display for PamFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("0");
+        }
+        let mut rest = self.0;
+        let mut separator = "";
+        for (flag, name) in FLAG_NAMES {
+            if self.contains(flag) {
+                write!(f, "{separator}{name}")?;
+                rest &= !flag.0;
+                separator = " | ";
+            }
+        }
+        if rest != 0 {
+            write!(f, "{separator}{rest:#x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Shows the set flags by name, as in `PamFlag(PAM_SILENT | PAM_UPDATE_AUTHTOK)`.
+impl fmt::Debug for PamFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PamFlag({self})")
+    }
+}
+
+impl From<c_uint> for PamFlag {
+    fn from(bits: c_uint) -> Self {
+        Self(bits)
+    }
+}
+
+impl From<PamFlag> for c_uint {
+    fn from(flags: PamFlag) -> Self {
+        flags.0
+    }
+}
+
+impl BitOr for PamFlag {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for PamFlag {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for PamFlag {
+    type Output = Self;
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+/// What `sm_setcred` has been asked to do with the user's credentials.
+///
+/// Exactly one of these is passed in the flags, possibly along with
+/// `PAM_SILENT`.  The action says what to do, while `PAM_SILENT` only says
+/// not to talk to the user while doing it, so check it separately with
+/// [`PamFlag::is_silent`].
+///
+/// ```no_run
+/// # use pam::constants::{CredAction, PamFlag, PamResultCode};
+/// # use pam::module::PamHandle;
+/// # use std::ffi::CStr;
+/// fn sm_setcred(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+///     match CredAction::from_flags(flags) {
+///         Ok(CredAction::Establish | CredAction::Reinitialize) => {
+///             // Fetch a ticket...
+///             PamResultCode::PAM_SUCCESS
+///         }
+///         Ok(CredAction::Refresh) => PamResultCode::PAM_SUCCESS,
+///         Ok(CredAction::Delete) => PamResultCode::PAM_SUCCESS,
+///         Err(code) => code,
+///     }
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CredAction {
+    /// `PAM_ESTABLISH_CRED`: set up the user's credentials.
+    Establish,
+    /// `PAM_DELETE_CRED`: remove the user's credentials.
+    Delete,
+    /// `PAM_REINITIALIZE_CRED`: set up the user's credentials from scratch.
+    Reinitialize,
+    /// `PAM_REFRESH_CRED`: extend the lifetime of the user's credentials.
+    Refresh,
+}
+
+impl CredAction {
+    /// Extracts the action from the flags passed to `sm_setcred`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_SYSTEM_ERR` if no action, or more than one, is set.
+    /// (libpam passes `PAM_ESTABLISH_CRED` if the application gave none,
+    /// so a module should never see this.)
+    pub fn from_flags(flags: PamFlag) -> Result<Self, PamResultCode> {
+        let actions = flags
+            & (PAM_ESTABLISH_CRED | PAM_DELETE_CRED | PAM_REINITIALIZE_CRED | PAM_REFRESH_CRED);
+        match actions {
+            PAM_ESTABLISH_CRED => Ok(CredAction::Establish),
+            PAM_DELETE_CRED => Ok(CredAction::Delete),
+            PAM_REINITIALIZE_CRED => Ok(CredAction::Reinitialize),
+            PAM_REFRESH_CRED => Ok(CredAction::Refresh),
+            _ => Err(PamResultCode::PAM_SYSTEM_ERR),
+        }
+    }
+
+    /// The flag for this action.
+    pub const fn flag(self) -> PamFlag {
+        match self {
+            CredAction::Establish => PAM_ESTABLISH_CRED,
+            CredAction::Delete => PAM_DELETE_CRED,
+            CredAction::Reinitialize => PAM_REINITIALIZE_CRED,
+            CredAction::Refresh => PAM_REFRESH_CRED,
+        }
+    }
+}
+
+// Flags that libpam ORs into the status passed to `pam_set_data` cleanup
+// functions.
+/// The data is being replaced by a new value with the same key.
+pub const PAM_DATA_REPLACE: c_int = 0x2000_0000;
+/// The cleanup is happening in a forked process, so shared resources
+/// shouldn't be torn down.
+pub const PAM_DATA_SILENT: c_int = 0x4000_0000;
 
 // Message styles
 pub const PAM_PROMPT_ECHO_OFF: PamMessageStyle = 1;
@@ -25,10 +252,38 @@ pub const PAM_TEXT_INFO: PamMessageStyle = 4;
 pub const PAM_RADIO_TYPE: PamMessageStyle = 5;
 pub const PAM_BINARY_PROMPT: PamMessageStyle = 7;
 
-// The Linux-PAM return values
-// see /usr/include/security/_pam_types.h
+// Conversation limits
+/// The most messages that may be sent in a single conversation call.
+pub const PAM_MAX_NUM_MSG: c_int = 32;
+/// The longest a message may be, in bytes, including the nul.
+pub const PAM_MAX_MSG_SIZE: usize = 512;
+/// The longest a response may be, in bytes, including the nul.
+pub const PAM_MAX_RESP_SIZE: usize = 512;
+
+/// The Linux-PAM return values.
+///
+/// See `/usr/include/security/_pam_types.h`.
+///
+/// Ignoring a result code is almost always a bug, since it means a failure
+/// goes unnoticed, so the compiler warns when one is discarded.
+/// (The same goes for a [`PamResult`], like any `Result`.)
+///
+/// ```compile_fail
+/// #![deny(unused_must_use)]
+/// # use pam::constants::PamResultCode;
+/// # use pam::module::PamHandle;
+/// fn check(pamh: &mut PamHandle) -> PamResultCode {
+///     # PamResultCode::PAM_SUCCESS
+///     // ...
+/// }
+///
+/// fn authenticate(pamh: &mut PamHandle) {
+///     check(pamh);
+/// }
+/// ```
 #[allow(non_camel_case_types, dead_code)]
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[must_use]
 #[repr(C)]
 pub enum PamResultCode {
     PAM_SUCCESS = 0,
@@ -64,3 +319,511 @@ pub enum PamResultCode {
     PAM_CONV_AGAIN = 30,
     PAM_INCOMPLETE = 31,
 }
+
+impl PamResultCode {
+    /// Converts a raw return value into a `PamResultCode`, if it is one.
+    ///
+    /// This is the same as [`PamResultCode::try_from`], for use in `const`
+    /// contexts and `Option` chains.
+    pub const fn from_raw(raw: c_int) -> Option<Self> {
+        Some(match raw {
+            0 => PamResultCode::PAM_SUCCESS,
+            1 => PamResultCode::PAM_OPEN_ERR,
+            2 => PamResultCode::PAM_SYMBOL_ERR,
+            3 => PamResultCode::PAM_SERVICE_ERR,
+            4 => PamResultCode::PAM_SYSTEM_ERR,
+            5 => PamResultCode::PAM_BUF_ERR,
+            6 => PamResultCode::PAM_PERM_DENIED,
+            7 => PamResultCode::PAM_AUTH_ERR,
+            8 => PamResultCode::PAM_CRED_INSUFFICIENT,
+            9 => PamResultCode::PAM_AUTHINFO_UNAVAIL,
+            10 => PamResultCode::PAM_USER_UNKNOWN,
+            11 => PamResultCode::PAM_MAXTRIES,
+            12 => PamResultCode::PAM_NEW_AUTHTOK_REQD,
+            13 => PamResultCode::PAM_ACCT_EXPIRED,
+            14 => PamResultCode::PAM_SESSION_ERR,
+            15 => PamResultCode::PAM_CRED_UNAVAIL,
+            16 => PamResultCode::PAM_CRED_EXPIRED,
+            17 => PamResultCode::PAM_CRED_ERR,
+            18 => PamResultCode::PAM_NO_MODULE_DATA,
+            19 => PamResultCode::PAM_CONV_ERR,
+            20 => PamResultCode::PAM_AUTHTOK_ERR,
+            21 => PamResultCode::PAM_AUTHTOK_RECOVERY_ERR,
+            22 => PamResultCode::PAM_AUTHTOK_LOCK_BUSY,
+            23 => PamResultCode::PAM_AUTHTOK_DISABLE_AGING,
+            24 => PamResultCode::PAM_TRY_AGAIN,
+            25 => PamResultCode::PAM_IGNORE,
+            26 => PamResultCode::PAM_ABORT,
+            27 => PamResultCode::PAM_AUTHTOK_EXPIRED,
+            28 => PamResultCode::PAM_MODULE_UNKNOWN,
+            29 => PamResultCode::PAM_BAD_ITEM,
+            30 => PamResultCode::PAM_CONV_AGAIN,
+            31 => PamResultCode::PAM_INCOMPLETE,
+            _ => return None,
+        })
+    }
+
+    /// Converts the result of a libpam function, taking anything that isn't
+    /// a known code (which a broken libpam or vendor module might return)
+    /// as `PAM_SYSTEM_ERR`.
+    #[cfg(feature = "std")]
+    pub(crate) const fn from_libpam(raw: c_int) -> Self {
+        match Self::from_raw(raw) {
+            Some(code) => code,
+            None => PamResultCode::PAM_SYSTEM_ERR,
+        }
+    }
+
+    /// The name of this code, as in the C headers, like `"PAM_AUTH_ERR"`.
+    pub fn name(self) -> &'static str {
+        match self {
+            PamResultCode::PAM_SUCCESS => "PAM_SUCCESS",
+            PamResultCode::PAM_OPEN_ERR => "PAM_OPEN_ERR",
+            PamResultCode::PAM_SYMBOL_ERR => "PAM_SYMBOL_ERR",
+            PamResultCode::PAM_SERVICE_ERR => "PAM_SERVICE_ERR",
+            PamResultCode::PAM_SYSTEM_ERR => "PAM_SYSTEM_ERR",
+            PamResultCode::PAM_BUF_ERR => "PAM_BUF_ERR",
+            PamResultCode::PAM_PERM_DENIED => "PAM_PERM_DENIED",
+            PamResultCode::PAM_AUTH_ERR => "PAM_AUTH_ERR",
+            PamResultCode::PAM_CRED_INSUFFICIENT => "PAM_CRED_INSUFFICIENT",
+            PamResultCode::PAM_AUTHINFO_UNAVAIL => "PAM_AUTHINFO_UNAVAIL",
+            PamResultCode::PAM_USER_UNKNOWN => "PAM_USER_UNKNOWN",
+            PamResultCode::PAM_MAXTRIES => "PAM_MAXTRIES",
+            PamResultCode::PAM_NEW_AUTHTOK_REQD => "PAM_NEW_AUTHTOK_REQD",
+            PamResultCode::PAM_ACCT_EXPIRED => "PAM_ACCT_EXPIRED",
+            PamResultCode::PAM_SESSION_ERR => "PAM_SESSION_ERR",
+            PamResultCode::PAM_CRED_UNAVAIL => "PAM_CRED_UNAVAIL",
+            PamResultCode::PAM_CRED_EXPIRED => "PAM_CRED_EXPIRED",
+            PamResultCode::PAM_CRED_ERR => "PAM_CRED_ERR",
+            PamResultCode::PAM_NO_MODULE_DATA => "PAM_NO_MODULE_DATA",
+            PamResultCode::PAM_CONV_ERR => "PAM_CONV_ERR",
+            PamResultCode::PAM_AUTHTOK_ERR => "PAM_AUTHTOK_ERR",
+            PamResultCode::PAM_AUTHTOK_RECOVERY_ERR => "PAM_AUTHTOK_RECOVERY_ERR",
+            PamResultCode::PAM_AUTHTOK_LOCK_BUSY => "PAM_AUTHTOK_LOCK_BUSY",
+            PamResultCode::PAM_AUTHTOK_DISABLE_AGING => "PAM_AUTHTOK_DISABLE_AGING",
+            PamResultCode::PAM_TRY_AGAIN => "PAM_TRY_AGAIN",
+            PamResultCode::PAM_IGNORE => "PAM_IGNORE",
+            PamResultCode::PAM_ABORT => "PAM_ABORT",
+            PamResultCode::PAM_AUTHTOK_EXPIRED => "PAM_AUTHTOK_EXPIRED",
+            PamResultCode::PAM_MODULE_UNKNOWN => "PAM_MODULE_UNKNOWN",
+            PamResultCode::PAM_BAD_ITEM => "PAM_BAD_ITEM",
+            PamResultCode::PAM_CONV_AGAIN => "PAM_CONV_AGAIN",
+            PamResultCode::PAM_INCOMPLETE => "PAM_INCOMPLETE",
+        }
+    }
+
+    /// A description of this code, from [`pam_strerror`](crate::module::strerror).
+    #[cfg(feature = "std")]
+    pub fn description(self) -> &'static str {
+        crate::module::strerror(self)
+    }
+
+    /// True if this is `PAM_SUCCESS`.
+    pub fn is_success(&self) -> bool {
+        *self == PamResultCode::PAM_SUCCESS
+    }
+
+    /// True if this is `PAM_IGNORE`, i.e., the module should not be counted.
+    pub fn is_ignore(&self) -> bool {
+        *self == PamResultCode::PAM_IGNORE
+    }
+
+    /// True if this is `PAM_INCOMPLETE`, i.e., the conversation must be resumed.
+    pub fn is_incomplete(&self) -> bool {
+        *self == PamResultCode::PAM_INCOMPLETE
+    }
+
+    /// Classifies this code by what it means for the user or application.
+    ///
+    /// | Category       | Codes |
+    /// |----------------|-------|
+    /// | `Success`      | `PAM_SUCCESS` |
+    /// | `Ignore`       | `PAM_IGNORE` |
+    /// | `AuthFailure`  | `PAM_AUTH_ERR`, `PAM_PERM_DENIED`, `PAM_CRED_INSUFFICIENT`, `PAM_AUTHINFO_UNAVAIL`, `PAM_MAXTRIES` |
+    /// | `UserError`    | `PAM_USER_UNKNOWN`, `PAM_ACCT_EXPIRED` |
+    /// | `SessionError` | `PAM_SESSION_ERR`, `PAM_CRED_UNAVAIL`, `PAM_CRED_EXPIRED`, `PAM_CRED_ERR` |
+    /// | `TokenError`   | `PAM_NEW_AUTHTOK_REQD`, `PAM_AUTHTOK_ERR`, `PAM_AUTHTOK_RECOVERY_ERR`, `PAM_AUTHTOK_LOCK_BUSY`, `PAM_AUTHTOK_DISABLE_AGING`, `PAM_TRY_AGAIN`, `PAM_AUTHTOK_EXPIRED` |
+    /// | `Incomplete`   | `PAM_CONV_AGAIN`, `PAM_INCOMPLETE` |
+    /// | `SystemError`  | everything else |
+    pub fn category(self) -> PamCategory {
+        use PamResultCode::*;
+        match self {
+            PAM_SUCCESS => PamCategory::Success,
+            PAM_IGNORE => PamCategory::Ignore,
+            PAM_AUTH_ERR
+            | PAM_PERM_DENIED
+            | PAM_CRED_INSUFFICIENT
+            | PAM_AUTHINFO_UNAVAIL
+            | PAM_MAXTRIES => PamCategory::AuthFailure,
+            PAM_USER_UNKNOWN | PAM_ACCT_EXPIRED => PamCategory::UserError,
+            PAM_SESSION_ERR | PAM_CRED_UNAVAIL | PAM_CRED_EXPIRED | PAM_CRED_ERR => {
+                PamCategory::SessionError
+            }
+            PAM_NEW_AUTHTOK_REQD
+            | PAM_AUTHTOK_ERR
+            | PAM_AUTHTOK_RECOVERY_ERR
+            | PAM_AUTHTOK_LOCK_BUSY
+            | PAM_AUTHTOK_DISABLE_AGING
+            | PAM_TRY_AGAIN
+            | PAM_AUTHTOK_EXPIRED => PamCategory::TokenError,
+            PAM_CONV_AGAIN | PAM_INCOMPLETE => PamCategory::Incomplete,
+            PAM_OPEN_ERR | PAM_SYMBOL_ERR | PAM_SERVICE_ERR | PAM_SYSTEM_ERR | PAM_BUF_ERR
+            | PAM_NO_MODULE_DATA | PAM_CONV_ERR | PAM_ABORT | PAM_MODULE_UNKNOWN | PAM_BAD_ITEM => {
+                PamCategory::SystemError
+            }
+        }
+    }
+
+    /// Converts `PAM_SUCCESS` into `Ok(())` and every other code into an `Err`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `self` as the error if it is not `PAM_SUCCESS`.
+    pub fn into_result(self) -> Result<(), PamResultCode> {
+        match self {
+            PamResultCode::PAM_SUCCESS => Ok(()),
+            otherwise => Err(otherwise),
+        }
+    }
+}
+
+/// Shows the name along with the description,
+/// as in `PAM_AUTH_ERR ("Authentication failure")`.
+/// Without the `std` feature, shows only the name.
+impl fmt::Debug for PamResultCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        #[cfg(feature = "std")]
+        return write!(f, "{} ({:?})", self.name(), self.description());
+        #[cfg(not(feature = "std"))]
+        f.write_str(self.name())
+    }
+}
+
+/// Shows the description, as in `Authentication failure`.
+/// Without the `std` feature, shows the name, as in `PAM_AUTH_ERR`.
+impl fmt::Display for PamResultCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        #[cfg(feature = "std")]
+        return f.write_str(self.description());
+        #[cfg(not(feature = "std"))]
+        f.write_str(self.name())
+    }
+}
+
+impl From<PamResultCode> for c_int {
+    fn from(code: PamResultCode) -> Self {
+        code as c_int
+    }
+}
+
+impl TryFrom<c_int> for PamResultCode {
+    type Error = c_int;
+
+    /// Converts a raw return value, giving it back as the error if it is not
+    /// a known code.
+    fn try_from(value: c_int) -> Result<Self, Self::Error> {
+        Self::from_raw(value).ok_or(value)
+    }
+}
+
+/// A broad classification of [`PamResultCode`]s, from
+/// [`PamResultCode::category`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PamCategory {
+    /// The operation succeeded.
+    Success,
+    /// The module should not be counted (only returned by modules).
+    Ignore,
+    /// The user could not be authenticated, or was refused access.
+    AuthFailure,
+    /// Something is wrong with the user's account.
+    UserError,
+    /// The user's credentials or session could not be set up.
+    SessionError,
+    /// The user's authentication token must be, or could not be, changed.
+    TokenError,
+    /// The operation was interrupted, and should be called again.
+    Incomplete,
+    /// PAM itself, a module, or the application went wrong.
+    SystemError,
+}
+
+/// Maps an I/O error to a result code, so that `?` can be used on I/O
+/// operations in functions returning a [`PamResult`]:
+///
+/// | `io::ErrorKind`    | `PamResultCode`    |
+/// |--------------------|--------------------|
+/// | `NotFound`         | `PAM_USER_UNKNOWN` |
+/// | `PermissionDenied` | `PAM_PERM_DENIED`  |
+/// | `OutOfMemory`      | `PAM_BUF_ERR`      |
+/// | anything else      | `PAM_SYSTEM_ERR`   |
+///
+/// `NotFound` is mapped on the assumption that the missing file belongs to
+/// the user (like `~/.k5login`).  Where a missing file means something else,
+/// such as a missing configuration file, map the error explicitly.
+///
+/// ```no_run
+/// # use pam::module::PamResult;
+/// fn read_allowed_users() -> PamResult<String> {
+///     Ok(std::fs::read_to_string("/etc/security/allowed_users")?)
+/// }
+/// ```
+#[cfg(feature = "std")]
+impl From<std::io::Error> for PamResultCode {
+    fn from(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::NotFound => PamResultCode::PAM_USER_UNKNOWN,
+            std::io::ErrorKind::PermissionDenied => PamResultCode::PAM_PERM_DENIED,
+            std::io::ErrorKind::OutOfMemory => PamResultCode::PAM_BUF_ERR,
+            _ => PamResultCode::PAM_SYSTEM_ERR,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flag_predicates() {
+        assert!(PAM_SILENT.is_silent());
+        assert!(!PAM_SILENT.disallow_null_authtok());
+        assert!(PAM_DISALLOW_NULL_AUTHTOK.disallow_null_authtok());
+        assert!(PAM_ESTABLISH_CRED.is_establish_cred());
+        assert!(PAM_DELETE_CRED.is_delete_cred());
+        assert!(PAM_REINITIALIZE_CRED.is_reinitialize_cred());
+        assert!(PAM_REFRESH_CRED.is_refresh_cred());
+        assert!(PAM_CHANGE_EXPIRED_AUTHTOK.is_change_expired_authtok());
+        assert!(PAM_PRELIM_CHECK.is_prelim_check());
+        assert!(PAM_UPDATE_AUTHTOK.is_update_authtok());
+        assert!(!PamFlag::default().is_silent());
+    }
+
+    #[test]
+    fn flag_formatting() {
+        let cases = [
+            (PamFlag::default(), "0"),
+            (PAM_SILENT, "PAM_SILENT"),
+            (
+                PAM_SILENT | PAM_UPDATE_AUTHTOK,
+                "PAM_SILENT | PAM_UPDATE_AUTHTOK",
+            ),
+            (
+                PAM_PRELIM_CHECK | PAM_CHANGE_EXPIRED_AUTHTOK,
+                "PAM_CHANGE_EXPIRED_AUTHTOK | PAM_PRELIM_CHECK",
+            ),
+            (
+                PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK,
+                "PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK",
+            ),
+            (PAM_DELETE_CRED | PamFlag(0x100), "PAM_DELETE_CRED | 0x100"),
+            (PamFlag(0x0010_0000), "0x100000"),
+        ];
+        for (flags, expected) in cases {
+            assert_eq!(expected, flags.to_string());
+            assert_eq!(format!("PamFlag({expected})"), format!("{flags:?}"));
+        }
+    }
+
+    #[test]
+    fn cred_actions() {
+        for action in [
+            CredAction::Establish,
+            CredAction::Delete,
+            CredAction::Reinitialize,
+            CredAction::Refresh,
+        ] {
+            assert_eq!(Ok(action), CredAction::from_flags(action.flag()));
+            assert_eq!(
+                Ok(action),
+                CredAction::from_flags(action.flag() | PAM_SILENT)
+            );
+        }
+        assert_eq!(
+            Err(PamResultCode::PAM_SYSTEM_ERR),
+            CredAction::from_flags(PamFlag::default())
+        );
+        assert_eq!(
+            Err(PamResultCode::PAM_SYSTEM_ERR),
+            CredAction::from_flags(PAM_SILENT)
+        );
+        assert_eq!(
+            Err(PamResultCode::PAM_SYSTEM_ERR),
+            CredAction::from_flags(PAM_ESTABLISH_CRED | PAM_DELETE_CRED)
+        );
+    }
+
+    #[test]
+    fn flag_combinations() {
+        let flags = PAM_SILENT | PAM_UPDATE_AUTHTOK;
+        assert!(flags.is_silent());
+        assert!(flags.is_update_authtok());
+        assert!(!flags.is_prelim_check());
+        assert!(flags.contains(PAM_SILENT | PAM_UPDATE_AUTHTOK));
+        assert!(!flags.contains(PAM_SILENT | PAM_PRELIM_CHECK));
+        assert_eq!(PAM_SILENT, flags & PAM_SILENT);
+
+        let mut flags = PAM_DELETE_CRED;
+        flags |= PAM_SILENT;
+        assert_eq!(0x8004, flags.bits());
+        assert_eq!(flags, PamFlag::from(0x8004));
+        assert_eq!(0x8004, c_uint::from(flags));
+    }
+
+    #[test]
+    fn result_code_predicates() {
+        assert!(PamResultCode::PAM_SUCCESS.is_success());
+        assert!(!PamResultCode::PAM_IGNORE.is_success());
+        assert!(PamResultCode::PAM_IGNORE.is_ignore());
+        assert!(!PamResultCode::PAM_INCOMPLETE.is_ignore());
+        assert!(PamResultCode::PAM_INCOMPLETE.is_incomplete());
+        assert!(!PamResultCode::PAM_SUCCESS.is_incomplete());
+    }
+
+    #[test]
+    fn result_code_into_result() {
+        assert_eq!(Ok(()), PamResultCode::PAM_SUCCESS.into_result());
+        assert_eq!(
+            Err(PamResultCode::PAM_IGNORE),
+            PamResultCode::PAM_IGNORE.into_result()
+        );
+        assert_eq!(
+            Err(PamResultCode::PAM_AUTH_ERR),
+            PamResultCode::PAM_AUTH_ERR.into_result()
+        );
+    }
+
+    #[test]
+    fn names_and_descriptions() {
+        let cases = [
+            (PamResultCode::PAM_SUCCESS, "PAM_SUCCESS", "Success"),
+            (
+                PamResultCode::PAM_AUTH_ERR,
+                "PAM_AUTH_ERR",
+                "Authentication failure",
+            ),
+            (
+                PamResultCode::PAM_USER_UNKNOWN,
+                "PAM_USER_UNKNOWN",
+                "User not known to the underlying authentication module",
+            ),
+            (
+                PamResultCode::PAM_CONV_ERR,
+                "PAM_CONV_ERR",
+                "Conversation error",
+            ),
+            (
+                PamResultCode::PAM_INCOMPLETE,
+                "PAM_INCOMPLETE",
+                "Application needs to call libpam again",
+            ),
+        ];
+        for (code, name, description) in cases {
+            assert_eq!(name, code.name());
+            assert_eq!(description, code.description());
+            assert_eq!(description, code.to_string());
+            assert_eq!(format!("{name} ({description:?})"), format!("{code:?}"));
+        }
+        for raw in 0..=31 {
+            let code = PamResultCode::from_raw(raw).unwrap();
+            assert_eq!(raw, c_int::from(code));
+            assert!(format!("{code:?}").starts_with(code.name()));
+        }
+    }
+
+    #[test]
+    fn raw_round_trip() {
+        for raw in 0..=31 {
+            let code = PamResultCode::try_from(raw).unwrap();
+            assert_eq!(raw, c_int::from(code));
+            assert_eq!(Ok(code), PamResultCode::try_from(c_int::from(code)));
+        }
+        for raw in [-1, 32, 0x2000_0000, c_int::MIN, c_int::MAX] {
+            assert_eq!(Err(raw), PamResultCode::try_from(raw));
+            assert_eq!(
+                PamResultCode::PAM_SYSTEM_ERR,
+                PamResultCode::from_libpam(raw)
+            );
+        }
+        const IGNORE: Option<PamResultCode> = PamResultCode::from_raw(25);
+        assert_eq!(Some(PamResultCode::PAM_IGNORE), IGNORE);
+        assert_eq!(PamResultCode::PAM_ABORT, PamResultCode::from_libpam(26));
+    }
+
+    #[test]
+    fn from_io_error() {
+        use std::io::{Error, ErrorKind};
+        let cases = [
+            (ErrorKind::NotFound, PamResultCode::PAM_USER_UNKNOWN),
+            (ErrorKind::PermissionDenied, PamResultCode::PAM_PERM_DENIED),
+            (ErrorKind::OutOfMemory, PamResultCode::PAM_BUF_ERR),
+            (ErrorKind::ConnectionRefused, PamResultCode::PAM_SYSTEM_ERR),
+            (ErrorKind::InvalidData, PamResultCode::PAM_SYSTEM_ERR),
+            (ErrorKind::Other, PamResultCode::PAM_SYSTEM_ERR),
+        ];
+        for (kind, code) in cases {
+            assert_eq!(code, PamResultCode::from(Error::from(kind)), "{kind:?}");
+        }
+        let missing = std::fs::File::open("/nonexistent/nonstick");
+        assert_eq!(
+            Err(PamResultCode::PAM_USER_UNKNOWN),
+            missing.map(drop).map_err(PamResultCode::from)
+        );
+    }
+
+    #[test]
+    fn header_values() {
+        // From <security/_pam_types.h>.
+        assert_eq!(1, PAM_PROMPT_ECHO_OFF);
+        assert_eq!(2, PAM_PROMPT_ECHO_ON);
+        assert_eq!(3, PAM_ERROR_MSG);
+        assert_eq!(4, PAM_TEXT_INFO);
+        assert_eq!(32, PAM_MAX_NUM_MSG);
+        assert_eq!(512, PAM_MAX_MSG_SIZE);
+        assert_eq!(512, PAM_MAX_RESP_SIZE);
+    }
+
+    #[test]
+    fn categories() {
+        use PamCategory::*;
+        let expected = [
+            Success,
+            SystemError,
+            SystemError,
+            SystemError,
+            SystemError,
+            SystemError,
+            AuthFailure,
+            AuthFailure,
+            AuthFailure,
+            AuthFailure,
+            UserError,
+            AuthFailure,
+            TokenError,
+            UserError,
+            SessionError,
+            SessionError,
+            SessionError,
+            SessionError,
+            SystemError,
+            SystemError,
+            TokenError,
+            TokenError,
+            TokenError,
+            TokenError,
+            TokenError,
+            Ignore,
+            SystemError,
+            TokenError,
+            SystemError,
+            SystemError,
+            Incomplete,
+            Incomplete,
+        ];
+        for (raw, category) in (0..).zip(expected) {
+            let code = PamResultCode::from_raw(raw).unwrap();
+            assert_eq!(category, code.category(), "{code:?}");
+        }
+        assert_eq!(None, PamResultCode::from_raw(32));
+    }
+}
@@ -2,23 +2,51 @@ use libc::{c_char, c_int};
 use std::ffi::{CStr, CString};
 use std::ptr;
 
-use crate::constants::PamMessageStyle;
+use crate::args::ModuleArgs;
 use crate::constants::PamResultCode;
-use crate::items::Item;
-use crate::module::PamResult;
+use crate::constants::{
+    PamFlag, PamMessageStyle, PAM_ERROR_MSG, PAM_MAX_NUM_MSG, PAM_MAX_RESP_SIZE,
+    PAM_PROMPT_ECHO_OFF, PAM_PROMPT_ECHO_ON, PAM_TEXT_INFO,
+};
+use crate::items::{AuthTok, FromRaw, IntoRaw, Item};
+use crate::module::{to_c_string, PamHandle, PamResult};
+use crate::util::{zero_bytes, Secret};
 
+/// A message sent to a conversation function (`struct pam_message`).
 #[repr(C)]
-struct PamMessage {
-    msg_style: PamMessageStyle,
-    msg: *const c_char,
+#[derive(Debug)]
+pub struct PamMessage {
+    /// The kind of message, e.g. `PAM_PROMPT_ECHO_OFF`.
+    pub msg_style: PamMessageStyle,
+    /// The text of the message.
+    pub msg: *const c_char,
 }
 
+/// A response returned by a conversation function (`struct pam_response`).
 #[repr(C)]
-struct PamResponse {
-    resp: *const c_char,
-    resp_retcode: libc::c_int, // Unused - always zero
+#[derive(Debug)]
+pub struct PamResponse {
+    /// The `malloc`ed response, or null if there is none.
+    pub resp: *mut c_char,
+    /// Unused; always zero.
+    pub resp_retcode: libc::c_int,
 }
 
+/// The signature of a conversation function.
+///
+/// `pam_message` points to an array of `num_msg` pointers to messages.
+/// The function must set `pam_response` to a `malloc`ed array of `num_msg`
+/// responses, whose non-null `resp` strings are also `malloc`ed.
+///
+/// It returns a plain `int`, like [`RawConvFn`](crate::client::RawConvFn),
+/// since an application's function may return any value.
+pub(crate) type ConvFn = extern "C" fn(
+    num_msg: c_int,
+    pam_message: *const *const PamMessage,
+    pam_response: *mut *mut PamResponse,
+    appdata_ptr: *const libc::c_void,
+) -> c_int;
+
 /// `PamConv` acts as a channel for communicating with user.
 ///
 /// Communication is mediated by the pam client (the application that invoked
@@ -26,69 +54,1037 @@ struct PamResponse {
 /// will be relayed back.
 #[repr(C)]
 pub struct Inner {
-    conv: extern "C" fn(
-        num_msg: c_int,
-        pam_message: &&PamMessage,
-        pam_response: &mut *const PamResponse,
-        appdata_ptr: *const libc::c_void,
-    ) -> PamResultCode,
-    appdata_ptr: *const libc::c_void,
+    /// The callback, which a broken application might leave null.
+    pub(crate) conv: Option<ConvFn>,
+    pub(crate) appdata_ptr: *const libc::c_void,
+}
+
+/// A message to send through a [`Conv`], which determines its style.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Prompt {
+    /// Asks for a password, with echo off, as `Password: `.
+    Password,
+    /// Asks for a user name, with echo on, as `login: `.
+    Username,
+    /// Shows an informational message (`PAM_TEXT_INFO`).
+    Info(String),
+    /// Shows an error message (`PAM_ERROR_MSG`).
+    Error(String),
+    /// Sends a message with any style, e.g. a prompt with custom text.
+    Custom {
+        /// The message style, like `PAM_PROMPT_ECHO_OFF`.
+        style: PamMessageStyle,
+        /// The text of the message.
+        text: String,
+    },
+}
+
+impl Prompt {
+    /// The message style to send this with.
+    pub fn style(&self) -> PamMessageStyle {
+        match self {
+            Prompt::Password => PAM_PROMPT_ECHO_OFF,
+            Prompt::Username => PAM_PROMPT_ECHO_ON,
+            Prompt::Info(_) => PAM_TEXT_INFO,
+            Prompt::Error(_) => PAM_ERROR_MSG,
+            Prompt::Custom { style, .. } => *style,
+        }
+    }
+
+    /// The text of the message.
+    pub fn text(&self) -> &str {
+        match self {
+            Prompt::Password => "Password: ",
+            Prompt::Username => "login: ",
+            Prompt::Info(text) | Prompt::Error(text) | Prompt::Custom { text, .. } => text,
+        }
+    }
+
+    /// A prompt with custom text, with echo off.
+    pub fn secret(text: impl Into<String>) -> Self {
+        Prompt::Custom {
+            style: PAM_PROMPT_ECHO_OFF,
+            text: text.into(),
+        }
+    }
+
+    /// A prompt with custom text, with echo on.
+    pub fn visible(text: impl Into<String>) -> Self {
+        Prompt::Custom {
+            style: PAM_PROMPT_ECHO_ON,
+            text: text.into(),
+        }
+    }
 }
 
-pub struct Conv<'a>(&'a Inner);
+/// A borrowed reference to the conversation installed in a PAM handle.
+///
+/// The callback and its `appdata_ptr` are owned by the application.  They are
+/// only guaranteed to be valid while the PAM handle they came from is alive
+/// and the conversation item has not been replaced, so a `Conv` should never
+/// outlive the borrow of the handle it was retrieved from, which the compiler
+/// enforces for both `get_item::<Conv>()` and [`Conv::from_borrowed`].
+///
+/// A `Conv` is ready to use as soon as it is retrieved.  If the application
+/// didn't provide a conversation, there is no `Conv`, and modules that need
+/// one usually fail with `PAM_CONV_ERR`:
+///
+/// ```no_run
+/// # use pam::constants::PamResultCode;
+/// # use pam::conv::{Conv, Prompt};
+/// # use pam::module::{PamHandle, PamResult};
+/// fn ask_pet_name(pamh: &PamHandle) -> PamResult<String> {
+///     let conv = pamh.get_item::<Conv>()?.ok_or(PamResultCode::PAM_CONV_ERR)?;
+///     let answer = conv.send(&Prompt::visible("Name of your first pet: "))?;
+///     Ok(answer.map(|a| a.to_string_lossy().into_owned()).unwrap_or_default())
+/// }
+/// ```
+pub struct Conv<'a>(pub(crate) &'a Inner);
+
+impl<'a> Conv<'a> {
+    /// Gets the conversation from `pamh`, borrowed for as long as `pamh` is.
+    ///
+    /// This is shorthand for `pamh.get_item::<Conv>()`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails.
+    pub fn from_borrowed(pamh: &'a PamHandle) -> PamResult<Option<Self>> {
+        pamh.get_item::<Conv<'a>>()
+    }
+
+    /// True if the conversation has a callback to send messages to.
+    pub(crate) fn has_callback(&self) -> bool {
+        self.0.conv.is_some()
+    }
+
+    /// The application-provided data pointer that is passed to every call
+    /// of the conversation callback.
+    ///
+    /// This is opaque to modules; only the application knows what it points to.
+    pub fn appdata_ptr(&self) -> *const libc::c_void {
+        self.0.appdata_ptr
+    }
 
-impl Conv<'_> {
     /// Sends a message to the pam client.
     ///
     /// This will typically result in the user seeing a message or a prompt.
-    /// There are several message styles available:
-    ///
-    /// - PAM_PROMPT_ECHO_OFF
-    /// - PAM_PROMPT_ECHO_ON
-    /// - PAM_ERROR_MSG
-    /// - PAM_TEXT_INFO
-    /// - PAM_RADIO_TYPE
-    /// - PAM_BINARY_PROMPT
+    /// The [`Prompt`] determines the message style, so that, for instance,
+    /// passwords are always asked for with echo off.
     ///
     /// Note that the user experience will depend on how the client implements
     /// these message styles - and not all applications implement all message
     /// styles.
-    pub fn send(&self, style: PamMessageStyle, msg: &str) -> PamResult<Option<&CStr>> {
-        let mut resp_ptr: *const PamResponse = ptr::null();
-        let msg_cstr = CString::new(msg).unwrap();
-        let msg = PamMessage {
-            msg_style: style,
-            msg: msg_cstr.as_ptr(),
-        };
+    ///
+    /// The response is copied, and the application's buffer zeroed and freed.
+    /// Messages that don't take an answer, like `PAM_TEXT_INFO`, always get
+    /// `None`.
+    ///
+    /// A response may be at most [`PAM_MAX_RESP_SIZE`] bytes long, including
+    /// the nul.  A longer response is an error rather than being truncated,
+    /// since a truncated password might still be accepted.  It is never
+    /// copied, but it is still zeroed in full before it is freed, in case it
+    /// is a password.  The same goes for every other way of prompting here.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the conversation fails, or `PAM_CONV_ERR` if the
+    /// message contains a nul byte or the response is too long.
+    pub fn send(&self, prompt: &Prompt) -> PamResult<Option<CString>> {
+        let mut responses = self.exchange(std::slice::from_ref(prompt))?;
+        Ok(responses.answers[0].take())
+    }
 
-        let ret = (self.0.conv)(1, &&msg, &mut resp_ptr, self.0.appdata_ptr);
+    /// Asks for a secret, like a password or a one-time code, with echo off.
+    ///
+    /// There is no way to ask for a [`Secret`] with echo on, nor a plain
+    /// `String` with echo off, so that what the user types is only shown
+    /// if it is thrown away.  The response buffer is zeroed once copied.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the conversation fails, `PAM_CONV_ERR` if the user
+    /// gives no answer or too long a one or the prompt contains a nul byte,
+    /// or `PAM_BUF_ERR` if the answer is not UTF-8.
+    pub fn prompt_secret(&self, text: &str) -> PamResult<Secret> {
+        let response = self.send(&Prompt::secret(text))?;
+        into_secret(response.ok_or(PamResultCode::PAM_CONV_ERR)?)
+    }
 
-        if PamResultCode::PAM_SUCCESS == ret {
-            // PamResponse.resp is null for styles that don't return user input like PAM_TEXT_INFO
-            let response = unsafe { (*resp_ptr).resp };
-            if response.is_null() {
-                Ok(None)
-            } else {
-                Ok(Some(unsafe { CStr::from_ptr(response) }))
+    /// Asks for something that isn't secret, like a user name, with echo on.
+    ///
+    /// See [`prompt_secret`](Self::prompt_secret) for secrets.
+    ///
+    /// # Errors
+    ///
+    /// As for [`prompt_secret`](Self::prompt_secret).
+    pub fn prompt_visible(&self, text: &str) -> PamResult<String> {
+        self.send(&Prompt::visible(text))?
+            .ok_or(PamResultCode::PAM_CONV_ERR)?
+            .into_string()
+            .map_err(|_| PamResultCode::PAM_BUF_ERR)
+    }
+
+    /// Sends `prompts` in one call to the conversation function, and takes
+    /// the answers, zeroing and freeing the application's buffers.
+    fn exchange(&self, prompts: &[Prompt]) -> PamResult<Responses> {
+        let texts = prompts
+            .iter()
+            .map(|prompt| to_c_string(prompt.text()))
+            .collect::<PamResult<Vec<_>>>()?;
+        let msgs: Vec<PamMessage> = prompts
+            .iter()
+            .zip(&texts)
+            .map(|(prompt, text)| PamMessage {
+                msg_style: prompt.style(),
+                msg: text.as_ptr(),
+            })
+            .collect();
+        let msg_ptrs: Vec<*const PamMessage> = msgs.iter().map(ptr::from_ref).collect();
+
+        let conv = self.0.conv.ok_or(PamResultCode::PAM_CONV_ERR)?;
+        let mut resp_ptr: *mut PamResponse = ptr::null_mut();
+        let ret = conv(
+            prompts.len() as c_int,
+            msg_ptrs.as_ptr(),
+            &mut resp_ptr,
+            self.0.appdata_ptr,
+        );
+        PamResultCode::try_from(ret)
+            .unwrap_or(PamResultCode::PAM_CONV_ERR)
+            .into_result()?;
+        unsafe { take_responses(resp_ptr, prompts) }
+    }
+
+    /// Prompts for a value (with echo off) up to `max_attempts` times,
+    /// until `validate` accepts one.
+    ///
+    /// After each rejected response but the last, a `PAM_ERROR_MSG` asking
+    /// the user to try again is sent, unless `flags` includes `PAM_SILENT`.
+    /// A missing response is treated as an empty string.  Since responses
+    /// may be secret, each is zeroed once `validate` has seen it, and only
+    /// the accepted one is returned, as a copy.
+    ///
+    /// Returns `Ok(None)` if every attempt was rejected, which a hook will
+    /// usually turn into `PAM_AUTH_ERR`.
+    ///
+    /// ```no_run
+    /// # use pam::constants::{PamFlag, PamResultCode};
+    /// # use pam::conv::Conv;
+    /// # use pam::module::PamHandle;
+    /// # fn check_pin(pamh: &PamHandle, flags: PamFlag) -> PamResultCode {
+    /// let conv = match Conv::from_borrowed(pamh) {
+    ///     Ok(Some(conv)) => conv,
+    ///     _ => return PamResultCode::PAM_CONV_ERR,
+    /// };
+    /// let is_pin = |pin: &str| pin.len() == 4 && pin.bytes().all(|b| b.is_ascii_digit());
+    /// match conv.prompt_retry("PIN: ", 3, flags, is_pin) {
+    ///     Ok(Some(_pin)) => PamResultCode::PAM_SUCCESS,
+    ///     Ok(None) => PamResultCode::PAM_AUTH_ERR,
+    ///     Err(e) => e,
+    /// }
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the conversation fails, `PAM_CONV_ERR` if the
+    /// prompt contains a nul byte, or `PAM_BUF_ERR` if a response is not UTF-8.
+    pub fn prompt_retry(
+        &self,
+        prompt: &str,
+        max_attempts: u32,
+        flags: PamFlag,
+        mut validate: impl FnMut(&str) -> bool,
+    ) -> PamResult<Option<String>> {
+        let notifier = Notifier::new(self, flags);
+        let prompt = Prompt::secret(prompt);
+        for attempt in 1..=max_attempts {
+            // Held as a Secret, so that rejected answers are zeroed.
+            let response = into_secret(self.send(&prompt)?.unwrap_or_default())?;
+            if validate(response.expose()) {
+                return Ok(Some(response.expose().to_owned()));
+            }
+            if attempt < max_attempts {
+                notifier.error("Sorry, try again.")?;
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Assembles a batch of messages to send in a single conversation call.
+///
+/// Applications that show a form, like graphical greeters, can then
+/// present all the prompts at once rather than one at a time.
+///
+/// A batch may hold at most [`PAM_MAX_NUM_MSG`] messages, since that is all
+/// libpam promises an application will accept in one call.  Adding messages
+/// doesn't fail, so that they can be chained; a batch that is too big is
+/// rejected by [`send`](Self::send) before anything is sent.  Code that
+/// builds a batch in a loop can stop when it [`is_full`](Self::is_full).
+///
+/// ```no_run
+/// # use pam::constants::PamResultCode;
+/// # use pam::conv::{Conv, ConvBuilder};
+/// # use pam::module::{PamHandle, PamResult};
+/// fn ask_for_codes(pamh: &PamHandle) -> PamResult<()> {
+///     let conv = Conv::from_borrowed(pamh)?.ok_or(PamResultCode::PAM_CONV_ERR)?;
+///     let answers = ConvBuilder::new()
+///         .info("Your account requires two factors.")
+///         .prompt_off("Password: ")
+///         .prompt_on("One-time code: ")
+///         .send(&conv)?;
+///     let _password = answers.get(1).ok_or(PamResultCode::PAM_CONV_ERR)?;
+///     let _code = answers.get(2).ok_or(PamResultCode::PAM_CONV_ERR)?;
+///     // ...
+/// #   Ok(())
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ConvBuilder {
+    prompts: Vec<Prompt>,
+}
+
+impl ConvBuilder {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a prompt with echo off, e.g. for a password.
+    pub fn prompt_off(self, text: impl Into<String>) -> Self {
+        self.message(Prompt::secret(text))
+    }
+
+    /// Adds a prompt with echo on, e.g. for a user name.
+    pub fn prompt_on(self, text: impl Into<String>) -> Self {
+        self.message(Prompt::visible(text))
+    }
+
+    /// Adds an informational message (`PAM_TEXT_INFO`).
+    pub fn info(self, text: impl Into<String>) -> Self {
+        self.message(Prompt::Info(text.into()))
+    }
+
+    /// Adds an error message (`PAM_ERROR_MSG`).
+    pub fn error(self, text: impl Into<String>) -> Self {
+        self.message(Prompt::Error(text.into()))
+    }
+
+    /// Adds a message of any style.
+    pub fn message(mut self, prompt: Prompt) -> Self {
+        self.prompts.push(prompt);
+        self
+    }
+
+    /// The number of messages in the batch.
+    pub fn len(&self) -> usize {
+        self.prompts.len()
+    }
+
+    /// True if no messages have been added.
+    pub fn is_empty(&self) -> bool {
+        self.prompts.is_empty()
+    }
+
+    /// True if the batch holds [`PAM_MAX_NUM_MSG`] messages, so that adding
+    /// any more would make it too big to send.
+    pub fn is_full(&self) -> bool {
+        self.prompts.len() >= PAM_MAX_NUM_MSG as usize
+    }
+
+    /// Sends every message in one call to the conversation function,
+    /// and returns the answers in the same order.
+    ///
+    /// The response buffers are zeroed and freed once copied.  As with
+    /// [`Conv::send`], no answer may be longer than [`PAM_MAX_RESP_SIZE`].
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_CONV_ERR` without sending anything if the batch is empty,
+    /// holds more than [`PAM_MAX_NUM_MSG`] messages, or a message contains a
+    /// nul byte.  Otherwise, returns an error if the conversation fails,
+    /// or `PAM_CONV_ERR` if any answer is too long.
+    pub fn send(&self, conv: &Conv) -> PamResult<Responses> {
+        let count = self.prompts.len();
+        if count == 0 || count > PAM_MAX_NUM_MSG as usize {
+            return Err(PamResultCode::PAM_CONV_ERR);
+        }
+        conv.exchange(&self.prompts)
+    }
+}
+
+/// The answers to a batch of messages sent with a [`ConvBuilder`],
+/// one per message, in order.
+///
+/// Messages that don't take an answer, like `PAM_TEXT_INFO`, have `None`,
+/// as do prompts the application left unanswered.  The answers are zeroed
+/// when dropped, and left out of the `Debug` output.
+#[derive(Default, PartialEq, Eq)]
+pub struct Responses {
+    answers: Vec<Option<CString>>,
+}
+
+impl Responses {
+    /// The answer to the message at `index`, if there is one.
+    pub fn get(&self, index: usize) -> Option<&CStr> {
+        self.answers.get(index)?.as_deref()
+    }
+
+    /// The number of messages that were sent.
+    pub fn len(&self) -> usize {
+        self.answers.len()
+    }
+
+    /// True if no messages were sent.
+    pub fn is_empty(&self) -> bool {
+        self.answers.is_empty()
+    }
+
+    /// Iterates over the answers in order.
+    pub fn iter(&self) -> impl Iterator<Item = Option<&CStr>> {
+        self.answers.iter().map(Option::as_deref)
+    }
+}
+
+/// Shows only which messages were answered, as in `[None, Some(..)]`,
+/// since answers may be passwords.
+impl std::fmt::Debug for Responses {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        struct Redacted;
+        impl std::fmt::Debug for Redacted {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("..")
             }
-        } else {
-            Err(ret)
+        }
+        f.debug_list()
+            .entries(self.answers.iter().map(|a| a.as_ref().map(|_| Redacted)))
+            .finish()
+    }
+}
+
+impl Drop for Responses {
+    fn drop(&mut self) {
+        for answer in self.answers.iter_mut().flatten() {
+            let mut bytes = std::mem::take(answer).into_bytes();
+            zero_bytes(&mut bytes);
         }
     }
 }
 
+/// Copies the responses to `prompts` from the array a conversation function
+/// returned in `resp_ptr`, then zeroes and frees every response string and
+/// the array, whether or not they could all be copied.
+///
+/// Messages that don't take an answer get `None`, whatever the response.
+///
+/// # Errors
+///
+/// Returns `PAM_CONV_ERR` if `resp_ptr` is null or any response is too long.
+///
+/// # Safety
+///
+/// `resp_ptr` must be null, or a `malloc`ed array of `prompts.len()`
+/// responses whose strings are each null or `malloc`ed.
+unsafe fn take_responses(resp_ptr: *mut PamResponse, prompts: &[Prompt]) -> PamResult<Responses> {
+    if resp_ptr.is_null() {
+        return Err(PamResultCode::PAM_CONV_ERR);
+    }
+    let mut too_long = false;
+    let answers = prompts
+        .iter()
+        .enumerate()
+        .map(|(i, prompt)| {
+            let resp = (*resp_ptr.add(i)).resp;
+            if resp.is_null() {
+                return None;
+            }
+            let mut len = libc::strnlen(resp, PAM_MAX_RESP_SIZE);
+            if len == PAM_MAX_RESP_SIZE {
+                too_long = true;
+                len = libc::strlen(resp);
+            }
+            let bytes = std::slice::from_raw_parts_mut(resp.cast::<u8>(), len);
+            let takes_answer = !matches!(prompt.style(), PAM_TEXT_INFO | PAM_ERROR_MSG);
+            let answer = (takes_answer && !too_long).then(|| copy_response(bytes));
+            zero_bytes(bytes);
+            #[cfg(test)]
+            tests::ZEROED.with(|zeroed| zeroed.borrow_mut().push(len));
+            libc::free(resp.cast());
+            answer
+        })
+        .collect();
+    libc::free(resp_ptr.cast());
+    let responses = Responses { answers };
+    if too_long {
+        return Err(PamResultCode::PAM_CONV_ERR);
+    }
+    Ok(responses)
+}
+
+/// Copies a response into a `CString`, allocated at its final size so that
+/// no copy is left behind by growing it.
+fn copy_response(bytes: &[u8]) -> CString {
+    let mut copy = Vec::with_capacity(bytes.len() + 1);
+    copy.extend_from_slice(bytes);
+    // SAFETY: `bytes` ends at the first nul.
+    unsafe { CString::from_vec_unchecked(copy) }
+}
+
+/// Turns an answer into a [`Secret`] without copying it, or zeroes it if
+/// it is not UTF-8.
+fn into_secret(answer: CString) -> PamResult<Secret> {
+    match answer.into_string() {
+        Ok(token) => Ok(Secret::new(token)),
+        Err(e) => {
+            zero_bytes(&mut e.into_cstring().into_bytes());
+            Err(PamResultCode::PAM_BUF_ERR)
+        }
+    }
+}
+
+/// Gets the user's password the way `pam_unix` and most other modules do,
+/// following the `use_first_pass` and `try_first_pass` module arguments.
+///
+/// - With `use_first_pass`, only the token an earlier module in the stack
+///   stored in `PAM_AUTHTOK` is used.  The user is never prompted, and if
+///   there is no token, this fails with `PAM_AUTHTOK_RECOVERY_ERR`.
+/// - With `try_first_pass`, the stored token is used if there is one,
+///   and otherwise the user is prompted.
+/// - With neither, the user is always prompted.
+///
+/// A token obtained by prompting (with echo off) is stored in `PAM_AUTHTOK`,
+/// so that modules later in the stack can use it in turn.  This module's
+/// copy is returned as a [`Secret`], which is zeroed when dropped.
+///
+/// ```no_run
+/// # use pam::args::ModuleArgs;
+/// # use pam::constants::{PamFlag, PamResultCode};
+/// # use pam::conv::obtain_authtok;
+/// # use pam::module::PamHandle;
+/// # use std::ffi::CStr;
+/// fn sm_authenticate(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+///     let password = match obtain_authtok(pamh, &ModuleArgs::new(&args), "Password: ") {
+///         Ok(password) => password,
+///         Err(code) => return code,
+///     };
+///     // ...
+/// #   PamResultCode::PAM_SUCCESS
+/// }
+/// ```
+///
+/// # Errors
+///
+/// Returns `PAM_AUTHTOK_RECOVERY_ERR` as described above, an error if the
+/// conversation fails or storing the token fails, `PAM_CONV_ERR` if
+/// there is no conversation, the user gives no answer, or the prompt contains
+/// a nul byte, or `PAM_BUF_ERR` if the token is not UTF-8.
+pub fn obtain_authtok(pamh: &mut PamHandle, args: &ModuleArgs, prompt: &str) -> PamResult<Secret> {
+    let use_first_pass = args.has_flag(b"use_first_pass");
+    if use_first_pass || args.has_flag(b"try_first_pass") {
+        match pamh.get_item_secret::<AuthTok>()? {
+            Some(token) => return Ok(token),
+            None if use_first_pass => return Err(PamResultCode::PAM_AUTHTOK_RECOVERY_ERR),
+            None => {}
+        }
+    }
+    let token = Conv::from_borrowed(pamh)?
+        .ok_or(PamResultCode::PAM_CONV_ERR)?
+        .send(&Prompt::secret(prompt))?
+        .ok_or(PamResultCode::PAM_CONV_ERR)?;
+    let stored = match token.to_str() {
+        Ok(_) => pamh.set_item(AuthTok(&token)),
+        Err(_) => Err(PamResultCode::PAM_BUF_ERR),
+    };
+    // Converted even if storing it failed, so that it is zeroed either way.
+    let token = into_secret(token)?;
+    stored.map(|()| token)
+}
+
+/// Sends informational and error messages to the user, unless the module
+/// was invoked with `PAM_SILENT`.
+///
+/// PAM convention is that a silent module sends *no* messages, so
+/// under `PAM_SILENT` even error messages are suppressed.  Prompts that need
+/// a response should still be sent directly with [`Conv::send`].
+pub struct Notifier<'a> {
+    conv: &'a Conv<'a>,
+    silent: bool,
+}
+
+impl<'a> Notifier<'a> {
+    /// Creates a notifier for a hook that was called with `flags`.
+    pub fn new(conv: &'a Conv<'a>, flags: PamFlag) -> Self {
+        Self {
+            conv,
+            silent: flags.is_silent(),
+        }
+    }
+
+    /// Sends a `PAM_TEXT_INFO` message, unless silent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the conversation fails.
+    pub fn info(&self, msg: &str) -> PamResult<()> {
+        self.notify(Prompt::Info(msg.to_owned()))
+    }
+
+    /// Sends a `PAM_ERROR_MSG` message, unless silent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the conversation fails.
+    pub fn error(&self, msg: &str) -> PamResult<()> {
+        self.notify(Prompt::Error(msg.to_owned()))
+    }
+
+    fn notify(&self, prompt: Prompt) -> PamResult<()> {
+        if self.silent {
+            return Ok(());
+        }
+        self.conv.send(&prompt).map(drop)
+    }
+}
+
 impl Item for Conv<'_> {
     type Raw = Inner;
 
-    fn type_id() -> crate::items::ItemType {
-        crate::items::ItemType::Conv
+    fn type_id() -> crate::constants::PamItemType {
+        crate::items::ItemType::Conv.into()
     }
+}
 
+impl<'a> FromRaw<'a> for Conv<'a> {
     unsafe fn from_raw(raw: *const Self::Raw) -> Self {
         Self(&*raw)
     }
+}
 
-    fn into_raw(self) -> *const Self::Raw {
+impl IntoRaw for Conv<'_> {
+    fn as_raw(&self) -> *const Self::Raw {
         self.0 as _
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::ffi::CString;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    static CALLED: AtomicBool = AtomicBool::new(false);
+
+    thread_local! {
+        /// The lengths of the responses zeroed on this thread.
+        pub(super) static ZEROED: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+    }
+
+    extern "C" fn record_call(
+        _: c_int,
+        _: *const *const PamMessage,
+        _: *mut *mut PamResponse,
+        _: *const libc::c_void,
+    ) -> c_int {
+        CALLED.store(true, Ordering::SeqCst);
+        PamResultCode::PAM_CONV_ERR.into()
+    }
+
+    #[test]
+    fn nul_message_is_error() {
+        let inner = Inner {
+            conv: Some(record_call),
+            appdata_ptr: ptr::null(),
+        };
+        let conv = Conv(&inner);
+        assert_eq!(
+            Err(PamResultCode::PAM_CONV_ERR),
+            conv.send(&Prompt::Info("hello\0world".to_owned()))
+        );
+        assert!(!CALLED.load(Ordering::SeqCst));
+    }
+
+    /// Returns a number that isn't a `PamResultCode`.
+    extern "C" fn bogus_code(
+        _: c_int,
+        _: *const *const PamMessage,
+        _: *mut *mut PamResponse,
+        _: *const libc::c_void,
+    ) -> c_int {
+        1000
+    }
+
+    #[test]
+    fn unknown_result_is_conv_error() {
+        let inner = Inner {
+            conv: Some(bogus_code),
+            appdata_ptr: ptr::null(),
+        };
+        assert_eq!(
+            Err(PamResultCode::PAM_CONV_ERR),
+            Conv(&inner).send(&Prompt::Password)
+        );
+    }
+
+    extern "C" fn count_calls(
+        _: c_int,
+        _: *const *const PamMessage,
+        resp: *mut *mut PamResponse,
+        appdata_ptr: *const libc::c_void,
+    ) -> c_int {
+        let counter = unsafe { &*appdata_ptr.cast::<AtomicUsize>() };
+        counter.fetch_add(1, Ordering::SeqCst);
+        unsafe { *resp = libc::calloc(1, std::mem::size_of::<PamResponse>()).cast() };
+        PamResultCode::PAM_SUCCESS.into()
+    }
+
+    #[test]
+    fn appdata_is_threaded_through() {
+        let counter = AtomicUsize::new(0);
+        let appdata_ptr = (&counter as *const AtomicUsize).cast();
+        let inner = Inner {
+            conv: Some(count_calls),
+            appdata_ptr,
+        };
+        let conv = Conv(&inner);
+        assert_eq!(appdata_ptr, conv.appdata_ptr());
+        assert_eq!(Ok(None), conv.send(&Prompt::Info("one".to_owned())));
+        assert_eq!(Ok(None), conv.send(&Prompt::Info("two".to_owned())));
+        assert_eq!(2, counter.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn notifier_respects_silent() {
+        let counter = AtomicUsize::new(0);
+        let inner = Inner {
+            conv: Some(count_calls),
+            appdata_ptr: (&counter as *const AtomicUsize).cast(),
+        };
+        let conv = Conv(&inner);
+
+        let silent = Notifier::new(&conv, crate::constants::PAM_SILENT);
+        assert_eq!(Ok(()), silent.info("hello"));
+        assert_eq!(Ok(()), silent.error("oops"));
+        assert_eq!(0, counter.load(Ordering::SeqCst));
+
+        let loud = Notifier::new(&conv, PamFlag::default());
+        assert_eq!(Ok(()), loud.info("hello"));
+        assert_eq!(Ok(()), loud.error("oops"));
+        assert_eq!(2, counter.load(Ordering::SeqCst));
+    }
+
+    /// Answers prompts from `answers`, and records every message it is sent.
+    #[derive(Default)]
+    struct Script {
+        answers: Vec<&'static str>,
+        seen: Vec<(PamMessageStyle, String)>,
+    }
+
+    extern "C" fn scripted(
+        num_msg: c_int,
+        msg: *const *const PamMessage,
+        resp: *mut *mut PamResponse,
+        appdata_ptr: *const libc::c_void,
+    ) -> c_int {
+        let mut script = unsafe { &*appdata_ptr.cast::<RefCell<Script>>() }.borrow_mut();
+        let count = num_msg as usize;
+        let responses = unsafe { libc::calloc(count, std::mem::size_of::<PamResponse>()) }
+            .cast::<PamResponse>();
+        for i in 0..count {
+            let msg = unsafe { &**msg.add(i) };
+            let text = unsafe { CStr::from_ptr(msg.msg) }
+                .to_str()
+                .unwrap()
+                .to_owned();
+            script.seen.push((msg.msg_style, text));
+            if msg.msg_style == PAM_PROMPT_ECHO_OFF {
+                let answer = CString::new(script.answers.remove(0)).unwrap();
+                unsafe { (*responses.add(i)).resp = libc::strdup(answer.as_ptr()) };
+            }
+        }
+        unsafe { *resp = responses };
+        PamResultCode::PAM_SUCCESS.into()
+    }
+
+    fn with_script(answers: Vec<&'static str>, f: impl FnOnce(&Conv)) -> Script {
+        let script = RefCell::new(Script {
+            answers,
+            ..Script::default()
+        });
+        let inner = Inner {
+            conv: Some(scripted),
+            appdata_ptr: (&script as *const RefCell<Script>).cast(),
+        };
+        f(&Conv(&inner));
+        script.into_inner()
+    }
+
+    #[test]
+    fn get_item_returns_usable_conv() {
+        let mut mock = crate::mock::MockPam::new();
+        assert!(mock.handle().get_item::<Conv>().unwrap().is_none());
+
+        let script = RefCell::new(Script {
+            answers: vec!["Rex"],
+            ..Script::default()
+        });
+        mock.set_conv(scripted, (&script as *const RefCell<Script>).cast());
+        let pamh = mock.handle();
+        let conv = pamh.get_item::<Conv>().unwrap().unwrap();
+        assert_eq!(
+            Ok(Some(c"Rex".to_owned())),
+            conv.send(&Prompt::secret("Name of your first pet: "))
+        );
+        assert_eq!(Ok(None), conv.send(&Prompt::Info("Thanks!".to_owned())));
+        let seen = script.into_inner().seen;
+        assert_eq!((PAM_TEXT_INFO, "Thanks!".to_owned()), seen[1]);
+    }
+
+    #[test]
+    fn prompt_styles() {
+        use crate::constants::{PAM_PROMPT_ECHO_ON, PAM_RADIO_TYPE};
+        let cases = [
+            (Prompt::Password, PAM_PROMPT_ECHO_OFF, "Password: "),
+            (Prompt::Username, PAM_PROMPT_ECHO_ON, "login: "),
+            (Prompt::Info("hi".to_owned()), PAM_TEXT_INFO, "hi"),
+            (Prompt::Error("oops".to_owned()), PAM_ERROR_MSG, "oops"),
+            (Prompt::secret("PIN: "), PAM_PROMPT_ECHO_OFF, "PIN: "),
+            (Prompt::visible("Email: "), PAM_PROMPT_ECHO_ON, "Email: "),
+            (
+                Prompt::Custom {
+                    style: PAM_RADIO_TYPE,
+                    text: "Continue?".to_owned(),
+                },
+                PAM_RADIO_TYPE,
+                "Continue?",
+            ),
+        ];
+        for (prompt, style, text) in cases {
+            assert_eq!(style, prompt.style(), "{prompt:?}");
+            assert_eq!(text, prompt.text(), "{prompt:?}");
+        }
+    }
+
+    #[test]
+    fn prompt_secret_and_visible() {
+        // The script only answers prompts with echo off.
+        let script = with_script(vec!["1234", "5678"], |conv| {
+            assert_eq!("1234", conv.prompt_secret("PIN: ").unwrap().expose());
+            assert_eq!(
+                Err(PamResultCode::PAM_CONV_ERR),
+                conv.prompt_visible("Email: ")
+            );
+            assert_eq!("5678", conv.prompt_secret("PIN again: ").unwrap().expose());
+        });
+        let expected = vec![
+            (PAM_PROMPT_ECHO_OFF, "PIN: ".to_owned()),
+            (PAM_PROMPT_ECHO_ON, "Email: ".to_owned()),
+            (PAM_PROMPT_ECHO_OFF, "PIN again: ".to_owned()),
+        ];
+        assert_eq!(expected, script.seen);
+    }
+
+    #[test]
+    fn prompt_retry() {
+        let script = with_script(vec!["12", "abcd", "1234"], |conv| {
+            let is_pin = |pin: &str| pin.len() == 4 && pin.bytes().all(|b| b.is_ascii_digit());
+            let got = conv.prompt_retry("PIN: ", 3, PamFlag::default(), is_pin);
+            assert_eq!(Ok(Some("1234".to_owned())), got);
+        });
+        // Every answer was zeroed once it was done with.
+        assert_eq!(
+            vec![vec![0; 2], vec![0; 4], vec![0; 4]],
+            crate::util::DROPPED_SECRETS.with(|dropped| dropped.take())
+        );
+        let retry = (PAM_ERROR_MSG, "Sorry, try again.".to_owned());
+        let prompt = (PAM_PROMPT_ECHO_OFF, "PIN: ".to_owned());
+        let expected = vec![prompt.clone(), retry.clone(), prompt.clone(), retry, prompt];
+        assert_eq!(expected, script.seen);
+    }
+
+    #[test]
+    fn prompt_retry_gives_up_silently() {
+        let script = with_script(vec!["no", "nope"], |conv| {
+            let got =
+                conv.prompt_retry("Answer: ", 2, crate::constants::PAM_SILENT, |a| a == "yes");
+            assert_eq!(Ok(None), got);
+        });
+        assert!(script.answers.is_empty());
+        assert!(script
+            .seen
+            .iter()
+            .all(|(style, _)| *style == PAM_PROMPT_ECHO_OFF));
+        assert_eq!(2, script.seen.len());
+    }
+
+    #[test]
+    fn builder_batch() {
+        let script = with_script(vec!["hunter2", "123456"], |conv| {
+            let answers = ConvBuilder::new()
+                .info("Two factors required.")
+                .prompt_off("Password: ")
+                .prompt_on("Email: ")
+                .prompt_off("Code: ")
+                .error("Last attempt!")
+                .send(conv)
+                .unwrap();
+            assert_eq!(5, answers.len());
+            assert_eq!(Some(c"hunter2"), answers.get(1));
+            assert_eq!(None, answers.get(5));
+            assert_eq!(
+                vec![None, Some(c"hunter2"), None, Some(c"123456"), None],
+                answers.iter().collect::<Vec<_>>()
+            );
+            assert_eq!(
+                "[None, Some(..), None, Some(..), None]",
+                format!("{answers:?}")
+            );
+        });
+        let expected = vec![
+            (PAM_TEXT_INFO, "Two factors required.".to_owned()),
+            (PAM_PROMPT_ECHO_OFF, "Password: ".to_owned()),
+            (PAM_PROMPT_ECHO_ON, "Email: ".to_owned()),
+            (PAM_PROMPT_ECHO_OFF, "Code: ".to_owned()),
+            (PAM_ERROR_MSG, "Last attempt!".to_owned()),
+        ];
+        assert_eq!(expected, script.seen);
+    }
+
+    #[test]
+    fn builder_over_limit() {
+        let script = with_script(vec![], |conv| {
+            assert_eq!(
+                Err(PamResultCode::PAM_CONV_ERR),
+                ConvBuilder::new().send(conv)
+            );
+            let full = (0..PAM_MAX_NUM_MSG).fold(ConvBuilder::new(), |b, i| b.info(i.to_string()));
+            assert!(full.is_full());
+            assert_eq!(PAM_MAX_NUM_MSG as usize, full.send(conv).unwrap().len());
+            let over = full.info("one too many");
+            assert_eq!(Err(PamResultCode::PAM_CONV_ERR), over.send(conv));
+        });
+        assert_eq!(PAM_MAX_NUM_MSG as usize, script.seen.len());
+    }
+
+    /// Answers every message with `appdata_ptr` copies of `x`.
+    extern "C" fn long_answers(
+        num_msg: c_int,
+        _: *const *const PamMessage,
+        resp: *mut *mut PamResponse,
+        appdata_ptr: *const libc::c_void,
+    ) -> c_int {
+        let len = appdata_ptr as usize;
+        let count = num_msg as usize;
+        unsafe {
+            let responses =
+                libc::calloc(count, std::mem::size_of::<PamResponse>()).cast::<PamResponse>();
+            for i in 0..count {
+                let answer = libc::calloc(len + 1, 1).cast::<c_char>();
+                libc::memset(answer.cast(), b'x'.into(), len);
+                (*responses.add(i)).resp = answer;
+            }
+            *resp = responses;
+        }
+        PamResultCode::PAM_SUCCESS.into()
+    }
+
+    #[test]
+    fn oversized_response() {
+        ZEROED.with(|zeroed| zeroed.take());
+        let longest = PAM_MAX_RESP_SIZE - 1;
+        for (len, ok) in [(longest, true), (longest + 1, false), (1 << 16, false)] {
+            let inner = Inner {
+                conv: Some(long_answers),
+                appdata_ptr: len as *const libc::c_void,
+            };
+            let conv = Conv(&inner);
+            let sent = conv.send(&Prompt::Password);
+            assert_eq!(ok, sent.is_ok(), "{len}");
+            if ok {
+                assert_eq!(Some(len), sent.unwrap().map(|r| r.to_bytes().len()));
+            } else {
+                assert_eq!(Err(PamResultCode::PAM_CONV_ERR), sent);
+                assert_eq!(
+                    Err(PamResultCode::PAM_CONV_ERR),
+                    conv.prompt_secret("PIN: ")
+                );
+                assert_eq!(
+                    Err(PamResultCode::PAM_CONV_ERR),
+                    ConvBuilder::new()
+                        .prompt_off("A: ")
+                        .prompt_on("B: ")
+                        .send(&conv)
+                );
+            }
+            // Every response is zeroed in full, however long.
+            let sends = if ok { 1 } else { 4 };
+            assert_eq!(vec![len; sends], ZEROED.with(|zeroed| zeroed.take()));
+        }
+    }
+
+    /// Runs `obtain_authtok` with `args` against a handle whose
+    /// `PAM_AUTHTOK` is `stored`, and whose user answers `answers`.
+    fn obtain_with(
+        args: &[&CStr],
+        stored: Option<&CStr>,
+        answers: Vec<&'static str>,
+    ) -> (PamResult<String>, Option<String>, Script) {
+        let mut mock = crate::mock::MockPam::new();
+        let script = RefCell::new(Script {
+            answers,
+            ..Script::default()
+        });
+        mock.set_conv(scripted, (&script as *const RefCell<Script>).cast());
+        let pamh = mock.handle();
+        if let Some(stored) = stored {
+            pamh.set_item(AuthTok(stored)).unwrap();
+        }
+        let got = obtain_authtok(pamh, &ModuleArgs::new(args), "Password: ")
+            .map(|token| token.expose().to_owned());
+        let after = pamh.get_authtok_cached().unwrap();
+        (got, after, script.into_inner())
+    }
+
+    #[test]
+    fn obtain_authtok_use_first_pass() {
+        let (got, after, script) = obtain_with(&[c"use_first_pass"], Some(c"hunter2"), vec![]);
+        assert_eq!(Ok("hunter2".to_owned()), got);
+        assert_eq!(Some("hunter2".to_owned()), after);
+        assert!(script.seen.is_empty());
+
+        let (got, after, script) = obtain_with(&[c"use_first_pass"], None, vec!["never"]);
+        assert_eq!(Err(PamResultCode::PAM_AUTHTOK_RECOVERY_ERR), got);
+        assert_eq!(None, after);
+        assert!(script.seen.is_empty());
+    }
+
+    #[test]
+    fn obtain_authtok_try_first_pass() {
+        let (got, _, script) = obtain_with(&[c"try_first_pass"], Some(c"hunter2"), vec![]);
+        assert_eq!(Ok("hunter2".to_owned()), got);
+        assert!(script.seen.is_empty());
+
+        let (got, after, script) = obtain_with(&[c"try_first_pass"], None, vec!["swordfish"]);
+        assert_eq!(Ok("swordfish".to_owned()), got);
+        assert_eq!(Some("swordfish".to_owned()), after);
+        assert_eq!(
+            vec![(PAM_PROMPT_ECHO_OFF, "Password: ".to_owned())],
+            script.seen
+        );
+    }
+
+    #[test]
+    fn obtain_authtok_always_prompts() {
+        let (got, after, script) = obtain_with(&[c"debug"], Some(c"hunter2"), vec!["swordfish"]);
+        assert_eq!(Ok("swordfish".to_owned()), got);
+        assert_eq!(Some("swordfish".to_owned()), after);
+        assert_eq!(1, script.seen.len());
+        // The answer was returned as a Secret, which zeroed it when dropped.
+        assert_eq!(
+            vec![vec![0; 9]],
+            crate::util::DROPPED_SECRETS.with(|dropped| dropped.take())
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Access to the PAM environment, which is passed on to the user's session.
+
+use libc::c_char;
+use std::ffi::CStr;
+use std::marker::PhantomData;
+
+#[cfg(test)]
+use crate::mock::free;
+#[cfg(not(test))]
+use libc::free;
+
+/// A copy of the PAM environment, as returned by `pam_getenvlist`.
+///
+/// Entries are decoded lazily as `(name, value)` pairs when iterating over
+/// a borrowed `EnvList`, and the underlying memory is freed when it is dropped.
+/// Entries that are not valid UTF-8, or which have no `=`, are skipped.
+///
+/// ```no_run
+/// # use pam::module::{PamHandle, PamResult};
+/// # fn has_display(pamh: &PamHandle) -> PamResult<bool> {
+/// let env = pamh.env_iter()?;
+/// Ok(env.iter().any(|(name, _)| name == "DISPLAY"))
+/// # }
+/// ```
+pub struct EnvList {
+    list: *mut *mut c_char,
+}
+
+impl EnvList {
+    /// Takes ownership of an array returned by `pam_getenvlist`.
+    ///
+    /// # Safety
+    ///
+    /// `list` must be a non-null, null-terminated array of C strings,
+    /// all allocated with `malloc`.
+    pub(crate) unsafe fn from_raw(list: *mut *mut c_char) -> Self {
+        Self { list }
+    }
+
+    /// Iterates over the `(name, value)` pairs in the environment.
+    pub fn iter(&self) -> EnvIter<'_> {
+        EnvIter {
+            next: self.list,
+            _list: PhantomData,
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a EnvList {
+    type Item = (&'a str, &'a str);
+    type IntoIter = EnvIter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl Drop for EnvList {
+    fn drop(&mut self) {
+        unsafe {
+            let mut entry = self.list;
+            while !(*entry).is_null() {
+                free((*entry).cast());
+                entry = entry.add(1);
+            }
+            free(self.list.cast());
+        }
+    }
+}
+
+/// An iterator over the entries of an [`EnvList`].
+pub struct EnvIter<'a> {
+    next: *const *mut c_char,
+    _list: PhantomData<&'a EnvList>,
+}
+
+impl<'a> Iterator for EnvIter<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = unsafe { *self.next };
+            if entry.is_null() {
+                return None;
+            }
+            self.next = unsafe { self.next.add(1) };
+            let entry = unsafe { CStr::from_ptr(entry) };
+            if let Some(pair) = entry.to_str().ok().and_then(|e| e.split_once('=')) {
+                return Some(pair);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mock::{self, MockPam};
+
+    #[test]
+    fn iterate_and_free() {
+        let mut mock = MockPam::new();
+        mock.putenv("HOME=/home/alice");
+        mock.putenv("not an assignment");
+        mock.putenv("SHELL=/bin/sh");
+        let pamh = mock.handle();
+
+        let freed = mock::freed();
+        let env = pamh.env_iter().unwrap();
+        let got: Vec<_> = env.iter().collect();
+        assert_eq!(vec![("HOME", "/home/alice"), ("SHELL", "/bin/sh")], got);
+        drop(env);
+        assert_eq!(freed + 4, mock::freed());
+    }
+
+    #[test]
+    fn early_break_still_frees() {
+        let mut mock = MockPam::new();
+        mock.putenv("A=1");
+        mock.putenv("B=2");
+        let pamh = mock.handle();
+
+        let freed = mock::freed();
+        {
+            let env = pamh.env_iter().unwrap();
+            assert_eq!(Some(("A", "1")), env.iter().next());
+        }
+        assert_eq!(freed + 3, mock::freed());
+    }
+
+    #[test]
+    fn getenvlist() {
+        let mut mock = MockPam::new();
+        mock.putenv("LANG=C.UTF-8");
+        assert_eq!(
+            vec![("LANG".to_owned(), "C.UTF-8".to_owned())],
+            mock.handle().getenvlist().unwrap()
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Errors for applications using the [`client`](crate::client) module.
+//!
+//! Modules report failures to libpam as a bare [`PamResultCode`], since that
+//! is what the ABI passes back.  Applications instead get an [`Error`], which
+//! also says which operation failed and for which service, so that it can be
+//! logged or shown as is.
+
+use std::fmt;
+
+use crate::constants::PamResultCode;
+
+/// A libpam function that an application calls on a transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Operation {
+    /// `pam_start`, which begins the transaction.
+    Start,
+    /// `pam_authenticate`.
+    Authenticate,
+    /// `pam_acct_mgmt`.
+    AcctMgmt,
+    /// `pam_chauthtok`.
+    Chauthtok,
+    /// `pam_set_item`.
+    SetItem,
+    /// `pam_get_item`.
+    GetItem,
+    /// `pam_end`, which finishes the transaction.
+    End,
+}
+
+impl Operation {
+    /// The name of the libpam function, as in `pam_authenticate`.
+    pub fn function_name(self) -> &'static str {
+        match self {
+            Operation::Start => "pam_start",
+            Operation::Authenticate => "pam_authenticate",
+            Operation::AcctMgmt => "pam_acct_mgmt",
+            Operation::Chauthtok => "pam_chauthtok",
+            Operation::SetItem => "pam_set_item",
+            Operation::GetItem => "pam_get_item",
+            Operation::End => "pam_end",
+        }
+    }
+}
+
+/// Shows the function name, as in `pam_authenticate`.
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.function_name())
+    }
+}
+
+/// A failed call to libpam from an application.
+///
+/// Every error has a [`code`](Self::code), which is what to match on to
+/// decide what to do next.  The rest is context for people: the `Display`
+/// form reads like `pam_authenticate failed for service "login":
+/// Authentication failure`.
+///
+/// ```no_run
+/// # use pam::client::{Converse, PamContext};
+/// # use pam::constants::{PamFlag, PamResultCode};
+/// # fn f<C: Converse>(ctx: &mut PamContext<C>) {
+/// match ctx.authenticate(PamFlag::default()) {
+///     Ok(()) => println!("Welcome!"),
+///     Err(e) if e.code() == PamResultCode::PAM_AUTH_ERR => println!("Wrong password."),
+///     Err(e) => eprintln!("{e}"),
+/// }
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Error {
+    /// An operation on a transaction failed.
+    Failed {
+        /// The function that failed.
+        operation: Operation,
+        /// The service the transaction was started for.
+        service: String,
+        /// The code it failed with.
+        code: PamResultCode,
+    },
+    /// The service name can't be used as a file name in `/etc/pam.d`,
+    /// so no transaction was started.
+    InvalidService(String),
+    /// A failure known only by its code.
+    Code(PamResultCode),
+}
+
+impl Error {
+    /// Creates an error for `operation` on `service` failing with `code`.
+    pub fn failed(operation: Operation, service: &str, code: PamResultCode) -> Self {
+        Error::Failed {
+            operation,
+            service: service.to_owned(),
+            code,
+        }
+    }
+
+    /// The result code, which is `PAM_BAD_ITEM` for an invalid service name.
+    pub fn code(&self) -> PamResultCode {
+        match self {
+            Error::Failed { code, .. } | Error::Code(code) => *code,
+            Error::InvalidService(_) => PamResultCode::PAM_BAD_ITEM,
+        }
+    }
+
+    /// The operation that failed, if known.
+    pub fn operation(&self) -> Option<Operation> {
+        match self {
+            Error::Failed { operation, .. } => Some(*operation),
+            Error::InvalidService(_) => Some(Operation::Start),
+            Error::Code(_) => None,
+        }
+    }
+
+    /// The service name, if known.
+    pub fn service(&self) -> Option<&str> {
+        match self {
+            Error::Failed { service, .. } | Error::InvalidService(service) => Some(service),
+            Error::Code(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Failed {
+                operation,
+                service,
+                code,
+            } => write!(f, "{operation} failed for service {service:?}: {code}"),
+            Error::InvalidService(service) => write!(f, "invalid PAM service name {service:?}"),
+            Error::Code(code) => write!(f, "{code}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<PamResultCode> for Error {
+    fn from(code: PamResultCode) -> Self {
+        Error::Code(code)
+    }
+}
+
+/// Lets `?` turn an `Error` back into a code, as for a module that
+/// runs a transaction of its own.
+impl From<Error> for PamResultCode {
+    fn from(err: Error) -> Self {
+        err.code()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn context_and_display() {
+        let err = Error::failed(
+            Operation::Authenticate,
+            "login",
+            PamResultCode::PAM_AUTH_ERR,
+        );
+        assert_eq!(PamResultCode::PAM_AUTH_ERR, err.code());
+        assert_eq!(Some(Operation::Authenticate), err.operation());
+        assert_eq!(Some("login"), err.service());
+        assert_eq!(
+            "pam_authenticate failed for service \"login\": Authentication failure",
+            err.to_string()
+        );
+
+        let err = Error::InvalidService("../etc".to_owned());
+        assert_eq!(PamResultCode::PAM_BAD_ITEM, err.code());
+        assert_eq!(Some(Operation::Start), err.operation());
+        assert_eq!("invalid PAM service name \"../etc\"", err.to_string());
+
+        let err = Error::from(PamResultCode::PAM_CONV_ERR);
+        assert_eq!(None, err.operation());
+        assert_eq!(None, err.service());
+        assert_eq!(PamResultCode::PAM_CONV_ERR, PamResultCode::from(err));
+    }
+}
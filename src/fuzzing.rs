@@ -0,0 +1,41 @@
+//! Internals exposed for the fuzz targets in `fuzz/`, with the `fuzzing`
+//! feature.  These are not part of the API and may change at any time.
+//!
+//! See `fuzz/README.md` for how to run the fuzzers.
+
+use std::ffi::{c_char, OsString};
+
+use crate::module::{self, PamResult};
+use crate::util::Secret;
+
+pub use crate::macros::extract_argv;
+
+/// Copies a string returned by libpam, as for
+/// [`PamHandle::get_user`](crate::module::PamHandle::get_user).
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a nul-terminated string.
+pub unsafe fn copy_pam_string(ptr: *const c_char) -> PamResult<String> {
+    module::copy_pam_string(ptr)
+}
+
+/// Copies the bytes of a string returned by libpam, as for
+/// [`PamHandle::get_user_os`](crate::module::PamHandle::get_user_os).
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a nul-terminated string.
+pub unsafe fn copy_pam_os_string(ptr: *const c_char) -> OsString {
+    module::copy_pam_os_string(ptr)
+}
+
+/// Copies a token returned by libpam into a [`Secret`], as for
+/// [`PamHandle::get_authtok_secret`](crate::module::PamHandle::get_authtok_secret).
+///
+/// # Safety
+///
+/// `ptr` must be null or point to a nul-terminated string.
+pub unsafe fn copy_pam_secret(ptr: *const c_char) -> PamResult<Secret> {
+    module::copy_pam_secret(ptr)
+}
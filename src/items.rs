@@ -1,4 +1,19 @@
-#[repr(u32)]
+use libc::c_int;
+use std::ffi::CStr;
+use std::net::IpAddr;
+
+use crate::constants::{PamItemType, PamResultCode};
+use crate::module::{copy_pam_secret, PamResult};
+use crate::util::Secret;
+
+/// The type of an item, passed to `pam_get_item` and `pam_set_item`.
+///
+/// Most items are nul-terminated C strings.  The exceptions are `Conv`
+/// (a `struct pam_conv`), `FailDelay` (a function pointer), and
+/// `XAuthData` (a `struct pam_xauth_data`, whose data is length-delimited
+/// binary and may contain nul bytes).
+#[repr(i32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum ItemType {
     /// The service name
     Service = 1,
@@ -28,27 +43,107 @@ pub enum ItemType {
     AuthTokType = 13,
 }
 
-// A type that can be requested by `pam::Handle::get_item`.
+impl TryFrom<c_int> for ItemType {
+    type Error = PamResultCode;
+
+    /// Converts a raw item type, rejecting unknown values with `PAM_BAD_ITEM`.
+    fn try_from(value: c_int) -> Result<Self, Self::Error> {
+        Ok(match value {
+            1 => ItemType::Service,
+            2 => ItemType::User,
+            3 => ItemType::Tty,
+            4 => ItemType::RHost,
+            5 => ItemType::Conv,
+            6 => ItemType::AuthTok,
+            7 => ItemType::OldAuthTok,
+            8 => ItemType::RUser,
+            9 => ItemType::UserPrompt,
+            10 => ItemType::FailDelay,
+            11 => ItemType::XDisplay,
+            12 => ItemType::XAuthData,
+            13 => ItemType::AuthTokType,
+            _ => return Err(PamResultCode::PAM_BAD_ITEM),
+        })
+    }
+}
+
+impl From<ItemType> for c_int {
+    fn from(item_type: ItemType) -> Self {
+        item_type as c_int
+    }
+}
+
+/// A value that can be stored in the PAM handle with `pam_set_item`,
+/// and read back with `pam_get_item`.
+///
+/// Item memory is always owned by libpam: `pam_set_item` copies the value
+/// it is given, and `pam_get_item` returns a pointer into libpam's copy.
+/// So reading and writing an item never transfers ownership, and the two
+/// directions are split into [`FromRaw`] and [`IntoRaw`] to make this explicit.
 pub trait Item {
     /// The `repr(C)` type that is returned (by pointer) by the underlying `pam_get_item` function.
     type Raw;
 
-    /// The `ItemType` for this type
-    fn type_id() -> ItemType;
+    /// The raw item type passed to libpam for this type, usually an [`ItemType`].
+    fn type_id() -> PamItemType;
+}
 
-    /// The function to convert from the pointer to the C-representation to this safer wrapper type
+/// An item that can be read from a PAM handle borrowed for `'a`.
+///
+/// The value returned by `pam_get_item` is owned by libpam and is only valid
+/// until the item is changed or the handle is ended, so `from_raw` must borrow
+/// it for no longer than `'a`, and must never free it.  Implement this trait
+/// only for `'a`-bounded wrappers (like `User<'a>`), so that `get_item` can't
+/// be used to produce a `User<'static>`.
+pub trait FromRaw<'a>: Item {
+    /// Wraps the raw value returned by `pam_get_item`, without taking
+    /// ownership of it.
     ///
     /// # Safety
     ///
-    /// This function can assume the pointer is a valid pointer to a `Self::Raw` instance.
+    /// `raw` must be a valid pointer to a `Self::Raw` instance that remains
+    /// valid (and unmodified) for `'a`.
     unsafe fn from_raw(raw: *const Self::Raw) -> Self;
+}
+
+/// An item whose value is a secret, which can be copied straight into
+/// a [`Secret`] with [`PamHandle::get_item_secret`].
+///
+/// Only the authentication tokens, [`AuthTok`] and [`OldAuthTok`], are
+/// treated as secret.  Reading them with `get_item_secret` rather than
+/// [`get_item`](PamHandle::get_item) makes just the one copy, which is
+/// zeroed when dropped, as is any copy that turns out not to be UTF-8.
+///
+/// [`PamHandle::get_item_secret`]: crate::module::PamHandle::get_item_secret
+/// [`PamHandle`]: crate::module::PamHandle
+pub trait SecretItem: Item {
+    /// Copies the raw value returned by `pam_get_item` into a `Secret`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must be a valid, non-null pointer to a `Self::Raw` instance.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the value can't be represented as a `Secret`,
+    /// such as `PAM_BUF_ERR` for a token that is not valid UTF-8.
+    unsafe fn copy_secret(raw: *const Self::Raw) -> PamResult<Secret>;
+}
 
-    /// The function to convert from this wrapper type to a C-compatible pointer.
-    fn into_raw(self) -> *const Self::Raw;
+/// An item that can be stored in a PAM handle.
+pub trait IntoRaw: Item {
+    /// Returns a pointer to pass to `pam_set_item`.
+    ///
+    /// The pointer need only be valid while `self` is borrowed, because
+    /// libpam copies the value.  The caller keeps ownership of whatever it
+    /// points to, so it must not be leaked or handed over (e.g., with
+    /// `Box::into_raw` or `CString::into_raw`).
+    fn as_raw(&self) -> *const Self::Raw;
 }
 
 macro_rules! cstr_item {
-    ($name:ident) => {
+    ($(#[$attr:meta])* $name:ident) => {
+        $(#[$attr])*
         #[derive(Debug)]
         pub struct $name<'s>(pub &'s std::ffi::CStr);
 
@@ -62,15 +157,19 @@ macro_rules! cstr_item {
         impl<'s> Item for $name<'s> {
             type Raw = libc::c_char;
 
-            fn type_id() -> ItemType {
-                ItemType::$name
+            fn type_id() -> PamItemType {
+                ItemType::$name.into()
             }
+        }
 
+        impl<'s> FromRaw<'s> for $name<'s> {
             unsafe fn from_raw(raw: *const Self::Raw) -> Self {
                 Self(std::ffi::CStr::from_ptr(raw))
             }
+        }
 
-            fn into_raw(self) -> *const Self::Raw {
+        impl<'s> IntoRaw for $name<'s> {
+            fn as_raw(&self) -> *const Self::Raw {
                 self.0.as_ptr()
             }
         }
@@ -86,3 +185,372 @@ cstr_item!(AuthTok);
 cstr_item!(OldAuthTok);
 cstr_item!(RUser);
 cstr_item!(UserPrompt);
+// FailDelay
+cstr_item!(XDisplay);
+cstr_item!(
+    /// The kind of token that is being changed (Linux-PAM only).
+    ///
+    /// When this is set, the prompts shown by `pam_get_authtok` while changing
+    /// a token include it, reading e.g. `New Kerberos password:` rather than
+    /// just `New password:`.  It does not change the prompt for the current
+    /// password.
+    AuthTokType
+);
+
+impl SecretItem for AuthTok<'_> {
+    unsafe fn copy_secret(raw: *const Self::Raw) -> PamResult<Secret> {
+        copy_pam_secret(raw)
+    }
+}
+
+impl SecretItem for OldAuthTok<'_> {
+    unsafe fn copy_secret(raw: *const Self::Raw) -> PamResult<Secret> {
+        copy_pam_secret(raw)
+    }
+}
+
+/// How long the token type set by
+/// [`PamHandle::get_authtok_typed`](crate::module::PamHandle::get_authtok_typed)
+/// stays set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AuthTokTypeScope {
+    /// Only for that call.  The item is put back the way it was afterward,
+    /// so later modules in the stack don't show this module's token type.
+    #[default]
+    Call,
+    /// For the rest of the stack, as if this module had set the item itself.
+    Stack,
+}
+
+/// True if `type_id` is one of the standard item types in [`ItemType`].
+pub const fn is_standard_item(type_id: PamItemType) -> bool {
+    matches!(type_id, 1..=13)
+}
+
+/// Defines a string item with a vendor-specific item type, just like the
+/// standard string items in this module.
+///
+/// Some deployments patch libpam to support extra items.  This generates
+/// a wrapper around a `&CStr` that can be used with `get_item` and `set_item`:
+///
+/// ```no_run
+/// # use pam::module::PamHandle;
+/// # use pam::constants::PamResultCode;
+/// pam::impl_string_item!(
+///     /// The smart card reader the user logged in with.
+///     CardReader,
+///     0x1001
+/// );
+///
+/// # fn f(pamh: &mut PamHandle) -> Result<(), PamResultCode> {
+/// pamh.set_item(CardReader(c"reader0"))?;
+/// let reader = pamh.get_item::<CardReader>()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The item's value must really be a nul-terminated string, and libpam
+/// must know the item type: an unpatched libpam rejects unknown item types
+/// with `PAM_BAD_ITEM`.  Be careful to pick a number that no other patch,
+/// or future version of Linux-PAM, will use for something else: if it
+/// comes to mean a non-string item, reading it as a string is undefined
+/// behavior.  For this reason, the standard item types are rejected at
+/// compile time:
+///
+/// ```compile_fail
+/// pam::impl_string_item!(NotTheConv, 5);
+/// ```
+#[macro_export]
+macro_rules! impl_string_item {
+    ($(#[$attr:meta])* $name:ident, $type_id:expr) => {
+        $(#[$attr])*
+        #[derive(Debug)]
+        pub struct $name<'s>(pub &'s ::std::ffi::CStr);
+
+        const _: () = assert!(
+            !$crate::items::is_standard_item($type_id),
+            "standard items are already defined in pam::items",
+        );
+
+        impl<'s> ::std::ops::Deref for $name<'s> {
+            type Target = &'s ::std::ffi::CStr;
+            fn deref(&self) -> &Self::Target {
+                &self.0
+            }
+        }
+
+        impl<'s> $crate::items::Item for $name<'s> {
+            type Raw = ::std::ffi::c_char;
+
+            fn type_id() -> $crate::constants::PamItemType {
+                $type_id
+            }
+        }
+
+        impl<'s> $crate::items::FromRaw<'s> for $name<'s> {
+            unsafe fn from_raw(raw: *const Self::Raw) -> Self {
+                Self(::std::ffi::CStr::from_ptr(raw))
+            }
+        }
+
+        impl<'s> $crate::items::IntoRaw for $name<'s> {
+            fn as_raw(&self) -> *const Self::Raw {
+                self.0.as_ptr()
+            }
+        }
+    };
+}
+
+/// The layout of `struct pam_xauth_data`, the raw form of [`XAuthData`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct RawXAuthData {
+    pub(crate) namelen: c_int,
+    pub(crate) name: *const libc::c_char,
+    pub(crate) datalen: c_int,
+    pub(crate) data: *const libc::c_char,
+}
+
+/// The X server authentication data to use for the user's session
+/// (Linux-PAM only), such as an `MIT-MAGIC-COOKIE-1` cookie.
+///
+/// Unlike other items, the data is binary.  Both the name and the data are
+/// read using their explicit lengths, so nul bytes are preserved.
+#[derive(Clone, Copy, Debug)]
+pub struct XAuthData<'a> {
+    raw: RawXAuthData,
+    _data: std::marker::PhantomData<&'a [u8]>,
+}
+
+impl<'a> XAuthData<'a> {
+    /// Wraps an authentication method name, and its data.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_BUF_ERR` if either is too long for a C `int`.
+    pub fn new(name: &'a CStr, data: &'a [u8]) -> Result<Self, PamResultCode> {
+        let too_long = |_| PamResultCode::PAM_BUF_ERR;
+        Ok(Self {
+            raw: RawXAuthData {
+                namelen: c_int::try_from(name.to_bytes().len()).map_err(too_long)?,
+                name: name.as_ptr(),
+                datalen: c_int::try_from(data.len()).map_err(too_long)?,
+                data: data.as_ptr().cast(),
+            },
+            _data: std::marker::PhantomData,
+        })
+    }
+
+    /// The name of the authentication method.
+    pub fn name(&self) -> &'a [u8] {
+        unsafe { bytes(self.raw.name, self.raw.namelen) }
+    }
+
+    /// The authentication data.
+    pub fn data(&self) -> &'a [u8] {
+        unsafe { bytes(self.raw.data, self.raw.datalen) }
+    }
+}
+
+/// Borrows `len` bytes from `ptr`, or nothing if `ptr` is null.
+///
+/// # Safety
+///
+/// `ptr` must be null or valid for reads of `len` bytes for `'a`.
+unsafe fn bytes<'a>(ptr: *const libc::c_char, len: c_int) -> &'a [u8] {
+    match usize::try_from(len) {
+        Ok(len) if !ptr.is_null() => std::slice::from_raw_parts(ptr.cast(), len),
+        _ => &[],
+    }
+}
+
+impl Item for XAuthData<'_> {
+    type Raw = RawXAuthData;
+
+    fn type_id() -> PamItemType {
+        ItemType::XAuthData.into()
+    }
+}
+
+impl<'a> FromRaw<'a> for XAuthData<'a> {
+    unsafe fn from_raw(raw: *const Self::Raw) -> Self {
+        Self {
+            raw: *raw,
+            _data: std::marker::PhantomData,
+        }
+    }
+}
+
+impl IntoRaw for XAuthData<'_> {
+    fn as_raw(&self) -> *const Self::Raw {
+        &self.raw
+    }
+}
+
+/// The items describing where the user is logging in from,
+/// as returned by [`PamHandle::terminal_info`](crate::module::PamHandle::terminal_info).
+///
+/// Each field is `None` if the application didn't set that item.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TerminalInfo {
+    /// The terminal name (`PAM_TTY`), e.g. `/dev/tty1` or `:0`.
+    pub tty: Option<String>,
+    /// The remote host name (`PAM_RHOST`).
+    pub rhost: Option<String>,
+    /// The remote user name (`PAM_RUSER`).
+    pub ruser: Option<String>,
+    /// The X display name (`PAM_XDISPLAY`).
+    pub xdisplay: Option<String>,
+}
+
+/// The remote host (`PAM_RHOST`), as returned by
+/// [`PamHandle::rhost_addr`](crate::module::PamHandle::rhost_addr).
+///
+/// Applications set `PAM_RHOST` to either an address or a host name,
+/// depending on what they know and whether they do reverse lookups.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RhostValue {
+    /// An IPv4 or IPv6 address.
+    Ip(IpAddr),
+    /// Anything else, normally a host name.  This is not resolved.
+    Hostname(String),
+}
+
+impl RhostValue {
+    /// Parses an `rhost` value.  IPv6 addresses may be in brackets,
+    /// as in `[::1]`.
+    pub fn parse(rhost: &str) -> Self {
+        let unbracketed = rhost
+            .strip_prefix('[')
+            .and_then(|r| r.strip_suffix(']'))
+            .unwrap_or(rhost);
+        match unbracketed.parse() {
+            Ok(ip @ IpAddr::V6(_)) => RhostValue::Ip(ip),
+            Ok(ip @ IpAddr::V4(_)) if unbracketed == rhost => RhostValue::Ip(ip),
+            _ => RhostValue::Hostname(rhost.to_owned()),
+        }
+    }
+
+    /// The address, if this is one.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            RhostValue::Ip(ip) => Some(*ip),
+            RhostValue::Hostname(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockPam};
+    use std::ffi::CString;
+
+    #[test]
+    fn item_type_round_trip() {
+        for raw in 1..=13 {
+            let item_type = ItemType::try_from(raw).unwrap();
+            assert_eq!(raw, c_int::from(item_type));
+        }
+        assert_eq!(ItemType::User, ItemType::try_from(2).unwrap());
+        assert_eq!(ItemType::AuthTokType, ItemType::try_from(13).unwrap());
+        assert_eq!(Err(PamResultCode::PAM_BAD_ITEM), ItemType::try_from(0));
+        assert_eq!(Err(PamResultCode::PAM_BAD_ITEM), ItemType::try_from(14));
+        assert_eq!(Err(PamResultCode::PAM_BAD_ITEM), ItemType::try_from(-1));
+    }
+
+    #[test]
+    fn authtok_type_round_trip() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        assert!(pamh.get_item::<AuthTokType>().unwrap().is_none());
+
+        let kind = CString::new("Kerberos").unwrap();
+        pamh.set_item(AuthTokType(&kind)).unwrap();
+        let got = pamh.get_item::<AuthTokType>().unwrap().unwrap();
+        assert_eq!(kind.as_c_str(), *got);
+    }
+
+    crate::impl_string_item!(
+        /// A made-up vendor item.
+        Vendor,
+        0x4242
+    );
+
+    #[test]
+    fn vendor_item_round_trip() {
+        assert!(!is_standard_item(Vendor::type_id()));
+        assert!(is_standard_item(User::type_id()));
+
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        assert!(pamh.get_item::<Vendor>().unwrap().is_none());
+        pamh.set_item(Vendor(c"badge-1234")).unwrap();
+        assert_eq!(c"badge-1234", *pamh.get_item::<Vendor>().unwrap().unwrap());
+        assert!(pamh.get_item::<User>().unwrap().is_none());
+    }
+
+    #[test]
+    fn set_item_copies_and_get_item_borrows() {
+        let live = mock::live_allocations();
+        {
+            let mut mock = MockPam::new();
+            let pamh = mock.handle();
+            let tty = CString::new("/dev/tty1").unwrap();
+            pamh.set_item(Tty(&tty)).unwrap();
+            pamh.set_item(Tty(&tty)).unwrap();
+
+            let got = pamh.get_item::<Tty>().unwrap().unwrap();
+            assert_eq!(tty.as_c_str(), *got);
+            assert_ne!(tty.as_ptr(), got.as_ptr());
+            drop(tty);
+            assert_eq!(c"/dev/tty1", *pamh.get_item::<Tty>().unwrap().unwrap());
+        }
+        assert_eq!(live, mock::live_allocations());
+    }
+
+    #[test]
+    fn xauth_data_keeps_nul_bytes() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        assert!(pamh.get_item::<XAuthData>().unwrap().is_none());
+
+        let cookie = b"\x00\x01co\0kie\0";
+        pamh.set_item(XAuthData::new(c"MIT-MAGIC-COOKIE-1", cookie).unwrap())
+            .unwrap();
+        let got = pamh.get_item::<XAuthData>().unwrap().unwrap();
+        assert_eq!(b"MIT-MAGIC-COOKIE-1", got.name());
+        assert_eq!(cookie, got.data());
+    }
+
+    #[test]
+    fn rhost_values() {
+        let cases = [
+            ("192.0.2.1", RhostValue::Ip("192.0.2.1".parse().unwrap())),
+            (
+                "2001:db8::1",
+                RhostValue::Ip("2001:db8::1".parse().unwrap()),
+            ),
+            (
+                "[2001:db8::1]",
+                RhostValue::Ip("2001:db8::1".parse().unwrap()),
+            ),
+            (
+                "host.example.com",
+                RhostValue::Hostname("host.example.com".to_owned()),
+            ),
+            (
+                "[192.0.2.1]",
+                RhostValue::Hostname("[192.0.2.1]".to_owned()),
+            ),
+            (
+                "fe80::1%eth0",
+                RhostValue::Hostname("fe80::1%eth0".to_owned()),
+            ),
+            ("", RhostValue::Hostname(String::new())),
+        ];
+        for (rhost, expected) in cases {
+            assert_eq!(expected, RhostValue::parse(rhost), "{rhost}");
+        }
+    }
+}
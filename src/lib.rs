@@ -1,8 +1,9 @@
 //! Interface to the pluggable authentication module framework (PAM).
 //!
 //! The goal of this library is to provide a type-safe API that can be used to
-//! interact with PAM.  The library is incomplete - currently it supports
-//! a subset of functions for use in a pam authentication module.  A pam module
+//! interact with PAM.  The library is incomplete - currently it mostly supports
+//! a subset of functions for use in a pam authentication module, along with
+//! the beginnings of an API for applications in the `client` module.  A pam module
 //! is a shared library that is invoked to authenticate a user, or to perform
 //! other functions.
 //!
@@ -23,12 +24,69 @@
 //! hard-coded in the `constants` module.  The values there are taken from
 //! a Linux system.  That means that it might take some work to get this library
 //! to work on other platforms.
+//!
+//! Most modules can start with `use pam::prelude::*;`, which imports
+//! the types and macros they commonly need.
+//!
+//! Everything but the `constants` module needs the `std` feature, which is
+//! on by default.  Without it, the crate is `no_std`, and provides only the
+//! constants and codes.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 
+#[cfg(feature = "std")]
 extern crate libc;
+// `#[pam_hooks]` generates code that names this crate `::pam`.
+#[cfg(all(test, feature = "macros"))]
+extern crate self as pam;
 
+#[cfg(feature = "std")]
+pub mod args;
+#[cfg(feature = "macros")]
+pub mod attr;
+#[cfg(feature = "std")]
+pub mod cache;
+#[cfg(feature = "std")]
+pub mod client;
+#[cfg(feature = "std")]
+mod compat;
+#[cfg(feature = "std")]
+pub mod config;
 pub mod constants;
+#[cfg(feature = "std")]
 pub mod conv;
+#[cfg(feature = "std")]
+pub mod env;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+#[cfg(feature = "std")]
 pub mod items;
+#[cfg(feature = "std")]
+pub mod logging;
+#[cfg(feature = "std")]
 #[doc(hidden)]
 pub mod macros;
+#[cfg(all(test, feature = "std"))]
+mod mock;
+#[cfg(feature = "std")]
 pub mod module;
+#[cfg(feature = "std")]
+pub mod modutil;
+#[cfg(feature = "std")]
+pub mod password;
+#[cfg(feature = "std")]
+pub mod prelude;
+#[cfg(feature = "std")]
+pub mod response;
+#[cfg(feature = "std")]
+pub mod session;
+#[cfg(feature = "std")]
+pub mod username;
+#[cfg(feature = "std")]
+pub mod util;
+
+#[cfg(feature = "std")]
+pub use error::Error;
@@ -0,0 +1,304 @@
+//! Logging from modules, to the system log or (with the `tracing` feature)
+//! to [`tracing`](https://docs.rs/tracing).
+
+use libc::c_int;
+
+use crate::constants::PamFlag;
+use crate::module::PamHandle;
+
+pub use libc::{
+    LOG_ALERT, LOG_CRIT, LOG_DEBUG, LOG_EMERG, LOG_ERR, LOG_INFO, LOG_NOTICE, LOG_WARNING,
+};
+
+/// Logs a message about the hook being run, at the given `syslog` priority.
+///
+/// By default, this is the same as [`PamHandle::syslog`].  With the `tracing`
+/// feature, it instead emits a `tracing` event with the target `pam` at the
+/// corresponding level, with the `service`, `user` and `flags` of the call
+/// as fields.  This crate reports its own problems, like a hook panicking,
+/// through this function.
+///
+/// To send those events on to the system log, install a `SyslogLayer`.
+pub fn log(pamh: &PamHandle, flags: PamFlag, priority: c_int, msg: &str) {
+    #[cfg(feature = "tracing")]
+    trace(pamh, flags, priority, msg);
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = flags;
+        pamh.syslog(priority, msg);
+    }
+}
+
+/// The field names that [`Redactor::default`] treats as secret.
+pub const DEFAULT_SECRET_KEYS: &[&str] = &[
+    "authtok",
+    "oldauthtok",
+    "password",
+    "passwd",
+    "secret",
+    "token",
+    "otp",
+    "pin",
+];
+
+/// Formats log messages with `key=value` fields, hiding the values of
+/// secret fields as `***`.
+///
+/// Only the fields are redacted; the free-form message is logged as it is,
+/// so never format a secret into it.  Field names are compared ignoring
+/// ASCII case.
+///
+/// ```no_run
+/// # use pam::constants::PamFlag;
+/// # use pam::logging::{Redactor, LOG_INFO};
+/// # use pam::module::PamHandle;
+/// # fn f(pamh: &PamHandle, flags: PamFlag, user: &str, password: &str) {
+/// // Logs "login attempt user=alice password=***".
+/// Redactor::default().log(pamh, flags, LOG_INFO, "login attempt", &[
+///     ("user", &user),
+///     ("password", &password),
+/// ]);
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Redactor {
+    secret_keys: Vec<String>,
+}
+
+impl Default for Redactor {
+    /// Redacts the fields named in [`DEFAULT_SECRET_KEYS`].
+    fn default() -> Self {
+        Self::new(DEFAULT_SECRET_KEYS.iter().copied())
+    }
+}
+
+impl Redactor {
+    /// Redacts only the fields named in `secret_keys`.
+    pub fn new<K: Into<String>>(secret_keys: impl IntoIterator<Item = K>) -> Self {
+        Self {
+            secret_keys: secret_keys.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Redacts the field named `key` too.
+    pub fn with_key(mut self, key: impl Into<String>) -> Self {
+        self.secret_keys.push(key.into());
+        self
+    }
+
+    /// True if the value of the field named `key` is redacted.
+    pub fn is_secret(&self, key: &str) -> bool {
+        self.secret_keys.iter().any(|k| k.eq_ignore_ascii_case(key))
+    }
+
+    /// Formats `msg`, followed by each field as ` key=value`.
+    pub fn format(&self, msg: &str, fields: &[(&str, &dyn std::fmt::Display)]) -> String {
+        use std::fmt::Write;
+
+        let mut line = msg.to_owned();
+        for (key, value) in fields {
+            if self.is_secret(key) {
+                let _ = write!(line, " {key}=***");
+            } else {
+                let _ = write!(line, " {key}={value}");
+            }
+        }
+        line
+    }
+
+    /// Logs `msg` and `fields`, formatted as by [`format`](Self::format),
+    /// with [`log`].
+    pub fn log(
+        &self,
+        pamh: &PamHandle,
+        flags: PamFlag,
+        priority: c_int,
+        msg: &str,
+        fields: &[(&str, &dyn std::fmt::Display)],
+    ) {
+        log(pamh, flags, priority, &self.format(msg, fields));
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn trace(pamh: &PamHandle, flags: PamFlag, priority: c_int, msg: &str) {
+    use crate::items::{Service, User};
+    use tracing::Level;
+
+    let service = pamh.get_item::<Service>().ok().flatten();
+    let service = service.as_ref().map(|s| s.to_string_lossy());
+    let user = pamh.get_item::<User>().ok().flatten();
+    let user = user.as_ref().map(|u| u.to_string_lossy());
+    macro_rules! event {
+        ($level:expr) => {
+            tracing::event!(
+                target: "pam",
+                $level,
+                service = service.as_deref(),
+                user = user.as_deref(),
+                flags = flags.bits(),
+                "{msg}"
+            )
+        };
+    }
+    match priority & libc::LOG_PRIMASK {
+        LOG_EMERG | LOG_ALERT | LOG_CRIT | LOG_ERR => event!(Level::ERROR),
+        LOG_WARNING => event!(Level::WARN),
+        LOG_NOTICE | LOG_INFO => event!(Level::INFO),
+        _ => event!(Level::DEBUG),
+    }
+}
+
+/// A [`tracing_subscriber::Layer`] that writes every event to the system
+/// log, with the `LOG_AUTHPRIV` facility.
+///
+/// Since events aren't tied to a PAM handle, this uses `syslog` directly
+/// rather than `pam_syslog`.  The message is followed by the event's other
+/// fields, as `name=value`.
+#[cfg(feature = "tracing")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SyslogLayer;
+
+#[cfg(feature = "tracing")]
+impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SyslogLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _: tracing_subscriber::layer::Context<'_, S>) {
+        let priority = match *event.metadata().level() {
+            tracing::Level::ERROR => LOG_ERR,
+            tracing::Level::WARN => LOG_WARNING,
+            tracing::Level::INFO => LOG_INFO,
+            _ => LOG_DEBUG,
+        };
+        let msg = format_event(event).replace('\0', "\\0");
+        let msg = std::ffi::CString::new(msg).unwrap_or_default();
+        unsafe { libc::syslog(libc::LOG_AUTHPRIV | priority, c"%s".as_ptr(), msg.as_ptr()) };
+    }
+}
+
+/// Formats an event as its message followed by its other fields.
+#[cfg(feature = "tracing")]
+fn format_event(event: &tracing::Event<'_>) -> String {
+    use std::fmt::Write;
+    use tracing::field::{Field, Visit};
+
+    #[derive(Default)]
+    struct Formatter {
+        message: String,
+        fields: String,
+    }
+
+    impl Visit for Formatter {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.record_debug(field, &format_args!("{value}"));
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                let _ = write!(self.message, "{value:?}");
+            } else {
+                let _ = write!(self.fields, " {}={value:?}", field.name());
+            }
+        }
+    }
+
+    let mut formatter = Formatter::default();
+    event.record(&mut formatter);
+    formatter.message + &formatter.fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::items::{Service, User};
+    use crate::mock::MockPam;
+
+    fn with_logged_in_handle(f: impl FnOnce(&PamHandle)) {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        pamh.set_item(Service(c"login")).unwrap();
+        pamh.set_item(User(c"alice")).unwrap();
+        f(pamh);
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    #[test]
+    fn log_goes_to_syslog() {
+        with_logged_in_handle(|pamh| log(pamh, PamFlag::default(), LOG_ERR, "oh\0no"));
+        assert_eq!(
+            vec![(LOG_ERR, "oh\\0no".to_owned())],
+            crate::mock::take_logged()
+        );
+    }
+
+    #[test]
+    fn secret_fields_are_redacted() {
+        let redactor = Redactor::default();
+        let attempt: &[(&str, &dyn std::fmt::Display)] =
+            &[("user", &"alice"), ("AuthTok", &"hunter2"), ("tries", &3)];
+        assert_eq!(
+            "login user=alice AuthTok=*** tries=3",
+            redactor.format("login", attempt)
+        );
+        assert_eq!(
+            "login user=*** AuthTok=*** tries=3",
+            redactor.clone().with_key("user").format("login", attempt)
+        );
+        assert_eq!(
+            "login user=alice AuthTok=hunter2 tries=***",
+            Redactor::new(["tries"]).format("login", attempt)
+        );
+
+        #[cfg(not(feature = "tracing"))]
+        {
+            with_logged_in_handle(|pamh| {
+                let fields: &[(&str, &dyn std::fmt::Display)] = &[("password", &"hunter2")];
+                redactor.log(pamh, PamFlag::default(), LOG_NOTICE, "changed", fields)
+            });
+            assert_eq!(
+                vec![(LOG_NOTICE, "changed password=***".to_owned())],
+                crate::mock::take_logged()
+            );
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn log_goes_to_tracing() {
+        use std::sync::{Arc, Mutex};
+        use tracing::Level;
+        use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+        type Events = Arc<Mutex<Vec<(Level, String)>>>;
+        struct Recorder(Events);
+
+        impl<S: tracing::Subscriber> Layer<S> for Recorder {
+            fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+                assert_eq!("pam", event.metadata().target());
+                let level = *event.metadata().level();
+                self.0.lock().unwrap().push((level, format_event(event)));
+            }
+        }
+
+        let events = Events::default();
+        let subscriber = tracing_subscriber::registry().with(Recorder(Arc::clone(&events)));
+        tracing::subscriber::with_default(subscriber, || {
+            with_logged_in_handle(|pamh| {
+                log(pamh, crate::constants::PAM_SILENT, LOG_WARNING, "careful");
+                log(pamh, PamFlag::default(), LOG_DEBUG, "details");
+            });
+        });
+        assert_eq!(
+            vec![
+                (
+                    Level::WARN,
+                    "careful service=login user=alice flags=32768".to_owned()
+                ),
+                (
+                    Level::DEBUG,
+                    "details service=login user=alice flags=0".to_owned()
+                ),
+            ],
+            *events.lock().unwrap()
+        );
+        assert!(crate::mock::take_logged().is_empty());
+    }
+}
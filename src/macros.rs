@@ -1,7 +1,48 @@
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+
+use crate::args::ModuleArgs;
+use crate::constants::{PamFlag, PamResultCode};
+use crate::logging;
+use crate::module::{PamHandle, PamResult};
+
 /// Macro to generate the `extern "C"` entrypoint bindings needed by PAM
 ///
 /// You can call `pam_hooks!(SomeType);` for any type that implements `PamHooks`
 ///
+/// A panic must not unwind into libpam, so if a hook panics, the panic is caught
+/// and the entrypoint returns `PAM_SERVICE_ERR`.  To return a different code,
+/// pass the name of a `PamResultCode` variant as `panic_code`:
+/// `pam_hooks!(SomeType, panic_code = PAM_AUTH_ERR);`
+/// Either way, the panic is logged with [`logging::log`](crate::logging::log).
+///
+/// The entrypoints are only exported (with `#[no_mangle]`) when the calling
+/// crate is not being compiled with `cfg(test)`.  Under `cargo test`, they are
+/// ordinary functions, so the hooks can be unit-tested without their symbols
+/// clashing with anything else in the test binary.  To build a module that can
+/// also be used as a library, list both crate types in `Cargo.toml`:
+///
+/// ```toml
+/// [lib]
+/// crate-type = ["cdylib", "lib"]
+/// ```
+///
+/// With the `macros` feature, the `#[pam_hooks]` attribute in `pam::attr`
+/// does the same from the type's `impl PamHooks` block.  Their defaults
+/// differ, though: without `groups`, this macro exports every entrypoint,
+/// while the attribute exports only the groups the block implements a hook
+/// for (or all of them, if it implements none).
+///
+/// The type must implement `PamHooks`:
+///
+/// ```compile_fail,E0277
+/// #[macro_use] extern crate pam;
+///
+/// # fn main() {}
+/// struct NotAModule;
+/// pam_hooks!(NotAModule);
+/// ```
+///
 /// ## Examples:
 ///
 /// Here is full example of a PAM module that would authenticate and authorize everybody:
@@ -29,9 +70,207 @@
 ///    }
 /// }
 /// ```
+///
+/// A module whose panics should look like authentication failures:
+///
+/// ```
+/// #[macro_use] extern crate pam;
+///
+/// use pam::module::{PamHooks, PamHandle};
+/// use pam::constants::{PamResultCode, PamFlag};
+/// use std::ffi::CStr;
+///
+/// # fn main() {}
+/// struct Picky;
+/// pam_hooks!(Picky, panic_code = PAM_AUTH_ERR);
+///
+/// impl PamHooks for Picky {
+///    fn sm_authenticate(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+///        let user = pamh.get_user(None).expect("no user");
+///        if user == "alice" {
+///            PamResultCode::PAM_SUCCESS
+///        } else {
+///            PamResultCode::PAM_AUTH_ERR
+///        }
+///    }
+/// }
+/// ```
+///
+/// ## Exporting only some entrypoints
+///
+/// A module meant for only some of the PAM management groups can export
+/// only their entrypoints, so that listing it under any other group in
+/// `/etc/pam.d` fails to load rather than silently doing nothing.  Name the
+/// groups as the PAM configuration does: `auth` (`pam_sm_authenticate` and
+/// `pam_sm_setcred`), `account` (`pam_sm_acct_mgmt`), `session`
+/// (`pam_sm_open_session` and `pam_sm_close_session`) and `password`
+/// (`pam_sm_chauthtok`).  `panic_code` can be given after them:
+/// `pam_hooks!(SomeType, groups = [auth, account], panic_code = PAM_AUTH_ERR);`
+///
+/// Either way, the macro also defines `PAM_HOOKS_EXPORTED`, the names of the
+/// entrypoints it generated, for tests or tools that check a module against
+/// the stacks it is used in:
+///
+/// ```
+/// #[macro_use] extern crate pam;
+///
+/// use pam::module::{PamHooks, PamHandle};
+/// use pam::constants::{PamResultCode, PamFlag};
+/// use std::ffi::CStr;
+///
+/// struct Motd;
+/// pam_hooks!(Motd, groups = [session]);
+///
+/// impl PamHooks for Motd {
+///    fn sm_open_session(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+///        PamResultCode::PAM_SUCCESS
+///    }
+/// }
+///
+/// # fn main() {
+/// assert_eq!(&["pam_sm_open_session", "pam_sm_close_session"], PAM_HOOKS_EXPORTED);
+/// # }
+/// ```
+///
+/// An unknown group is an error:
+///
+/// ```compile_fail
+/// #[macro_use] extern crate pam;
+///
+/// # fn main() {}
+/// struct Motd;
+/// impl pam::module::PamHooks for Motd {}
+/// pam_hooks!(Motd, groups = [sessions]);
+/// ```
+///
+/// ## Choosing an implementation at runtime
+///
+/// One module can hold several implementations, and pick one each time it
+/// is called from an argument in the PAM configuration.  Give the argument's
+/// key, then each value along with the type that implements it:
+/// `pam_hooks!(select "mode" { "strict" => Strict, "lenient" => Lenient });`
+/// With `mode=lenient` among the arguments, every hook is forwarded to
+/// `Lenient`; with no `mode=` argument, to the first type listed.  Any other
+/// value is a configuration mistake, which is logged, and the hook returns
+/// `PAM_SERVICE_ERR`.  The arguments are passed on whole, including the
+/// selection key.  `panic_code` can be given after the braces, as above.
+///
+/// ```
+/// #[macro_use] extern crate pam;
+///
+/// use pam::module::{PamHooks, PamHandle};
+/// use pam::constants::{PamResultCode, PamFlag};
+/// use std::ffi::CStr;
+///
+/// # fn main() {}
+/// struct Strict;
+/// struct Lenient;
+/// pam_hooks!(select "mode" { "strict" => Strict, "lenient" => Lenient });
+///
+/// impl PamHooks for Strict {
+///    fn sm_authenticate(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+///        PamResultCode::PAM_AUTH_ERR
+///    }
+/// }
+///
+/// impl PamHooks for Lenient {
+///    fn sm_authenticate(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+///        PamResultCode::PAM_SUCCESS
+///    }
+/// }
+/// ```
 #[macro_export]
 macro_rules! pam_hooks {
+    (select $key:literal { $($value:literal => $impl:ident),+ $(,)? } $(, panic_code = $panic_code:ident)?) => {
+        /// Forwards each hook to the implementation chosen by the arguments.
+        struct PamHooksSelect;
+
+        impl $crate::module::PamHooks for PamHooksSelect {
+            fn acct_mgmt(
+                pamh: &mut $crate::module::PamHandle,
+                args: Vec<&std::ffi::CStr>,
+                flags: $crate::constants::PamFlag,
+            ) -> $crate::constants::PamResultCode {
+                $crate::pam_hooks!(@forward acct_mgmt(pamh, args, flags), $key, $($value => $impl),+)
+            }
+
+            fn sm_authenticate(
+                pamh: &mut $crate::module::PamHandle,
+                args: Vec<&std::ffi::CStr>,
+                flags: $crate::constants::PamFlag,
+            ) -> $crate::constants::PamResultCode {
+                $crate::pam_hooks!(@forward sm_authenticate(pamh, args, flags), $key, $($value => $impl),+)
+            }
+
+            fn sm_chauthtok(
+                pamh: &mut $crate::module::PamHandle,
+                args: Vec<&std::ffi::CStr>,
+                flags: $crate::constants::PamFlag,
+            ) -> $crate::constants::PamResultCode {
+                $crate::pam_hooks!(@forward sm_chauthtok(pamh, args, flags), $key, $($value => $impl),+)
+            }
+
+            fn sm_close_session(
+                pamh: &mut $crate::module::PamHandle,
+                args: Vec<&std::ffi::CStr>,
+                flags: $crate::constants::PamFlag,
+            ) -> $crate::constants::PamResultCode {
+                $crate::pam_hooks!(@forward sm_close_session(pamh, args, flags), $key, $($value => $impl),+)
+            }
+
+            fn sm_open_session(
+                pamh: &mut $crate::module::PamHandle,
+                args: Vec<&std::ffi::CStr>,
+                flags: $crate::constants::PamFlag,
+            ) -> $crate::constants::PamResultCode {
+                $crate::pam_hooks!(@forward sm_open_session(pamh, args, flags), $key, $($value => $impl),+)
+            }
+
+            fn sm_setcred(
+                pamh: &mut $crate::module::PamHandle,
+                args: Vec<&std::ffi::CStr>,
+                flags: $crate::constants::PamFlag,
+            ) -> $crate::constants::PamResultCode {
+                $crate::pam_hooks!(@forward sm_setcred(pamh, args, flags), $key, $($value => $impl),+)
+            }
+        }
+
+        $crate::pam_hooks!(PamHooksSelect $(, panic_code = $panic_code)?);
+    };
+    (@forward $hook:ident($pamh:ident, $args:ident, $flags:ident), $key:literal, $($value:literal => $impl:ident),+) => {{
+        let selected =
+            match $crate::macros::select_strategy($pamh, $flags, &$args, $key, &[$($value),+]) {
+                Ok(selected) => selected,
+                Err(code) => return code,
+            };
+        $(
+            if selected == $value {
+                return <$impl as $crate::module::PamHooks>::$hook($pamh, $args, $flags);
+            }
+        )+
+        $crate::constants::PamResultCode::PAM_SERVICE_ERR
+    }};
     ($ident:ident) => {
+        $crate::pam_hooks!($ident, panic_code = PAM_SERVICE_ERR);
+    };
+    ($ident:ident, panic_code = $panic_code:ident) => {
+        $crate::pam_hooks!(
+            $ident,
+            groups = [auth, account, session, password],
+            panic_code = $panic_code
+        );
+    };
+    ($ident:ident, groups = [$($group:ident),+ $(,)?]) => {
+        $crate::pam_hooks!($ident, groups = [$($group),+], panic_code = PAM_SERVICE_ERR);
+    };
+    ($ident:ident, groups = [$($group:ident),+ $(,)?], panic_code = $panic_code:ident) => {
+        // Checked here, so that a missing impl is reported at the call site
+        // rather than inside the generated entrypoints.
+        const _: fn() = || {
+            fn assert_pam_hooks<T: $crate::module::PamHooks>() {}
+            assert_pam_hooks::<$ident>();
+        };
+
         pub use self::pam_hooks_scope::*;
         mod pam_hooks_scope {
             use std::ffi::CStr;
@@ -39,79 +278,158 @@ macro_rules! pam_hooks {
             use $crate::constants::{PamFlag, PamResultCode};
             use $crate::module::{PamHandle, PamHooks};
 
-            fn extract_argv<'a>(argc: c_int, argv: *const *const c_char) -> Vec<&'a CStr> {
-                (0..argc)
-                    .map(|o| unsafe { CStr::from_ptr(*argv.offset(o as isize) as *const c_char) })
-                    .collect()
-            }
+            /// The names of the entrypoints this module exports.
+            pub const PAM_HOOKS_EXPORTED: &[&str] = $crate::pam_hooks!(@names [] $($group),+);
 
-            #[no_mangle]
-            pub extern "C" fn pam_sm_acct_mgmt(
-                pamh: &mut PamHandle,
-                flags: PamFlag,
-                argc: c_int,
-                argv: *const *const c_char,
-            ) -> PamResultCode {
-                let args = extract_argv(argc, argv);
-                super::$ident::acct_mgmt(pamh, args, flags)
+            /// Borrows the arguments libpam passed in, which last the call.
+            fn extract_argv<'a>(argc: c_int, argv: *const *const c_char) -> Vec<&'a CStr> {
+                unsafe { $crate::macros::extract_argv(argc, argv) }
             }
 
-            #[no_mangle]
-            pub extern "C" fn pam_sm_authenticate(
+            /// Runs the hook between `before_hook` and `after_hook`,
+            /// turning a panic in any of them into `panic_code`.
+            fn catch_panic(
                 pamh: &mut PamHandle,
                 flags: PamFlag,
-                argc: c_int,
-                argv: *const *const c_char,
+                hook: &str,
+                run: impl FnOnce(&mut PamHandle) -> PamResultCode,
             ) -> PamResultCode {
-                let args = extract_argv(argc, argv);
-                super::$ident::sm_authenticate(pamh, args, flags)
+                let start = std::time::Instant::now();
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    super::$ident::before_hook(pamh, hook, flags);
+                    run(pamh)
+                }))
+                .unwrap_or_else(|_| panicked(pamh, flags, hook));
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    super::$ident::after_hook(pamh, hook, flags, result, start.elapsed());
+                    result
+                }))
+                .unwrap_or_else(|_| panicked(pamh, flags, "after_hook"))
             }
 
-            #[no_mangle]
-            pub extern "C" fn pam_sm_chauthtok(
-                pamh: &mut PamHandle,
-                flags: PamFlag,
-                argc: c_int,
-                argv: *const *const c_char,
-            ) -> PamResultCode {
-                let args = extract_argv(argc, argv);
-                super::$ident::sm_chauthtok(pamh, args, flags)
+            fn panicked(pamh: &PamHandle, flags: PamFlag, hook: &str) -> PamResultCode {
+                let msg = format!("{} panicked", hook);
+                $crate::logging::log(pamh, flags, $crate::logging::LOG_CRIT, &msg);
+                PamResultCode::$panic_code
             }
 
-            #[no_mangle]
-            pub extern "C" fn pam_sm_close_session(
-                pamh: &mut PamHandle,
-                flags: PamFlag,
-                argc: c_int,
-                argv: *const *const c_char,
-            ) -> PamResultCode {
+            $($crate::pam_hooks!(@group $group $ident);)+
+        }
+    };
+    (@names [$($name:expr),*]) => {
+        &[$($name),*]
+    };
+    (@names [$($name:expr),*] auth $(, $rest:ident)*) => {
+        $crate::pam_hooks!(@names [$($name,)* "pam_sm_authenticate", "pam_sm_setcred"] $($rest),*)
+    };
+    (@names [$($name:expr),*] account $(, $rest:ident)*) => {
+        $crate::pam_hooks!(@names [$($name,)* "pam_sm_acct_mgmt"] $($rest),*)
+    };
+    (@names [$($name:expr),*] session $(, $rest:ident)*) => {
+        $crate::pam_hooks!(@names [$($name,)* "pam_sm_open_session", "pam_sm_close_session"] $($rest),*)
+    };
+    (@names [$($name:expr),*] password $(, $rest:ident)*) => {
+        $crate::pam_hooks!(@names [$($name,)* "pam_sm_chauthtok"] $($rest),*)
+    };
+    (@names [$($name:expr),*] $other:ident $(, $rest:ident)*) => {
+        compile_error!(concat!(
+            "unknown PAM group `",
+            stringify!($other),
+            "`; expected auth, account, session or password"
+        ))
+    };
+    (@group auth $ident:ident) => {
+        $crate::pam_hooks!(@entry pam_sm_authenticate sm_authenticate $ident);
+        $crate::pam_hooks!(@entry pam_sm_setcred sm_setcred $ident);
+    };
+    (@group account $ident:ident) => {
+        $crate::pam_hooks!(@entry pam_sm_acct_mgmt acct_mgmt $ident);
+    };
+    (@group session $ident:ident) => {
+        $crate::pam_hooks!(@entry pam_sm_open_session sm_open_session $ident);
+        $crate::pam_hooks!(@entry pam_sm_close_session sm_close_session $ident);
+    };
+    (@group password $ident:ident) => {
+        $crate::pam_hooks!(@entry pam_sm_chauthtok sm_chauthtok $ident);
+    };
+    (@group $other:ident $ident:ident) => {};
+    (@entry $name:ident $hook:ident $ident:ident) => {
+        #[cfg_attr(not(test), no_mangle)]
+        pub extern "C" fn $name(
+            pamh: &mut PamHandle,
+            flags: PamFlag,
+            argc: c_int,
+            argv: *const *const c_char,
+        ) -> PamResultCode {
+            catch_panic(pamh, flags, stringify!($hook), |pamh| {
                 let args = extract_argv(argc, argv);
-                super::$ident::sm_close_session(pamh, args, flags)
-            }
+                super::$ident::$hook(pamh, args, flags)
+            })
+        }
+    };
+}
 
-            #[no_mangle]
-            pub extern "C" fn pam_sm_open_session(
-                pamh: &mut PamHandle,
-                flags: PamFlag,
-                argc: c_int,
-                argv: *const *const c_char,
-            ) -> PamResultCode {
-                let args = extract_argv(argc, argv);
-                super::$ident::sm_open_session(pamh, args, flags)
+/// Borrows the arguments libpam passed to an entrypoint, for `pam_hooks!`.
+///
+/// This is public only so that the macro can reach it through `$crate`, and
+/// the fuzz targets through `pam::fuzzing`; it is not part of the API.
+///
+/// A broken stack might pass a null `argv`, which is taken as no arguments,
+/// a negative `argc`, which is taken as zero, or null elements, which are
+/// taken as empty arguments.
+///
+/// # Safety
+///
+/// Unless it is null, `argv` must point to at least `argc` pointers, each
+/// null or pointing to a nul-terminated string that outlives `'a`.
+#[doc(hidden)]
+pub unsafe fn extract_argv<'a>(argc: c_int, argv: *const *const c_char) -> Vec<&'a CStr> {
+    if argv.is_null() {
+        return Vec::new();
+    }
+    (0..usize::try_from(argc).unwrap_or(0))
+        .map(|i| {
+            let arg = *argv.add(i);
+            if arg.is_null() {
+                <&CStr>::default()
+            } else {
+                CStr::from_ptr(arg)
             }
+        })
+        .collect()
+}
 
-            #[no_mangle]
-            pub extern "C" fn pam_sm_setcred(
-                pamh: &mut PamHandle,
-                flags: PamFlag,
-                argc: c_int,
-                argv: *const *const c_char,
-            ) -> PamResultCode {
-                let args = extract_argv(argc, argv);
-                super::$ident::sm_setcred(pamh, args, flags)
-            }
+/// Picks the value of the `key=` argument from `values`, for
+/// `pam_hooks!(select ...)`, or the first value if there is no such argument.
+///
+/// # Errors
+///
+/// Returns `PAM_SERVICE_ERR` if the argument is not one of `values`, or
+/// is not valid UTF-8.
+pub fn select_strategy<'v>(
+    pamh: &PamHandle,
+    flags: PamFlag,
+    args: &[&CStr],
+    key: &str,
+    values: &[&'v str],
+) -> PamResult<&'v str> {
+    let chosen = match ModuleArgs::new(args).get(key)? {
+        Some(chosen) => chosen,
+        None => {
+            return values
+                .first()
+                .copied()
+                .ok_or(PamResultCode::PAM_SERVICE_ERR)
         }
     };
+    match values.iter().find(|value| **value == chosen) {
+        Some(value) => Ok(value),
+        None => {
+            let msg = format!("unknown {key}={chosen}, expected one of {values:?}");
+            logging::log(pamh, flags, logging::LOG_ERR, &msg);
+            Err(PamResultCode::PAM_SERVICE_ERR)
+        }
+    }
 }
 
 #[macro_export]
@@ -132,10 +450,278 @@ macro_rules! pam_try {
 
 #[cfg(test)]
 pub mod test {
-    use crate::module::PamHooks;
+    use crate::constants::{PamFlag, PamResultCode};
+    use crate::mock::MockPam;
+    use crate::module::{PamHandle, PamHooks};
+    use std::ffi::CStr;
 
     struct Foo;
-    impl PamHooks for Foo {}
+    impl PamHooks for Foo {
+        fn sm_authenticate(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+            panic!("oh no")
+        }
+
+        fn sm_open_session(_: &mut PamHandle, args: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+            let expected: Vec<&CStr> = vec![c"debug", c"", c"retry=3"];
+            if args.is_empty() || args == expected {
+                PamResultCode::PAM_SUCCESS
+            } else {
+                PamResultCode::PAM_SESSION_ERR
+            }
+        }
+    }
 
     pam_hooks!(Foo);
+
+    #[test]
+    fn all_entrypoints_exported() {
+        assert_eq!(
+            &[
+                "pam_sm_authenticate",
+                "pam_sm_setcred",
+                "pam_sm_acct_mgmt",
+                "pam_sm_open_session",
+                "pam_sm_close_session",
+                "pam_sm_chauthtok",
+            ],
+            PAM_HOOKS_EXPORTED
+        );
+    }
+
+    pub mod groups {
+        use crate::constants::{PamFlag, PamResultCode};
+        use crate::mock::MockPam;
+        use crate::module::{PamHandle, PamHooks};
+        use std::ffi::CStr;
+
+        struct Gatekeeper;
+
+        impl PamHooks for Gatekeeper {
+            fn acct_mgmt(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+                PamResultCode::PAM_PERM_DENIED
+            }
+
+            fn sm_chauthtok(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+                panic!("not yet")
+            }
+        }
+
+        pam_hooks!(
+            Gatekeeper,
+            groups = [account, password],
+            panic_code = PAM_AUTHTOK_ERR
+        );
+
+        #[test]
+        fn only_some_entrypoints_exported() {
+            assert_eq!(
+                &["pam_sm_acct_mgmt", "pam_sm_chauthtok"],
+                PAM_HOOKS_EXPORTED
+            );
+            let mut mock = MockPam::new();
+            let flags = PamFlag::default();
+            let null = std::ptr::null();
+            assert_eq!(
+                PamResultCode::PAM_PERM_DENIED,
+                pam_sm_acct_mgmt(mock.handle(), flags, 0, null)
+            );
+            assert_eq!(
+                PamResultCode::PAM_AUTHTOK_ERR,
+                pam_sm_chauthtok(mock.handle(), flags, 0, null)
+            );
+        }
+    }
+
+    #[test]
+    fn panics_are_caught() {
+        let mut mock = MockPam::new();
+        let flags = PamFlag::default();
+        assert_eq!(
+            PamResultCode::PAM_SERVICE_ERR,
+            pam_sm_authenticate(mock.handle(), flags, 0, std::ptr::null())
+        );
+        assert_eq!(
+            PamResultCode::PAM_IGNORE,
+            pam_sm_acct_mgmt(mock.handle(), flags, 0, std::ptr::null())
+        );
+        #[cfg(not(feature = "tracing"))]
+        assert_eq!(
+            vec![(libc::LOG_CRIT, "sm_authenticate panicked".to_owned())],
+            crate::mock::take_logged()
+        );
+    }
+
+    pub mod select {
+        use crate::constants::{PamFlag, PamResultCode};
+        use crate::mock::MockPam;
+        use crate::module::{PamHandle, PamHooks};
+        use std::ffi::CStr;
+
+        struct Strict;
+        struct Lenient;
+
+        impl PamHooks for Strict {
+            fn sm_authenticate(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+                PamResultCode::PAM_AUTH_ERR
+            }
+        }
+
+        impl PamHooks for Lenient {
+            fn sm_authenticate(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+                PamResultCode::PAM_SUCCESS
+            }
+
+            fn sm_setcred(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+                panic!("not yet")
+            }
+        }
+
+        pam_hooks!(select "mode" { "strict" => Strict, "lenient" => Lenient }, panic_code = PAM_CRED_ERR);
+
+        #[test]
+        fn strategy_from_argv() {
+            let mut mock = MockPam::new();
+            let flags = PamFlag::default();
+            let authenticate = |mock: &mut MockPam, argv: &[*const libc::c_char]| {
+                pam_sm_authenticate(mock.handle(), flags, argv.len() as _, argv.as_ptr())
+            };
+            assert_eq!(PamResultCode::PAM_AUTH_ERR, authenticate(&mut mock, &[]));
+            assert_eq!(
+                PamResultCode::PAM_AUTH_ERR,
+                authenticate(&mut mock, &[c"debug".as_ptr(), c"mode=strict".as_ptr()])
+            );
+            assert_eq!(
+                PamResultCode::PAM_SUCCESS,
+                authenticate(&mut mock, &[c"mode=lenient".as_ptr()])
+            );
+            assert_eq!(
+                PamResultCode::PAM_SERVICE_ERR,
+                authenticate(&mut mock, &[c"mode=lax".as_ptr()])
+            );
+            #[cfg(not(feature = "tracing"))]
+            assert_eq!(
+                vec![(
+                    libc::LOG_ERR,
+                    r#"unknown mode=lax, expected one of ["strict", "lenient"]"#.to_owned()
+                )],
+                crate::mock::take_logged()
+            );
+
+            let argv = [c"mode=lenient".as_ptr()];
+            assert_eq!(
+                PamResultCode::PAM_IGNORE,
+                pam_sm_acct_mgmt(mock.handle(), flags, 1, argv.as_ptr())
+            );
+            assert_eq!(
+                PamResultCode::PAM_CRED_ERR,
+                pam_sm_setcred(mock.handle(), flags, 1, argv.as_ptr())
+            );
+        }
+    }
+
+    pub mod instrumented {
+        use crate::constants::{PamFlag, PamResultCode, PAM_SILENT};
+        use crate::mock::MockPam;
+        use crate::module::{PamHandle, PamHooks};
+        use std::cell::RefCell;
+        use std::ffi::CStr;
+        use std::time::Duration;
+
+        thread_local! {
+            static CALLS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+        }
+
+        struct Audited;
+
+        impl PamHooks for Audited {
+            fn sm_authenticate(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+                CALLS.with(|calls| calls.borrow_mut().push("authenticating".to_owned()));
+                PamResultCode::PAM_SUCCESS
+            }
+
+            fn sm_setcred(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+                panic!("no credentials")
+            }
+
+            fn before_hook(_: &mut PamHandle, hook: &str, flags: PamFlag) {
+                let call = format!("before {hook} {flags}");
+                CALLS.with(|calls| calls.borrow_mut().push(call));
+            }
+
+            fn after_hook(
+                _: &mut PamHandle,
+                hook: &str,
+                flags: PamFlag,
+                result: PamResultCode,
+                _: Duration,
+            ) {
+                let call = format!("after {hook} {flags} {}", result.name());
+                CALLS.with(|calls| calls.borrow_mut().push(call));
+            }
+        }
+
+        pam_hooks!(Audited);
+
+        #[test]
+        fn callbacks_surround_hooks() {
+            let mut mock = MockPam::new();
+            let null = std::ptr::null();
+            let flags = PamFlag::default();
+            assert_eq!(
+                PamResultCode::PAM_SUCCESS,
+                pam_sm_authenticate(mock.handle(), PAM_SILENT, 0, null)
+            );
+            assert_eq!(
+                PamResultCode::PAM_SERVICE_ERR,
+                pam_sm_setcred(mock.handle(), flags, 0, null)
+            );
+            assert_eq!(
+                PamResultCode::PAM_IGNORE,
+                pam_sm_close_session(mock.handle(), flags, 0, null)
+            );
+            assert_eq!(
+                vec![
+                    "before sm_authenticate PAM_SILENT",
+                    "authenticating",
+                    "after sm_authenticate PAM_SILENT PAM_SUCCESS",
+                    "before sm_setcred 0",
+                    "after sm_setcred 0 PAM_SERVICE_ERR",
+                    "before sm_close_session 0",
+                    "after sm_close_session 0 PAM_IGNORE",
+                ],
+                CALLS.with(|calls| calls.take())
+            );
+        }
+    }
+
+    #[test]
+    fn extract_malformed_argv() {
+        use super::extract_argv;
+        let argv = [c"debug".as_ptr(), std::ptr::null(), c"retry=3".as_ptr()];
+        assert_eq!(vec![c"debug", c"", c"retry=3"], unsafe {
+            extract_argv(3, argv.as_ptr())
+        });
+        assert_eq!(vec![c"debug"], unsafe { extract_argv(1, argv.as_ptr()) });
+        assert!(unsafe { extract_argv(-1, argv.as_ptr()) }.is_empty());
+        assert!(unsafe { extract_argv(3, std::ptr::null()) }.is_empty());
+    }
+
+    #[test]
+    fn malformed_argv() {
+        let mut mock = MockPam::new();
+        let flags = PamFlag::default();
+        let argv = [c"debug".as_ptr(), std::ptr::null(), c"retry=3".as_ptr()];
+        assert_eq!(
+            PamResultCode::PAM_SUCCESS,
+            pam_sm_open_session(mock.handle(), flags, 3, argv.as_ptr())
+        );
+        assert_eq!(
+            PamResultCode::PAM_SUCCESS,
+            pam_sm_open_session(mock.handle(), flags, 2, std::ptr::null())
+        );
+        assert_eq!(
+            PamResultCode::PAM_SUCCESS,
+            pam_sm_open_session(mock.handle(), flags, -1, argv.as_ptr())
+        );
+    }
 }
@@ -0,0 +1,664 @@
+//! A fake libpam, for testing code that calls PAM functions.
+//!
+//! When building tests, this crate doesn't link against libpam.  Instead,
+//! this module exports functions under the same names, which act on a
+//! [`MockPam`] masquerading as a [`PamHandle`].  They only implement
+//! enough of the real behavior to exercise this crate.
+
+use libc::{c_char, c_int, c_void};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::ptr;
+
+use crate::constants::{
+    PamFlag, PamItemType, PamMessageStyle, PamResultCode, PAM_DATA_REPLACE, PAM_ERROR_MSG,
+    PAM_PRELIM_CHECK, PAM_PROMPT_ECHO_OFF, PAM_PROMPT_ECHO_ON, PAM_UPDATE_AUTHTOK,
+};
+use crate::conv::{ConvFn, Inner, PamMessage};
+use crate::items::{ItemType, RawXAuthData};
+use crate::module::{PamHandle, PamHooks};
+
+type Cleanup = extern "C" fn(*const PamHandle, *mut c_void, c_int);
+
+#[derive(Default)]
+struct State {
+    items: HashMap<PamItemType, CString>,
+    conv: Option<Box<Inner>>,
+    xauth: Option<XAuth>,
+    data: HashMap<CString, (*mut c_void, Cleanup)>,
+    env: Vec<CString>,
+    module: Option<Module>,
+    /// Whether this is the application's handle, outside any module.
+    from_app: bool,
+    /// The last entry returned by `pam_modutil_getpwnam`, which the handle
+    /// owns, as in libpam.
+    #[cfg(target_os = "linux")]
+    passwd: Option<Box<libc::passwd>>,
+}
+
+/// A copy of the `PAM_XAUTHDATA` item, and the buffers it points to.
+struct XAuth {
+    raw: RawXAuthData,
+    _name: CString,
+    _data: Vec<u8>,
+}
+
+type Hook = fn(&mut PamHandle, Vec<&CStr>, PamFlag) -> PamResultCode;
+
+/// The hooks of the module that handles every operation on a handle
+/// created by the fake `pam_start`.
+#[derive(Clone, Copy)]
+struct Module {
+    acct_mgmt: Hook,
+    authenticate: Hook,
+    chauthtok: Hook,
+}
+
+thread_local! {
+    static MODULE: Cell<Option<Module>> = const { Cell::new(None) };
+    static ENDED: RefCell<Vec<c_int>> = const { RefCell::new(Vec::new()) };
+    static LOGGED: RefCell<Vec<(c_int, String)>> = const { RefCell::new(Vec::new()) };
+    static AUTHTOK_TYPES: RefCell<Vec<Option<CString>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Makes `M` the only module in the stack of handles that are created by
+/// `pam_start` on the current thread.
+pub fn set_module<M: PamHooks>() {
+    MODULE.with(|module| {
+        module.set(Some(Module {
+            acct_mgmt: M::acct_mgmt,
+            authenticate: M::sm_authenticate,
+            chauthtok: M::sm_chauthtok,
+        }))
+    });
+}
+
+/// Returns (and forgets) the messages passed to `pam_syslog` on this thread.
+pub fn take_logged() -> Vec<(c_int, String)> {
+    LOGGED.with(|logged| logged.take())
+}
+
+/// Returns (and forgets) the statuses passed to `pam_end` on this thread.
+pub fn take_ended() -> Vec<c_int> {
+    ENDED.with(|ended| ended.take())
+}
+
+/// Returns (and forgets) the `PAM_AUTHTOK_TYPE` item as it was during each
+/// call to `pam_get_authtok` on this thread.
+pub fn take_authtok_types() -> Vec<Option<CString>> {
+    AUTHTOK_TYPES.with(|types| types.take())
+}
+
+/// Runs a hook of the current module like libpam would, with a stack
+/// where a module that ignores the request leads to failure.
+fn run_hook(pamh: *mut PamHandle, flags: PamFlag, hook: fn(Module) -> Hook) -> PamResultCode {
+    let Some(module) = state(pamh).module else {
+        return PamResultCode::PAM_PERM_DENIED;
+    };
+    match hook(module)(unsafe { &mut *pamh }, Vec::new(), flags) {
+        PamResultCode::PAM_IGNORE => PamResultCode::PAM_PERM_DENIED,
+        other => other,
+    }
+}
+
+/// A PAM handle that is backed by the fake functions in this module.
+///
+/// When dropped, the cleanup functions of all stored data are called.
+pub struct MockPam(Box<State>);
+
+impl MockPam {
+    pub fn new() -> Self {
+        Self(Box::default())
+    }
+
+    /// Adds a raw `NAME=value` entry to the PAM environment.
+    pub fn putenv(&mut self, entry: &str) {
+        self.0.env.push(CString::new(entry).unwrap());
+    }
+
+    /// Installs a conversation function, as `pam_start` would.
+    ///
+    /// Responses are freed with `libc::free`, so `conv` must allocate them
+    /// with `malloc`.
+    pub fn set_conv(&mut self, conv: ConvFn, appdata_ptr: *const c_void) {
+        self.0.conv = Some(Box::new(Inner {
+            conv: Some(conv),
+            appdata_ptr,
+        }));
+    }
+
+    /// Treats calls as coming from the application rather than a module,
+    /// so that `pam_set_data` fails with `PAM_SYSTEM_ERR`, as in libpam.
+    pub fn as_application(&mut self) {
+        self.0.from_app = true;
+    }
+
+    /// The fake handle, to call methods on.
+    pub fn handle(&mut self) -> &mut PamHandle {
+        unsafe { &mut *(self.0.as_mut() as *mut State).cast::<PamHandle>() }
+    }
+
+    /// Ends the fake transaction like `pam_end`, calling the cleanup
+    /// functions of all stored data with `status`.
+    pub fn end(mut self, status: c_int) {
+        let handle: *const PamHandle = self.handle();
+        for (_, (data, cleanup)) in self.0.data.drain() {
+            cleanup(handle, data, status);
+        }
+    }
+}
+
+impl Drop for MockPam {
+    fn drop(&mut self) {
+        let handle: *const PamHandle = self.handle();
+        for (_, (data, cleanup)) in self.0.data.drain() {
+            cleanup(handle, data, PamResultCode::PAM_SUCCESS.into());
+        }
+    }
+}
+
+thread_local! {
+    static FREED: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Frees memory like `libc::free`, but counts how many times it was called
+/// on the current thread.
+pub unsafe fn free(ptr: *mut c_void) {
+    FREED.with(|freed| freed.set(freed.get() + 1));
+    libc::free(ptr);
+}
+
+/// The number of times [`free`] has been called on the current thread.
+pub fn freed() -> usize {
+    FREED.with(Cell::get)
+}
+
+thread_local! {
+    static LIVE: Cell<isize> = const { Cell::new(0) };
+}
+
+/// The system allocator, counting allocations per thread to catch leaks.
+struct LeakCounter;
+
+// SAFETY: Defers everything to the system allocator.
+unsafe impl GlobalAlloc for LeakCounter {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = LIVE.try_with(|live| live.set(live.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let _ = LIVE.try_with(|live| live.set(live.get() - 1));
+        System.dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: LeakCounter = LeakCounter;
+
+/// The number of Rust allocations made on the current thread that
+/// have not yet been freed (on the current thread).
+pub fn live_allocations() -> isize {
+    LIVE.with(Cell::get)
+}
+
+fn state<'a>(pamh: *const PamHandle) -> &'a mut State {
+    unsafe { &mut *(pamh as *mut State) }
+}
+
+#[no_mangle]
+extern "C" fn pam_get_item(
+    pamh: *const PamHandle,
+    item_type: PamItemType,
+    item: &mut *const c_void,
+) -> PamResultCode {
+    let state = state(pamh);
+    *item = match ItemType::try_from(item_type) {
+        Ok(ItemType::Conv) => state
+            .conv
+            .as_deref()
+            .map_or(ptr::null(), |c| (c as *const Inner).cast()),
+        Ok(ItemType::XAuthData) => state
+            .xauth
+            .as_ref()
+            .map_or(ptr::null(), |x| (&x.raw as *const RawXAuthData).cast()),
+        Ok(ItemType::FailDelay) => return PamResultCode::PAM_BAD_ITEM,
+        _ => state
+            .items
+            .get(&item_type)
+            .map_or(ptr::null(), |s| s.as_ptr().cast()),
+    };
+    PamResultCode::PAM_SUCCESS
+}
+
+#[no_mangle]
+extern "C" fn pam_set_item(
+    pamh: *mut PamHandle,
+    item_type: PamItemType,
+    item: *const c_void,
+) -> PamResultCode {
+    let state = state(pamh);
+    match ItemType::try_from(item_type) {
+        Ok(ItemType::Conv) => {
+            state.conv =
+                (!item.is_null()).then(|| Box::new(unsafe { ptr::read(item.cast::<Inner>()) }));
+        }
+        // Like libpam, copies the name as a C string, but the data by length.
+        Ok(ItemType::XAuthData) => {
+            state.xauth = (!item.is_null()).then(|| {
+                let mut raw = unsafe { ptr::read(item.cast::<RawXAuthData>()) };
+                let name = unsafe { CStr::from_ptr(raw.name) }.to_owned();
+                let data = unsafe {
+                    std::slice::from_raw_parts(raw.data.cast::<u8>(), raw.datalen as usize)
+                }
+                .to_vec();
+                raw.name = name.as_ptr();
+                raw.data = data.as_ptr().cast();
+                XAuth {
+                    raw,
+                    _name: name,
+                    _data: data,
+                }
+            });
+        }
+        Ok(ItemType::FailDelay) => return PamResultCode::PAM_BAD_ITEM,
+        // Unlike libpam, accepts any other item type as a string, like a
+        // vendor-patched libpam might.
+        _ if item.is_null() => {
+            state.items.remove(&item_type);
+        }
+        _ => {
+            let value = unsafe { CStr::from_ptr(item.cast()) }.to_owned();
+            state.items.insert(item_type, value);
+        }
+    }
+    PamResultCode::PAM_SUCCESS
+}
+
+#[no_mangle]
+extern "C" fn pam_get_data(
+    pamh: *const PamHandle,
+    module_data_name: *const c_char,
+    data: &mut *const c_void,
+) -> PamResultCode {
+    let name = unsafe { CStr::from_ptr(module_data_name) };
+    match state(pamh).data.get(name) {
+        Some(&(value, _)) => {
+            *data = value;
+            PamResultCode::PAM_SUCCESS
+        }
+        None => PamResultCode::PAM_NO_MODULE_DATA,
+    }
+}
+
+#[no_mangle]
+extern "C" fn pam_set_data(
+    pamh: *mut PamHandle,
+    module_data_name: *const c_char,
+    data: *mut c_void,
+    cleanup: Cleanup,
+) -> PamResultCode {
+    if state(pamh).from_app {
+        return PamResultCode::PAM_SYSTEM_ERR;
+    }
+    let name = unsafe { CStr::from_ptr(module_data_name) }.to_owned();
+    if let Some((old, old_cleanup)) = state(pamh).data.insert(name, (data, cleanup)) {
+        old_cleanup(
+            pamh,
+            old,
+            c_int::from(PamResultCode::PAM_SUCCESS) | PAM_DATA_REPLACE,
+        );
+    }
+    PamResultCode::PAM_SUCCESS
+}
+
+/// Sends a single message through the conversation, if there is one,
+/// and returns the response.
+fn converse(state: &State, style: PamMessageStyle, msg: &CStr) -> Option<CString> {
+    let conv = state.conv.as_deref()?;
+    let callback = conv.conv?;
+    let message = PamMessage {
+        msg_style: style,
+        msg: msg.as_ptr(),
+    };
+    let message_ptr: *const PamMessage = &message;
+    let mut resp = ptr::null_mut();
+    let res = callback(1, &message_ptr, &mut resp, conv.appdata_ptr);
+    if res != c_int::from(PamResultCode::PAM_SUCCESS) || resp.is_null() {
+        return None;
+    }
+    unsafe {
+        let answer = (*resp).resp;
+        let owned = (!answer.is_null()).then(|| CStr::from_ptr(answer).to_owned());
+        libc::free(answer.cast());
+        libc::free(resp.cast());
+        owned
+    }
+}
+
+/// Returns the username if it has already been set.  Otherwise, like libpam,
+/// prompts for it with `prompt`, the `PAM_USER_PROMPT` item, or `login: `.
+#[no_mangle]
+extern "C" fn pam_get_user(
+    pamh: *const PamHandle,
+    user: &mut *const c_char,
+    prompt: *const c_char,
+) -> PamResultCode {
+    let state = state(pamh);
+    if !state.items.contains_key(&ItemType::User.into()) {
+        let prompt = match unsafe { prompt.as_ref() } {
+            Some(prompt) => unsafe { CStr::from_ptr(prompt) }.to_owned(),
+            None => state
+                .items
+                .get(&ItemType::UserPrompt.into())
+                .cloned()
+                .unwrap_or_else(|| c"login: ".to_owned()),
+        };
+        match converse(state, PAM_PROMPT_ECHO_ON, &prompt) {
+            Some(name) => state.items.insert(ItemType::User.into(), name),
+            None => return PamResultCode::PAM_CONV_ERR,
+        };
+    }
+    *user = state.items[&ItemType::User.into()].as_ptr();
+    PamResultCode::PAM_SUCCESS
+}
+
+/// Returns the token if it has already been set.  The mock never prompts.
+#[no_mangle]
+extern "C" fn pam_get_authtok(
+    pamh: *const PamHandle,
+    item_type: PamItemType,
+    data: &mut *const c_char,
+    _prompt: *const c_char,
+) -> PamResultCode {
+    let state = state(pamh);
+    let authtok_type = state.items.get(&ItemType::AuthTokType.into()).cloned();
+    AUTHTOK_TYPES.with(|types| types.borrow_mut().push(authtok_type));
+    match state.items.get(&item_type) {
+        Some(token) => {
+            *data = token.as_ptr();
+            PamResultCode::PAM_SUCCESS
+        }
+        None => PamResultCode::PAM_CONV_ERR,
+    }
+}
+
+/// Like libpam, returns the token if it has already been set, and otherwise
+/// prompts for a new one with `prompt` or `New password: `.
+#[no_mangle]
+extern "C" fn pam_get_authtok_noverify(
+    pamh: *const PamHandle,
+    authtok: &mut *const c_char,
+    prompt: *const c_char,
+) -> PamResultCode {
+    let state = state(pamh);
+    if !state.items.contains_key(&ItemType::AuthTok.into()) {
+        let prompt = match unsafe { prompt.as_ref() } {
+            Some(prompt) => unsafe { CStr::from_ptr(prompt) },
+            None => c"New password: ",
+        };
+        match converse(state, PAM_PROMPT_ECHO_OFF, prompt) {
+            Some(token) => state.items.insert(ItemType::AuthTok.into(), token),
+            None => return PamResultCode::PAM_CONV_ERR,
+        };
+    }
+    *authtok = state.items[&ItemType::AuthTok.into()].as_ptr();
+    PamResultCode::PAM_SUCCESS
+}
+
+/// Like libpam, prompts for the token again with `prompt` or
+/// `Retype new password: `, and if it doesn't match `*authtok`,
+/// unsets the token and fails with `PAM_TRY_AGAIN`.
+#[no_mangle]
+extern "C" fn pam_get_authtok_verify(
+    pamh: *const PamHandle,
+    authtok: &mut *const c_char,
+    prompt: *const c_char,
+) -> PamResultCode {
+    let state = state(pamh);
+    let prompt = match unsafe { prompt.as_ref() } {
+        Some(prompt) => unsafe { CStr::from_ptr(prompt) },
+        None => c"Retype new password: ",
+    };
+    let Some(again) = converse(state, PAM_PROMPT_ECHO_OFF, prompt) else {
+        return PamResultCode::PAM_CONV_ERR;
+    };
+    if authtok.is_null() || unsafe { CStr::from_ptr(*authtok) } != again.as_c_str() {
+        state.items.remove(&ItemType::AuthTok.into());
+        converse(state, PAM_ERROR_MSG, c"Sorry, passwords do not match.");
+        return PamResultCode::PAM_TRY_AGAIN;
+    }
+    state.items.insert(ItemType::AuthTok.into(), again);
+    *authtok = state.items[&ItemType::AuthTok.into()].as_ptr();
+    PamResultCode::PAM_SUCCESS
+}
+
+/// The mock user database has just one user, `alice`.
+#[cfg(target_os = "linux")]
+#[no_mangle]
+extern "C" fn pam_modutil_getpwnam(
+    pamh: *const PamHandle,
+    user: *const c_char,
+) -> *mut libc::passwd {
+    if unsafe { CStr::from_ptr(user) } != c"alice" {
+        return ptr::null_mut();
+    }
+    let entry = state(pamh).passwd.insert(Box::new(libc::passwd {
+        pw_name: c"alice".as_ptr().cast_mut(),
+        pw_passwd: c"x".as_ptr().cast_mut(),
+        pw_uid: 1000,
+        pw_gid: 100,
+        pw_gecos: c"Alice Liddell,,,".as_ptr().cast_mut(),
+        pw_dir: c"/home/alice".as_ptr().cast_mut(),
+        pw_shell: c"/bin/sh".as_ptr().cast_mut(),
+    }));
+    &mut **entry
+}
+
+/// The `is_dropped` states of libpam's `pam_modutil_privs`.
+#[cfg(target_os = "linux")]
+const PRIV_MAGIC: c_int = 0x1004000a;
+#[cfg(target_os = "linux")]
+const PRIV_MAGIC_DONOTHING: c_int = 0xdead000a_u32 as c_int;
+
+/// Changes only the filesystem IDs, which are per-thread, and leaves the
+/// supplementary groups alone, since they would change for every test.
+#[cfg(target_os = "linux")]
+#[no_mangle]
+extern "C" fn pam_modutil_drop_priv(
+    _pamh: *const PamHandle,
+    p: *mut crate::modutil::ModutilPrivs,
+    pw: *const libc::passwd,
+) -> c_int {
+    let (p, pw) = unsafe { (&mut *p, &*pw) };
+    if p.is_dropped != 0 {
+        return -1;
+    }
+    if unsafe { libc::geteuid() } != 0 || pw.pw_uid == 0 {
+        p.is_dropped = PRIV_MAGIC_DONOTHING;
+        return 0;
+    }
+    p.old_gid = unsafe { libc::setfsgid(pw.pw_gid) } as libc::gid_t;
+    p.old_uid = unsafe { libc::setfsuid(pw.pw_uid) } as libc::uid_t;
+    p.is_dropped = PRIV_MAGIC;
+    0
+}
+
+#[cfg(target_os = "linux")]
+#[no_mangle]
+extern "C" fn pam_modutil_regain_priv(
+    _pamh: *const PamHandle,
+    p: *mut crate::modutil::ModutilPrivs,
+) -> c_int {
+    let p = unsafe { &mut *p };
+    match p.is_dropped {
+        PRIV_MAGIC => {}
+        PRIV_MAGIC_DONOTHING => {
+            p.is_dropped = 0;
+            return 0;
+        }
+        _ => {
+            let msg = "pam_modutil_regain_priv: called with invalid state".to_owned();
+            LOGGED.with(|logged| logged.borrow_mut().push((libc::LOG_CRIT, msg)));
+            return -1;
+        }
+    }
+    unsafe {
+        libc::setfsuid(p.old_uid);
+        libc::setfsgid(p.old_gid);
+    }
+    p.is_dropped = 0;
+    0
+}
+
+#[no_mangle]
+extern "C" fn pam_getenvlist(pamh: *const PamHandle) -> *mut *mut c_char {
+    let env = &state(pamh).env;
+    unsafe {
+        let list =
+            libc::calloc(env.len() + 1, std::mem::size_of::<*mut c_char>()).cast::<*mut c_char>();
+        for (i, entry) in env.iter().enumerate() {
+            *list.add(i) = libc::strdup(entry.as_ptr());
+        }
+        list
+    }
+}
+
+#[no_mangle]
+extern "C" fn pam_putenv(pamh: *mut PamHandle, name_value: *const c_char) -> PamResultCode {
+    let entry = unsafe { CStr::from_ptr(name_value) };
+    let bytes = entry.to_bytes();
+    let name = bytes.split(|&b| b == b'=').next().unwrap_or_default();
+    if name.is_empty() {
+        return PamResultCode::PAM_BAD_ITEM;
+    }
+    let env = &mut state(pamh).env;
+    let existing = env
+        .iter()
+        .position(|e| e.to_bytes().split(|&b| b == b'=').next() == Some(name));
+    match (existing, bytes.len() > name.len()) {
+        (Some(i), true) => env[i] = entry.to_owned(),
+        (None, true) => env.push(entry.to_owned()),
+        (Some(i), false) => drop(env.remove(i)),
+        (None, false) => return PamResultCode::PAM_BAD_ITEM,
+    }
+    PamResultCode::PAM_SUCCESS
+}
+
+/// The messages from Linux-PAM's `pam_strerror`.
+#[no_mangle]
+extern "C" fn pam_strerror(_: *const PamHandle, errnum: c_int) -> *const c_char {
+    let msg = match errnum {
+        0 => c"Success",
+        1 => c"Failed to load module",
+        2 => c"Symbol not found",
+        3 => c"Error in service module",
+        4 => c"System error",
+        5 => c"Memory buffer error",
+        6 => c"Permission denied",
+        7 => c"Authentication failure",
+        8 => c"Insufficient credentials to access authentication data",
+        9 => c"Authentication service cannot retrieve authentication info",
+        10 => c"User not known to the underlying authentication module",
+        11 => c"Have exhausted maximum number of retries for service",
+        12 => c"Authentication token is no longer valid; new one required",
+        13 => c"User account has expired",
+        14 => c"Cannot make/remove an entry for the specified session",
+        15 => c"Authentication service cannot retrieve user credentials",
+        16 => c"User credentials expired",
+        17 => c"Failure setting user credentials",
+        18 => c"No module specific data is present",
+        19 => c"Conversation error",
+        20 => c"Authentication token manipulation error",
+        21 => c"Authentication information cannot be recovered",
+        22 => c"Authentication token lock busy",
+        23 => c"Authentication token aging disabled",
+        24 => c"Failed preliminary check by password service",
+        25 => c"The return value should be ignored by PAM dispatch",
+        26 => c"Critical error - immediate abort",
+        27 => c"Authentication token expired",
+        28 => c"Module is unknown",
+        29 => c"Bad item passed to pam_*_item()",
+        30 => c"Conversation is waiting for event",
+        31 => c"Application needs to call libpam again",
+        _ => c"Unknown PAM error",
+    };
+    msg.as_ptr()
+}
+
+/// Only supports `"%s"` as the format.  The real `pam_syslog` is variadic,
+/// which stable Rust can't define, so this takes the single string argument
+/// instead, and the crate declares it this way under `cfg(test)`.
+#[no_mangle]
+extern "C" fn pam_syslog(
+    _: *const PamHandle,
+    priority: c_int,
+    fmt: *const c_char,
+    msg: *const c_char,
+) {
+    assert_eq!(c"%s", unsafe { CStr::from_ptr(fmt) });
+    let msg = unsafe { CStr::from_ptr(msg) }
+        .to_string_lossy()
+        .into_owned();
+    LOGGED.with(|logged| logged.borrow_mut().push((priority, msg)));
+}
+
+#[no_mangle]
+extern "C" fn pam_start(
+    service_name: *const c_char,
+    user: *const c_char,
+    pam_conversation: *const Inner,
+    pamh: &mut *mut PamHandle,
+) -> PamResultCode {
+    let state = State {
+        module: MODULE.with(Cell::get),
+        ..State::default()
+    };
+    let handle = Box::into_raw(Box::new(state)).cast::<PamHandle>();
+    for (item_type, item) in [
+        (ItemType::Service, service_name.cast()),
+        (ItemType::User, user.cast()),
+        (ItemType::Conv, pam_conversation.cast()),
+    ] {
+        let ret = pam_set_item(handle, item_type.into(), item);
+        if ret != PamResultCode::PAM_SUCCESS {
+            return ret;
+        }
+    }
+    *pamh = handle;
+    PamResultCode::PAM_SUCCESS
+}
+
+#[no_mangle]
+extern "C" fn pam_end(pamh: *mut PamHandle, pam_status: c_int) -> PamResultCode {
+    let mut state = unsafe { Box::from_raw(pamh.cast::<State>()) };
+    for (_, (data, cleanup)) in state.data.drain() {
+        cleanup(pamh, data, pam_status);
+    }
+    ENDED.with(|ended| ended.borrow_mut().push(pam_status));
+    PamResultCode::PAM_SUCCESS
+}
+
+#[no_mangle]
+extern "C" fn pam_authenticate(pamh: *mut PamHandle, flags: PamFlag) -> PamResultCode {
+    run_hook(pamh, flags, |m| m.authenticate)
+}
+
+#[no_mangle]
+extern "C" fn pam_acct_mgmt(pamh: *mut PamHandle, flags: PamFlag) -> PamResultCode {
+    run_hook(pamh, flags, |m| m.acct_mgmt)
+}
+
+/// Like libpam, runs the preliminary check, then the update if it succeeds.
+#[no_mangle]
+extern "C" fn pam_chauthtok(pamh: *mut PamHandle, flags: PamFlag) -> PamResultCode {
+    if flags.contains(PAM_PRELIM_CHECK) || flags.contains(PAM_UPDATE_AUTHTOK) {
+        return PamResultCode::PAM_SYMBOL_ERR;
+    }
+    match run_hook(pamh, flags | PAM_PRELIM_CHECK, |m| m.chauthtok) {
+        PamResultCode::PAM_SUCCESS => run_hook(pamh, flags | PAM_UPDATE_AUTHTOK, |m| m.chauthtok),
+        other => other,
+    }
+}
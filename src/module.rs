@@ -1,76 +1,247 @@
 //! Functions for use in pam modules.
 
-use libc::c_char;
-use std::ffi::{CStr, CString};
+use libc::{c_char, c_int};
+use std::ffi::{CStr, CString, OsString};
+use std::marker::PhantomData;
+use std::os::unix::ffi::OsStringExt;
+use std::time::Duration;
 
-use crate::constants::{PamFlag, PamResultCode};
-use crate::items::ItemType;
+use crate::compat;
+use crate::constants::{PamFlag, PamItemType, PamResultCode, PAM_DATA_REPLACE, PAM_DATA_SILENT};
+use crate::env::EnvList;
+use crate::items::{
+    AuthTokType, AuthTokTypeScope, FromRaw, IntoRaw, ItemType, RhostValue, SecretItem, TerminalInfo,
+};
+use crate::username::NamePolicy;
+use crate::util::{zero_bytes, Secret};
 
 /// Opaque type, used as a pointer when making pam API calls.
 ///
 /// A module is invoked via an external function such as `pam_sm_authenticate`.
 /// Such a call provides a pam handle pointer.  The same pointer should be given
 /// as an argument when making API calls.
+///
+/// The handle is really a pointer to state owned by libpam, and is shared with
+/// the application and every other module in the stack.  Within a module,
+/// methods that change that state (`set_data`, `set_item`) take
+/// `&mut self`, while methods that only read it take `&self`.  This means that
+/// a reference returned by a getter cannot outlive a later change to the handle,
+/// which could otherwise free the memory it points to.
+///
+/// # Borrowing
+///
+/// Only these methods need `&mut self`: [`set_item`](Self::set_item),
+/// [`set_user`](Self::set_user), [`set_user_prompt`](Self::set_user_prompt),
+/// [`set_authtok`](Self::set_authtok),
+/// [`get_authtok_typed`](Self::get_authtok_typed), the `set_data` family,
+/// [`update_data`](Self::update_data), [`putenv`](Self::putenv),
+/// [`set_env`](Self::set_env), [`unset_env`](Self::unset_env),
+/// [`putenv_all`](Self::putenv_all), and the methods built on them, like
+/// [`get_user_with_default`](Self::get_user_with_default).  Everything
+/// else, including prompting, logging and the helpers in other modules
+/// (e.g., [`Conv::from_borrowed`](crate::conv::Conv::from_borrowed),
+/// [`logging::log`](crate::logging::log)), takes `&PamHandle`.
+///
+/// Helpers of your own should do the same, and take `&PamHandle` unless
+/// they change the handle.  A hook's `&mut PamHandle` is then passed to
+/// each of them as is, reborrowed just for that call:
+///
+/// ```no_run
+/// # use pam::constants::{PamFlag, PamResultCode};
+/// # use pam::module::{PamHandle, PamResult};
+/// fn describe(pamh: &PamHandle) -> PamResult<String> {
+///     Ok(format!("{:?} on {:?}", pamh.service()?, pamh.tty()?))
+/// }
+///
+/// fn sm_open_session(pamh: &mut PamHandle, flags: PamFlag) -> PamResult<()> {
+///     let session = describe(pamh)?;
+///     let user = pamh.get_user(None)?;
+///     pamh.syslog(libc::LOG_INFO, &format!("{user} opened {session}"));
+///     pamh.set_env("SESSION_OWNER", &user)
+/// }
+/// ```
+///
+/// What does get in the way is holding a reference from a getter (like
+/// [`get_item`](Self::get_item) or [`get_data`](Self::get_data)) across
+/// a change.  The getters that return owned values, like
+/// [`get_user`](Self::get_user) and
+/// [`get_data_cloned`](Self::get_data_cloned), avoid that.
+///
+/// # Thread safety
+///
+/// Linux-PAM does no locking of its own, so a handle must never be used
+/// from two threads at the same time.  Nor can it be handed to another
+/// thread: the data stored with [`set_data`](Self::set_data) need not be
+/// `Send`, and could then be cloned or dropped from the wrong thread.  So
+/// `PamHandle` is neither `Send` nor `Sync`, and a hook uses its handle
+/// only on the thread that called it:
+///
+/// ```compile_fail
+/// fn assert_send<T: Send + ?Sized>() {}
+/// assert_send::<&mut pam::module::PamHandle>();
+/// ```
+///
+/// ```compile_fail
+/// fn assert_sync<T: Sync>() {}
+/// assert_sync::<pam::module::PamHandle>();
+/// ```
 #[repr(C)]
 pub struct PamHandle {
     _data: [u8; 0],
+    _not_send: PhantomData<*const ()>,
 }
 
-#[link(name = "pam")]
+#[cfg_attr(not(test), link(name = "pam"))]
 extern "C" {
     fn pam_get_data(
         pamh: *const PamHandle,
         module_data_name: *const c_char,
         data: &mut *const libc::c_void,
-    ) -> PamResultCode;
+    ) -> c_int;
 
     fn pam_set_data(
-        pamh: *const PamHandle,
+        pamh: *mut PamHandle,
         module_data_name: *const c_char,
         data: *mut libc::c_void,
         cleanup: extern "C" fn(
             pamh: *const PamHandle,
             data: *mut libc::c_void,
-            error_status: PamResultCode,
+            error_status: c_int,
         ),
-    ) -> PamResultCode;
+    ) -> c_int;
 
     fn pam_get_item(
         pamh: *const PamHandle,
-        item_type: ItemType,
+        item_type: PamItemType,
         item: &mut *const libc::c_void,
-    ) -> PamResultCode;
+    ) -> c_int;
 
     fn pam_set_item(
         pamh: *mut PamHandle,
-        item_type: ItemType,
+        item_type: PamItemType,
         item: *const libc::c_void,
-    ) -> PamResultCode;
+    ) -> c_int;
 
     fn pam_get_user(
         pamh: *const PamHandle,
-        user: &*mut c_char,
+        user: &mut *const c_char,
         prompt: *const c_char,
-    ) -> PamResultCode;
+    ) -> c_int;
 
-    fn pam_get_authtok(
-        pamh: *const PamHandle,
-        item_type: ItemType,
-        data: &*mut c_char,
-        prompt: *const c_char,
-    ) -> PamResultCode;
+    fn pam_getenvlist(pamh: *const PamHandle) -> *mut *mut c_char;
+
+    fn pam_putenv(pamh: *mut PamHandle, name_value: *const c_char) -> c_int;
 
+    fn pam_strerror(pamh: *const PamHandle, errnum: c_int) -> *const c_char;
 }
 
-pub extern "C" fn cleanup<T>(_: *const PamHandle, c_data: *mut libc::c_void, _: PamResultCode) {
+pub extern "C" fn cleanup<T>(_: *const PamHandle, c_data: *mut libc::c_void, _: c_int) {
     unsafe {
         let _data: Box<T> = Box::from_raw(c_data.cast::<T>());
     }
 }
 
+/// Like [`cleanup`], but first calls the callback stored with the data.
+extern "C" fn cleanup_with<T, F: FnOnce(&T, PamResultCode, bool)>(
+    _: *const PamHandle,
+    c_data: *mut libc::c_void,
+    error_status: c_int,
+) {
+    let (data, callback) = *unsafe { Box::from_raw(c_data.cast::<(T, F)>()) };
+    let silent = error_status & PAM_DATA_SILENT != 0;
+    callback(&data, status_code(error_status), silent);
+}
+
+/// Frees a buffer stored by [`PamHandle::set_data_serde`].
+///
+/// This doesn't depend on the type that was serialized, so a buffer can be
+/// cleaned up by a different build of the module than the one that stored it.
+#[cfg(feature = "serde")]
+extern "C" fn cleanup_buffer(_: *const PamHandle, c_data: *mut libc::c_void, _: c_int) {
+    unsafe { libc::free(c_data) };
+}
+
+/// Extracts the result code from the `error_status` passed to a cleanup
+/// function, which may have flags like `PAM_DATA_REPLACE` ORed into it.
+fn status_code(error_status: c_int) -> PamResultCode {
+    PamResultCode::try_from(error_status & !(PAM_DATA_REPLACE | PAM_DATA_SILENT))
+        .unwrap_or(PamResultCode::PAM_SYSTEM_ERR)
+}
+
 pub type PamResult<T> = Result<T, PamResultCode>;
 
+/// Extra combinators for [`PamResult`].
+pub trait PamResultExt<T> {
+    /// Replaces any error with `PAM_IGNORE`.
+    ///
+    /// This is useful for optional modules, which should step aside rather
+    /// than fail the stack when something goes wrong:
+    ///
+    /// ```no_run
+    /// # use pam::module::{PamHandle, PamResultExt};
+    /// # use pam::constants::PamResultCode;
+    /// # fn hook(pamh: &mut PamHandle) -> PamResultCode {
+    /// let user = pam::pam_try!(pamh.get_user(None).or_ignore());
+    /// # PamResultCode::PAM_SUCCESS
+    /// # }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_IGNORE` if `self` is an error.
+    fn or_ignore(self) -> PamResult<T>;
+}
+
+impl<T> PamResultExt<T> for PamResult<T> {
+    fn or_ignore(self) -> PamResult<T> {
+        self.map_err(|_| PamResultCode::PAM_IGNORE)
+    }
+}
+
 impl PamHandle {
+    /// Returns the raw handle pointer, for calling libpam functions that
+    /// this crate doesn't wrap.
+    ///
+    /// The pointer is only valid for as long as the borrow it came from.
+    /// It may only be passed to libpam functions that don't change the state
+    /// of the handle; for anything that does, use [`PamHandle::as_mut_ptr`].
+    ///
+    /// ```no_run
+    /// use pam::module::PamHandle;
+    /// use std::ffi::{c_char, c_int, c_void, CStr};
+    ///
+    /// extern "C" {
+    ///     fn pam_get_item(pamh: *const PamHandle, item_type: c_int, item: *mut *const c_void) -> c_int;
+    /// }
+    ///
+    /// /// Gets the `PAM_RHOST` item without going through this crate.
+    /// fn rhost(pamh: &PamHandle) -> Option<String> {
+    ///     let mut item = std::ptr::null();
+    ///     // SAFETY: libpam only reads through the handle, and the item it
+    ///     // returns is a C string (if set).
+    ///     unsafe {
+    ///         if pam_get_item(pamh.as_ptr(), 4, &mut item) != 0 || item.is_null() {
+    ///             return None;
+    ///         }
+    ///         Some(CStr::from_ptr(item.cast::<c_char>()).to_string_lossy().into_owned())
+    ///     }
+    /// }
+    /// ```
+    pub fn as_ptr(&self) -> *const PamHandle {
+        self
+    }
+
+    /// Returns the raw handle pointer, for calling libpam functions that
+    /// this crate doesn't wrap, including ones that change the handle.
+    ///
+    /// The pointer is only valid for as long as the borrow it came from,
+    /// and while it is in use, no references previously returned by this
+    /// handle (for instance, from [`PamHandle::get_item`]) may be used,
+    /// since libpam may have freed the memory they point to.
+    pub fn as_mut_ptr(&mut self) -> *mut PamHandle {
+        self
+    }
+
     /// Gets some value, identified by `key`, that has been set by the module
     /// previously.
     ///
@@ -79,60 +250,299 @@ impl PamHandle {
     ///
     /// # Errors
     ///
-    /// Returns an error if the underlying PAM function call fails.
+    /// Returns `PAM_NO_MODULE_DATA` if nothing (or null) is stored under
+    /// `key`, an error if the underlying PAM function call fails,
+    /// or `PAM_CONV_ERR` if the key contains a nul byte.
     ///
     /// # Safety
     ///
     /// The data stored under the provided key must be of type `T` otherwise the
     /// behaviour of this function is undefined.
     pub unsafe fn get_data<T>(&self, key: &str) -> PamResult<&T> {
-        let c_key = CString::new(key).unwrap();
+        let c_key = to_c_string(key)?;
         let mut ptr: *const libc::c_void = std::ptr::null();
-        let res = pam_get_data(self, c_key.as_ptr(), &mut ptr);
-        if PamResultCode::PAM_SUCCESS == res && !ptr.is_null() {
-            let typed_ptr = ptr.cast::<T>();
-            let data: &T = &*typed_ptr;
-            Ok(data)
-        } else {
-            Err(res)
+        match PamResultCode::from_libpam(pam_get_data(self, c_key.as_ptr(), &mut ptr)) {
+            // Another module may have stored null, which is as good as nothing.
+            PamResultCode::PAM_SUCCESS if ptr.is_null() => Err(PamResultCode::PAM_NO_MODULE_DATA),
+            PamResultCode::PAM_SUCCESS => Ok(&*ptr.cast::<T>()),
+            err => Err(err),
+        }
+    }
+
+    /// Gets a copy of some value, identified by `key`, that has been set by the
+    /// module previously, or `None` if there is no value under `key`.
+    ///
+    /// Unlike the reference from [`get_data`](Self::get_data), the copy can be
+    /// kept while the handle is changed, for instance by `set_item` or `set_data`:
+    ///
+    /// ```no_run
+    /// # use pam::items::User;
+    /// # use pam::module::{PamHandle, PamResult};
+    /// fn restore_user(pamh: &mut PamHandle) -> PamResult<()> {
+    ///     if let Some(name) = unsafe { pamh.get_data_cloned::<std::ffi::CString>("last-user") }? {
+    ///         pamh.set_item(User(&name))?;
+    ///     }
+    ///     Ok(())
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails for any
+    /// reason but `PAM_NO_MODULE_DATA`, or `PAM_CONV_ERR` if the key contains
+    /// a nul byte.
+    ///
+    /// # Safety
+    ///
+    /// The data stored under the provided key must be of type `T` otherwise the
+    /// behaviour of this function is undefined.
+    pub unsafe fn get_data_cloned<T: Clone>(&self, key: &str) -> PamResult<Option<T>> {
+        match self.get_data::<T>(key) {
+            Ok(data) => Ok(Some(data.clone())),
+            Err(PamResultCode::PAM_NO_MODULE_DATA) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Modifies some value, identified by `key`, that has been set by the module
+    /// previously, and returns the result of `f`.
+    ///
+    /// This is the way to keep mutable state (e.g., a retry counter) across
+    /// calls into a module without resorting to a `RefCell`.  Because it takes
+    /// `&mut self`, no reference from `get_data` can be alive at the same time.
+    ///
+    /// ```no_run
+    /// # use pam::module::{PamHandle, PamResult};
+    /// fn count_attempt(pamh: &mut PamHandle) -> PamResult<u32> {
+    ///     if unsafe { pamh.get_data::<u32>("attempts") }.is_err() {
+    ///         pamh.set_data("attempts", Box::new(0u32))?;
+    ///     }
+    ///     unsafe { pamh.update_data("attempts", |n: &mut u32| { *n += 1; *n }) }
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_NO_MODULE_DATA` if nothing (or null) is stored under
+    /// `key`, an error if the underlying PAM function call fails,
+    /// or `PAM_CONV_ERR` if the key contains a nul byte.
+    ///
+    /// # Safety
+    ///
+    /// The data stored under the provided key must be of type `T` otherwise the
+    /// behaviour of this function is undefined.
+    pub unsafe fn update_data<T, R>(
+        &mut self,
+        key: &str,
+        f: impl FnOnce(&mut T) -> R,
+    ) -> PamResult<R> {
+        let c_key = to_c_string(key)?;
+        let mut ptr: *const libc::c_void = std::ptr::null();
+        match PamResultCode::from_libpam(pam_get_data(self, c_key.as_ptr(), &mut ptr)) {
+            PamResultCode::PAM_SUCCESS if ptr.is_null() => Err(PamResultCode::PAM_NO_MODULE_DATA),
+            PamResultCode::PAM_SUCCESS => Ok(f(&mut *ptr.cast_mut().cast::<T>())),
+            err => Err(err),
         }
     }
 
     /// Stores a value that can be retrieved later with `get_data`.  The value lives
     /// as long as the current pam cycle.
     ///
+    /// If a value is already stored under `key`, libpam calls its cleanup
+    /// function before storing the new one, so the old value is dropped then.
+    /// This means a hook can update its state on every call without leaking,
+    /// but also that any reference to the old value must be gone by then,
+    /// which the `&mut self` receiver ensures.
+    ///
     /// See the [`pam_set_data` manual page](
     /// https://www.man7.org/linux/man-pages/man3/pam_set_data.3.html).
     ///
     /// # Errors
     ///
-    /// Returns an error if the underlying PAM function call fails.
-    pub fn set_data<T>(&self, key: &str, data: Box<T>) -> PamResult<()> {
-        let c_key = CString::new(key).unwrap();
-        let res = unsafe {
-            pam_set_data(
-                self,
-                c_key.as_ptr(),
-                Box::into_raw(data).cast::<libc::c_void>(),
-                cleanup::<T>,
-            )
-        };
-        to_result(res)
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_CONV_ERR` if the key contains a nul byte.  Either way, the
+    /// value is dropped.
+    ///
+    /// # Examples
+    ///
+    /// Because setting data and setting items both take `&mut self`, a hook
+    /// can freely do both in sequence:
+    ///
+    /// ```no_run
+    /// use pam::items::User;
+    /// use pam::module::{PamHandle, PamResult};
+    /// use std::ffi::CStr;
+    ///
+    /// fn remember_user(pamh: &mut PamHandle, name: &CStr) -> PamResult<()> {
+    ///     pamh.set_data("last-user", Box::new(name.to_owned()))?;
+    ///     pamh.set_item(User(name))
+    /// }
+    /// ```
+    ///
+    /// The value is dropped long after this call, by libpam, so it can't
+    /// borrow anything:
+    ///
+    /// ```compile_fail,E0597
+    /// # use pam::module::{PamHandle, PamResult};
+    /// fn remember_name(pamh: &mut PamHandle) -> PamResult<()> {
+    ///     let name = String::from("alice");
+    ///     pamh.set_data("name", Box::new(name.as_str()))
+    /// }
+    /// ```
+    pub fn set_data<T: 'static>(&mut self, key: &str, data: Box<T>) -> PamResult<()> {
+        let c_key = to_c_string(key)?;
+        let data = Box::into_raw(data);
+        let res = unsafe { pam_set_data(self, c_key.as_ptr(), data.cast(), cleanup::<T>) };
+        let res = PamResultCode::from_libpam(res);
+        if res != PamResultCode::PAM_SUCCESS {
+            // libpam didn't take it, so it is still ours to drop.
+            drop(unsafe { Box::from_raw(data) });
+        }
+        res.into_result()
+    }
+
+    /// Stores a value like [`set_data`](Self::set_data), along with a function
+    /// to call when the value is cleaned up.
+    ///
+    /// The function receives the value and the status that the PAM transaction
+    /// ended with (e.g., so that a cache entry is only persisted on success).
+    /// If the value is replaced by another call to `set_data` with the same key,
+    /// the function receives the status `PAM_SUCCESS`.  It must not panic.
+    /// Like the value, it can't borrow anything, since it outlives this call.
+    ///
+    /// The function also receives whether `PAM_DATA_SILENT` was set.  An
+    /// application that forks after opening a session may call `pam_end`
+    /// in both processes, setting `PAM_DATA_SILENT` in the child, where the
+    /// function should only free what is its own, and leave alone anything
+    /// shared with the parent, like a network connection or a lock file.
+    ///
+    /// ```no_run
+    /// # use pam::module::{PamHandle, PamResult};
+    /// # use std::net::{Shutdown, TcpStream};
+    /// fn keep_connection(pamh: &mut PamHandle, conn: TcpStream) -> PamResult<()> {
+    ///     pamh.set_data_with_cleanup("conn", conn, |conn, _, silent| {
+    ///         if !silent {
+    ///             let _ = conn.shutdown(Shutdown::Both);
+    ///         }
+    ///     })
+    /// }
+    /// ```
+    ///
+    /// See the [`pam_set_data` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_set_data.3.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_CONV_ERR` if the key contains a nul byte.  Either way, the
+    /// value is dropped.
+    pub fn set_data_with_cleanup<T, F>(
+        &mut self,
+        key: &str,
+        data: T,
+        on_cleanup: F,
+    ) -> PamResult<()>
+    where
+        T: 'static,
+        F: FnOnce(&T, PamResultCode, bool) + 'static,
+    {
+        let c_key = to_c_string(key)?;
+        let data = Box::into_raw(Box::new((data, on_cleanup)));
+        let res = unsafe { pam_set_data(self, c_key.as_ptr(), data.cast(), cleanup_with::<T, F>) };
+        let res = PamResultCode::from_libpam(res);
+        if res != PamResultCode::PAM_SUCCESS {
+            // The value was never stored, so it is dropped without cleanup.
+            drop(unsafe { Box::from_raw(data) });
+        }
+        res.into_result()
+    }
+
+    /// Stores a value that can be retrieved later with
+    /// [`get_data_serde`](Self::get_data_serde), as serialized bytes.
+    ///
+    /// Unlike [`set_data`](Self::set_data), what is stored is a plain buffer
+    /// (the length as a native-endian `u64`, then the value as JSON), so it
+    /// can be read back as any type that deserializes from the same JSON,
+    /// even by a different version of the module.  This costs a
+    /// serialization here and a deserialization on every read, and the
+    /// value is copied rather than borrowed, so it is best kept for small
+    /// values that must outlive a change to their type.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// `PAM_CONV_ERR` if the key contains a nul byte, `PAM_SYSTEM_ERR` if the
+    /// value cannot be serialized, or `PAM_BUF_ERR` if memory runs out.
+    #[cfg(feature = "serde")]
+    pub fn set_data_serde<T: serde::Serialize>(&mut self, key: &str, data: &T) -> PamResult<()> {
+        let c_key = to_c_string(key)?;
+        let bytes = serde_json::to_vec(data).map_err(|_| PamResultCode::PAM_SYSTEM_ERR)?;
+        let prefix = (bytes.len() as u64).to_ne_bytes();
+        let buffer = unsafe { libc::malloc(prefix.len() + bytes.len()) }.cast::<u8>();
+        if buffer.is_null() {
+            return Err(PamResultCode::PAM_BUF_ERR);
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(prefix.as_ptr(), buffer, prefix.len());
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), buffer.add(prefix.len()), bytes.len());
+        }
+        let res = PamResultCode::from_libpam(unsafe {
+            pam_set_data(self, c_key.as_ptr(), buffer.cast(), cleanup_buffer)
+        });
+        if res != PamResultCode::PAM_SUCCESS {
+            unsafe { libc::free(buffer.cast()) };
+        }
+        res.into_result()
+    }
+
+    /// Gets a value stored with [`set_data_serde`](Self::set_data_serde).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// `PAM_CONV_ERR` if the key contains a nul byte, or `PAM_SYSTEM_ERR`
+    /// if the stored value does not deserialize as a `T`.
+    ///
+    /// # Safety
+    ///
+    /// The data stored under the provided key must have been stored with
+    /// `set_data_serde`, by any version of this crate and with any type,
+    /// otherwise the behaviour of this function is undefined.
+    #[cfg(feature = "serde")]
+    pub unsafe fn get_data_serde<T: serde::de::DeserializeOwned>(&self, key: &str) -> PamResult<T> {
+        let c_key = to_c_string(key)?;
+        let mut ptr: *const libc::c_void = std::ptr::null();
+        PamResultCode::from_libpam(pam_get_data(self, c_key.as_ptr(), &mut ptr)).into_result()?;
+        if ptr.is_null() {
+            return Err(PamResultCode::PAM_NO_MODULE_DATA);
+        }
+        let mut prefix = [0; std::mem::size_of::<u64>()];
+        std::ptr::copy_nonoverlapping(ptr.cast::<u8>(), prefix.as_mut_ptr(), prefix.len());
+        let len = usize::try_from(u64::from_ne_bytes(prefix))
+            .map_err(|_| PamResultCode::PAM_SYSTEM_ERR)?;
+        let bytes = std::slice::from_raw_parts(ptr.cast::<u8>().add(prefix.len()), len);
+        serde_json::from_slice(bytes).map_err(|_| PamResultCode::PAM_SYSTEM_ERR)
     }
 
     /// Retrieves a value that has been set, possibly by the pam client.  This is
     /// particularly useful for getting a `PamConv` reference.
     ///
+    /// Returns `Ok(None)` if the call succeeded but the item has never been set
+    /// (or was set to null).  See [`get_item_required`](Self::get_item_required)
+    /// if an unset item should be treated as an error.
+    ///
     /// See the [`pam_get_item` manual page](
     /// https://www.man7.org/linux/man-pages/man3/pam_get_item.3.html).
     ///
     /// # Errors
     ///
-    /// Returns an error if the underlying PAM function call fails.
-    pub fn get_item<T: crate::items::Item>(&self) -> PamResult<Option<T>> {
+    /// Returns an error if the underlying PAM function call fails, for instance
+    /// `PAM_BAD_ITEM` if the item type is unknown or may not be read by
+    /// the caller.
+    pub fn get_item<'a, T: FromRaw<'a>>(&'a self) -> PamResult<Option<T>> {
         let mut ptr: *const libc::c_void = std::ptr::null();
         let (res, item) = unsafe {
-            let r = pam_get_item(self, T::type_id(), &mut ptr);
+            let r = PamResultCode::from_libpam(pam_get_item(self, T::type_id(), &mut ptr));
             let typed_ptr = ptr.cast::<T::Raw>();
             let t = if typed_ptr.is_null() {
                 None
@@ -147,10 +557,139 @@ impl PamHandle {
         }
     }
 
+    /// Retrieves a secret item, like `get_item_secret::<AuthTok>()`, as a
+    /// [`Secret`] rather than a borrowed string.
+    ///
+    /// See [`SecretItem`](crate::items::SecretItem) for which items are
+    /// secret.  Returns `Ok(None)` if the item is not set.
+    ///
+    /// See the [`pam_get_item` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_get_item.3.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_BUF_ERR` if the token is not valid UTF-8.
+    pub fn get_item_secret<T: SecretItem>(&self) -> PamResult<Option<Secret>> {
+        let mut ptr: *const libc::c_void = std::ptr::null();
+        PamResultCode::from_libpam(unsafe { pam_get_item(self, T::type_id(), &mut ptr) })
+            .into_result()?;
+        if ptr.is_null() {
+            Ok(None)
+        } else {
+            unsafe { T::copy_secret(ptr.cast()) }.map(Some)
+        }
+    }
+
+    /// Retrieves a value like [`get_item`](Self::get_item), but treats an
+    /// unset item as an error.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_NO_MODULE_DATA` if the item is not set.
+    pub fn get_item_required<'a, T: FromRaw<'a>>(&'a self) -> PamResult<T> {
+        self.get_item()?.ok_or(PamResultCode::PAM_NO_MODULE_DATA)
+    }
+
+    /// True if the application has installed a conversation function,
+    /// so that the module can send messages and prompt the user.
+    ///
+    /// Applications that aren't interactive (like `cron`) might not have one,
+    /// or might leave its callback null.  A module can check this before
+    /// prompting, to either fail cleanly with `PAM_CONV_ERR` or carry on
+    /// without asking the user anything.  (Sending a message through a
+    /// [`Conv`](crate::conv::Conv) without a callback fails with
+    /// `PAM_CONV_ERR` too, but the module may already have done work by then.)
+    ///
+    /// This says nothing about whether the conversation will work: the
+    /// application can still fail any message it is sent.
+    pub fn conv_available(&self) -> bool {
+        matches!(self.get_item::<crate::conv::Conv>(), Ok(Some(conv)) if conv.has_callback())
+    }
+
+    /// Gets the service name (`PAM_SERVICE`), which the application passed
+    /// to `pam_start`, and which names its configuration in `/etc/pam.d`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_BUF_ERR` if the name is not valid UTF-8.
+    pub fn service(&self) -> PamResult<Option<String>> {
+        self.get_item_string(ItemType::Service)
+    }
+
+    /// Gets the terminal name (`PAM_TTY`), e.g. `/dev/tty1` or `:0`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_BUF_ERR` if the name is not valid UTF-8.
+    pub fn tty(&self) -> PamResult<Option<String>> {
+        self.get_item_string(ItemType::Tty)
+    }
+
+    /// Gets the items describing the user's terminal and where they are
+    /// logging in from, all at once.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the underlying PAM function calls fail,
+    /// or `PAM_BUF_ERR` if any item is not valid UTF-8.
+    pub fn terminal_info(&self) -> PamResult<TerminalInfo> {
+        Ok(TerminalInfo {
+            tty: self.get_item_string(ItemType::Tty)?,
+            rhost: self.get_item_string(ItemType::RHost)?,
+            ruser: self.get_item_string(ItemType::RUser)?,
+            xdisplay: self.get_item_string(ItemType::XDisplay)?,
+        })
+    }
+
+    /// Gets the remote host (`PAM_RHOST`), parsed as an address if it is one.
+    ///
+    /// Use `get_item::<RHost>()` for the value exactly as the application
+    /// set it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_BUF_ERR` if the item is not valid UTF-8.
+    pub fn rhost_addr(&self) -> PamResult<Option<RhostValue>> {
+        Ok(self
+            .get_item_string(ItemType::RHost)?
+            .map(|rhost| RhostValue::parse(&rhost)))
+    }
+
+    /// Sets a string item by type.
+    pub(crate) fn set_string_item(&mut self, item_type: ItemType, value: &CStr) -> PamResult<()> {
+        match item_type {
+            ItemType::Conv | ItemType::FailDelay | ItemType::XAuthData => {
+                Err(PamResultCode::PAM_BAD_ITEM)
+            }
+            _ => {
+                let res = unsafe { pam_set_item(self, item_type.into(), value.as_ptr().cast()) };
+                PamResultCode::from_libpam(res).into_result()
+            }
+        }
+    }
+
+    /// Gets a copy of a string item, or `None` if it is not set.
+    fn get_item_string(&self, item_type: ItemType) -> PamResult<Option<String>> {
+        let mut ptr: *const libc::c_void = std::ptr::null();
+        PamResultCode::from_libpam(unsafe { pam_get_item(self, item_type.into(), &mut ptr) })
+            .into_result()?;
+        if ptr.is_null() {
+            Ok(None)
+        } else {
+            copy_pam_string(ptr.cast()).map(Some)
+        }
+    }
+
     /// Sets a value in the pam context. The value can be retrieved using
     /// `get_item`.
     ///
-    /// Note that all items are strings, except `PAM_CONV` and `PAM_FAIL_DELAY`.
+    /// Note that all items are strings, except `PAM_CONV`, `PAM_FAIL_DELAY`
+    /// and `PAM_XAUTHDATA`.
     ///
     /// See the [`pam_set_item` manual page](
     /// https://www.man7.org/linux/man-pages/man3/pam_set_item.3.html).
@@ -158,98 +697,634 @@ impl PamHandle {
     /// # Errors
     ///
     /// Returns an error if the underlying PAM function call fails.
+    pub fn set_item<T: IntoRaw>(&mut self, item: T) -> PamResult<()> {
+        let res = unsafe { pam_set_item(self, T::type_id(), item.as_raw().cast::<libc::c_void>()) };
+        PamResultCode::from_libpam(res).into_result()
+    }
+
+    /// Sets a value in the pam context.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// Panics if the provided item key contains a nul byte.
-    pub fn set_item_str<T: crate::items::Item>(&mut self, item: T) -> PamResult<()> {
-        let res =
-            unsafe { pam_set_item(self, T::type_id(), item.into_raw().cast::<libc::c_void>()) };
-        to_result(res)
+    /// Returns an error if the underlying PAM function call fails.
+    #[deprecated(note = "renamed to `set_item`, since not all items are strings")]
+    pub fn set_item_str<T: IntoRaw>(&mut self, item: T) -> PamResult<()> {
+        self.set_item(item)
+    }
+
+    /// Sets the name of the user (the `PAM_USER` item).
+    ///
+    /// This is for modules that map the name they were given to a canonical
+    /// one, for instance by stripping a Kerberos realm.  libpam copies the
+    /// name, so it need not outlive this call.
+    ///
+    /// Modules run in the order they appear in the configuration, and earlier
+    /// ones may already have looked the user up under the old name, so a
+    /// module that does this should come first in the stack.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_CONV_ERR` if the name contains a nul byte.
+    pub fn set_user(&mut self, name: &str) -> PamResult<()> {
+        let name = to_c_string(name)?;
+        self.set_item(crate::items::User(&name))
+    }
+
+    /// Sets the authentication token (the `PAM_AUTHTOK` item), and keeps
+    /// `token` until the token is set again or the transaction ends.
+    ///
+    /// Later calls into the module can read the token back with
+    /// [`authtok_secret`](Self::authtok_secret) without copying it again.
+    /// It is kept as module data under the key
+    /// <code>nonstick.authtok.<var>version</var></code>, where
+    /// <var>version</var> is the version of this crate.
+    /// When the token is replaced, by this or at `pam_end`, the `Secret`
+    /// kept for the old one is dropped, and so zeroed; so is the temporary
+    /// copy passed to libpam.  The copy libpam makes of the item is its own
+    /// responsibility: Linux-PAM overwrites it when the item is replaced.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_CONV_ERR` if the token contains a nul byte.
+    pub fn set_authtok(&mut self, token: Secret) -> PamResult<()> {
+        // Checked first, since the copy in a `NulError` wouldn't be zeroed.
+        if token.expose().contains('\0') {
+            return Err(PamResultCode::PAM_CONV_ERR);
+        }
+        let c_token = to_c_string(token.expose())?;
+        let result = self.set_item(crate::items::AuthTok(&c_token));
+        zero_bytes(&mut c_token.into_bytes_with_nul());
+        result?;
+        self.set_data(AUTHTOK_KEY, Box::new(token))
+    }
+
+    /// The token most recently set with [`set_authtok`](Self::set_authtok),
+    /// by any call into this module during this transaction.
+    ///
+    /// This may differ from the `PAM_AUTHTOK` item, if other modules have
+    /// changed it since.
+    ///
+    /// # Safety
+    ///
+    /// Nothing but `set_authtok` may have stored data under its key,
+    /// including other modules in the stack.  The key includes this crate's
+    /// version, so another module that uses `set_authtok` is only a problem
+    /// if it was built against this same version with a different compiler.
+    pub unsafe fn authtok_secret(&self) -> Option<&Secret> {
+        self.get_data::<Secret>(AUTHTOK_KEY).ok()
+    }
+
+    /// Sets the prompt used to ask for the user's name (the
+    /// `PAM_USER_PROMPT` item).
+    ///
+    /// From then on, [`get_user(None)`](Self::get_user) uses this prompt,
+    /// whether it is called by this module or by any later module in the
+    /// stack (or by C modules calling `pam_get_user` with a null prompt),
+    /// so this is how a stack can agree on a prompt like `Username: `.  It has
+    /// no effect once the user's name is known.  libpam copies the prompt,
+    /// so it need not outlive this call.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_CONV_ERR` if the prompt contains a nul byte.
+    pub fn set_user_prompt(&mut self, prompt: &str) -> PamResult<()> {
+        let prompt = to_c_string(prompt)?;
+        self.set_item(crate::items::UserPrompt(&prompt))
     }
 
     /// Retrieves the name of the user who is authenticating or logging in.
     ///
-    /// This is really a specialization of `get_item`.
+    /// This is really a specialization of `get_item`.  If the `PAM_USER` item
+    /// is already set, libpam returns it without asking.  Otherwise, it asks
+    /// the user, with the first of these prompts that is available:
+    ///
+    /// 1. `prompt`,
+    /// 2. the `PAM_USER_PROMPT` item,
+    /// 3. libpam's default, `login: `.
+    ///
+    /// and stores the answer in `PAM_USER` for the rest of the stack.
     ///
     /// See the [`pam_get_user` manual page](
     /// https://www.man7.org/linux/man-pages/man3/pam_get_user.3.html).
     ///
     /// # Errors
     ///
-    /// Returns an error if the underlying PAM function call fails.
+    /// Returns an error if the underlying PAM function call fails,
+    /// `PAM_CONV_ERR` if the prompt contains a nul byte, or `PAM_BUF_ERR`
+    /// if the name is not valid UTF-8 (see [`get_user_os`](Self::get_user_os)).
+    pub fn get_user(&self, prompt: Option<&str>) -> PamResult<String> {
+        copy_pam_string(self.get_user_raw(prompt)?)
+    }
+
+    /// Retrieves the name of the user like [`get_user`](Self::get_user),
+    /// but as whatever bytes libpam has, without requiring UTF-8.
     ///
-    /// # Panics
+    /// # Errors
     ///
-    /// Panics if the provided prompt string contains a nul byte.
-    pub fn get_user(&self, prompt: Option<&str>) -> PamResult<String> {
-        let prompt_string;
-        let c_prompt = match prompt {
-            Some(p) => {
-                prompt_string = CString::new(p).unwrap();
-                prompt_string.as_ptr()
-            }
-            None => std::ptr::null(),
-        };
-        let output: *mut c_char = std::ptr::null_mut();
-        let res = unsafe { pam_get_user(self, &output, c_prompt) };
-        match res {
-            PamResultCode::PAM_SUCCESS => copy_pam_string(output),
-            otherwise => Err(otherwise),
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_CONV_ERR` if the prompt contains a nul byte.
+    pub fn get_user_os(&self, prompt: Option<&str>) -> PamResult<OsString> {
+        Ok(copy_pam_os_string(self.get_user_raw(prompt)?))
+    }
+
+    fn get_user_raw(&self, prompt: Option<&str>) -> PamResult<*const c_char> {
+        let mut output: *const c_char = std::ptr::null();
+        with_prompt(prompt, |c_prompt| unsafe {
+            PamResultCode::from_libpam(pam_get_user(self, &mut output, c_prompt))
+        })?
+        .into_result()?;
+        Ok(output)
+    }
+
+    /// Retrieves the name of the user like [`get_user`](Self::get_user),
+    /// and normalizes it according to `policy`.
+    ///
+    /// `PAM_USER` is left as it was; to have the rest of the stack use the
+    /// normalized name, use [`normalize_user`](Self::normalize_user).
+    ///
+    /// # Errors
+    ///
+    /// As for [`get_user`](Self::get_user), and `PAM_USER_UNKNOWN` if the
+    /// policy rejects the name.
+    pub fn get_user_normalized(
+        &self,
+        prompt: Option<&str>,
+        policy: &NamePolicy,
+    ) -> PamResult<String> {
+        policy.normalize(&self.get_user(prompt)?)
+    }
+
+    /// Retrieves and normalizes the name of the user like
+    /// [`get_user_normalized`](Self::get_user_normalized), and stores the
+    /// normalized name back in `PAM_USER` if it changed.
+    ///
+    /// As with [`set_user`](Self::set_user), a module that does this should
+    /// come first in the stack.
+    ///
+    /// # Errors
+    ///
+    /// As for [`get_user_normalized`](Self::get_user_normalized), or an
+    /// error if the underlying PAM function call to store the name fails.
+    pub fn normalize_user(
+        &mut self,
+        prompt: Option<&str>,
+        policy: &NamePolicy,
+    ) -> PamResult<String> {
+        let name = self.get_user(prompt)?;
+        let normalized = policy.normalize(&name)?;
+        if normalized != name {
+            self.set_user(&normalized)?;
         }
+        Ok(normalized)
+    }
+
+    /// Retrieves the name of the user like [`get_user`](Self::get_user),
+    /// but with `default` in place of libpam's default prompt.
+    ///
+    /// If the user has to be asked, and neither `prompt` nor the
+    /// `PAM_USER_PROMPT` item is set, `PAM_USER_PROMPT` is set to `default`,
+    /// so that later modules in the stack ask the same way.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function calls fail,
+    /// or `PAM_CONV_ERR` if either prompt contains a nul byte.
+    pub fn get_user_with_default(
+        &mut self,
+        prompt: Option<&str>,
+        default: &str,
+    ) -> PamResult<String> {
+        let needs_prompt = self.get_item::<crate::items::User>()?.is_none()
+            && prompt.is_none()
+            && self.get_item::<crate::items::UserPrompt>()?.is_none();
+        if needs_prompt {
+            self.set_user_prompt(default)?;
+        }
+        self.get_user(prompt)
     }
 
     /// Retrieves the authentication token from the user.
     ///
-    /// This is really a specialization of `get_item`.
+    /// This is really a specialization of `get_item`.  To compare the token
+    /// with a stored secret, use [`constant_time_eq`](crate::util::constant_time_eq)
+    /// rather than `==`.
+    ///
+    /// The user is always asked with echo off (`PAM_PROMPT_ECHO_OFF`).  Like
+    /// every prompt this crate builds, the copy of `prompt` passed to libpam
+    /// is zeroed once the call returns.  To have the token zeroed too, use
+    /// [`get_authtok_secret`](Self::get_authtok_secret).
     ///
     /// See the [`pam_get_authtok` manual page](
     /// https://www.man7.org/linux/man-pages/man3/pam_get_authtok.3.html).
     ///
     /// # Errors
     ///
-    /// Returns an error if the underlying PAM function call fails.
+    /// Returns an error if the underlying PAM function call fails,
+    /// `PAM_CONV_ERR` if the prompt contains a nul byte, or `PAM_BUF_ERR`
+    /// if the token is not valid UTF-8 (see [`get_authtok_os`](Self::get_authtok_os)).
+    pub fn get_authtok(&self, prompt: Option<&str>) -> PamResult<String> {
+        copy_pam_string(self.get_authtok_raw(prompt)?)
+    }
+
+    /// Retrieves the authentication token like [`get_authtok`](Self::get_authtok),
+    /// as a [`Secret`], whose copy of the token is zeroed when it is dropped.
     ///
-    /// # Panics
+    /// Only this crate's copy is zeroed: the `PAM_AUTHTOK` item stays in
+    /// libpam's memory until `pam_end`, which overwrites it.
     ///
-    /// Panics if the provided prompt string contains a nul byte.
-    pub fn get_authtok(&self, prompt: Option<&str>) -> PamResult<String> {
-        let prompt_string;
-        let c_prompt = match prompt {
-            Some(p) => {
-                prompt_string = CString::new(p).unwrap();
-                prompt_string.as_ptr()
+    /// # Errors
+    ///
+    /// As for [`get_authtok`](Self::get_authtok).
+    pub fn get_authtok_secret(&self, prompt: Option<&str>) -> PamResult<Secret> {
+        copy_pam_secret(self.get_authtok_raw(prompt)?)
+    }
+
+    /// Retrieves the authentication token like [`get_authtok`](Self::get_authtok),
+    /// but as whatever bytes the user typed, without requiring UTF-8.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_CONV_ERR` if the prompt contains a nul byte.
+    pub fn get_authtok_os(&self, prompt: Option<&str>) -> PamResult<OsString> {
+        Ok(copy_pam_os_string(self.get_authtok_raw(prompt)?))
+    }
+
+    /// Gets the authentication token like [`get_authtok`](Self::get_authtok),
+    /// with the `PAM_AUTHTOK_TYPE` item set to `token_type` during the call.
+    ///
+    /// While a token is being changed, libpam includes the type in its
+    /// prompts, so that with a `token_type` of `LDAP` they read `New LDAP
+    /// password: ` and `Retype new LDAP password: `.  With the default
+    /// `scope`, [`AuthTokTypeScope::Call`], the item is put back the way it
+    /// was afterward, whether or not getting the token succeeded.
+    ///
+    /// ```no_run
+    /// # use pam::items::AuthTokTypeScope;
+    /// # use pam::module::{PamHandle, PamResult};
+    /// fn new_ldap_password(pamh: &mut PamHandle) -> PamResult<String> {
+    ///     pamh.get_authtok_typed("LDAP", None, AuthTokTypeScope::Call)
+    /// }
+    /// ```
+    ///
+    /// # Errors
+    ///
+    /// As for [`get_authtok`](Self::get_authtok).  Also returns
+    /// `PAM_CONV_ERR` if `token_type` contains a nul byte, or an error
+    /// if the item can't be set or put back.
+    pub fn get_authtok_typed(
+        &mut self,
+        token_type: &str,
+        prompt: Option<&str>,
+        scope: AuthTokTypeScope,
+    ) -> PamResult<String> {
+        let token_type = to_c_string(token_type)?;
+        let previous = self
+            .get_item::<AuthTokType>()?
+            .map(|previous| previous.0.to_owned());
+        self.set_item(AuthTokType(&token_type))?;
+        let token = self.get_authtok(prompt);
+        if scope == AuthTokTypeScope::Call {
+            match &previous {
+                Some(previous) => self.set_item(AuthTokType(previous))?,
+                None => {
+                    let res = unsafe {
+                        pam_set_item(self, ItemType::AuthTokType.into(), std::ptr::null())
+                    };
+                    PamResultCode::from_libpam(res).into_result()?
+                }
             }
-            None => std::ptr::null(),
+        }
+        token
+    }
+
+    /// Gets the authentication token like [`get_authtok`](Self::get_authtok),
+    /// and passes libpam's own copy of it to `f`, without copying it.
+    ///
+    /// This suits a module that only needs to look at the token briefly,
+    /// say to hash it or compare it, since it neither allocates nor leaves
+    /// another copy of the token behind.  The `&str` is only valid during
+    /// the call, because libpam may free or overwrite the token as soon as
+    /// the handle is used again, so it can't be kept:
+    ///
+    /// ```compile_fail
+    /// # use pam::module::{PamHandle, PamResult};
+    /// fn keep<'a>(pamh: &'a PamHandle) -> PamResult<&'a str> {
+    ///     pamh.with_authtok(None, |token| token)
+    /// }
+    /// ```
+    ///
+    /// Copy what's needed out of it instead, or use
+    /// [`get_authtok_secret`](Self::get_authtok_secret).
+    ///
+    /// # Errors
+    ///
+    /// As for [`get_authtok`](Self::get_authtok).
+    pub fn with_authtok<R>(&self, prompt: Option<&str>, f: impl FnOnce(&str) -> R) -> PamResult<R> {
+        let token = self.get_authtok_raw(prompt)?;
+        let token = if token.is_null() {
+            <&CStr>::default()
+        } else {
+            unsafe { CStr::from_ptr(token) }
         };
-        let output: *mut c_char = std::ptr::null_mut();
-        let res = unsafe { pam_get_authtok(self, ItemType::AuthTok, &output, c_prompt) };
-        match res {
-            PamResultCode::PAM_SUCCESS => copy_pam_string(output),
+        let token = token.to_str().map_err(|_| PamResultCode::PAM_BUF_ERR)?;
+        Ok(f(token))
+    }
+
+    fn get_authtok_raw(&self, prompt: Option<&str>) -> PamResult<*const c_char> {
+        let mut output: *const c_char = std::ptr::null();
+        with_prompt(prompt, |c_prompt| unsafe {
+            compat::get_authtok(self, ItemType::AuthTok.into(), &mut output, c_prompt)
+        })?
+        .into_result()?;
+        Ok(output)
+    }
+
+    /// Gets a new authentication token in `sm_chauthtok`, prompting for it
+    /// twice and making sure that both entries match.
+    ///
+    /// The first prompt is `prompt`, or libpam's default (`New password: `),
+    /// and the second is libpam's `Retype new password: `.  If an earlier
+    /// module has already set the token, it is returned without prompting.
+    /// If the entries don't match, the user is told so, and the token is
+    /// left unset, so that calling this again prompts afresh.
+    ///
+    /// See the [`pam_get_authtok_verify` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_get_authtok_verify.3.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_AUTHTOK_ERR` if the entries don't match (libpam itself
+    /// returns `PAM_TRY_AGAIN`, which would mean something else to the
+    /// application if passed on from `sm_chauthtok`).  Otherwise returns an
+    /// error if the underlying PAM function calls fail, `PAM_CONV_ERR` if the
+    /// prompt contains a nul byte, or `PAM_BUF_ERR` if the token is not valid
+    /// UTF-8.
+    pub fn get_authtok_verify(&self, prompt: Option<&str>) -> PamResult<String> {
+        if let Some(token) = self.get_authtok_cached()? {
+            return Ok(token);
+        }
+        let mut token: *const c_char = std::ptr::null();
+        with_prompt(prompt, |c_prompt| unsafe {
+            compat::get_authtok_noverify(self, &mut token, c_prompt)
+        })?
+        .into_result()?;
+        match unsafe { compat::get_authtok_verify(self, &mut token, std::ptr::null()) } {
+            PamResultCode::PAM_SUCCESS => copy_pam_string(token),
+            PamResultCode::PAM_TRY_AGAIN => Err(PamResultCode::PAM_AUTHTOK_ERR),
             otherwise => Err(otherwise),
         }
     }
+
+    /// Gets the authentication token that an earlier module in the stack
+    /// has already obtained, without ever prompting the user.
+    ///
+    /// This is what modules need to support the `use_first_pass` and
+    /// `try_first_pass` options.  Returns `Ok(None)` if no token is set.
+    /// To keep the copy as a [`Secret`], use
+    /// [`get_item_secret::<AuthTok>()`](Self::get_item_secret).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails,
+    /// or `PAM_BUF_ERR` if the token is not valid UTF-8.
+    pub fn get_authtok_cached(&self) -> PamResult<Option<String>> {
+        self.get_item_string(ItemType::AuthTok)
+    }
+
+    /// Gets a copy of the PAM environment, which will be passed on to the
+    /// user's session.  Entries are decoded lazily when iterating over it.
+    ///
+    /// See the [`pam_getenvlist` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_getenvlist.3.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_BUF_ERR` if the environment could not be copied.
+    pub fn env_iter(&self) -> PamResult<EnvList> {
+        let list = unsafe { pam_getenvlist(self) };
+        if list.is_null() {
+            Err(PamResultCode::PAM_BUF_ERR)
+        } else {
+            Ok(unsafe { EnvList::from_raw(list) })
+        }
+    }
+
+    /// Gets an owned copy of every `(name, value)` pair in the PAM environment.
+    ///
+    /// See [`env_iter`](Self::env_iter) to avoid copying every entry.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_BUF_ERR` if the environment could not be copied.
+    pub fn getenvlist(&self) -> PamResult<Vec<(String, String)>> {
+        let env = self.env_iter()?;
+        Ok(env
+            .iter()
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect())
+    }
+
+    /// Changes the PAM environment, which will be passed on to the
+    /// user's session.
+    ///
+    /// `name_value` is either `NAME=value`, to set a variable (`NAME=` sets it
+    /// to the empty string), or just `NAME`, to remove it.
+    ///
+    /// See the [`pam_putenv` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_putenv.3.html).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the underlying PAM function call fails (for
+    /// instance, `PAM_BAD_ITEM` when removing a variable that isn't set),
+    /// or `PAM_CONV_ERR` if `name_value` contains a nul byte.
+    pub fn putenv(&mut self, name_value: &str) -> PamResult<()> {
+        let name_value = to_c_string(name_value)?;
+        PamResultCode::from_libpam(unsafe { pam_putenv(self, name_value.as_ptr()) }).into_result()
+    }
+
+    /// Sets the variable `name` in the PAM environment to `value`.
+    ///
+    /// This builds the `NAME=value` string for [`putenv`](Self::putenv),
+    /// checking `name` first so that a mistake can't set some other
+    /// variable, or remove one.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_BAD_ITEM` if `name` is empty or contains `=` or a nul
+    /// byte, `PAM_CONV_ERR` if `value` contains a nul byte, or an error if
+    /// the underlying PAM function call fails.
+    pub fn set_env(&mut self, name: &str, value: &str) -> PamResult<()> {
+        check_env_name(name)?;
+        self.putenv(&format!("{name}={value}"))
+    }
+
+    /// Removes the variable `name` from the PAM environment.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_BAD_ITEM` if `name` is empty or contains `=` or a nul
+    /// byte, or if the variable isn't set, or an error if the underlying PAM
+    /// function call fails.
+    pub fn unset_env(&mut self, name: &str) -> PamResult<()> {
+        check_env_name(name)?;
+        self.putenv(name)
+    }
+
+    /// Sets several variables in the PAM environment, all or nothing.
+    ///
+    /// The variables are set in order.  If setting one fails, the ones set
+    /// before it are put back the way they were (restoring their old values,
+    /// or removing them if they weren't set), and the error is returned.
+    /// libpam has no transactions, so this is only a best effort: if putting
+    /// a variable back fails too, it is left as it is.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the first variable that couldn't be set,
+    /// as for [`set_env`](Self::set_env), or `PAM_BUF_ERR` if the environment
+    /// could not be copied beforehand.
+    pub fn putenv_all(&mut self, vars: &[(&str, &str)]) -> PamResult<()> {
+        let before = self.getenvlist()?;
+        for (i, (name, value)) in vars.iter().enumerate() {
+            if let Err(e) = self.set_env(name, value) {
+                for (name, _) in vars[..i].iter().rev() {
+                    let _ = match before.iter().find(|(n, _)| n == name) {
+                        Some((_, old)) => self.set_env(name, old),
+                        None => self.unset_env(name),
+                    };
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a message to the system log, at the given `syslog` priority
+    /// (e.g., `libc::LOG_ERR`).
+    ///
+    /// The message is prefixed with the service and module names, and goes
+    /// to the `LOG_AUTHPRIV` facility unless `priority` names another one.
+    /// Any nul bytes in the message are written as `\0`.
+    ///
+    /// To send messages to `tracing` instead, when that feature is enabled,
+    /// use [`logging::log`](crate::logging::log).
+    ///
+    /// See the [`pam_syslog` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_syslog.3.html).
+    pub fn syslog(&self, priority: c_int, msg: &str) {
+        let msg = CString::new(msg.replace('\0', "\\0")).unwrap_or_default();
+        compat::syslog(self, priority, &msg);
+    }
+
+    /// Describes a result code, in the language of this handle's locale.
+    ///
+    /// See [`strerror`] for a version which doesn't need a handle.
+    /// See the [`pam_strerror` manual page](
+    /// https://www.man7.org/linux/man-pages/man3/pam_strerror.3.html).
+    pub fn strerror(&self, code: PamResultCode) -> &str {
+        unsafe { describe(pam_strerror(self, code.into())) }
+    }
+}
+
+/// Checks that `name` can be used alone as the name of a PAM environment
+/// variable, returning `PAM_BAD_ITEM` if not.
+fn check_env_name(name: &str) -> PamResult<()> {
+    if name.is_empty() || name.contains(['=', '\0']) {
+        Err(PamResultCode::PAM_BAD_ITEM)
+    } else {
+        Ok(())
+    }
+}
+
+/// Describes a result code, like `"Authentication failure"` for `PAM_AUTH_ERR`.
+///
+/// This calls `pam_strerror` without a handle.  Linux-PAM never looks at
+/// the handle, and returns a static string (translated, if translations are
+/// installed), so this is safe to call anywhere.  OpenPAM and other
+/// implementations may require a real handle; on those, use
+/// [`PamHandle::strerror`] instead.
+///
+/// See the [`pam_strerror` manual page](
+/// https://www.man7.org/linux/man-pages/man3/pam_strerror.3.html).
+pub fn strerror(code: PamResultCode) -> &'static str {
+    unsafe { describe(pam_strerror(std::ptr::null(), code.into())) }
+}
+
+/// Reads a string returned by `pam_strerror`, which libpam never frees.
+unsafe fn describe(msg: *const c_char) -> &'static str {
+    if msg.is_null() {
+        return "Unknown PAM error";
+    }
+    CStr::from_ptr(msg).to_str().unwrap_or("Unknown PAM error")
 }
 
 /// Creates an owned copy of a string that is returned from a
 /// <code>pam_get_<var>whatever</var></code> function.
-fn copy_pam_string(result_ptr: *const c_char) -> PamResult<String> {
+///
+/// This is only for nul-terminated strings; binary values like
+/// [`XAuthData`](crate::items::XAuthData) must be read by length.
+///
+/// Bytes that are not valid UTF-8 are rejected with `PAM_BUF_ERR`, so that
+/// they can be told apart from a failed conversation (`PAM_CONV_ERR`).
+/// They usually mean the user's locale isn't UTF-8.
+pub(crate) fn copy_pam_string(result_ptr: *const c_char) -> PamResult<String> {
+    let bytes = copy_pam_os_string(result_ptr).into_vec();
+    String::from_utf8(bytes).map_err(|_| PamResultCode::PAM_BUF_ERR)
+}
+
+/// Like [`copy_pam_string`], but copies the bytes as they are.
+pub(crate) fn copy_pam_os_string(result_ptr: *const c_char) -> OsString {
     // We really shouldn't get a null pointer back here, but if we do, return nothing.
     if result_ptr.is_null() {
-        return Ok(String::from(""));
+        return OsString::new();
     }
     let bytes = unsafe { CStr::from_ptr(result_ptr).to_bytes() };
-    String::from_utf8(bytes.to_vec()).map_err(|_| PamResultCode::PAM_CONV_ERR)
+    OsString::from_vec(bytes.to_vec())
 }
 
-/// Convenience to transform a `PamResultCode` into a unit `PamResult`.
-fn to_result(result: PamResultCode) -> PamResult<()> {
-    match result {
-        PamResultCode::PAM_SUCCESS => Ok(()),
-        otherwise => Err(otherwise),
+/// Like [`copy_pam_string`], but into a [`Secret`], zeroing the copy
+/// if it is not valid UTF-8.
+pub(crate) fn copy_pam_secret(result_ptr: *const c_char) -> PamResult<Secret> {
+    let bytes = copy_pam_os_string(result_ptr).into_vec();
+    match String::from_utf8(bytes) {
+        Ok(token) => Ok(Secret::new(token)),
+        Err(e) => {
+            zero_bytes(&mut e.into_bytes());
+            Err(PamResultCode::PAM_BUF_ERR)
+        }
     }
 }
 
+/// The module data key under which [`PamHandle::set_authtok`] keeps the token.
+const AUTHTOK_KEY: &str = concat!("nonstick.authtok.", env!("CARGO_PKG_VERSION"));
+
+/// Passes `prompt` to `f` as a C string (or null if there is none),
+/// and zeroes it afterward.
+fn with_prompt<R>(prompt: Option<&str>, f: impl FnOnce(*const c_char) -> R) -> PamResult<R> {
+    let prompt = prompt.map(to_c_string).transpose()?;
+    let result = f(prompt.as_ref().map_or(std::ptr::null(), |p| p.as_ptr()));
+    if let Some(prompt) = prompt {
+        let mut bytes = prompt.into_bytes_with_nul();
+        zero_bytes(&mut bytes);
+        #[cfg(test)]
+        tests::ZEROED.with(|zeroed| zeroed.borrow_mut().push(bytes));
+    }
+    Ok(result)
+}
+
+/// Converts a Rust string into a C string to pass to PAM.
+///
+/// A string containing a nul byte can't be represented as a C string,
+/// so it is rejected with `PAM_CONV_ERR` rather than panicking.
+pub(crate) fn to_c_string(s: &str) -> PamResult<CString> {
+    CString::new(s).map_err(|_| PamResultCode::PAM_CONV_ERR)
+}
+
 /// Provides functions that are invoked by the entrypoints generated by the
 /// [`pam_hooks!` macro](../macro.pam_hooks.html).
 ///
@@ -278,6 +1353,11 @@ pub trait PamHooks {
     /// `PAM_PRELIM_CHECK` and then, if the module does not return `PAM_TRY_AGAIN`, subsequently with
     /// `PAM_UPDATE_AUTHTOK`. It is only on the second call that the authorization token is
     /// (possibly) changed.
+    ///
+    /// `PAM_TRY_AGAIN` is only meaningful in the first call, and makes libpam skip the
+    /// second call for every module in the stack.  [`ChauthtokResult`] checks this for you.
+    ///
+    /// [`ChauthtokResult`]: crate::password::ChauthtokResult
     fn sm_chauthtok(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
         PamResultCode::PAM_IGNORE
     }
@@ -297,7 +1377,831 @@ pub trait PamHooks {
     /// information about a user than their authentication token. This function is used to make such
     /// information available to the application. It should only be called after the user has been
     /// authenticated but before a session has been established.
+    ///
+    /// Use [`CredAction::from_flags`](crate::constants::CredAction::from_flags)
+    /// to find out which action is being requested.
     fn sm_setcred(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
         PamResultCode::PAM_IGNORE
     }
+
+    /// Called by the entrypoints before every hook, with the name of the hook
+    /// (e.g., `"sm_authenticate"`).
+    ///
+    /// This and [`after_hook`](Self::after_hook) are for instrumentation that
+    /// applies to every hook alike, like auditing or metrics.  Both do nothing
+    /// by default.  With `pam_hooks!(select ...)`, they are called on the
+    /// generated type, not on the implementations it selects from.
+    fn before_hook(pamh: &mut PamHandle, hook: &str, flags: PamFlag) {}
+
+    /// Called by the entrypoints after every hook, with the name of the hook,
+    /// the code it is returning (`panic_code` if it panicked), and how long
+    /// it took.
+    ///
+    /// ```no_run
+    /// # use pam::constants::{PamFlag, PamResultCode};
+    /// # use pam::module::{PamHandle, PamHooks};
+    /// # use std::time::Duration;
+    /// struct Timed;
+    ///
+    /// impl PamHooks for Timed {
+    ///     fn after_hook(
+    ///         pamh: &mut PamHandle,
+    ///         hook: &str,
+    ///         flags: PamFlag,
+    ///         result: PamResultCode,
+    ///         elapsed: Duration,
+    ///     ) {
+    ///         let msg = format!("{hook} returned {result:?} after {elapsed:?}");
+    ///         pamh.syslog(libc::LOG_INFO, &msg);
+    ///     }
+    /// }
+    /// ```
+    fn after_hook(
+        pamh: &mut PamHandle,
+        hook: &str,
+        flags: PamFlag,
+        result: PamResultCode,
+        elapsed: Duration,
+    ) {
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::items::Tty;
+    use crate::mock::{self, MockPam};
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    #[test]
+    fn nul_bytes_are_errors() {
+        let mut handle = PamHandle {
+            _data: [],
+            _not_send: PhantomData,
+        };
+        let err = Some(PamResultCode::PAM_CONV_ERR);
+        assert_eq!(err, handle.get_user(Some("user\0name")).err());
+        assert_eq!(err, handle.get_authtok(Some("pass\0word")).err());
+        assert_eq!(err, handle.set_data("da\0ta", Box::new(1)).err());
+        assert_eq!(err, handle.set_user("us\0er").err());
+        assert_eq!(err, unsafe { handle.get_data::<i32>("da\0ta") }.err());
+    }
+
+    #[test]
+    fn get_item_present_and_absent() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        assert!(pamh.get_item::<Tty>().unwrap().is_none());
+        assert_eq!(
+            Some(PamResultCode::PAM_NO_MODULE_DATA),
+            pamh.get_item_required::<Tty>().err()
+        );
+
+        let tty = CString::new("/dev/tty1").unwrap();
+        pamh.set_item(Tty(&tty)).unwrap();
+        assert_eq!(tty.as_c_str(), *pamh.get_item::<Tty>().unwrap().unwrap());
+        assert_eq!(tty.as_c_str(), *pamh.get_item_required::<Tty>().unwrap());
+    }
+
+    #[test]
+    fn terminal_info() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        assert_eq!(TerminalInfo::default(), pamh.terminal_info().unwrap());
+
+        pamh.set_item(crate::items::RHost(c"example.com")).unwrap();
+        pamh.set_item(crate::items::XDisplay(c":0")).unwrap();
+        let expected = TerminalInfo {
+            rhost: Some("example.com".to_owned()),
+            xdisplay: Some(":0".to_owned()),
+            ..TerminalInfo::default()
+        };
+        assert_eq!(expected, pamh.terminal_info().unwrap());
+    }
+
+    #[test]
+    fn service_and_tty() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        assert_eq!(Ok(None), pamh.service());
+        assert_eq!(Ok(None), pamh.tty());
+
+        pamh.set_item(crate::items::Service(c"sshd")).unwrap();
+        pamh.set_item(Tty(c"/dev/pts/3")).unwrap();
+        assert_eq!(Ok(Some("sshd".to_owned())), pamh.service());
+        assert_eq!(Ok(Some("/dev/pts/3".to_owned())), pamh.tty());
+
+        pamh.set_item(Tty(c"/dev/\xff")).unwrap();
+        assert_eq!(Err(PamResultCode::PAM_BUF_ERR), pamh.tty());
+    }
+
+    #[test]
+    fn putenv_all_rolls_back() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        pamh.putenv("LANG=C").unwrap();
+
+        assert_eq!(
+            Err(PamResultCode::PAM_BAD_ITEM),
+            pamh.putenv_all(&[
+                ("LANG", "en_US.UTF-8"),
+                ("KRB5CCNAME", "FILE:/tmp/cc"),
+                ("", "oops"),
+                ("TZ", "UTC")
+            ])
+        );
+        assert_eq!(
+            Ok(vec![("LANG".to_owned(), "C".to_owned())]),
+            pamh.getenvlist()
+        );
+
+        assert_eq!(
+            Ok(()),
+            pamh.putenv_all(&[("LANG", "en_US.UTF-8"), ("TZ", "UTC")])
+        );
+        assert_eq!(
+            Ok(vec![
+                ("LANG".to_owned(), "en_US.UTF-8".to_owned()),
+                ("TZ".to_owned(), "UTC".to_owned()),
+            ]),
+            pamh.getenvlist()
+        );
+    }
+
+    #[test]
+    fn set_and_unset_env() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        assert_eq!(Ok(()), pamh.set_env("LANG", "C"));
+        assert_eq!(Ok(()), pamh.set_env("GREETING", "a=b c"));
+        assert_eq!(Ok(()), pamh.set_env("EMPTY", ""));
+        assert_eq!(Ok(()), pamh.set_env("LANG", "C.UTF-8"));
+        assert_eq!(
+            Ok(vec![
+                ("LANG".to_owned(), "C.UTF-8".to_owned()),
+                ("GREETING".to_owned(), "a=b c".to_owned()),
+                ("EMPTY".to_owned(), String::new()),
+            ]),
+            pamh.getenvlist()
+        );
+
+        assert_eq!(Ok(()), pamh.unset_env("GREETING"));
+        assert_eq!(Err(PamResultCode::PAM_BAD_ITEM), pamh.unset_env("GREETING"));
+        assert_eq!(Ok(2), pamh.getenvlist().map(|env| env.len()));
+
+        for name in ["", "A=B", "LANG=", "NUL\0"] {
+            assert_eq!(
+                Err(PamResultCode::PAM_BAD_ITEM),
+                pamh.set_env(name, "x"),
+                "{name:?}"
+            );
+            assert_eq!(
+                Err(PamResultCode::PAM_BAD_ITEM),
+                pamh.unset_env(name),
+                "{name:?}"
+            );
+        }
+        assert_eq!(Err(PamResultCode::PAM_CONV_ERR), pamh.set_env("A", "\0"));
+        assert_eq!(Ok(2), pamh.getenvlist().map(|env| env.len()));
+    }
+
+    #[test]
+    fn conv_available() {
+        use crate::conv::{Conv, Inner};
+        let mut mock = MockPam::new();
+        assert!(!mock.handle().conv_available());
+
+        let null_callback = Inner {
+            conv: None,
+            appdata_ptr: std::ptr::null(),
+        };
+        let pamh = mock.handle();
+        pamh.set_item(Conv(&null_callback)).unwrap();
+        assert!(!pamh.conv_available());
+        let conv = pamh.get_item::<Conv>().unwrap().unwrap();
+        assert_eq!(
+            Err(PamResultCode::PAM_CONV_ERR),
+            conv.send(&crate::conv::Prompt::Password)
+        );
+
+        mock.set_conv(answer_alice, std::ptr::null());
+        assert!(mock.handle().conv_available());
+    }
+
+    #[test]
+    fn rhost_addr() {
+        use crate::items::RHost;
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        assert_eq!(Ok(None), pamh.rhost_addr());
+
+        let cases = [
+            (c"203.0.113.7", RhostValue::Ip([203, 0, 113, 7].into())),
+            (c"::1", RhostValue::Ip(std::net::Ipv6Addr::LOCALHOST.into())),
+            (
+                c"gw.example.net",
+                RhostValue::Hostname("gw.example.net".to_owned()),
+            ),
+        ];
+        for (rhost, expected) in cases {
+            pamh.set_item(RHost(rhost)).unwrap();
+            assert_eq!(Ok(Some(expected)), pamh.rhost_addr());
+            assert_eq!(rhost, *pamh.get_item::<RHost>().unwrap().unwrap());
+        }
+    }
+
+    #[test]
+    fn strerror_with_and_without_handle() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        for code in [PamResultCode::PAM_PERM_DENIED, PamResultCode::PAM_BAD_ITEM] {
+            assert_eq!(strerror(code), pamh.strerror(code));
+        }
+        assert_eq!(
+            "Permission denied",
+            strerror(PamResultCode::PAM_PERM_DENIED)
+        );
+    }
+
+    thread_local! {
+        /// Every prompt buffer, as it was just before being freed.
+        pub(super) static ZEROED: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+    }
+
+    #[test]
+    fn prompts_and_secrets_are_zeroed() {
+        let take_prompts = || PROMPTS.with(|prompts| prompts.take());
+        let take_zeroed = || ZEROED.with(|zeroed| zeroed.take());
+        let mut mock = MockPam::new();
+        mock.set_conv(answer_alice, std::ptr::null());
+        let pamh = mock.handle();
+
+        assert_eq!(Ok("alice".to_owned()), pamh.get_user(Some("Who? ")));
+        assert_eq!(vec!["Who? "], take_prompts());
+        assert_eq!(vec![vec![0; 6]], take_zeroed());
+
+        // The mock never prompts for the token, but the prompt is still zeroed.
+        pamh.set_item(crate::items::AuthTok(c"alice")).unwrap();
+        let token = pamh.get_authtok_secret(Some("PIN: ")).unwrap();
+        assert_eq!("alice", token.expose());
+        assert_eq!(vec![vec![0; 6]], take_zeroed());
+
+        // Without a prompt, there is nothing to zero.
+        assert_eq!(Ok("alice".to_owned()), pamh.get_authtok(None));
+        assert!(take_zeroed().is_empty());
+    }
+
+    thread_local! {
+        static PROMPTS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    /// Records the prompt, and answers "alice".
+    extern "C" fn answer_alice(
+        _: c_int,
+        msg: *const *const crate::conv::PamMessage,
+        resp: *mut *mut crate::conv::PamResponse,
+        _: *const libc::c_void,
+    ) -> c_int {
+        unsafe {
+            let prompt = CStr::from_ptr((**msg).msg).to_str().unwrap().to_owned();
+            PROMPTS.with(|prompts| prompts.borrow_mut().push(prompt));
+            let response = libc::calloc(1, std::mem::size_of::<crate::conv::PamResponse>())
+                .cast::<crate::conv::PamResponse>();
+            (*response).resp = libc::strdup(c"alice".as_ptr());
+            *resp = response;
+        }
+        PamResultCode::PAM_SUCCESS.into()
+    }
+
+    /// Answers each prompt with the next answer in the `Vec<&str>` that
+    /// `appdata_ptr` points to, and records every message.
+    extern "C" fn answer_from(
+        _: c_int,
+        msg: *const *const crate::conv::PamMessage,
+        resp: *mut *mut crate::conv::PamResponse,
+        appdata_ptr: *const libc::c_void,
+    ) -> c_int {
+        unsafe {
+            let message = CStr::from_ptr((**msg).msg).to_str().unwrap().to_owned();
+            PROMPTS.with(|prompts| prompts.borrow_mut().push(message));
+            let response = libc::calloc(1, std::mem::size_of::<crate::conv::PamResponse>())
+                .cast::<crate::conv::PamResponse>();
+            if (**msg).msg_style != crate::constants::PAM_ERROR_MSG {
+                let answers = &mut *appdata_ptr.cast_mut().cast::<Vec<&CStr>>();
+                (*response).resp = libc::strdup(answers.remove(0).as_ptr());
+            }
+            *resp = response;
+        }
+        PamResultCode::PAM_SUCCESS.into()
+    }
+
+    #[test]
+    fn get_authtok_verify() {
+        let take_prompts = || PROMPTS.with(|prompts| prompts.take());
+        let mut answers = vec![c"hunter2", c"hunter3", c"hunter2", c"hunter2"];
+        let mut mock = MockPam::new();
+        mock.set_conv(answer_from, (&mut answers as *mut Vec<&CStr>).cast());
+        let pamh = mock.handle();
+
+        assert_eq!(
+            Err(PamResultCode::PAM_AUTHTOK_ERR),
+            pamh.get_authtok_verify(Some("New PIN: "))
+        );
+        assert_eq!(
+            vec![
+                "New PIN: ",
+                "Retype new password: ",
+                "Sorry, passwords do not match."
+            ],
+            take_prompts()
+        );
+        assert_eq!(Ok(None), pamh.get_authtok_cached());
+
+        assert_eq!(Ok("hunter2".to_owned()), pamh.get_authtok_verify(None));
+        assert_eq!(
+            vec!["New password: ", "Retype new password: "],
+            take_prompts()
+        );
+        assert_eq!(Ok(Some("hunter2".to_owned())), pamh.get_authtok_cached());
+
+        // Once the token is set, it is not asked for again.
+        assert_eq!(Ok("hunter2".to_owned()), pamh.get_authtok_verify(None));
+        assert!(take_prompts().is_empty());
+        assert!(answers.is_empty());
+    }
+
+    #[test]
+    fn get_user_with_default() {
+        let take_prompts = || PROMPTS.with(|prompts| prompts.take());
+        let mut mock = MockPam::new();
+        mock.set_conv(answer_alice, std::ptr::null());
+        let pamh = mock.handle();
+
+        pamh.set_user("bob").unwrap();
+        assert_eq!(
+            Ok("bob".to_owned()),
+            pamh.get_user_with_default(None, "Name: ")
+        );
+        assert!(pamh
+            .get_item::<crate::items::UserPrompt>()
+            .unwrap()
+            .is_none());
+        assert!(take_prompts().is_empty());
+
+        let mut mock = MockPam::new();
+        mock.set_conv(answer_alice, std::ptr::null());
+        let pamh = mock.handle();
+        assert_eq!(
+            Ok("alice".to_owned()),
+            pamh.get_user_with_default(None, "Name: ")
+        );
+        assert_eq!(vec!["Name: "], take_prompts());
+        let prompt = pamh
+            .get_item::<crate::items::UserPrompt>()
+            .unwrap()
+            .unwrap();
+        assert_eq!(c"Name: ", *prompt);
+
+        let mut mock = MockPam::new();
+        mock.set_conv(answer_alice, std::ptr::null());
+        let pamh = mock.handle();
+        let got = pamh.get_user_with_default(Some("Who are you? "), "Name: ");
+        assert_eq!(Ok("alice".to_owned()), got);
+        assert_eq!(vec!["Who are you? "], take_prompts());
+        assert!(pamh
+            .get_item::<crate::items::UserPrompt>()
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn get_authtok_cached_never_prompts() {
+        let mut mock = MockPam::new();
+        mock.set_conv(answer_alice, std::ptr::null());
+        let pamh = mock.handle();
+        assert_eq!(Ok(None), pamh.get_authtok_cached());
+        pamh.set_item(crate::items::AuthTok(c"hunter2")).unwrap();
+        assert_eq!(Ok(Some("hunter2".to_owned())), pamh.get_authtok_cached());
+        assert!(PROMPTS.with(|prompts| prompts.take()).is_empty());
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn set_item_str_still_works() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        pamh.set_item_str(Tty(c"/dev/tty2")).unwrap();
+        assert_eq!(c"/dev/tty2", *pamh.get_item::<Tty>().unwrap().unwrap());
+    }
+
+    #[test]
+    fn set_user_is_copied() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        let name = String::from("alice@EXAMPLE.COM");
+        let local = name.split('@').next().unwrap();
+        pamh.set_user(local).unwrap();
+        drop(name);
+        assert_eq!(Ok("alice".to_owned()), pamh.get_user(None));
+    }
+
+    #[test]
+    fn invalid_utf8_is_not_a_conversation_error() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        // With no conversation, prompting fails.
+        assert_eq!(Err(PamResultCode::PAM_CONV_ERR), pamh.get_user(None));
+        assert_eq!(Err(PamResultCode::PAM_CONV_ERR), pamh.get_user_os(None));
+
+        pamh.set_item(crate::items::User(c"j\xfcrgen")).unwrap();
+        assert_eq!(Err(PamResultCode::PAM_BUF_ERR), pamh.get_user(None));
+        assert_eq!(b"j\xfcrgen", pamh.get_user_os(None).unwrap().as_bytes());
+
+        pamh.set_item(crate::items::AuthTok(c"p\xe4ss")).unwrap();
+        assert_eq!(Err(PamResultCode::PAM_BUF_ERR), pamh.get_authtok(None));
+        assert_eq!(Err(PamResultCode::PAM_BUF_ERR), pamh.get_authtok_cached());
+        assert_eq!(b"p\xe4ss", pamh.get_authtok_os(None).unwrap().as_bytes());
+    }
+
+    #[test]
+    fn read_only_helpers_in_sequence() {
+        fn who(pamh: &PamHandle) -> PamResult<String> {
+            pamh.get_user(None)
+        }
+        fn where_from(pamh: &PamHandle) -> PamResult<Option<String>> {
+            pamh.tty()
+        }
+        fn hook(pamh: &mut PamHandle, flags: PamFlag) -> PamResult<String> {
+            let user = who(pamh)?;
+            let tty = where_from(pamh)?.unwrap_or_default();
+            crate::logging::log(pamh, flags, libc::LOG_INFO, &user);
+            let has_conv = crate::conv::Conv::from_borrowed(pamh)?.is_some();
+            pamh.set_item(Tty(c"pts/1"))?;
+            Ok(format!("{user} {tty} {has_conv} {:?}", where_from(pamh)?))
+        }
+
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        pamh.set_user("alice").unwrap();
+        pamh.set_item(Tty(c"tty1")).unwrap();
+        assert_eq!(
+            Ok(r#"alice tty1 false Some("pts/1")"#.to_owned()),
+            hook(pamh, PamFlag::default())
+        );
+    }
+
+    #[test]
+    fn normalized_user() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        pamh.set_user(" Alice@EXAMPLE.COM").unwrap();
+        let policy = NamePolicy::new()
+            .trim()
+            .strip_realm("@example.com")
+            .lowercase();
+        assert_eq!(
+            Ok("alice".to_owned()),
+            pamh.get_user_normalized(None, &policy)
+        );
+        assert_eq!(Ok(" Alice@EXAMPLE.COM".to_owned()), pamh.get_user(None));
+        assert_eq!(Ok("alice".to_owned()), pamh.normalize_user(None, &policy));
+        assert_eq!(Ok("alice".to_owned()), pamh.get_user(None));
+
+        let strict = NamePolicy::new().reject_chars("/");
+        pamh.set_user("../root").unwrap();
+        assert_eq!(
+            Err(PamResultCode::PAM_USER_UNKNOWN),
+            pamh.normalize_user(None, &strict)
+        );
+        assert_eq!(Ok("../root".to_owned()), pamh.get_user(None));
+    }
+
+    #[test]
+    fn set_user_prompt() {
+        let take_prompts = || PROMPTS.with(|prompts| prompts.take());
+        let mut mock = MockPam::new();
+        mock.set_conv(answer_alice, std::ptr::null());
+        let pamh = mock.handle();
+        let prompt = String::from("Username: ");
+        pamh.set_user_prompt(&prompt).unwrap();
+        drop(prompt);
+        assert_eq!(
+            c"Username: ",
+            *pamh
+                .get_item::<crate::items::UserPrompt>()
+                .unwrap()
+                .unwrap()
+        );
+        assert_eq!(Ok("alice".to_owned()), pamh.get_user(None));
+        assert_eq!(vec!["Username: "], take_prompts());
+        assert_eq!(
+            Err(PamResultCode::PAM_CONV_ERR),
+            pamh.set_user_prompt("User\0name: ")
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_data_round_trip() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Attempt {
+            user: String,
+            count: u32,
+        }
+
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Count {
+            count: u32,
+        }
+
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        let attempt = Attempt {
+            user: "alice".to_owned(),
+            count: 3,
+        };
+        pamh.set_data_serde("attempt", &attempt).unwrap();
+        assert_eq!(Ok(attempt), unsafe {
+            pamh.get_data_serde::<Attempt>("attempt")
+        });
+        // A different type can read the fields it shares.
+        assert_eq!(Ok(Count { count: 3 }), unsafe {
+            pamh.get_data_serde::<Count>("attempt")
+        });
+        assert_eq!(Err(PamResultCode::PAM_SYSTEM_ERR), unsafe {
+            pamh.get_data_serde::<u32>("attempt")
+        });
+        assert_eq!(Err(PamResultCode::PAM_NO_MODULE_DATA), unsafe {
+            pamh.get_data_serde::<u32>("missing")
+        });
+
+        pamh.set_data_serde("attempt", &Count { count: 4 }.count)
+            .unwrap();
+        assert_eq!(Ok(4), unsafe { pamh.get_data_serde::<u32>("attempt") });
+    }
+
+    /// Counts how many times it has been dropped.
+    struct Counted(Rc<Cell<usize>>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn set_data_drops_replaced_value() {
+        let drops = Rc::new(Cell::new(0));
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        for round in 1..=3 {
+            pamh.set_data("state", Box::new(Counted(Rc::clone(&drops))))
+                .unwrap();
+            assert_eq!(round - 1, drops.get());
+        }
+        drop(mock);
+        assert_eq!(3, drops.get());
+        assert_eq!(1, Rc::strong_count(&drops));
+    }
+
+    #[test]
+    fn set_data_drops_value_not_stored() {
+        let drops = Rc::new(Cell::new(0));
+        let mut mock = MockPam::new();
+        mock.as_application();
+        let pamh = mock.handle();
+        assert_eq!(
+            Err(PamResultCode::PAM_SYSTEM_ERR),
+            pamh.set_data("state", Box::new(Counted(Rc::clone(&drops))))
+        );
+        assert_eq!(1, drops.get());
+        assert_eq!(
+            Err(PamResultCode::PAM_SYSTEM_ERR),
+            pamh.set_data_with_cleanup("state", Counted(Rc::clone(&drops)), |_, _, _| {
+                panic!("cleanup of a value that was never stored")
+            })
+        );
+        assert_eq!(2, drops.get());
+        assert_eq!(1, Rc::strong_count(&drops));
+    }
+
+    #[test]
+    fn cleanup_gets_status() {
+        type Log = Rc<RefCell<Vec<(i32, PamResultCode, bool)>>>;
+        fn recorder(log: &Log) -> impl FnOnce(&i32, PamResultCode, bool) {
+            let log = Rc::clone(log);
+            move |data, status, silent| log.borrow_mut().push((*data, status, silent))
+        }
+
+        let log = Log::default();
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        pamh.set_data_with_cleanup("key", 1, recorder(&log))
+            .unwrap();
+        pamh.set_data_with_cleanup("key", 2, recorder(&log))
+            .unwrap();
+        pamh.set_data_with_cleanup("other", 3, recorder(&log))
+            .unwrap();
+        assert_eq!(vec![(1, PamResultCode::PAM_SUCCESS, false)], *log.borrow());
+
+        mock.end(c_int::from(PamResultCode::PAM_AUTH_ERR) | PAM_DATA_SILENT);
+        let mut entries = log.take();
+        entries.sort_by_key(|&(data, _, _)| data);
+        assert_eq!(
+            vec![
+                (1, PamResultCode::PAM_SUCCESS, false),
+                (2, PamResultCode::PAM_AUTH_ERR, true),
+                (3, PamResultCode::PAM_AUTH_ERR, true),
+            ],
+            entries
+        );
+
+        let mut mock = MockPam::new();
+        mock.handle()
+            .set_data_with_cleanup("key", 4, recorder(&log))
+            .unwrap();
+        mock.end(PamResultCode::PAM_SUCCESS.into());
+        assert_eq!(
+            vec![(4, PamResultCode::PAM_SUCCESS, false)],
+            Rc::try_unwrap(log).unwrap().into_inner()
+        );
+    }
+
+    #[test]
+    fn update_data() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        let incr = |n: &mut u32| {
+            *n += 1;
+            *n
+        };
+        assert_eq!(
+            Some(PamResultCode::PAM_NO_MODULE_DATA),
+            unsafe { pamh.update_data("count", incr) }.err()
+        );
+        pamh.set_data("count", Box::new(0u32)).unwrap();
+        assert_eq!(Ok(1), unsafe { pamh.update_data("count", incr) });
+        assert_eq!(Ok(2), unsafe { pamh.update_data("count", incr) });
+        assert_eq!(Ok(&2), unsafe { pamh.get_data::<u32>("count") });
+    }
+
+    #[test]
+    fn authtok_type_set_around_call() {
+        use crate::items::AuthTok;
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        pamh.set_item(AuthTok(c"hunter2")).unwrap();
+        let authtok_type = |pamh: &PamHandle| {
+            pamh.get_item::<AuthTokType>()
+                .unwrap()
+                .map(|t| t.0.to_owned())
+        };
+        mock::take_authtok_types();
+
+        assert_eq!(
+            Ok("hunter2".to_owned()),
+            pamh.get_authtok_typed("LDAP", None, AuthTokTypeScope::Call)
+        );
+        assert_eq!(None, authtok_type(pamh));
+
+        pamh.set_item(AuthTokType(c"Kerberos")).unwrap();
+        assert!(pamh
+            .get_authtok_typed("LDAP", None, AuthTokTypeScope::default())
+            .is_ok());
+        assert_eq!(Some(c"Kerberos".to_owned()), authtok_type(pamh));
+
+        assert!(pamh
+            .get_authtok_typed("SMB", None, AuthTokTypeScope::Stack)
+            .is_ok());
+        assert_eq!(Some(c"SMB".to_owned()), authtok_type(pamh));
+
+        assert_eq!(
+            vec![
+                Some(c"LDAP".to_owned()),
+                Some(c"LDAP".to_owned()),
+                Some(c"SMB".to_owned())
+            ],
+            mock::take_authtok_types()
+        );
+    }
+
+    #[test]
+    fn with_authtok_hashes_in_place() {
+        use crate::items::AuthTok;
+        use std::hash::{DefaultHasher, Hash, Hasher};
+        let hash = |token: &str| {
+            let mut hasher = DefaultHasher::new();
+            token.hash(&mut hasher);
+            hasher.finish()
+        };
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        pamh.set_item(AuthTok(c"hunter2")).unwrap();
+        assert_eq!(Ok(hash("hunter2")), pamh.with_authtok(None, hash));
+        assert_eq!(
+            Ok(true),
+            pamh.with_authtok(Some("Password: "), |token| {
+                crate::util::constant_time_eq(token.as_bytes(), b"hunter2")
+            })
+        );
+
+        pamh.set_item(AuthTok(c"\xff")).unwrap();
+        assert_eq!(
+            Err(PamResultCode::PAM_BUF_ERR),
+            pamh.with_authtok(None, hash)
+        );
+    }
+
+    #[test]
+    fn get_item_secret() {
+        use crate::items::{AuthTok, OldAuthTok};
+        let take_dropped = || crate::util::DROPPED_SECRETS.with(|dropped| dropped.take());
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        assert_eq!(Ok(None), pamh.get_item_secret::<AuthTok>());
+
+        pamh.set_item(AuthTok(c"hunter2")).unwrap();
+        pamh.set_item(OldAuthTok(c"hunter1")).unwrap();
+        take_dropped();
+        let token = pamh.get_item_secret::<AuthTok>().unwrap().unwrap();
+        assert_eq!("hunter2", token.expose());
+        let old = pamh.get_item_secret::<OldAuthTok>().unwrap().unwrap();
+        assert_eq!("hunter1", old.expose());
+        drop(token);
+        assert_eq!(vec![vec![0; 7]], take_dropped());
+
+        pamh.set_item(AuthTok(c"\xff\xfe")).unwrap();
+        assert_eq!(
+            Err(PamResultCode::PAM_BUF_ERR),
+            pamh.get_item_secret::<AuthTok>()
+        );
+    }
+
+    #[test]
+    fn set_authtok_zeroes_old_token() {
+        let take_dropped = || crate::util::DROPPED_SECRETS.with(|dropped| dropped.take());
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        assert_eq!(None, unsafe { pamh.authtok_secret() });
+
+        pamh.set_authtok(Secret::new("old".to_owned())).unwrap();
+        assert!(take_dropped().is_empty());
+        pamh.set_authtok(Secret::new("newer".to_owned())).unwrap();
+        assert_eq!(vec![vec![0; 3]], take_dropped());
+        assert_eq!(Ok("newer".to_owned()), pamh.get_authtok(None));
+        assert_eq!("newer", unsafe { pamh.authtok_secret() }.unwrap().expose());
+
+        assert_eq!(
+            Err(PamResultCode::PAM_CONV_ERR),
+            pamh.set_authtok(Secret::new("bad\0".to_owned()))
+        );
+        assert_eq!(vec![vec![0; 4]], take_dropped());
+        drop(mock);
+        assert_eq!(vec![vec![0; 5]], take_dropped());
+    }
+
+    #[test]
+    fn null_data_is_no_data() {
+        extern "C" fn ignore(_: *const PamHandle, _: *mut libc::c_void, _: c_int) {}
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        let res = unsafe { pam_set_data(pamh, c"null".as_ptr(), std::ptr::null_mut(), ignore) };
+        assert_eq!(c_int::from(PamResultCode::PAM_SUCCESS), res);
+        assert_eq!(Err(PamResultCode::PAM_NO_MODULE_DATA), unsafe {
+            pamh.get_data::<u32>("null")
+        });
+        assert_eq!(Ok(None), unsafe { pamh.get_data_cloned::<u32>("null") });
+        assert_eq!(Err(PamResultCode::PAM_NO_MODULE_DATA), unsafe {
+            pamh.update_data("null", |n: &mut u32| *n += 1)
+        });
+    }
+
+    #[test]
+    fn get_data_cloned() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        assert_eq!(Ok(None), unsafe { pamh.get_data_cloned::<String>("name") });
+        pamh.set_data("name", Box::new("alice".to_owned())).unwrap();
+        let name = unsafe { pamh.get_data_cloned::<String>("name") }
+            .unwrap()
+            .unwrap();
+        pamh.set_data("name", Box::new("bob".to_owned())).unwrap();
+        pamh.set_user(&name).unwrap();
+        assert_eq!("alice", name);
+        assert_eq!(Ok(&"bob".to_owned()), unsafe {
+            pamh.get_data::<String>("name")
+        });
+    }
+
+    #[test]
+    fn or_ignore() {
+        assert_eq!(Ok(1), Ok(1).or_ignore());
+        assert_eq!(
+            Err::<(), _>(PamResultCode::PAM_IGNORE),
+            Err(PamResultCode::PAM_AUTH_ERR).or_ignore()
+        );
+    }
 }
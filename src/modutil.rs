@@ -0,0 +1,493 @@
+//! Helpers for module authors, modeled on Linux-PAM's `pam_modutil` functions.
+
+use libc::{c_char, gid_t, mode_t, passwd, rlim_t, uid_t};
+
+use crate::constants::PamResultCode;
+use crate::module::{copy_pam_string, to_c_string, PamHandle, PamResult};
+
+/// An owned copy of a user's entry in the system password database.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswdEntry {
+    /// The user's login name.
+    pub name: String,
+    /// The user's numeric user ID.
+    pub uid: uid_t,
+    /// The numeric ID of the user's primary group.
+    pub gid: gid_t,
+    /// The user's home directory.
+    pub dir: String,
+    /// The user's login shell.
+    pub shell: String,
+    /// The "real name" field, which often holds other comma-separated data.
+    pub gecos: String,
+}
+
+impl PasswdEntry {
+    /// Copies the contents of a `passwd` struct into a new `PasswdEntry`.
+    ///
+    /// # Safety
+    ///
+    /// All the string pointers in `raw` must be either null or valid C strings.
+    unsafe fn from_raw(raw: &passwd) -> PamResult<Self> {
+        Ok(Self {
+            name: copy_pam_string(raw.pw_name)?,
+            uid: raw.pw_uid,
+            gid: raw.pw_gid,
+            dir: copy_pam_string(raw.pw_dir)?,
+            shell: copy_pam_string(raw.pw_shell)?,
+            gecos: copy_pam_string(raw.pw_gecos)?,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[cfg_attr(not(test), link(name = "pam"))]
+extern "C" {
+    fn pam_modutil_getpwnam(pamh: *const PamHandle, user: *const c_char) -> *mut passwd;
+    fn pam_modutil_drop_priv(
+        pamh: *const PamHandle,
+        p: *mut ModutilPrivs,
+        pw: *const passwd,
+    ) -> libc::c_int;
+    fn pam_modutil_regain_priv(pamh: *const PamHandle, p: *mut ModutilPrivs) -> libc::c_int;
+}
+
+/// Looks up the password database entry for the user named `name`.
+///
+/// On Linux-PAM, this uses `pam_modutil_getpwnam`, which caches the result
+/// for the life of the PAM handle.  Elsewhere, it falls back to `getpwnam_r`.
+/// If there is no such user, returns `Ok(None)`.
+///
+/// See the [`pam_modutil_getpwnam` manual page](
+/// https://www.man7.org/linux/man-pages/man3/pam_modutil_getpwnam.3.html).
+///
+/// # Errors
+///
+/// Returns an error if the entry could not be read,
+/// or `PAM_CONV_ERR` if the name contains a nul byte.
+pub fn getpwnam(pamh: &PamHandle, name: &str) -> PamResult<Option<PasswdEntry>> {
+    let c_name = to_c_string(name)?;
+    #[cfg(target_os = "linux")]
+    {
+        let entry = unsafe { pam_modutil_getpwnam(pamh, c_name.as_ptr()) };
+        if entry.is_null() {
+            Ok(None)
+        } else {
+            unsafe { PasswdEntry::from_raw(&*entry) }.map(Some)
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = pamh;
+        getpwnam_r(&c_name)
+    }
+}
+
+/// Looks up a password database entry using the reentrant libc function.
+#[cfg(any(test, not(target_os = "linux")))]
+fn getpwnam_r(name: &std::ffi::CStr) -> PamResult<Option<PasswdEntry>> {
+    let mut buf_len = match unsafe { libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) } {
+        n if n > 0 => n as usize,
+        _ => 1024,
+    };
+    loop {
+        let mut buf: Vec<c_char> = vec![0; buf_len];
+        let mut pwd = std::mem::MaybeUninit::<passwd>::uninit();
+        let mut result: *mut passwd = std::ptr::null_mut();
+        let rc = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                pwd.as_mut_ptr(),
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        match rc {
+            0 if result.is_null() => return Ok(None),
+            0 => return unsafe { PasswdEntry::from_raw(&*result) }.map(Some),
+            libc::ERANGE => buf_len *= 2,
+            libc::ENOENT | libc::ESRCH => return Ok(None),
+            _ => return Err(PamResultCode::PAM_SYSTEM_ERR),
+        }
+    }
+}
+
+/// The number of supplementary groups saved without allocating,
+/// as `PAM_MODUTIL_NGROUPS`.
+#[cfg(target_os = "linux")]
+const NGROUPS: usize = 64;
+
+/// The saved credentials (`struct pam_modutil_privs`), which must stay at
+/// the same address from dropping privileges until regaining them.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+pub(crate) struct ModutilPrivs {
+    pub(crate) grplist: *mut gid_t,
+    pub(crate) number_of_groups: libc::c_int,
+    pub(crate) allocated: libc::c_int,
+    pub(crate) old_gid: gid_t,
+    pub(crate) old_uid: uid_t,
+    pub(crate) is_dropped: libc::c_int,
+}
+
+/// Temporarily acts as another user to access their files, until dropped.
+///
+/// Running as root, a session module that opens a file in the user's home
+/// directory can be tricked by a symlink into reading or overwriting any
+/// file on the system.  While a `PrivGuard` is alive, file access is
+/// checked as the user instead, so it can only reach files the user could.
+///
+/// This uses `pam_modutil_drop_priv`, which changes only the *filesystem*
+/// user and group IDs (with `setfsuid` and `setfsgid`), and only for the
+/// current thread, so the real and effective IDs stay root and the
+/// privileges can be regained.  The process's supplementary groups are
+/// saved and replaced with the user's own (with `initgroups`), so group
+/// permissions apply as they would for the user, and are restored along
+/// with the IDs when the guard is dropped.  Unlike the IDs, supplementary
+/// groups are shared by every thread in the process.
+///
+/// If the process isn't running as root, or `user` is root, nothing changes.
+///
+/// ```no_run
+/// # use pam::constants::PamResultCode;
+/// # use pam::modutil::{getpwnam, PrivGuard};
+/// # use pam::module::{PamHandle, PamResult};
+/// fn read_user_config(pamh: &PamHandle, name: &str) -> PamResult<String> {
+///     let user = getpwnam(pamh, name)?.ok_or(PamResultCode::PAM_USER_UNKNOWN)?;
+///     let _guard = PrivGuard::new(pamh, &user)?;
+///     std::fs::read_to_string(format!("{}/.config/mymodule", user.dir))
+///         .map_err(|_| PamResultCode::PAM_SESSION_ERR)
+/// }
+/// ```
+///
+/// See the [`pam_modutil_drop_priv` manual page](
+/// https://www.man7.org/linux/man-pages/man3/pam_modutil_drop_priv.3.html).
+#[cfg(target_os = "linux")]
+pub struct PrivGuard<'a> {
+    pamh: &'a PamHandle,
+    privs: Box<ModutilPrivs>,
+    _groups: Box<[gid_t; NGROUPS]>,
+    regained: bool,
+}
+
+#[cfg(target_os = "linux")]
+impl<'a> PrivGuard<'a> {
+    /// Switches file access to `user` until the guard is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_SESSION_ERR` if the credentials could not be changed,
+    /// in which case they are left as they were, or `PAM_CONV_ERR` if one
+    /// of `user`'s fields contains a nul byte.
+    pub fn new(pamh: &'a PamHandle, user: &PasswdEntry) -> PamResult<Self> {
+        let name = to_c_string(&user.name)?;
+        let dir = to_c_string(&user.dir)?;
+        let shell = to_c_string(&user.shell)?;
+        let gecos = to_c_string(&user.gecos)?;
+        let pw = passwd {
+            pw_name: name.as_ptr().cast_mut(),
+            pw_passwd: c"x".as_ptr().cast_mut(),
+            pw_uid: user.uid,
+            pw_gid: user.gid,
+            pw_gecos: gecos.as_ptr().cast_mut(),
+            pw_dir: dir.as_ptr().cast_mut(),
+            pw_shell: shell.as_ptr().cast_mut(),
+        };
+        let mut groups = Box::new([0; NGROUPS]);
+        let mut privs = Box::new(ModutilPrivs {
+            grplist: groups.as_mut_ptr(),
+            number_of_groups: NGROUPS as libc::c_int,
+            allocated: 0,
+            old_gid: gid_t::MAX,
+            old_uid: uid_t::MAX,
+            is_dropped: 0,
+        });
+        if unsafe { pam_modutil_drop_priv(pamh, &mut *privs, &pw) } != 0 {
+            return Err(PamResultCode::PAM_SESSION_ERR);
+        }
+        Ok(Self {
+            pamh,
+            privs,
+            _groups: groups,
+            regained: false,
+        })
+    }
+
+    /// Regains the original credentials now, rather than when dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_SESSION_ERR` if the credentials could not be restored.
+    pub fn restore(mut self) -> PamResult<()> {
+        self.regain()
+    }
+
+    fn regain(&mut self) -> PamResult<()> {
+        // libpam complains if privileges are regained twice.
+        if self.regained {
+            return Ok(());
+        }
+        match unsafe { pam_modutil_regain_priv(self.pamh, &mut *self.privs) } {
+            0 => {
+                self.regained = true;
+                Ok(())
+            }
+            _ => Err(PamResultCode::PAM_SESSION_ERR),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for PrivGuard<'_> {
+    /// Regains the original credentials.  A failure is only logged,
+    /// so call [`PrivGuard::restore`] to handle it.
+    fn drop(&mut self) {
+        if self.regain().is_err() {
+            self.pamh.syslog(
+                libc::LOG_ERR,
+                "PrivGuard: could not regain privileges after dropping them",
+            );
+        }
+    }
+}
+
+/// Sets the file mode creation mask, as for a session, returning the old one.
+///
+/// Like the other process settings below, this takes effect in the process
+/// that loaded the module, and so in whatever the application starts from
+/// it after `pam_open_session`, usually the user's session.  It is up to
+/// each module whether to do this at all; nothing here is done for you.
+///
+/// # Errors
+///
+/// Returns `PAM_SERVICE_ERR` (the module is misconfigured) if `mask` has
+/// bits other than the permission bits (`0o777`).
+pub fn set_umask(mask: mode_t) -> PamResult<mode_t> {
+    if mask & !0o777 != 0 {
+        return Err(PamResultCode::PAM_SERVICE_ERR);
+    }
+    Ok(unsafe { libc::umask(mask) })
+}
+
+/// Parses a umask in octal, like the `022` of a `umask=022` argument.
+///
+/// # Errors
+///
+/// Returns `PAM_SERVICE_ERR` if `mask` is not an octal number up to `0777`.
+pub fn parse_umask(mask: &str) -> PamResult<mode_t> {
+    if !mask.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(PamResultCode::PAM_SERVICE_ERR);
+    }
+    match mode_t::from_str_radix(mask, 8) {
+        Ok(mask) if mask <= 0o777 => Ok(mask),
+        _ => Err(PamResultCode::PAM_SERVICE_ERR),
+    }
+}
+
+/// The type of the resource constants for [`get_limit`] and [`set_limit`],
+/// like `libc::RLIMIT_NOFILE`.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub type Resource = libc::__rlimit_resource_t;
+/// The type of the resource constants for [`get_limit`] and [`set_limit`],
+/// like `libc::RLIMIT_NOFILE`.
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+pub type Resource = libc::c_int;
+
+/// A resource limit, as for `getrlimit` and `setrlimit`.  Either may be
+/// `libc::RLIM_INFINITY`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limit {
+    /// The limit that is enforced.
+    pub soft: rlim_t,
+    /// The most that the soft limit can be raised to without privileges.
+    pub hard: rlim_t,
+}
+
+/// Reads the limit on `resource` (e.g., `libc::RLIMIT_NOFILE`) for the
+/// process that loaded the module.
+///
+/// These are the application's own limits, not the limits configured for
+/// the user who is logging in; those only apply once a module sets them.
+///
+/// # Errors
+///
+/// Returns `PAM_SERVICE_ERR` if `resource` is not a valid resource.
+pub fn get_limit(resource: Resource) -> PamResult<Limit> {
+    let mut raw = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    match unsafe { libc::getrlimit(resource, &mut raw) } {
+        0 => Ok(Limit {
+            soft: raw.rlim_cur,
+            hard: raw.rlim_max,
+        }),
+        _ => Err(limit_error()),
+    }
+}
+
+/// Sets the limit on `resource` for the process that loaded the module,
+/// as [`set_umask`] does for the umask.
+///
+/// # Errors
+///
+/// Returns `PAM_PERM_DENIED` if the hard limit would be raised without the
+/// privilege to, `PAM_SERVICE_ERR` if `resource` is not a valid resource
+/// or the soft limit is above the hard limit, and `PAM_SYSTEM_ERR` if the
+/// limit could not be set for any other reason.
+pub fn set_limit(resource: Resource, limit: Limit) -> PamResult<()> {
+    let raw = libc::rlimit {
+        rlim_cur: limit.soft,
+        rlim_max: limit.hard,
+    };
+    match unsafe { libc::setrlimit(resource, &raw) } {
+        0 => Ok(()),
+        _ => Err(limit_error()),
+    }
+}
+
+/// Maps the `errno` of a failed `getrlimit` or `setrlimit`.
+fn limit_error() -> PamResultCode {
+    match std::io::Error::last_os_error().raw_os_error() {
+        Some(libc::EPERM) => PamResultCode::PAM_PERM_DENIED,
+        Some(libc::EINVAL) => PamResultCode::PAM_SERVICE_ERR,
+        _ => PamResultCode::PAM_SYSTEM_ERR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+
+    #[test]
+    fn getpwnam_r_root() {
+        let root = getpwnam_r(&CString::new("root").unwrap()).unwrap().unwrap();
+        assert_eq!("root", root.name);
+        assert_eq!(0, root.uid);
+        assert_eq!(0, root.gid);
+    }
+
+    #[test]
+    fn umask_round_trip() {
+        // The umask belongs to the whole process, so it's changed in a child,
+        // where it can't affect files that other tests create meanwhile.
+        // The child sticks to calls that are safe after `fork`.
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork: {}", std::io::Error::last_os_error()),
+            0 => {
+                let ok = set_umask(0o077).is_ok()
+                    && set_umask(0o027) == Ok(0o077)
+                    && set_umask(0o022) == Ok(0o027)
+                    && set_umask(0o1022) == Err(PamResultCode::PAM_SERVICE_ERR);
+                unsafe { libc::_exit(if ok { 0 } else { 1 }) }
+            }
+            child => {
+                let mut status = 0;
+                assert_eq!(child, unsafe { libc::waitpid(child, &mut status, 0) });
+                assert!(libc::WIFEXITED(status), "child status {status:#x}");
+                assert_eq!(0, libc::WEXITSTATUS(status));
+            }
+        }
+
+        assert_eq!(Ok(0o22), parse_umask("022"));
+        assert_eq!(Ok(0o777), parse_umask("777"));
+        for bad in ["", "1000", "089", "+22", "u=rwx"] {
+            assert_eq!(
+                Err(PamResultCode::PAM_SERVICE_ERR),
+                parse_umask(bad),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let limit = get_limit(libc::RLIMIT_NOFILE).unwrap();
+        assert_eq!(Ok(()), set_limit(libc::RLIMIT_NOFILE, limit));
+        assert_eq!(Ok(limit), get_limit(libc::RLIMIT_NOFILE));
+        if limit.hard != libc::RLIM_INFINITY {
+            let backwards = Limit {
+                soft: limit.hard + 1,
+                hard: limit.hard,
+            };
+            assert_eq!(
+                Err(PamResultCode::PAM_SERVICE_ERR),
+                set_limit(libc::RLIMIT_NOFILE, backwards)
+            );
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn fsuid() -> uid_t {
+        // An invalid ID changes nothing, but still returns the current one.
+        unsafe { libc::setfsuid(uid_t::MAX) as uid_t }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn priv_guard_restores_uid() {
+        if unsafe { libc::geteuid() } != 0 {
+            return; // Only root can drop privileges.
+        }
+        let nobody = getpwnam_r(c"nobody").unwrap().unwrap();
+        let mut mock = crate::mock::MockPam::new();
+        let pamh = mock.handle();
+
+        let guard = PrivGuard::new(pamh, &nobody).unwrap();
+        assert_eq!(nobody.uid, fsuid());
+        drop(guard);
+        assert_eq!(0, fsuid());
+
+        let guard = PrivGuard::new(pamh, &nobody).unwrap();
+        assert_eq!(nobody.uid, fsuid());
+        assert_eq!(Ok(()), guard.restore());
+        assert_eq!(0, fsuid());
+        assert_eq!(
+            Vec::<(libc::c_int, String)>::new(),
+            crate::mock::take_logged()
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn priv_guard_restore_logs_nothing() {
+        // Switching to root changes nothing, whoever runs the tests.
+        let root = getpwnam_r(c"root").unwrap().unwrap();
+        let mut mock = crate::mock::MockPam::new();
+        let pamh = mock.handle();
+
+        drop(PrivGuard::new(pamh, &root).unwrap());
+        let guard = PrivGuard::new(pamh, &root).unwrap();
+        assert_eq!(Ok(()), guard.restore());
+        assert_eq!(
+            Vec::<(libc::c_int, String)>::new(),
+            crate::mock::take_logged()
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn getpwnam_from_libpam() {
+        let mut mock = crate::mock::MockPam::new();
+        let pamh = mock.handle();
+        let alice = PasswdEntry {
+            name: "alice".to_owned(),
+            uid: 1000,
+            gid: 100,
+            dir: "/home/alice".to_owned(),
+            shell: "/bin/sh".to_owned(),
+            gecos: "Alice Liddell,,,".to_owned(),
+        };
+        assert_eq!(Ok(Some(alice)), getpwnam(pamh, "alice"));
+        assert_eq!(Ok(None), getpwnam(pamh, "bob"));
+        assert_eq!(Err(PamResultCode::PAM_CONV_ERR), getpwnam(pamh, "al\0ice"));
+    }
+
+    #[test]
+    fn getpwnam_r_missing() {
+        let nobody = getpwnam_r(&CString::new("no such user, surely").unwrap()).unwrap();
+        assert_eq!(None, nobody);
+    }
+}
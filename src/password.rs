@@ -0,0 +1,313 @@
+//! Helpers for writing password-changing (`sm_chauthtok`) modules.
+
+use crate::constants::{PamFlag, PamResultCode};
+use crate::conv::{Conv, Notifier};
+use crate::logging::{self, LOG_WARNING};
+use crate::module::{PamHandle, PamResult};
+
+/// A rule that a new password must follow.
+pub trait PasswordPolicy {
+    /// Checks whether `new` is acceptable as a password.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error (usually `PAM_AUTHTOK_ERR`) if it is not.
+    fn check(&self, new: &str) -> PamResult<()>;
+}
+
+/// Requires passwords to be at least some number of characters long.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MinLengthPolicy {
+    min_len: usize,
+}
+
+impl MinLengthPolicy {
+    /// Requires passwords of at least `min_len` characters (not bytes).
+    pub const fn new(min_len: usize) -> Self {
+        Self { min_len }
+    }
+}
+
+impl PasswordPolicy for MinLengthPolicy {
+    fn check(&self, new: &str) -> PamResult<()> {
+        if new.chars().count() >= self.min_len {
+            Ok(())
+        } else {
+            Err(PamResultCode::PAM_AUTHTOK_ERR)
+        }
+    }
+}
+
+/// Fails with `code`, first telling the user why.
+///
+/// The reason is sent as an error message (`PAM_ERROR_MSG`), unless `flags`
+/// includes `PAM_SILENT`.  Applications using [`PamContext`] can read it
+/// back with [`PamContext::error_message`], to show along with the failure.
+/// Failing to send the reason doesn't change the result.
+///
+/// ```no_run
+/// # use pam::constants::{PamFlag, PamResultCode};
+/// # use pam::module::PamHandle;
+/// # use pam::password::reject;
+/// # fn f(pamh: &mut PamHandle, flags: PamFlag, new: &str) -> PamResultCode {
+/// if new.contains("password") {
+///     return reject(pamh, flags, PamResultCode::PAM_AUTHTOK_ERR, "BAD PASSWORD: it is too obvious");
+/// }
+/// # PamResultCode::PAM_SUCCESS
+/// # }
+/// ```
+///
+/// [`PamContext`]: crate::client::PamContext
+/// [`PamContext::error_message`]: crate::client::PamContext::error_message
+pub fn reject(
+    pamh: &PamHandle,
+    flags: PamFlag,
+    code: PamResultCode,
+    reason: &str,
+) -> PamResultCode {
+    if let Ok(Some(conv)) = Conv::from_borrowed(pamh) {
+        let _ = Notifier::new(&conv, flags).error(reason);
+    }
+    code
+}
+
+/// Implements both phases of `sm_chauthtok` for a module that enforces
+/// `policy` on the new password, and stores it with `commit`.
+///
+/// libpam calls `sm_chauthtok` first with `PAM_PRELIM_CHECK`, then (only if
+/// every module agreed) with `PAM_UPDATE_AUTHTOK`.  This checks the new
+/// password against the policy in the first phase, so that a bad password is
+/// rejected with `PAM_AUTHTOK_ERR` before any module has changed anything.
+/// The check is repeated in the second phase, in case the password was changed
+/// in between, and only then is `commit` called.
+///
+/// The new password is read with [`PamHandle::get_authtok`], which may
+/// prompt for it (and caches it for the second phase).
+///
+/// ```no_run
+/// # use pam::constants::{PamFlag, PamResultCode};
+/// # use pam::module::{PamHandle, PamHooks};
+/// # use pam::password::{chauthtok_with_policy, MinLengthPolicy};
+/// # use std::ffi::CStr;
+/// # fn store_password(_: &str) -> PamResultCode { PamResultCode::PAM_SUCCESS }
+/// struct Strict;
+///
+/// impl PamHooks for Strict {
+///     fn sm_chauthtok(pamh: &mut PamHandle, _: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+///         chauthtok_with_policy(pamh, flags, &MinLengthPolicy::new(12), |_, new| {
+///             store_password(new)
+///         })
+///     }
+/// }
+/// ```
+pub fn chauthtok_with_policy(
+    pamh: &mut PamHandle,
+    flags: PamFlag,
+    policy: &impl PasswordPolicy,
+    commit: impl FnOnce(&mut PamHandle, &str) -> PamResultCode,
+) -> PamResultCode {
+    if !flags.is_prelim_check() && !flags.is_update_authtok() {
+        return PamResultCode::PAM_SERVICE_ERR;
+    }
+    let new = match pamh.get_authtok(None) {
+        Ok(new) => new,
+        Err(e) => return e,
+    };
+    if policy.check(&new).is_err() {
+        return PamResultCode::PAM_AUTHTOK_ERR;
+    }
+    if flags.is_prelim_check() {
+        PamResultCode::PAM_SUCCESS
+    } else {
+        commit(pamh, &new)
+    }
+}
+
+/// The outcome of one phase of `sm_chauthtok`, checked against the phase.
+///
+/// In the `PAM_PRELIM_CHECK` phase, a module that can't change the token
+/// right now (say, because its server is unreachable) returns
+/// `PAM_TRY_AGAIN`.  libpam then stops: no module gets the
+/// `PAM_UPDATE_AUTHTOK` call, and the application gets `PAM_TRY_AGAIN`.
+/// In the `PAM_UPDATE_AUTHTOK` phase it is too late for that, and
+/// `PAM_TRY_AGAIN` is not a valid result.  [`into_code`](Self::into_code)
+/// turns it into `PAM_AUTHTOK_ERR` there, and logs a warning.
+///
+/// ```no_run
+/// # use pam::constants::{PamFlag, PamResultCode};
+/// # use pam::module::PamHandle;
+/// # use pam::password::ChauthtokResult;
+/// # use std::ffi::CStr;
+/// # fn server_is_up() -> bool { true }
+/// fn sm_chauthtok(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+///     let result = if !server_is_up() {
+///         ChauthtokResult::try_again()
+///     } else if flags.is_prelim_check() {
+///         ChauthtokResult::ok()
+///     } else {
+///         // ...
+/// #       ChauthtokResult::error(PamResultCode::PAM_AUTHTOK_ERR)
+///     };
+///     result.into_code(pamh, flags)
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChauthtokResult {
+    /// The phase succeeded.
+    Ok,
+    /// The token can't be changed right now.  Only valid in `PAM_PRELIM_CHECK`.
+    TryAgain,
+    /// The phase failed with the given code.
+    Error(PamResultCode),
+}
+
+impl ChauthtokResult {
+    /// The phase succeeded.
+    pub const fn ok() -> Self {
+        Self::Ok
+    }
+
+    /// The token can't be changed right now; see the [type](Self) docs.
+    pub const fn try_again() -> Self {
+        Self::TryAgain
+    }
+
+    /// The phase failed with `code`.
+    ///
+    /// `PAM_SUCCESS` and `PAM_TRY_AGAIN` are the same as [`ok`](Self::ok)
+    /// and [`try_again`](Self::try_again).
+    pub const fn error(code: PamResultCode) -> Self {
+        match code {
+            PamResultCode::PAM_SUCCESS => Self::Ok,
+            PamResultCode::PAM_TRY_AGAIN => Self::TryAgain,
+            code => Self::Error(code),
+        }
+    }
+
+    /// The code to return from `sm_chauthtok` in the phase given by `flags`.
+    ///
+    /// Returns `PAM_AUTHTOK_ERR`, and logs a warning, for
+    /// [`TryAgain`](Self::TryAgain) outside of `PAM_PRELIM_CHECK`.
+    pub fn into_code(self, pamh: &PamHandle, flags: PamFlag) -> PamResultCode {
+        match Self::error(self.into()) {
+            Self::Ok => PamResultCode::PAM_SUCCESS,
+            Self::TryAgain if flags.is_prelim_check() => PamResultCode::PAM_TRY_AGAIN,
+            Self::TryAgain => {
+                let msg = format!(
+                    "sm_chauthtok returned PAM_TRY_AGAIN with flags {flags}, \
+                     but it is only valid with PAM_PRELIM_CHECK; returning PAM_AUTHTOK_ERR"
+                );
+                logging::log(pamh, flags, LOG_WARNING, &msg);
+                PamResultCode::PAM_AUTHTOK_ERR
+            }
+            Self::Error(code) => code,
+        }
+    }
+}
+
+impl From<ChauthtokResult> for PamResultCode {
+    /// The code as given, without checking it against the phase.
+    fn from(result: ChauthtokResult) -> Self {
+        match result {
+            ChauthtokResult::Ok => PamResultCode::PAM_SUCCESS,
+            ChauthtokResult::TryAgain => PamResultCode::PAM_TRY_AGAIN,
+            ChauthtokResult::Error(code) => code,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{PAM_PRELIM_CHECK, PAM_UPDATE_AUTHTOK};
+    use crate::items::AuthTok;
+    use crate::mock::MockPam;
+    use std::ffi::CStr;
+
+    #[test]
+    fn min_length() {
+        let policy = MinLengthPolicy::new(4);
+        assert_eq!(Ok(()), policy.check("four"));
+        assert_eq!(Ok(()), policy.check("ünïc"));
+        assert_eq!(Err(PamResultCode::PAM_AUTHTOK_ERR), policy.check("abc"));
+    }
+
+    fn run(password: &CStr, flags: PamFlag) -> (PamResultCode, Option<String>) {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        pamh.set_item(AuthTok(password)).unwrap();
+        let mut committed = None;
+        let policy = MinLengthPolicy::new(8);
+        let result = chauthtok_with_policy(pamh, flags, &policy, |_, new| {
+            committed = Some(new.to_owned());
+            PamResultCode::PAM_SUCCESS
+        });
+        (result, committed)
+    }
+
+    #[test]
+    fn prelim_check() {
+        assert_eq!(
+            (PamResultCode::PAM_SUCCESS, None),
+            run(c"long enough", PAM_PRELIM_CHECK)
+        );
+        assert_eq!(
+            (PamResultCode::PAM_AUTHTOK_ERR, None),
+            run(c"short", PAM_PRELIM_CHECK)
+        );
+    }
+
+    #[test]
+    fn chauthtok_result_phases() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        let denied = ChauthtokResult::error(PamResultCode::PAM_PERM_DENIED);
+        for flags in [PAM_PRELIM_CHECK, PAM_UPDATE_AUTHTOK] {
+            let code = ChauthtokResult::ok().into_code(pamh, flags);
+            assert_eq!(PamResultCode::PAM_SUCCESS, code);
+            assert_eq!(
+                PamResultCode::PAM_PERM_DENIED,
+                denied.into_code(pamh, flags)
+            );
+        }
+        assert_eq!(
+            PamResultCode::PAM_TRY_AGAIN,
+            ChauthtokResult::try_again().into_code(pamh, PAM_PRELIM_CHECK)
+        );
+        #[cfg(not(feature = "tracing"))]
+        assert!(crate::mock::take_logged().is_empty());
+
+        let again = ChauthtokResult::error(PamResultCode::PAM_TRY_AGAIN);
+        assert_eq!(ChauthtokResult::TryAgain, again);
+        assert_eq!(
+            PamResultCode::PAM_AUTHTOK_ERR,
+            again.into_code(pamh, PAM_UPDATE_AUTHTOK)
+        );
+        #[cfg(not(feature = "tracing"))]
+        assert_eq!(
+            vec![(
+                LOG_WARNING,
+                "sm_chauthtok returned PAM_TRY_AGAIN with flags PAM_UPDATE_AUTHTOK, \
+                 but it is only valid with PAM_PRELIM_CHECK; returning PAM_AUTHTOK_ERR"
+                    .to_owned()
+            )],
+            crate::mock::take_logged()
+        );
+    }
+
+    #[test]
+    fn update_authtok() {
+        assert_eq!(
+            (PamResultCode::PAM_SUCCESS, Some("long enough".to_owned())),
+            run(c"long enough", PAM_UPDATE_AUTHTOK)
+        );
+        assert_eq!(
+            (PamResultCode::PAM_AUTHTOK_ERR, None),
+            run(c"short", PAM_UPDATE_AUTHTOK)
+        );
+        assert_eq!(
+            (PamResultCode::PAM_SERVICE_ERR, None),
+            run(c"long enough", PamFlag::default())
+        );
+    }
+}
@@ -0,0 +1,28 @@
+//! The types and macros that most modules need, for a glob import.
+//!
+//! ```
+//! use pam::prelude::*;
+//!
+//! # fn main() {}
+//! struct NoRoot;
+//! pam_hooks!(NoRoot);
+//!
+//! impl PamHooks for NoRoot {
+//!     fn sm_authenticate(pamh: &mut PamHandle, args: Vec<&CStr>, flags: PamFlag) -> PamResultCode {
+//!         let args = ModuleArgs::new(&args);
+//!         let user = pam_try!(pamh.get_user(None));
+//!         if user == "root" && !args.has_flag(b"allow_root") {
+//!             return PamResultCode::PAM_AUTH_ERR;
+//!         }
+//!         PamResultCode::PAM_IGNORE
+//!     }
+//! }
+//! ```
+
+pub use std::ffi::CStr;
+
+pub use crate::args::ModuleArgs;
+pub use crate::constants::{CredAction, PamFlag, PamMessageStyle, PamResultCode};
+pub use crate::conv::Conv;
+pub use crate::module::{PamHandle, PamHooks, PamResult, PamResultExt};
+pub use crate::{pam_hooks, pam_try};
@@ -0,0 +1,209 @@
+//! Declarative responses from hooks.
+
+use std::fmt;
+
+use crate::constants::PamResultCode;
+use crate::conv::{Conv, Prompt};
+use crate::items::ItemType;
+use crate::module::{to_c_string, PamHandle, PamResult};
+
+/// Stores a value of some type as module data, under the given key.
+pub type StoreData = Box<dyn FnOnce(&mut PamHandle, &str) -> PamResult<()>>;
+
+/// A change to make to the PAM handle, as part of a [`ModuleResult`].
+pub enum Effect {
+    /// Sets a string item.
+    SetItem(ItemType, String),
+    /// Stores module data under the key, with a function that stores it.
+    SetData(String, StoreData),
+    /// Changes the PAM environment, like [`PamHandle::putenv`].
+    PutEnv(String),
+    /// Sends a message through the conversation.
+    Message(Prompt),
+}
+
+impl fmt::Debug for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Effect::SetItem(item_type, value) => f
+                .debug_tuple("SetItem")
+                .field(item_type)
+                .field(value)
+                .finish(),
+            Effect::SetData(key, _) => f.debug_tuple("SetData").field(key).finish_non_exhaustive(),
+            Effect::PutEnv(entry) => f.debug_tuple("PutEnv").field(entry).finish(),
+            Effect::Message(prompt) => f.debug_tuple("Message").field(prompt).finish(),
+        }
+    }
+}
+
+/// The result of a hook, along with the changes it makes to the PAM handle.
+///
+/// Rather than changing the handle as it goes, a hook can describe the
+/// changes it wants, and have them made all at once when it returns.
+/// The changes can be inspected (e.g., in tests) with [`effects`](Self::effects).
+///
+/// ```no_run
+/// # use pam::constants::{PamFlag, PamResultCode};
+/// # use pam::items::ItemType;
+/// # use pam::module::PamHandle;
+/// # use pam::response::ModuleResult;
+/// # use std::ffi::CStr;
+/// fn decide(name: &str) -> ModuleResult {
+///     match name.split_once('@') {
+///         Some((user, realm)) => ModuleResult::new(PamResultCode::PAM_SUCCESS)
+///             .set_item(ItemType::User, user)
+///             .putenv(format!("KRB5REALM={realm}")),
+///         None => ModuleResult::new(PamResultCode::PAM_USER_UNKNOWN)
+///             .error("Please give your user name as user@REALM."),
+///     }
+/// }
+///
+/// fn sm_authenticate(pamh: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+///     match pamh.get_user(None) {
+///         Ok(name) => decide(&name).apply(pamh),
+///         Err(e) => e,
+///     }
+/// }
+/// ```
+#[derive(Debug)]
+#[must_use = "a ModuleResult does nothing until it is applied"]
+pub struct ModuleResult {
+    code: PamResultCode,
+    effects: Vec<Effect>,
+}
+
+impl ModuleResult {
+    /// Starts a result that will return `code`.
+    pub fn new(code: PamResultCode) -> Self {
+        Self {
+            code,
+            effects: Vec::new(),
+        }
+    }
+
+    /// Sets the string item `item_type` to `value`.
+    pub fn set_item(self, item_type: ItemType, value: impl Into<String>) -> Self {
+        self.with(Effect::SetItem(item_type, value.into()))
+    }
+
+    /// Stores `data` under `key`, like [`PamHandle::set_data`].
+    pub fn set_data<T: 'static>(self, key: impl Into<String>, data: T) -> Self {
+        let store = move |pamh: &mut PamHandle, key: &str| pamh.set_data(key, Box::new(data));
+        self.with(Effect::SetData(key.into(), Box::new(store)))
+    }
+
+    /// Changes the PAM environment, like [`PamHandle::putenv`].
+    pub fn putenv(self, name_value: impl Into<String>) -> Self {
+        self.with(Effect::PutEnv(name_value.into()))
+    }
+
+    /// Sends a `PAM_TEXT_INFO` message.
+    pub fn info(self, msg: impl Into<String>) -> Self {
+        self.with(Effect::Message(Prompt::Info(msg.into())))
+    }
+
+    /// Sends a `PAM_ERROR_MSG` message.
+    pub fn error(self, msg: impl Into<String>) -> Self {
+        self.with(Effect::Message(Prompt::Error(msg.into())))
+    }
+
+    /// Adds an effect.
+    pub fn with(mut self, effect: Effect) -> Self {
+        self.effects.push(effect);
+        self
+    }
+
+    /// The code that will be returned if every effect succeeds.
+    pub fn code(&self) -> PamResultCode {
+        self.code
+    }
+
+    /// The effects, in the order they will be applied.
+    pub fn effects(&self) -> &[Effect] {
+        &self.effects
+    }
+
+    /// Makes each change in order, and returns the result code.
+    ///
+    /// If a change fails, the rest are skipped, and its error is returned
+    /// instead.  Changes already made are not undone.
+    pub fn apply(self, pamh: &mut PamHandle) -> PamResultCode {
+        for effect in self.effects {
+            if let Err(e) = apply(pamh, effect) {
+                return e;
+            }
+        }
+        self.code
+    }
+}
+
+fn apply(pamh: &mut PamHandle, effect: Effect) -> PamResult<()> {
+    match effect {
+        Effect::SetItem(item_type, value) => pamh.set_string_item(item_type, &to_c_string(&value)?),
+        Effect::SetData(key, store) => store(pamh, &key),
+        Effect::PutEnv(entry) => pamh.putenv(&entry),
+        Effect::Message(prompt) => Conv::from_borrowed(pamh)?
+            .ok_or(PamResultCode::PAM_CONV_ERR)?
+            .send(&prompt)
+            .map(drop),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::items::User;
+    use crate::mock::MockPam;
+
+    #[test]
+    fn effects_apply_in_order() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        let result = ModuleResult::new(PamResultCode::PAM_SUCCESS)
+            .set_item(ItemType::User, "alice")
+            .putenv("A=1")
+            .set_item(ItemType::User, "bob")
+            .putenv("A")
+            .putenv("B=2")
+            .set_data("count", 7u32);
+        assert_eq!(6, result.effects().len());
+        assert!(matches!(
+            result.effects()[0],
+            Effect::SetItem(ItemType::User, ref name) if name == "alice"
+        ));
+
+        assert_eq!(PamResultCode::PAM_SUCCESS, result.apply(pamh));
+        assert_eq!(c"bob", *pamh.get_item::<User>().unwrap().unwrap());
+        assert_eq!(
+            vec![("B".to_owned(), "2".to_owned())],
+            pamh.getenvlist().unwrap()
+        );
+        assert_eq!(Ok(&7), unsafe { pamh.get_data::<u32>("count") });
+    }
+
+    #[test]
+    fn first_failure_stops() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        let result = ModuleResult::new(PamResultCode::PAM_SUCCESS)
+            .putenv("A=1")
+            .info("no conversation is installed")
+            .putenv("B=2");
+        assert_eq!(PamResultCode::PAM_CONV_ERR, result.apply(pamh));
+        assert_eq!(
+            vec![("A".to_owned(), "1".to_owned())],
+            pamh.getenvlist().unwrap()
+        );
+
+        let result = ModuleResult::new(PamResultCode::PAM_AUTH_ERR)
+            .putenv("C")
+            .putenv("D=4");
+        assert_eq!(PamResultCode::PAM_BAD_ITEM, result.apply(pamh));
+        assert_eq!(1, pamh.getenvlist().unwrap().len());
+
+        let result = ModuleResult::new(PamResultCode::PAM_AUTH_ERR).putenv("D=4");
+        assert_eq!(PamResultCode::PAM_AUTH_ERR, result.apply(pamh));
+        assert_eq!(2, pamh.getenvlist().unwrap().len());
+    }
+}
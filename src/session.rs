@@ -0,0 +1,162 @@
+//! Helpers for writing session modules.
+
+use crate::constants::PamResultCode;
+use crate::module::PamHandle;
+
+/// Makes sure that `sm_close_session` only undoes a session that
+/// `sm_open_session` actually opened.
+///
+/// Applications don't always call the session functions in pairs, so a session
+/// module may be asked to close a session it never opened, or to close one
+/// twice.  A `SessionGuard` records whether a session is open as module data
+/// under its key, and skips the close function (returning `PAM_SUCCESS`)
+/// when there is nothing to close.
+///
+/// ```no_run
+/// # use pam::constants::{PamFlag, PamResultCode};
+/// # use pam::module::{PamHandle, PamHooks};
+/// # use pam::session::SessionGuard;
+/// # use std::ffi::CStr;
+/// # fn mount_home(_: &mut PamHandle) -> PamResultCode { PamResultCode::PAM_SUCCESS }
+/// # fn unmount_home(_: &mut PamHandle) -> PamResultCode { PamResultCode::PAM_SUCCESS }
+/// struct HomeMounter;
+///
+/// impl PamHooks for HomeMounter {
+///     fn sm_open_session(pamh: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+///         SessionGuard::default().open(pamh, mount_home)
+///     }
+///
+///     fn sm_close_session(pamh: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+///         SessionGuard::default().close(pamh, unmount_home)
+///     }
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionGuard {
+    key: &'static str,
+}
+
+impl SessionGuard {
+    /// The data key used by [`SessionGuard::default`].
+    pub const DEFAULT_KEY: &'static str = "nonstick.session_guard";
+
+    /// Creates a guard that stores its marker under `key`.
+    ///
+    /// The key must not be used to store any other data.  Modules that keep
+    /// track of more than one kind of session need a separate key for each.
+    pub const fn new(key: &'static str) -> Self {
+        Self { key }
+    }
+
+    /// Calls `open`, and records the session as open if it succeeds.
+    pub fn open(
+        &self,
+        pamh: &mut PamHandle,
+        open: impl FnOnce(&mut PamHandle) -> PamResultCode,
+    ) -> PamResultCode {
+        let result = open(pamh);
+        if result.is_success() {
+            if let Err(e) = pamh.set_data(self.key, Box::new(true)) {
+                return e;
+            }
+        }
+        result
+    }
+
+    /// Calls `close` if a session is open, and records the session as closed
+    /// if it succeeds.  If there is no open session, returns `PAM_SUCCESS`.
+    pub fn close(
+        &self,
+        pamh: &mut PamHandle,
+        close: impl FnOnce(&mut PamHandle) -> PamResultCode,
+    ) -> PamResultCode {
+        if !self.is_open(pamh) {
+            return PamResultCode::PAM_SUCCESS;
+        }
+        let result = close(pamh);
+        if result.is_success() {
+            if let Err(e) = pamh.set_data(self.key, Box::new(false)) {
+                return e;
+            }
+        }
+        result
+    }
+
+    /// True if a session was opened through this guard and not yet closed.
+    pub fn is_open(&self, pamh: &PamHandle) -> bool {
+        // SAFETY: Only this guard stores data under its key, and it always stores a bool.
+        unsafe { pamh.get_data::<bool>(self.key) }.is_ok_and(|&open| open)
+    }
+}
+
+impl Default for SessionGuard {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_KEY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockPam;
+
+    #[test]
+    fn open_then_close() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        let guard = SessionGuard::default();
+        let mut closes = 0;
+
+        assert_eq!(
+            PamResultCode::PAM_SUCCESS,
+            guard.open(pamh, |_| PamResultCode::PAM_SUCCESS)
+        );
+        assert!(guard.is_open(pamh));
+        for _ in 0..2 {
+            let result = guard.close(pamh, |_| {
+                closes += 1;
+                PamResultCode::PAM_SUCCESS
+            });
+            assert_eq!(PamResultCode::PAM_SUCCESS, result);
+        }
+        assert_eq!(1, closes);
+        assert!(!guard.is_open(pamh));
+    }
+
+    #[test]
+    fn close_without_open() {
+        let mut mock = MockPam::new();
+        let result = SessionGuard::default().close(mock.handle(), |_| {
+            panic!("nothing to close");
+        });
+        assert_eq!(PamResultCode::PAM_SUCCESS, result);
+    }
+
+    #[test]
+    fn failed_open() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        let guard = SessionGuard::new("failing");
+        assert_eq!(
+            PamResultCode::PAM_SESSION_ERR,
+            guard.open(pamh, |_| PamResultCode::PAM_SESSION_ERR)
+        );
+        assert!(!guard.is_open(pamh));
+    }
+
+    #[test]
+    fn failed_close_stays_open() {
+        let mut mock = MockPam::new();
+        let pamh = mock.handle();
+        let guard = SessionGuard::default();
+        assert_eq!(
+            PamResultCode::PAM_SUCCESS,
+            guard.open(pamh, |_| PamResultCode::PAM_SUCCESS)
+        );
+        assert_eq!(
+            PamResultCode::PAM_SESSION_ERR,
+            guard.close(pamh, |_| PamResultCode::PAM_SESSION_ERR)
+        );
+        assert!(guard.is_open(pamh));
+    }
+}
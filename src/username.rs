@@ -0,0 +1,137 @@
+//! Consistent normalization of user names across a stack.
+
+use crate::constants::PamResultCode;
+use crate::module::PamResult;
+
+/// How to turn the name a user typed into the name a module looks up.
+///
+/// If each module in a stack cleans up names its own way, one may find
+/// `Alice` where another finds no such user.  A policy is applied in this
+/// order, and only does what it is built to do:
+///
+/// 1. [`trim`](Self::trim) whitespace from both ends,
+/// 2. [`strip_realm`](Self::strip_realm), a suffix like `@EXAMPLE.COM`,
+/// 3. [`lowercase`](Self::lowercase) the name,
+/// 4. [`reject_chars`](Self::reject_chars) that may not appear in it.
+///
+/// An empty name is always rejected.
+///
+/// ```
+/// use pam::username::NamePolicy;
+///
+/// let policy = NamePolicy::new()
+///     .trim()
+///     .strip_realm("@EXAMPLE.COM")
+///     .lowercase()
+///     .reject_chars("/:");
+/// assert_eq!(Ok("alice".to_owned()), policy.normalize(" Alice@example.com "));
+/// assert!(policy.normalize("../alice").is_err());
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NamePolicy {
+    trim: bool,
+    realm: Option<String>,
+    lowercase: bool,
+    rejected: Option<String>,
+}
+
+impl NamePolicy {
+    /// A policy that leaves names as they are.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes whitespace from both ends of the name.
+    pub fn trim(mut self) -> Self {
+        self.trim = true;
+        self
+    }
+
+    /// Removes `realm` from the end of the name, if it is there, ignoring
+    /// ASCII case.  Include the separator, as in `@EXAMPLE.COM`.
+    pub fn strip_realm(mut self, realm: impl Into<String>) -> Self {
+        self.realm = Some(realm.into());
+        self
+    }
+
+    /// Converts the name to lowercase.
+    pub fn lowercase(mut self) -> Self {
+        self.lowercase = true;
+        self
+    }
+
+    /// Rejects names containing any of `chars`, as well as control characters.
+    pub fn reject_chars(mut self, chars: &str) -> Self {
+        self.rejected
+            .get_or_insert_with(String::new)
+            .push_str(chars);
+        self
+    }
+
+    /// Applies the policy to `name`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `PAM_USER_UNKNOWN` if the name is rejected.
+    pub fn normalize(&self, name: &str) -> PamResult<String> {
+        let mut name = if self.trim { name.trim() } else { name };
+        if let Some(realm) = &self.realm {
+            let split = name.len().checked_sub(realm.len());
+            if let Some(split) = split.filter(|&split| name.is_char_boundary(split)) {
+                if name[split..].eq_ignore_ascii_case(realm) {
+                    name = &name[..split];
+                }
+            }
+        }
+        let name = if self.lowercase {
+            name.to_lowercase()
+        } else {
+            name.to_owned()
+        };
+        let rejected = |c: char| match &self.rejected {
+            Some(rejected) => c.is_control() || rejected.contains(c),
+            None => false,
+        };
+        if name.is_empty() || name.chars().any(rejected) {
+            return Err(PamResultCode::PAM_USER_UNKNOWN);
+        }
+        Ok(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_transform() {
+        let unknown = Err(PamResultCode::PAM_USER_UNKNOWN);
+        let name = " Alice@Example.COM ";
+        assert_eq!(Ok(name.to_owned()), NamePolicy::new().normalize(name));
+        assert_eq!(
+            Ok("Alice@Example.COM".to_owned()),
+            NamePolicy::new().trim().normalize(name)
+        );
+
+        let realm = NamePolicy::new().strip_realm("@EXAMPLE.COM");
+        assert_eq!(Ok("Alice".to_owned()), realm.normalize("Alice@example.com"));
+        assert_eq!(Ok("bob@OTHER".to_owned()), realm.normalize("bob@OTHER"));
+        assert_eq!(unknown, realm.normalize("@EXAMPLE.COM"));
+        assert_eq!(Ok("é".to_owned()), realm.normalize("é"));
+
+        let lower = NamePolicy::new().lowercase();
+        assert_eq!(Ok("ölaf".to_owned()), lower.normalize("ÖLAF"));
+    }
+
+    #[test]
+    fn rejection() {
+        let unknown = Err(PamResultCode::PAM_USER_UNKNOWN);
+        let policy = NamePolicy::new().trim().reject_chars("/:");
+        assert_eq!(Ok("alice".to_owned()), policy.normalize("alice\n"));
+        assert_eq!(unknown, policy.normalize("../alice"));
+        assert_eq!(unknown, policy.normalize("ali:ce"));
+        assert_eq!(unknown, policy.normalize("ali\tce"));
+        assert_eq!(unknown, policy.normalize("   "));
+        assert_eq!(unknown, NamePolicy::new().normalize(""));
+    }
+}
@@ -0,0 +1,141 @@
+//! Small utilities for module authors.
+
+/// Compares two byte strings in time that depends only on their lengths,
+/// not on where they differ.
+///
+/// Use this rather than `==` when comparing a token the user provided with
+/// a secret (a one-time code, an API key), so that an attacker can't guess
+/// the secret byte by byte by timing failed attempts.  The comparison does
+/// reveal whether the lengths match, so compare fixed-length values (like
+/// hashes) where the length is sensitive.
+///
+/// ```
+/// use pam::util::constant_time_eq;
+///
+/// assert!(constant_time_eq(b"123456", b"123456"));
+/// assert!(!constant_time_eq(b"123456", b"123457"));
+/// ```
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a
+        .iter()
+        .zip(b)
+        .fold(0u8, |diff, (x, y)| std::hint::black_box(diff | (x ^ y)));
+    diff == 0
+}
+
+/// Overwrites `bytes` with zeros, in a way the compiler won't optimize
+/// away even if `bytes` is about to be freed.
+pub(crate) fn zero_bytes(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        unsafe { std::ptr::write_volatile(byte, 0) };
+    }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+/// A string, like a password, that is zeroed when it is dropped.
+///
+/// The contents are only available through [`expose`](Self::expose), so
+/// that every use of them stands out, and `Debug` doesn't show them.
+/// Comparisons with `==` take constant time, as with [`constant_time_eq`].
+///
+/// Only this copy is zeroed.  Don't copy the contents into a `String` of
+/// your own; anything that grew the string while it held them, or moved
+/// them elsewhere, may have left copies behind that can't be zeroed.
+///
+/// ```
+/// use pam::util::Secret;
+///
+/// let token = Secret::new("hunter2".to_owned());
+/// assert_eq!("Secret(..)", format!("{token:?}"));
+/// assert_eq!("hunter2", token.expose());
+/// ```
+#[derive(Clone, Default)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Takes ownership of `secret`, to zero it when dropped.
+    pub fn new(secret: String) -> Self {
+        Self(secret)
+    }
+
+    /// The secret itself.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(secret: String) -> Self {
+        Self::new(secret)
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        constant_time_eq(self.0.as_bytes(), other.0.as_bytes())
+    }
+}
+
+impl Eq for Secret {}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        // Zero bytes are valid UTF-8, so the string stays a string.
+        zero_bytes(unsafe { self.0.as_bytes_mut() });
+        #[cfg(test)]
+        DROPPED_SECRETS.with(|dropped| dropped.borrow_mut().push(self.0.clone().into_bytes()));
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Every secret, as it was just before being freed.
+    pub(crate) static DROPPED_SECRETS: std::cell::RefCell<Vec<Vec<u8>>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_and_unequal() {
+        assert!(constant_time_eq(b"", b""));
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+
+    #[test]
+    fn zeroing() {
+        let mut bytes = *b"hunter2";
+        zero_bytes(&mut bytes);
+        assert_eq!([0; 7], bytes);
+
+        let secret = Secret::from("hunter2".to_owned());
+        assert_eq!(secret, Secret::new("hunter2".to_owned()));
+        assert_ne!(secret, Secret::new("hunter3".to_owned()));
+        assert_eq!("Secret(..)", format!("{secret:?}"));
+    }
+
+    #[test]
+    fn every_position_counts() {
+        let secret = [0x5a; 64];
+        for i in 0..secret.len() {
+            for bit in 0..8 {
+                let mut guess = secret;
+                guess[i] ^= 1 << bit;
+                assert!(!constant_time_eq(&secret, &guess), "byte {i}, bit {bit}");
+            }
+        }
+    }
+}
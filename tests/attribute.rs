@@ -0,0 +1,87 @@
+//! Builds modules with the `#[pam_hooks]` attribute.
+
+use libc::{c_char, c_int};
+use pam::constants::{PamFlag, PamResultCode};
+use pam::module::{PamHandle, PamHooks};
+use std::ffi::CStr;
+use std::ptr::{self, NonNull};
+
+/// A module that only implements account management.
+mod account {
+    use super::*;
+    use pam::attr::pam_hooks;
+
+    pub struct Expired;
+
+    #[pam_hooks(panic_code = PAM_ACCT_EXPIRED)]
+    impl PamHooks for Expired {
+        fn acct_mgmt(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+            panic!("no expiry data")
+        }
+    }
+}
+
+/// A module with no hooks of its own, which exports everything.
+mod defaults {
+    use super::*;
+    use pam::attr::pam_hooks;
+
+    pub struct Nothing;
+
+    #[pam_hooks]
+    impl PamHooks for Nothing {}
+}
+
+/// A module that names its groups.
+mod chosen {
+    use super::*;
+
+    pub struct Chosen;
+
+    #[pam::attr::pam_hooks(groups = [auth, password])]
+    impl pam::module::PamHooks for Chosen {
+        fn sm_authenticate(_: &mut PamHandle, _: Vec<&CStr>, _: PamFlag) -> PamResultCode {
+            PamResultCode::PAM_SUCCESS
+        }
+    }
+}
+
+#[test]
+fn exports_implemented_groups() {
+    assert_eq!(&["pam_sm_acct_mgmt"], account::PAM_HOOKS_EXPORTED);
+    assert_eq!(
+        &[
+            "pam_sm_authenticate",
+            "pam_sm_setcred",
+            "pam_sm_acct_mgmt",
+            "pam_sm_open_session",
+            "pam_sm_close_session",
+            "pam_sm_chauthtok",
+        ],
+        defaults::PAM_HOOKS_EXPORTED
+    );
+    assert_eq!(
+        &["pam_sm_authenticate", "pam_sm_setcred", "pam_sm_chauthtok"],
+        chosen::PAM_HOOKS_EXPORTED
+    );
+}
+
+#[test]
+fn entrypoints_call_hooks() {
+    type Entrypoint =
+        extern "C" fn(&mut PamHandle, PamFlag, c_int, *const *const c_char) -> PamResultCode;
+    let entrypoints: [(Entrypoint, PamResultCode); 3] = [
+        (chosen::pam_sm_authenticate, PamResultCode::PAM_SUCCESS),
+        (chosen::pam_sm_setcred, PamResultCode::PAM_IGNORE),
+        (chosen::pam_sm_chauthtok, PamResultCode::PAM_IGNORE),
+    ];
+    for (entrypoint, expected) in entrypoints {
+        // The hooks never touch the handle, and since `PamHandle` has no
+        // size, a dangling pointer to one is a valid reference.
+        let pamh = unsafe { NonNull::<PamHandle>::dangling().as_mut() };
+        assert_eq!(
+            expected,
+            entrypoint(pamh, PamFlag::default(), 0, ptr::null())
+        );
+    }
+}
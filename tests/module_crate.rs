@@ -0,0 +1,60 @@
+//! Builds the `allow_all` example module as part of a test crate.
+//!
+//! Under `cfg(test)`, `pam_hooks!` doesn't export its entrypoints, so they
+//! can't clash with the symbols of libpam or of other modules.
+
+#[path = "../examples/allow_all.rs"]
+mod allow_all;
+
+use libc::{c_char, c_int};
+use pam::constants::{PamFlag, PamResultCode};
+use pam::module::PamHandle;
+use std::ptr::{self, NonNull};
+
+type Entrypoint =
+    extern "C" fn(&mut PamHandle, PamFlag, c_int, *const *const c_char) -> PamResultCode;
+
+#[test]
+fn entrypoints_are_plain_functions() {
+    let entrypoints: [(&str, Entrypoint, PamResultCode); 6] = [
+        (
+            "pam_sm_authenticate",
+            allow_all::pam_sm_authenticate,
+            PamResultCode::PAM_SUCCESS,
+        ),
+        (
+            "pam_sm_setcred",
+            allow_all::pam_sm_setcred,
+            PamResultCode::PAM_IGNORE,
+        ),
+        (
+            "pam_sm_acct_mgmt",
+            allow_all::pam_sm_acct_mgmt,
+            PamResultCode::PAM_SUCCESS,
+        ),
+        (
+            "pam_sm_open_session",
+            allow_all::pam_sm_open_session,
+            PamResultCode::PAM_IGNORE,
+        ),
+        (
+            "pam_sm_close_session",
+            allow_all::pam_sm_close_session,
+            PamResultCode::PAM_IGNORE,
+        ),
+        (
+            "pam_sm_chauthtok",
+            allow_all::pam_sm_chauthtok,
+            PamResultCode::PAM_IGNORE,
+        ),
+    ];
+    let names: Vec<&str> = entrypoints.iter().map(|(name, ..)| *name).collect();
+    assert_eq!(allow_all::PAM_HOOKS_EXPORTED, names);
+    for (name, entrypoint, expected) in entrypoints {
+        // The hooks never touch the handle, and since `PamHandle` has no
+        // size, a dangling pointer to one is a valid reference.
+        let pamh = unsafe { NonNull::<PamHandle>::dangling().as_mut() };
+        let result = entrypoint(pamh, PamFlag::default(), 0, ptr::null());
+        assert_eq!(expected, result, "{name}");
+    }
+}
@@ -0,0 +1,25 @@
+//! Checks that the crate still builds as `no_std` without the `std` feature.
+//!
+//! This runs a separate `cargo build`, with its own target directory so that
+//! it doesn't wait on the lock held by the build running this test.
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn builds_without_std() {
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--lib", "--no-default-features", "--manifest-path"])
+        .arg(manifest_dir.join("Cargo.toml"))
+        .env(
+            "CARGO_TARGET_DIR",
+            manifest_dir.join("target").join("no_std"),
+        )
+        .status()
+        .expect("couldn't run cargo");
+    assert!(
+        status.success(),
+        "`cargo build --no-default-features` failed"
+    );
+}